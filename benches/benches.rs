@@ -1,7 +1,11 @@
-use std::{hint::black_box, net::Ipv4Addr, time::Duration};
+use std::{
+    hint::black_box,
+    net::{IpAddr, Ipv4Addr},
+    time::Duration,
+};
 
 use criterion::{criterion_group, criterion_main, Criterion, Throughput};
-use leroyjenkins::{Args, Leroy};
+use leroyjenkins::Leroy;
 use mimalloc::MiMalloc;
 
 #[global_allocator]
@@ -9,20 +13,15 @@ static GLOBAL: MiMalloc = MiMalloc;
 
 fn make_leroy() -> Leroy {
     black_box(
-        Leroy::new(Args {
-            bl_threshold: 10,
-            bl_period: Duration::from_secs(5),
-            ipset_base_time: Duration::from_secs(30),
-            ipset_ban_ttl: Duration::from_secs(60 * 60),
-            ipset_ipv4_name: "leroy4".to_owned(),
-            ipset_ipv6_name: "leroy6".to_owned(),
-            reporting_ip_time_period: Duration::from_secs(1),
-            reporting_ban_time_period: Duration::from_secs(1),
-            cache_initial_capacity: 100000,
-            cache_max_size: 500000,
-            dry_run: true,
-        })
-        .unwrap(),
+        Leroy::builder()
+            .ipsets("leroy4", "leroy6")
+            .rate_limit(10, Duration::from_secs(5))
+            .ban_time(Duration::from_secs(30))
+            .recidivism_ttl(Duration::from_secs(60 * 60))
+            .cache_size(100000, 500000)
+            .dry_run(true)
+            .build()
+            .unwrap(),
     )
 }
 
@@ -33,7 +32,15 @@ fn dry_run(c: &mut Criterion) {
     group.bench_function("single_ipv4", |b| {
         let mut leroy = make_leroy();
         b.iter(|| {
-            leroy.handle_line(&black_box(b"142.250.185.142".to_vec()));
+            leroy.handle_line(black_box(b"142.250.185.142"));
+        })
+    });
+
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single_ipv4_parsed", |b| {
+        let mut leroy = make_leroy();
+        b.iter(|| {
+            leroy.handle_ip(black_box(IpAddr::V4(Ipv4Addr::new(142, 250, 185, 142))));
         })
     });
 
@@ -41,7 +48,7 @@ fn dry_run(c: &mut Criterion) {
     group.bench_function("single_ipv6", |b| {
         let mut leroy = make_leroy();
         b.iter(|| {
-            leroy.handle_line(&black_box(b"2a00:1450:4001:813::200e".to_vec()));
+            leroy.handle_line(black_box(b"2a00:1450:4001:813::200e"));
         })
     });
 
@@ -49,11 +56,26 @@ fn dry_run(c: &mut Criterion) {
     group.bench_function("hammer_few_ips", |b| {
         let mut leroy = make_leroy();
         b.iter(|| {
-            leroy.handle_line(&black_box(b"2001:41d0:307:b200::".to_vec()));
-            leroy.handle_line(&black_box(b"54.38.164.114".to_vec()));
-            leroy.handle_line(&black_box(b"152.228.187.173".to_vec()));
-            leroy.handle_line(&black_box(b"54.38.164.114".to_vec()));
-            leroy.handle_line(&black_box(b"54.38.164.114".to_vec()));
+            leroy.handle_line(black_box(b"2001:41d0:307:b200::"));
+            leroy.handle_line(black_box(b"54.38.164.114"));
+            leroy.handle_line(black_box(b"152.228.187.173"));
+            leroy.handle_line(black_box(b"54.38.164.114"));
+            leroy.handle_line(black_box(b"54.38.164.114"));
+        })
+    });
+
+    group.throughput(Throughput::Elements(5));
+    group.bench_function("hammer_few_ips_batch", |b| {
+        let mut leroy = make_leroy();
+        let lines: [&[u8]; 5] = [
+            b"2001:41d0:307:b200::",
+            b"54.38.164.114",
+            b"152.228.187.173",
+            b"54.38.164.114",
+            b"54.38.164.114",
+        ];
+        b.iter(|| {
+            leroy.handle_lines(black_box(lines));
         })
     });
 
@@ -0,0 +1,123 @@
+use std::{
+    error::Error,
+    fs,
+    path::Path,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use serde::Serialize;
+
+use crate::{event_log::BanCategory, masked_ip::MaskedIpAddr};
+
+#[derive(Serialize, Debug)]
+pub struct AbuseReport {
+    pub ip: MaskedIpAddr,
+    pub category: BanCategory,
+    pub recidivism: u32,
+    pub timeout: u32,
+}
+
+pub struct AbuseIpDb {
+    key: String,
+    /// See https://www.abuseipdb.com/categories
+    categories: String,
+}
+
+impl AbuseIpDb {
+    pub fn new(key_file: &Path, categories: &[u8]) -> Result<AbuseIpDb, Box<dyn Error>> {
+        let key = fs::read_to_string(key_file)
+            .map_err(|err| format!("Failed to read AbuseIPDB key {key_file:?}: {err}"))?;
+        Ok(AbuseIpDb {
+            key: key.trim().to_owned(),
+            categories: categories
+                .iter()
+                .map(u8::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+        })
+    }
+}
+
+/// Reports banned IPs to AbuseIPDB and/or a generic threat intelligence
+/// API from a background thread. Strictly best-effort: reports are dropped
+/// when the API is slow or the rate limit is exhausted.
+pub struct AbuseReporter {
+    sender: SyncSender<AbuseReport>,
+}
+
+impl AbuseReporter {
+    /// The generic API receives each report as a JSON object. At most
+    /// `max_per_day` reports are sent.
+    pub fn new(
+        abuseipdb: Option<AbuseIpDb>,
+        generic_url: Option<String>,
+        max_per_day: u32,
+    ) -> std::io::Result<AbuseReporter> {
+        let (sender, receiver) = mpsc::sync_channel(100);
+        let interval = Duration::from_secs(24 * 60 * 60) / max_per_day.max(1);
+        thread::Builder::new()
+            .name("abuse-report".to_owned())
+            .spawn(move || send_reports(receiver, abuseipdb, generic_url, interval))?;
+        Ok(AbuseReporter { sender })
+    }
+
+    pub fn report(&self, report: AbuseReport) {
+        match self.sender.try_send(report) {
+            Ok(()) => {}
+            Err(TrySendError::Full(report)) => {
+                debug!("Not reporting {}, too many pending reports", report.ip)
+            }
+            Err(TrySendError::Disconnected(_)) => warn!("Abuse reporter has stopped"),
+        }
+    }
+}
+
+fn send_reports(
+    receiver: Receiver<AbuseReport>,
+    abuseipdb: Option<AbuseIpDb>,
+    generic_url: Option<String>,
+    interval: Duration,
+) {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(Duration::from_secs(10))
+        .build();
+    for report in receiver {
+        // AbuseIPDB only takes single addresses.
+        if let Some(abuseipdb) = abuseipdb.as_ref().filter(|_| report.ip.is_single_addr()) {
+            let comment = format!(
+                "Banned for {}s by leroyjenkins ({:?}, recidivism {})",
+                report.timeout, report.category, report.recidivism
+            );
+            let result = agent
+                .post("https://api.abuseipdb.com/api/v2/report")
+                .set("Key", &abuseipdb.key)
+                .set("Accept", "application/json")
+                .send_form(&[
+                    ("ip", &report.ip.addr().to_string()),
+                    ("categories", &abuseipdb.categories),
+                    ("comment", &comment),
+                ]);
+            if let Err(err) = result {
+                warn!("Failed to report {} to AbuseIPDB: {err}", report.ip);
+            }
+        }
+        if let Some(ref url) = generic_url {
+            let result = serde_json::to_string(&report)
+                .map_err(|err| err.to_string())
+                .and_then(|body| {
+                    agent
+                        .post(url)
+                        .set("Content-Type", "application/json")
+                        .send_string(&body)
+                        .map_err(|err| err.to_string())
+                });
+            if let Err(err) = result {
+                warn!("Failed to report {} to {url}: {err}", report.ip);
+            }
+        }
+        thread::sleep(interval);
+    }
+}
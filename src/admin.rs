@@ -0,0 +1,496 @@
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::{UnixListener, UnixStream},
+    },
+    path::Path,
+    str::FromStr,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{ban_reason::BanReason, event_log::BanCategory, masked_ip::MaskedIpAddr};
+
+/// How long a connection waits for the main loop to answer.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AdminCommand {
+    /// Ban with the given duration, or as if it were a first offense.
+    Ban(MaskedIpAddr, Option<Duration>),
+    Unban(MaskedIpAddr),
+    Query(MaskedIpAddr),
+    /// Active bans with their expiry.
+    List,
+    /// Whether the daemon is paused, and some key numbers.
+    Status,
+    Stats,
+    /// Ignore input lines until resumed.
+    Pause,
+    Resume,
+    /// Stop adding bans to the sets, see --monitor-only.
+    Monitor,
+    Enforce,
+    /// The in-memory state as JSON, optionally only for keys overlapping a
+    /// network.
+    Dump(Option<MaskedIpAddr>),
+    /// Remove all bans of this instance from the sets.
+    Flush,
+    /// Wide bans waiting for approval, see --approve-wide-bans.
+    Pending,
+    Approve(u64),
+    Reject(u64),
+}
+
+impl FromStr for AdminCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AdminCommand, String> {
+        let mut words = s.split_whitespace();
+        let command = words.next().ok_or("empty command")?;
+        let mut ip =
+            || -> Result<MaskedIpAddr, String> { words.next().ok_or("missing ip")?.parse() };
+        let id = |id: Option<&str>| -> Result<u64, String> {
+            let id = id.ok_or("missing id")?;
+            id.parse().map_err(|_| format!("invalid id {id:?}"))
+        };
+        let command = match command {
+            "ban" => {
+                let ip = ip()?;
+                let duration = words
+                    .next()
+                    .map(|duration| {
+                        duration
+                            .parse::<humantime::Duration>()
+                            .map_err(|err| format!("invalid duration {duration:?}: {err}"))
+                    })
+                    .transpose()?;
+                AdminCommand::Ban(ip, duration.map(Into::into))
+            }
+            "unban" => AdminCommand::Unban(ip()?),
+            "query" => AdminCommand::Query(ip()?),
+            "list" => AdminCommand::List,
+            "status" => AdminCommand::Status,
+            "stats" => AdminCommand::Stats,
+            "pause" => AdminCommand::Pause,
+            "resume" => AdminCommand::Resume,
+            "monitor" => AdminCommand::Monitor,
+            "enforce" => AdminCommand::Enforce,
+            "dump" => AdminCommand::Dump(words.next().map(str::parse).transpose()?),
+            "flush" => AdminCommand::Flush,
+            "pending" => AdminCommand::Pending,
+            "approve" => AdminCommand::Approve(id(words.next())?),
+            "reject" => AdminCommand::Reject(id(words.next())?),
+            _ => return Err(format!("unknown command {command:?}")),
+        };
+        match words.next() {
+            Some(extra) => Err(format!("unexpected argument {extra:?}")),
+            None => Ok(command),
+        }
+    }
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminCommand::Ban(ip, None) => write!(f, "ban {ip}"),
+            AdminCommand::Ban(ip, Some(duration)) => {
+                write!(f, "ban {ip} {}", humantime::format_duration(*duration))
+            }
+            AdminCommand::Unban(ip) => write!(f, "unban {ip}"),
+            AdminCommand::Query(ip) => write!(f, "query {ip}"),
+            AdminCommand::List => f.write_str("list"),
+            AdminCommand::Status => f.write_str("status"),
+            AdminCommand::Stats => f.write_str("stats"),
+            AdminCommand::Pause => f.write_str("pause"),
+            AdminCommand::Resume => f.write_str("resume"),
+            AdminCommand::Monitor => f.write_str("monitor"),
+            AdminCommand::Enforce => f.write_str("enforce"),
+            AdminCommand::Dump(None) => f.write_str("dump"),
+            AdminCommand::Dump(Some(ip)) => write!(f, "dump {ip}"),
+            AdminCommand::Flush => f.write_str("flush"),
+            AdminCommand::Pending => f.write_str("pending"),
+            AdminCommand::Approve(id) => write!(f, "approve {id}"),
+            AdminCommand::Reject(id) => write!(f, "reject {id}"),
+        }
+    }
+}
+
+/// A point in time, shown in RFC 3339 format.
+#[derive(Debug, Copy, Clone)]
+pub struct Timestamp(pub SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", humantime::format_rfc3339_seconds(self.0))
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Timestamp, D::Error> {
+        humantime::parse_rfc3339(&String::deserialize(deserializer)?)
+            .map(Timestamp)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IpStatus {
+    pub ip: MaskedIpAddr,
+    /// The latest expiry of the bans that cover it, if known.
+    pub banned_until: Option<Timestamp>,
+    /// Whether it is banned, possibly as part of a subnet or asn ban.
+    pub banned: bool,
+    pub previous_bans: u32,
+    pub last_ban: Option<Timestamp>,
+    /// The reason of the last ban.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_reason: Option<BanReason>,
+    pub allowlisted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ActiveBan {
+    pub ip: MaskedIpAddr,
+    pub expires: Timestamp,
+    pub remaining_seconds: u64,
+    /// The reason of the latest ban of the address, if it is still known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BanReason>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DaemonStatus {
+    pub paused: bool,
+    pub monitor_only: bool,
+    pub uptime_seconds: u64,
+    pub lines: u64,
+    pub active_bans: u64,
+    pub attack_mode: bool,
+}
+
+/// How many more events a key of a rate limiter can have before it is
+/// rate limited.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Budget {
+    pub key: String,
+    pub remaining: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RateLimiterDump {
+    pub name: String,
+    pub budgets: Vec<Budget>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecidivismDump {
+    pub ip: MaskedIpAddr,
+    pub count: u32,
+    pub last_ban: Timestamp,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BanReason>,
+}
+
+/// A subnet or ASN ban waiting for approval, see --approve-wide-bans.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PendingBan {
+    pub id: u64,
+    pub category: BanCategory,
+    /// The autonomous system of an ASN ban.
+    pub asn: Option<u32>,
+    pub networks: Vec<MaskedIpAddr>,
+    /// The reason of the ban that led to it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BanReason>,
+    /// When the request is dropped unless approved.
+    pub expires: Timestamp,
+}
+
+impl PendingBan {
+    /// What would be banned, like `AS64496 (12 networks)` or `192.0.2.0/24`.
+    pub fn target(&self) -> String {
+        match self.asn {
+            Some(asn) => format!("AS{asn} ({} networks)", self.networks.len()),
+            None => self
+                .networks
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+}
+
+/// A snapshot of the in-memory state, to find out why an address was or was
+/// not banned.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StateDump {
+    pub rate_limiters: Vec<RateLimiterDump>,
+    pub recidivism: Vec<RecidivismDump>,
+    pub bans: Vec<ActiveBan>,
+}
+
+/// The answer to an [`AdminCommand`], as text on the admin socket and as
+/// JSON over HTTP.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum AdminReply {
+    Message { message: String },
+    Error { error: String },
+    Status(IpStatus),
+    Daemon(DaemonStatus),
+    Bans { bans: Vec<ActiveBan> },
+    Stats { stats: Vec<String> },
+    Dump(StateDump),
+    Pending { pending: Vec<PendingBan> },
+}
+
+impl AdminReply {
+    pub fn message(message: impl Into<String>) -> AdminReply {
+        AdminReply::Message {
+            message: message.into(),
+        }
+    }
+
+    pub fn error(error: impl Into<String>) -> AdminReply {
+        AdminReply::Error {
+            error: error.into(),
+        }
+    }
+}
+
+impl fmt::Display for AdminReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminReply::Message { message } => f.write_str(message),
+            AdminReply::Error { error } => write!(f, "error: {error}"),
+            AdminReply::Status(status) => {
+                write!(f, "{}: ", status.ip)?;
+                match status.banned_until {
+                    Some(banned_until) => write!(f, "banned until {banned_until}")?,
+                    None if status.banned => f.write_str("banned with its subnet or asn")?,
+                    None => f.write_str("not banned")?,
+                }
+                match status.last_ban {
+                    Some(last_ban) => write!(
+                        f,
+                        ", {} previous bans, last at {last_ban}",
+                        status.previous_bans
+                    )?,
+                    None => f.write_str(", no previous bans")?,
+                }
+                if let Some(reason) = status.last_reason {
+                    write!(f, " for {reason}")?;
+                }
+                if status.allowlisted {
+                    f.write_str(", allowlisted")?;
+                }
+                Ok(())
+            }
+            AdminReply::Daemon(status) => write!(
+                f,
+                "{}{}, up for {}, {} lines, {} active bans, attack mode {}",
+                if status.paused { "paused" } else { "running" },
+                if status.monitor_only {
+                    " (monitor only)"
+                } else {
+                    ""
+                },
+                humantime::format_duration(Duration::from_secs(status.uptime_seconds)),
+                status.lines,
+                status.active_bans,
+                if status.attack_mode { "on" } else { "off" }
+            ),
+            AdminReply::Bans { bans } if bans.is_empty() => f.write_str("no active bans"),
+            AdminReply::Bans { bans } => {
+                for (i, ban) in bans.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("\n")?;
+                    }
+                    write!(
+                        f,
+                        "{} until {} ({}s)",
+                        ban.ip, ban.expires, ban.remaining_seconds
+                    )?;
+                    if let Some(reason) = ban.reason {
+                        write!(f, " for {reason}")?;
+                    }
+                }
+                Ok(())
+            }
+            AdminReply::Stats { stats } => f.write_str(&stats.join("\n")),
+            AdminReply::Dump(dump) => {
+                let json = serde_json::to_string_pretty(dump).map_err(|_| fmt::Error)?;
+                f.write_str(&json)
+            }
+            AdminReply::Pending { pending } if pending.is_empty() => f.write_str("no pending bans"),
+            AdminReply::Pending { pending } => {
+                for (i, ban) in pending.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("\n")?;
+                    }
+                    write!(f, "#{} {} ban of {}", ban.id, ban.category, ban.target())?;
+                    if let Some(reason) = ban.reason {
+                        write!(f, " for {reason}")?;
+                    }
+                    write!(f, ", expires {}", ban.expires)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub struct AdminRequest {
+    pub command: AdminCommand,
+    pub reply: SyncSender<AdminReply>,
+}
+
+/// Queues admin requests for the main loop, which owns all state. The main
+/// loop polls [`AdminQueue::as_raw_fd`] along with its input, so that
+/// requests are answered even while no lines arrive.
+pub struct AdminQueue {
+    sender: SyncSender<AdminRequest>,
+    receiver: Receiver<AdminRequest>,
+    wakeup_sender: UnixStream,
+    wakeup_receiver: UnixStream,
+}
+
+impl AdminQueue {
+    pub fn new() -> io::Result<AdminQueue> {
+        let (sender, receiver) = mpsc::sync_channel(64);
+        let (wakeup_sender, wakeup_receiver) = UnixStream::pair()?;
+        wakeup_receiver.set_nonblocking(true)?;
+        Ok(AdminQueue {
+            sender,
+            receiver,
+            wakeup_sender,
+            wakeup_receiver,
+        })
+    }
+
+    /// A handle for other threads to submit requests.
+    pub fn client(&self) -> io::Result<AdminClient> {
+        Ok(AdminClient {
+            sender: self.sender.clone(),
+            wakeup: self.wakeup_sender.try_clone()?,
+        })
+    }
+
+    /// Returns the requests that are waiting to be handled.
+    pub fn take_requests(&mut self) -> Vec<AdminRequest> {
+        let mut buf = [0; 64];
+        while matches!(self.wakeup_receiver.read(&mut buf), Ok(n) if n > 0) {}
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl AsRawFd for AdminQueue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup_receiver.as_raw_fd()
+    }
+}
+
+pub struct AdminClient {
+    sender: SyncSender<AdminRequest>,
+    wakeup: UnixStream,
+}
+
+impl AdminClient {
+    pub fn try_clone(&self) -> io::Result<AdminClient> {
+        Ok(AdminClient {
+            sender: self.sender.clone(),
+            wakeup: self.wakeup.try_clone()?,
+        })
+    }
+
+    /// Submits the command to the main loop and waits for the reply.
+    pub fn request(&mut self, command: AdminCommand) -> Result<AdminReply, String> {
+        let (reply, reply_receiver) = mpsc::sync_channel(1);
+        self.sender
+            .send(AdminRequest { command, reply })
+            .map_err(|_| "shutting down")?;
+        self.wakeup
+            .write_all(&[0])
+            .map_err(|err| format!("failed to wake up main loop: {err}"))?;
+        reply_receiver
+            .recv_timeout(REPLY_TIMEOUT)
+            .map_err(|_| "timed out waiting for main loop".to_owned())
+    }
+}
+
+/// Accepts commands on a unix socket, one per line. Each reply is followed
+/// by an empty line.
+pub fn serve(listener: UnixListener, queue: &AdminQueue) -> io::Result<()> {
+    if let Some(path) = listener.local_addr()?.as_pathname() {
+        info!("Accepting admin commands on {path:?}");
+    }
+    let client = queue.client()?;
+    thread::Builder::new()
+        .name("admin".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    let client = client.try_clone()?;
+                    thread::Builder::new()
+                        .name("admin-connection".to_owned())
+                        .spawn(move || {
+                            if let Err(err) = handle_connection(stream, client) {
+                                debug!("Admin connection failed: {err}");
+                            }
+                        })
+                        .map(drop)
+                });
+                if let Err(err) = result {
+                    warn!("Failed to accept admin connection: {err}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Sends a command to the admin socket of a running daemon, and returns the
+/// reply.
+pub fn request(path: &Path, command: &AdminCommand) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{command}")?;
+    let mut reply = String::new();
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.is_empty() {
+            break;
+        }
+        reply.push_str(&line);
+        reply.push('\n');
+    }
+    Ok(reply)
+}
+
+fn handle_connection(stream: UnixStream, mut client: AdminClient) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = line
+            .parse()
+            .and_then(|command| client.request(command))
+            .unwrap_or_else(AdminReply::error);
+        write!(writer, "{reply}\n\n")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info, warn};
+use serde_json::json;
+
+use crate::{
+    admin::{AdminClient, AdminCommand, AdminReply},
+    masked_ip::MaskedIpAddr,
+};
+
+/// The most bytes of request line and headers that are read, before the
+/// token is checked.
+const MAX_REQUEST_BYTES: u64 = 8192;
+
+/// How many requests are handled at once. Further connections are closed
+/// right away.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Serves the admin commands as a JSON API, for requests with
+/// `Authorization: Bearer <token>`:
+///
+/// - `GET /bans` lists active bans.
+/// - `GET /bans/<ip>` queries an address or network.
+/// - `PUT /bans/<ip>?duration=<duration>` bans manually.
+/// - `DELETE /bans/<ip>` unbans.
+/// - `DELETE /bans` removes all bans of this instance.
+/// - `GET /status`, `GET /stats`, `POST /pause` and `POST /resume`.
+/// - `POST /monitor` and `POST /enforce` toggle monitor-only mode.
+/// - `GET /state` and `GET /state/<ip>` dump the in-memory state.
+pub fn serve(listener: TcpListener, token: String, client: AdminClient) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving admin API on http://{addr}/");
+    let limit = ConnectionLimit::new(MAX_CONNECTIONS);
+    thread::Builder::new()
+        .name("admin-http".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| {
+                    let Some(slot) = limit.acquire() else {
+                        debug!("Closed admin API connection, too many open");
+                        return Ok(());
+                    };
+                    let mut client = client.try_clone()?;
+                    let token = token.clone();
+                    thread::Builder::new()
+                        .name("admin-http-connection".to_owned())
+                        .spawn(move || {
+                            if let Err(err) = respond(stream, &token, &mut client) {
+                                debug!("Admin API error: {err}");
+                            }
+                            drop(slot);
+                        })
+                        .map(drop)
+                });
+                if let Err(err) = result {
+                    warn!("Failed to accept admin API connection: {err}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, token: &str, client: &mut AdminClient) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut authorized = false;
+    let mut complete = false;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            break;
+        }
+        if header.trim().is_empty() {
+            complete = true;
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("authorization") {
+                authorized = value
+                    .trim()
+                    .strip_prefix("Bearer ")
+                    .is_some_and(|candidate| constant_time_eq(candidate, token));
+            }
+        }
+    }
+
+    let (status, body) = if !complete {
+        (
+            "400 Bad Request",
+            json!({ "error": format!("request incomplete or over {MAX_REQUEST_BYTES} bytes") }),
+        )
+    } else if !authorized {
+        ("401 Unauthorized", json!({ "error": "unauthorized" }))
+    } else {
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default();
+        let target = parts.next().unwrap_or_default();
+        match route(method, target) {
+            Ok(command) => match client.request(command) {
+                Ok(reply @ AdminReply::Error { .. }) => ("409 Conflict", json!(reply)),
+                Ok(reply) => ("200 OK", json!(reply)),
+                Err(err) => ("503 Service Unavailable", json!({ "error": err })),
+            },
+            Err((status, err)) => (status, json!({ "error": err })),
+        }
+    };
+
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+fn route(method: &str, target: &str) -> Result<AdminCommand, (&'static str, String)> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let bad_request = |err: String| ("400 Bad Request", err);
+    match (method, path) {
+        ("GET", "/bans") => Ok(AdminCommand::List),
+        ("GET", "/status") => Ok(AdminCommand::Status),
+        ("GET", "/stats") => Ok(AdminCommand::Stats),
+        ("POST", "/pause") => Ok(AdminCommand::Pause),
+        ("POST", "/resume") => Ok(AdminCommand::Resume),
+        ("POST", "/monitor") => Ok(AdminCommand::Monitor),
+        ("POST", "/enforce") => Ok(AdminCommand::Enforce),
+        ("GET", "/state") => Ok(AdminCommand::Dump(None)),
+        ("DELETE", "/bans") => Ok(AdminCommand::Flush),
+        ("GET", "/pending") => Ok(AdminCommand::Pending),
+        ("GET", path) if path.starts_with("/state/") => Ok(AdminCommand::Dump(Some(
+            parse_ip(&path["/state/".len()..]).map_err(bad_request)?,
+        ))),
+        (_, path) if path.starts_with("/bans/") => {
+            let ip = parse_ip(&path["/bans/".len()..]).map_err(bad_request)?;
+            match method {
+                "GET" => Ok(AdminCommand::Query(ip)),
+                "PUT" => {
+                    let duration = query
+                        .split('&')
+                        .find_map(|param| param.strip_prefix("duration="))
+                        .map(|duration| {
+                            duration
+                                .parse::<humantime::Duration>()
+                                .map_err(|err| bad_request(format!("invalid duration: {err}")))
+                        })
+                        .transpose()?;
+                    Ok(AdminCommand::Ban(ip, duration.map(Into::into)))
+                }
+                "DELETE" => Ok(AdminCommand::Unban(ip)),
+                _ => Err(("405 Method Not Allowed", format!("{method} not allowed"))),
+            }
+        }
+        (_, path) if path.starts_with("/pending/") => {
+            let id = &path["/pending/".len()..];
+            let id = id
+                .parse()
+                .map_err(|_| bad_request(format!("invalid id {id:?}")))?;
+            match method {
+                "POST" => Ok(AdminCommand::Approve(id)),
+                "DELETE" => Ok(AdminCommand::Reject(id)),
+                _ => Err(("405 Method Not Allowed", format!("{method} not allowed"))),
+            }
+        }
+        _ => Err(("404 Not Found", format!("{path} not found"))),
+    }
+}
+
+fn parse_ip(s: &str) -> Result<MaskedIpAddr, String> {
+    s.replace("%2F", "/").replace("%2f", "/").parse()
+}
+
+/// Bounds the connections that are handled at once, each on a thread of its
+/// own, so that clients can not run the process out of threads.
+#[derive(Clone)]
+pub(crate) struct ConnectionLimit {
+    open: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub(crate) fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            open: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Returns `None` if `max` connections are open already. The
+    /// connection counts until the slot is dropped.
+    pub(crate) fn acquire(&self) -> Option<ConnectionSlot> {
+        self.open
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |open| {
+                (open < self.max).then_some(open + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(Arc::clone(&self.open)))
+    }
+}
+
+pub(crate) struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Compares without returning early, so that response times do not reveal
+/// how much of the token was guessed correctly.
+pub(crate) fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
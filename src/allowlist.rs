@@ -0,0 +1,73 @@
+use std::{
+    collections::HashSet, error::Error, fs, hash::BuildHasherDefault, net::IpAddr, path::Path,
+};
+
+use rustc_hash::FxHasher;
+
+use crate::{
+    ip_family::{ByIpFamily, IpFamily},
+    masked_ip::MaskedIpAddr,
+};
+
+/// Addresses and networks that must never be rate limited or banned.
+#[derive(Default)]
+pub struct Allowlist {
+    entries: HashSet<MaskedIpAddr, BuildHasherDefault<FxHasher>>,
+    prefix_lens: ByIpFamily<Vec<u8>>,
+}
+
+impl Allowlist {
+    /// Reads one address or CIDR network per line. Empty lines and
+    /// everything after `#` are ignored.
+    pub fn from_file(path: &Path) -> Result<Allowlist, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read allowlist {path:?}: {err}"))?;
+        let mut allowlist = Allowlist::default();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let entry = line
+                .parse::<MaskedIpAddr>()
+                .map_err(|err| format!("{}:{}: {err}", path.display(), index + 1))?;
+            allowlist.insert(entry);
+        }
+        Ok(allowlist)
+    }
+
+    fn insert(&mut self, entry: MaskedIpAddr) {
+        let prefix_lens = self.prefix_lens.by_family_mut(entry.family());
+        if !prefix_lens.contains(&entry.prefix_len()) {
+            prefix_lens.push(entry.prefix_len());
+        }
+        self.entries.insert(entry);
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &MaskedIpAddr> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Tests if the address is allowlisted. Costs one hash lookup per
+    /// distinct prefix length in the allowlist.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.prefix_lens
+            .by_family(IpFamily::from_ipv4(ip.is_ipv4()))
+            .iter()
+            .any(|prefix_len| self.entries.contains(&MaskedIpAddr::new(ip, *prefix_len)))
+    }
+
+    /// Tests if any allowlisted address is within the network (or the other
+    /// way around).
+    pub fn overlaps(&self, net: &MaskedIpAddr) -> bool {
+        if net.is_single_addr() {
+            self.contains(net.addr())
+        } else {
+            self.entries.iter().any(|entry| entry.overlaps(net))
+        }
+    }
+}
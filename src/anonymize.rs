@@ -0,0 +1,204 @@
+use std::{
+    borrow::Cow,
+    fmt, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{error::LeroyError, masked_ip::MaskedIpAddr};
+
+/// The networks that addresses are truncated to.
+const TRUNCATE_PREFIX_V4: u8 = 24;
+const TRUNCATE_PREFIX_V6: u8 = 48;
+
+/// How addresses are shown in logs and reports with --anonymize-ips.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpAnonymization {
+    /// The /24 or /48 network, like `192.0.2.0/24`.
+    Truncate,
+    /// A keyed hash, like `anon-5c1f0e2b9a7d4e36`, the same for the same
+    /// address and --anonymize-key-file.
+    Hash,
+}
+
+/// An address as shown by an [`IpAnonymizer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnonymizedIp {
+    Truncated(MaskedIpAddr),
+    Hashed(u64),
+}
+
+impl fmt::Display for AnonymizedIp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnonymizedIp::Truncated(net) => net.fmt(f),
+            AnonymizedIp::Hashed(hash) => write!(f, "anon-{hash:016x}"),
+        }
+    }
+}
+
+/// Hides addresses in logs and reports, see --anonymize-ips. Bans are still
+/// made with the real addresses.
+#[derive(Debug, Clone)]
+pub struct IpAnonymizer {
+    mode: IpAnonymization,
+    key: [u64; 2],
+}
+
+impl IpAnonymizer {
+    /// Returns `None` without a `mode`. Hashing needs a `key_file` with a
+    /// secret, so that the same address gets the same hash across restarts
+    /// until the secret is changed.
+    pub fn open(
+        mode: Option<IpAnonymization>,
+        key_file: Option<&Path>,
+    ) -> Result<Option<IpAnonymizer>, LeroyError> {
+        let Some(mode) = mode else {
+            return Ok(None);
+        };
+        let key = match (mode, key_file) {
+            (_, Some(path)) => {
+                let secret = fs::read_to_string(path).map_err(|err| {
+                    LeroyError::Config(format!(
+                        "Failed to read --anonymize-key-file {path:?}: {err}"
+                    ))
+                })?;
+                let secret = secret.trim();
+                if secret.is_empty() {
+                    return Err(format!("--anonymize-key-file {path:?} is empty").into());
+                }
+                [
+                    siphash([0, 0], secret.as_bytes()),
+                    siphash([0, 1], secret.as_bytes()),
+                ]
+            }
+            (IpAnonymization::Hash, None) => {
+                return Err("--anonymize-ips hash needs --anonymize-key-file".into());
+            }
+            (IpAnonymization::Truncate, None) => [0, 0],
+        };
+        Ok(Some(IpAnonymizer { mode, key }))
+    }
+
+    pub fn anonymize(&self, net: MaskedIpAddr) -> AnonymizedIp {
+        match self.mode {
+            IpAnonymization::Truncate => {
+                let prefix_len = match net.addr() {
+                    IpAddr::V4(_) => TRUNCATE_PREFIX_V4,
+                    IpAddr::V6(_) => TRUNCATE_PREFIX_V6,
+                };
+                AnonymizedIp::Truncated(MaskedIpAddr::new(
+                    net.addr(),
+                    net.prefix_len().min(prefix_len),
+                ))
+            }
+            IpAnonymization::Hash => {
+                let mut bytes = Vec::with_capacity(18);
+                match net.addr() {
+                    IpAddr::V4(addr) => bytes.extend(addr.octets()),
+                    IpAddr::V6(addr) => bytes.extend(addr.octets()),
+                }
+                bytes.push(net.prefix_len());
+                AnonymizedIp::Hashed(siphash(self.key, &bytes))
+            }
+        }
+    }
+
+    /// Replaces the addresses and networks in free text, like a log
+    /// message, including addresses with a port.
+    pub fn anonymize_text<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let is_addr_char = |c: char| c.is_ascii_hexdigit() || matches!(c, '.' | ':' | '/');
+        let mut result = String::new();
+        let mut copied = 0;
+        let mut rest = text;
+        while let Some(start) = rest.find(is_addr_char) {
+            let offset = text.len() - rest.len() + start;
+            let len = rest[start..]
+                .find(|c| !is_addr_char(c))
+                .unwrap_or(rest.len() - start);
+            let token = &text[offset..offset + len];
+            rest = &rest[start + len..];
+            // Tried with surrounding punctuation first, because of
+            // `2001:db8::` and `::1`.
+            let Some((candidate, net, port)) = [
+                token,
+                token.trim_end_matches(['.', ':']),
+                token.trim_matches(['.', ':']),
+            ]
+            .into_iter()
+            .find_map(|candidate| parse_token(candidate).map(|(net, port)| (candidate, net, port))) else {
+                continue;
+            };
+            let start = offset + (candidate.as_ptr() as usize - token.as_ptr() as usize);
+            result.push_str(&text[copied..start]);
+            result.push_str(&self.anonymize(net).to_string());
+            if let Some(port) = port {
+                result.push_str(port);
+            }
+            copied = start + candidate.len();
+        }
+        if copied == 0 {
+            return Cow::Borrowed(text);
+        }
+        result.push_str(&text[copied..]);
+        Cow::Owned(result)
+    }
+}
+
+/// An address or network, or an IPv4 address with a port, which is kept.
+fn parse_token(token: &str) -> Option<(MaskedIpAddr, Option<&str>)> {
+    if !token.contains(['.', ':']) {
+        return None;
+    }
+    if let Ok(net) = token.parse() {
+        return Some((net, None));
+    }
+    let (addr, port) = token.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    Some((
+        MaskedIpAddr::from(IpAddr::V4(addr.parse::<Ipv4Addr>().ok()?)),
+        Some(&token[addr.len()..]),
+    ))
+}
+
+/// SipHash-2-4, the keyed hash that `std` uses for `HashMap`, but with a
+/// stable output.
+fn siphash(key: [u64; 2], data: &[u8]) -> u64 {
+    let mut v = [
+        key[0] ^ 0x736f_6d65_7073_6575,
+        key[1] ^ 0x646f_7261_6e64_6f6d,
+        key[0] ^ 0x6c79_6765_6e65_7261,
+        key[1] ^ 0x7465_6462_7974_6573,
+    ];
+    let rounds = |v: &mut [u64; 4], n: usize| {
+        for _ in 0..n {
+            v[0] = v[0].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(13) ^ v[0];
+            v[0] = v[0].rotate_left(32);
+            v[2] = v[2].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(16) ^ v[2];
+            v[0] = v[0].wrapping_add(v[3]);
+            v[3] = v[3].rotate_left(21) ^ v[0];
+            v[2] = v[2].wrapping_add(v[1]);
+            v[1] = v[1].rotate_left(17) ^ v[2];
+            v[2] = v[2].rotate_left(32);
+        }
+    };
+    let mut chunks = data.chunks_exact(8);
+    let mut last = [0; 8];
+    last[..chunks.remainder().len()].copy_from_slice(chunks.remainder());
+    last[7] = data.len() as u8;
+    for m in chunks.by_ref().chain([last.as_slice()]) {
+        let m = u64::from_le_bytes(m.try_into().unwrap_or_default());
+        v[3] ^= m;
+        rounds(&mut v, 2);
+        v[0] ^= m;
+    }
+    v[2] ^= 0xff;
+    rounds(&mut v, 4);
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
@@ -0,0 +1,148 @@
+use std::time::{Duration, Instant};
+
+use crate::{
+    admin::{PendingBan, Timestamp},
+    ban_reason::BanReason,
+    clock::Clock,
+    event_log::BanCategory,
+    masked_ip::MaskedIpAddr,
+};
+
+/// How many wide bans can wait for approval at once. Further ones are
+/// dropped, and queued at their next event over the limit if there is room
+/// by then.
+const MAX_PENDING: usize = 1000;
+
+struct Pending {
+    ban: PendingBan,
+    deadline: Instant,
+    /// Rejected bans are kept until their deadline, so that they are not
+    /// queued again right away.
+    rejected: bool,
+}
+
+/// What [`ApprovalQueue::queue`] did with a wide ban.
+pub enum Queued {
+    New(PendingBan),
+    /// Already waiting for approval, or rejected recently.
+    Known,
+    Full,
+}
+
+/// Subnet and ASN bans waiting for approval through the admin API, see
+/// --approve-wide-bans. Requests that are neither approved nor rejected
+/// expire after --approval-timeout.
+pub struct ApprovalQueue {
+    pending: Vec<Pending>,
+    next_id: u64,
+    clock: Clock,
+}
+
+impl ApprovalQueue {
+    pub fn new(clock: Clock) -> ApprovalQueue {
+        ApprovalQueue {
+            pending: Vec::new(),
+            next_id: 1,
+            clock,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn queue(
+        &mut self,
+        category: BanCategory,
+        asn: Option<u32>,
+        networks: Vec<MaskedIpAddr>,
+        reason: Option<BanReason>,
+        timeout: Duration,
+    ) -> Queued {
+        if self
+            .pending
+            .iter()
+            .any(|pending| pending.ban.asn == asn && pending.ban.networks == networks)
+        {
+            return Queued::Known;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            return Queued::Full;
+        }
+        let ban = PendingBan {
+            id: self.next_id,
+            category,
+            asn,
+            networks,
+            reason,
+            expires: Timestamp(self.clock.system_now() + timeout),
+        };
+        self.next_id += 1;
+        self.pending.push(Pending {
+            ban: ban.clone(),
+            deadline: self.clock.now() + timeout,
+            rejected: false,
+        });
+        Queued::New(ban)
+    }
+
+    /// The bans waiting for approval, oldest first.
+    pub fn list(&self) -> Vec<PendingBan> {
+        self.pending
+            .iter()
+            .filter(|pending| !pending.rejected)
+            .map(|pending| pending.ban.clone())
+            .collect()
+    }
+
+    /// Queues a ban from [`ApprovalQueue::list`] of another queue, keeping
+    /// its id and expiry, for example after a handover.
+    pub fn restore(&mut self, ban: PendingBan) {
+        let remaining = ban
+            .expires
+            .0
+            .duration_since(self.clock.system_now())
+            .unwrap_or_default();
+        self.next_id = self.next_id.max(ban.id + 1);
+        self.pending.push(Pending {
+            deadline: self.clock.now() + remaining,
+            ban,
+            rejected: false,
+        });
+    }
+
+    /// Removes the ban from the queue, to be applied.
+    pub fn approve(&mut self, id: u64) -> Option<PendingBan> {
+        let index = self
+            .pending
+            .iter()
+            .position(|pending| pending.ban.id == id && !pending.rejected)?;
+        Some(self.pending.remove(index).ban)
+    }
+
+    pub fn reject(&mut self, id: u64) -> Option<PendingBan> {
+        let pending = self
+            .pending
+            .iter_mut()
+            .find(|pending| pending.ban.id == id && !pending.rejected)?;
+        pending.rejected = true;
+        Some(pending.ban.clone())
+    }
+
+    /// Removes and returns the bans that were not approved in time. Rejected
+    /// ones are dropped silently.
+    pub fn expire(&mut self) -> Vec<PendingBan> {
+        let now = self.clock.now();
+        let mut expired = Vec::new();
+        self.pending.retain(|pending| {
+            if now < pending.deadline {
+                return true;
+            }
+            if !pending.rejected {
+                expired.push(pending.ban.clone());
+            }
+            false
+        });
+        expired
+    }
+}
@@ -0,0 +1,127 @@
+use std::{
+    error::Error,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+};
+
+use crate::{
+    ip_family::{ByIpFamily, IpFamily},
+    masked_ip::MaskedIpAddr,
+};
+
+struct AsnRange {
+    start: u128,
+    end: u128,
+    asn: u32,
+}
+
+/// Maps addresses to the autonomous system announcing them.
+pub struct AsnDatabase {
+    /// Sorted and non-overlapping.
+    ranges: ByIpFamily<Vec<AsnRange>>,
+}
+
+impl AsnDatabase {
+    /// Reads an ip2asn TSV file (as published by https://iptoasn.com/) with
+    /// lines like `range_start range_end as_number country description`.
+    /// Unrouted ranges (AS 0) are skipped.
+    pub fn from_file(path: &Path) -> Result<AsnDatabase, Box<dyn Error>> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("Failed to read ASN database {path:?}: {err}"))?;
+        let mut ranges = ByIpFamily::<Vec<AsnRange>>::default();
+        for (index, line) in content.lines().enumerate() {
+            let invalid = || format!("{}:{}: invalid ip2asn line", path.display(), index + 1);
+            let mut fields = line.split('\t');
+            let (Some(start), Some(end), Some(asn)) = (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(invalid().into());
+            };
+            let (Ok(start), Ok(end), Ok(asn)) = (
+                start.parse::<IpAddr>(),
+                end.parse::<IpAddr>(),
+                asn.parse::<u32>(),
+            ) else {
+                return Err(invalid().into());
+            };
+            if asn == 0 {
+                continue;
+            }
+            let range = match (start, end) {
+                (IpAddr::V4(start), IpAddr::V4(end)) if start <= end => AsnRange {
+                    start: u128::from(start.to_bits()),
+                    end: u128::from(end.to_bits()),
+                    asn,
+                },
+                (IpAddr::V6(start), IpAddr::V6(end)) if start <= end => AsnRange {
+                    start: start.to_bits(),
+                    end: end.to_bits(),
+                    asn,
+                },
+                _ => return Err(invalid().into()),
+            };
+            ranges
+                .by_family_mut(IpFamily::from_ipv4(start.is_ipv4()))
+                .push(range);
+        }
+        for family in [IpFamily::V4, IpFamily::V6] {
+            ranges
+                .by_family_mut(family)
+                .sort_unstable_by_key(|range| range.start);
+        }
+        Ok(AsnDatabase { ranges })
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let (ranges, ip) = match ip {
+            IpAddr::V4(ip) => (&self.ranges.ipv4, u128::from(ip.to_bits())),
+            IpAddr::V6(ip) => (&self.ranges.ipv6, ip.to_bits()),
+        };
+        let index = ranges
+            .partition_point(|range| range.start <= ip)
+            .checked_sub(1)?;
+        let range = &ranges[index];
+        (ip <= range.end).then_some(range.asn)
+    }
+
+    /// All networks announced by the autonomous system. This scans the
+    /// entire database, so it should only be used for rare decisions.
+    pub fn prefixes(&self, asn: u32) -> Vec<MaskedIpAddr> {
+        let mut prefixes = Vec::new();
+        for family in [IpFamily::V4, IpFamily::V6] {
+            for range in self.ranges.by_family(family) {
+                if range.asn == asn {
+                    push_range_prefixes(&mut prefixes, family, range.start, range.end);
+                }
+            }
+        }
+        prefixes
+    }
+}
+
+/// Splits an address range into the smallest set of aligned networks.
+fn push_range_prefixes(prefixes: &mut Vec<MaskedIpAddr>, family: IpFamily, start: u128, end: u128) {
+    let bits = match family {
+        IpFamily::V4 => 32,
+        IpFamily::V6 => 128,
+    };
+    let host_mask = |host_bits: u32| u128::MAX.checked_shr(128 - host_bits).unwrap_or(0);
+    let mut start = start;
+    loop {
+        let mut host_bits = start.trailing_zeros().min(bits);
+        while start | host_mask(host_bits) > end {
+            host_bits -= 1;
+        }
+        let addr = match family {
+            IpFamily::V4 => IpAddr::V4(Ipv4Addr::from_bits(start as u32)),
+            IpFamily::V6 => IpAddr::V6(Ipv6Addr::from_bits(start)),
+        };
+        prefixes.push(MaskedIpAddr::new(addr, (bits - host_bits) as u8));
+
+        let last = start | host_mask(host_bits);
+        if last >= end {
+            break;
+        }
+        start = last + 1;
+    }
+}
@@ -0,0 +1,83 @@
+use std::{
+    io,
+    net::IpAddr,
+    sync::mpsc::{SendError, TrySendError},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt},
+    sync::oneshot,
+    task,
+};
+
+use crate::{handle::Input, AdminCommand, AdminReply, LeroyHandle, MaskedIpAddr};
+
+/// For use within a tokio runtime. While the owning thread is behind, these
+/// wait without blocking the runtime.
+impl LeroyHandle {
+    /// Like [`LeroyHandle::handle_line`].
+    pub async fn send_line(&self, line: &[u8]) -> Result<(), SendError<()>> {
+        self.send_async(Input::from_line(line)).await
+    }
+
+    /// Like [`LeroyHandle::handle_ip`].
+    pub async fn send_ip(&self, ip: IpAddr) -> Result<(), SendError<()>> {
+        self.send_async(Input::Ip(ip, None, None, None)).await
+    }
+
+    /// Sends every line of `reader`, until it ends.
+    pub async fn send_lines(&self, mut reader: impl AsyncBufRead + Unpin) -> io::Result<()> {
+        let mut line = Vec::with_capacity(40);
+        loop {
+            line.clear();
+            if reader.read_until(b'\n', &mut line).await? == 0 {
+                return Ok(());
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            self.send_line(&line)
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Leroy has stopped"))?;
+        }
+    }
+
+    /// Runs an admin command, like those of --admin-socket.
+    pub async fn command(&self, command: AdminCommand) -> Result<AdminReply, SendError<()>> {
+        let (sender, receiver) = oneshot::channel();
+        let reply = Box::new(move |reply| {
+            let _ = sender.send(reply);
+        });
+        self.send_async(Input::Command(command, reply)).await?;
+        receiver.await.map_err(|_| SendError(()))
+    }
+
+    /// Bans manually, by default like a first offense.
+    pub async fn ban(
+        &self,
+        ip: MaskedIpAddr,
+        duration: Option<Duration>,
+    ) -> Result<AdminReply, SendError<()>> {
+        self.command(AdminCommand::Ban(ip, duration)).await
+    }
+
+    /// Removes a ban and resets the rate limits.
+    pub async fn unban(&self, ip: MaskedIpAddr) -> Result<AdminReply, SendError<()>> {
+        self.command(AdminCommand::Unban(ip)).await
+    }
+
+    async fn send_async(&self, input: Input) -> Result<(), SendError<()>> {
+        match self.sender.try_send(input) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(input)) => {
+                let sender = self.sender.clone();
+                task::spawn_blocking(move || sender.send(input))
+                    .await
+                    .map_err(|_| SendError(()))?
+                    .map_err(|_| SendError(()))
+            }
+            Err(TrySendError::Disconnected(_)) => Err(SendError(())),
+        }
+    }
+}
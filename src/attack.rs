@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+
+use crate::clock::Clock;
+
+/// Detects attacks from the overall line and ban rates. Attack mode is
+/// entered when either rate exceeds its trip point within a window, and left
+/// only once both rates drop below half their trip points, so that it does
+/// not flap around the trip point.
+pub struct AttackDetector {
+    trip_lines: Option<u64>,
+    trip_bans: Option<u64>,
+    window: Duration,
+    window_start: Instant,
+    lines: u64,
+    bans: u64,
+    under_attack: bool,
+    clock: Clock,
+}
+
+impl AttackDetector {
+    pub fn new(
+        trip_lines: Option<u64>,
+        trip_bans: Option<u64>,
+        window: Duration,
+        clock: Clock,
+    ) -> AttackDetector {
+        AttackDetector {
+            trip_lines,
+            trip_bans,
+            window,
+            window_start: clock.now(),
+            lines: 0,
+            bans: 0,
+            under_attack: false,
+            clock,
+        }
+    }
+
+    /// Changes the trip points, keeping the current state.
+    pub fn set_trip_points(
+        &mut self,
+        trip_lines: Option<u64>,
+        trip_bans: Option<u64>,
+        window: Duration,
+    ) {
+        self.trip_lines = trip_lines;
+        self.trip_bans = trip_bans;
+        self.window = window;
+    }
+
+    pub fn under_attack(&self) -> bool {
+        self.under_attack
+    }
+
+    pub fn record_line(&mut self) {
+        self.lines += 1;
+    }
+
+    pub fn record_ban(&mut self) {
+        self.bans += 1;
+    }
+
+    pub fn maybe_update(&mut self) {
+        if self.clock.elapsed(self.window_start) < self.window {
+            return;
+        }
+
+        let divisor = if self.under_attack { 2 } else { 1 };
+        let exceeds =
+            |count: u64, trip: Option<u64>| trip.is_some_and(|trip| count > trip / divisor);
+        let under_attack =
+            exceeds(self.lines, self.trip_lines) || exceeds(self.bans, self.trip_bans);
+
+        if under_attack != self.under_attack {
+            if under_attack {
+                warn!(
+                    "Entering attack mode: {} lines and {} bans in the past {:?}",
+                    self.lines,
+                    self.bans,
+                    self.clock.elapsed(self.window_start)
+                );
+            } else {
+                info!(
+                    "Leaving attack mode: {} lines and {} bans in the past {:?}",
+                    self.lines,
+                    self.bans,
+                    self.clock.elapsed(self.window_start)
+                );
+            }
+            self.under_attack = under_attack;
+        }
+
+        self.lines = 0;
+        self.bans = 0;
+        self.window_start = self.clock.now();
+    }
+}
@@ -0,0 +1,226 @@
+use std::{error::Error, net::IpAddr, thread, time::Duration};
+
+use log::debug;
+
+pub use self::netlink::MacSet;
+use crate::{error::is_permission_error, mac::MacAddr, masked_ip::MaskedIpAddr, LeroyError};
+
+pub type BackendError = Box<dyn Error + Send + Sync>;
+
+/// How often a failed add is retried, unless it was not permitted. The
+/// delay before the first retry doubles with every retry, so that a failing
+/// set holds up each ban for at most 7ms.
+const ADD_RETRIES: u32 = 3;
+const ADD_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Where the bans of one address family go, usually an ipset.
+pub trait Backend {
+    /// Whether the address or network is in the set.
+    fn test(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError>;
+
+    /// Adds the address or network for `timeout` seconds. Returns `false`
+    /// if it was already in the set.
+    fn add(&mut self, net: MaskedIpAddr, timeout: u32) -> Result<bool, BackendError>;
+
+    /// Like [`Backend::add`], but also stores the comment with the entry,
+    /// for --ipset-comments. Backends without comments ignore it.
+    fn add_with_comment(
+        &mut self,
+        net: MaskedIpAddr,
+        timeout: u32,
+        _comment: String,
+    ) -> Result<bool, BackendError> {
+        self.add(net, timeout)
+    }
+
+    /// Returns `false` if the address or network was not in the set.
+    fn del(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError>;
+
+    /// All addresses and networks in the set, for --reconcile-interval.
+    /// Backends that can not list their entries fail.
+    fn list(&mut self) -> Result<Vec<MaskedIpAddr>, BackendError> {
+        Err("listing entries is not supported".into())
+    }
+}
+
+/// Like [`Backend::add`], or [`Backend::add_with_comment`] if there is a
+/// comment, but retries transient failures, like full netlink buffers, with
+/// backoff. Also returns the number of retries.
+pub fn add_with_retry(
+    backend: &mut dyn Backend,
+    net: MaskedIpAddr,
+    timeout: u32,
+    comment: Option<&str>,
+) -> (Result<bool, BackendError>, u32) {
+    let mut delay = ADD_RETRY_DELAY;
+    let mut retries = 0;
+    loop {
+        let result = match comment {
+            Some(comment) => backend.add_with_comment(net, timeout, comment.to_owned()),
+            None => backend.add(net, timeout),
+        };
+        match result {
+            Err(err) if retries < ADD_RETRIES && !is_permission_error(&err.to_string()) => {
+                debug!("Retrying to add {net} in {delay:?}: {err}");
+                thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            result => return (result, retries),
+        }
+    }
+}
+
+/// Opens the ipset `name`, and if a `test` address is given, checks that the
+/// set exists by testing whether it contains the address.
+pub fn open(name: &str, test: Option<IpAddr>) -> Result<Box<dyn Backend>, LeroyError> {
+    let mut backend = netlink::IpsetBackend::new(name);
+    if let Some(test) = test {
+        backend.test(MaskedIpAddr::from(test)).map_err(|err| {
+            LeroyError::netlink(format!(
+                "Failed to test set {name:?}: {err}. Please create before running."
+            ))
+        })?;
+    }
+    Ok(Box::new(backend))
+}
+
+/// Opens the ipset `name` of type hash:mac, and if `test` is set, checks
+/// that it exists by testing whether it contains the all zero address.
+pub fn open_mac(name: &str, test: bool) -> Result<MacSet, LeroyError> {
+    let mut set = MacSet::new(name);
+    if test {
+        set.test(MacAddr([0; 6])).map_err(|err| {
+            LeroyError::netlink(format!(
+                "Failed to test set {name:?}: {err}. Please create before running."
+            ))
+        })?;
+    }
+    Ok(set)
+}
+
+#[cfg(target_os = "linux")]
+mod netlink {
+    use ipset::{
+        types::{AddOption, HashMac, HashNet},
+        Session,
+    };
+
+    use super::{Backend, BackendError};
+    use crate::{mac::MacAddr, masked_ip::MaskedIpAddr};
+
+    /// Changes an ipset of type hash:net over netlink.
+    pub struct IpsetBackend {
+        session: Session<HashNet>,
+    }
+
+    impl IpsetBackend {
+        pub fn new(name: &str) -> IpsetBackend {
+            IpsetBackend {
+                session: Session::new(name.to_owned()),
+            }
+        }
+    }
+
+    impl Backend for IpsetBackend {
+        fn test(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+            Ok(self.session.test(net)?)
+        }
+
+        fn add(&mut self, net: MaskedIpAddr, timeout: u32) -> Result<bool, BackendError> {
+            Ok(self.session.add(net, vec![AddOption::Timeout(timeout)])?)
+        }
+
+        fn add_with_comment(
+            &mut self,
+            net: MaskedIpAddr,
+            timeout: u32,
+            comment: String,
+        ) -> Result<bool, BackendError> {
+            Ok(self.session.add(
+                net,
+                vec![AddOption::Timeout(timeout), AddOption::Comment(comment)],
+            )?)
+        }
+
+        fn del(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+            Ok(self.session.del(net)?)
+        }
+
+        fn list(&mut self) -> Result<Vec<MaskedIpAddr>, BackendError> {
+            Ok(self
+                .session
+                .list()?
+                .into_iter()
+                .map(MaskedIpAddr::from)
+                .collect())
+        }
+    }
+
+    /// Changes an ipset of type hash:mac over netlink.
+    pub struct MacSet {
+        session: Session<HashMac>,
+    }
+
+    impl MacSet {
+        pub fn new(name: &str) -> MacSet {
+            MacSet {
+                session: Session::new(name.to_owned()),
+            }
+        }
+
+        pub fn test(&mut self, mac: MacAddr) -> Result<bool, BackendError> {
+            Ok(self.session.test(mac.0)?)
+        }
+
+        pub fn add(&mut self, mac: MacAddr, timeout: u32) -> Result<bool, BackendError> {
+            Ok(self.session.add(mac.0, vec![AddOption::Timeout(timeout)])?)
+        }
+    }
+}
+
+/// Lets leroyjenkins build on other platforms for development, with
+/// --dry-run or a mock backend.
+#[cfg(not(target_os = "linux"))]
+mod netlink {
+    use super::{Backend, BackendError};
+    use crate::{mac::MacAddr, masked_ip::MaskedIpAddr};
+
+    pub struct IpsetBackend;
+
+    impl IpsetBackend {
+        pub fn new(_name: &str) -> IpsetBackend {
+            IpsetBackend
+        }
+    }
+
+    impl Backend for IpsetBackend {
+        fn test(&mut self, _net: MaskedIpAddr) -> Result<bool, BackendError> {
+            Err("ipsets are only supported on Linux".into())
+        }
+
+        fn add(&mut self, net: MaskedIpAddr, _timeout: u32) -> Result<bool, BackendError> {
+            self.test(net)
+        }
+
+        fn del(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+            self.test(net)
+        }
+    }
+
+    pub struct MacSet;
+
+    impl MacSet {
+        pub fn new(_name: &str) -> MacSet {
+            MacSet
+        }
+
+        pub fn test(&mut self, _mac: MacAddr) -> Result<bool, BackendError> {
+            Err("ipsets are only supported on Linux".into())
+        }
+
+        pub fn add(&mut self, mac: MacAddr, _timeout: u32) -> Result<bool, BackendError> {
+            self.test(mac)
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fmt, num::NonZeroU8, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const MAX_LEN: usize = 23;
+
+/// Why the input reported an event, like `login` in `192.0.2.1 login`.
+/// Carried along with the ban it leads to, into the ipset comment, the
+/// event log, the metrics and the admin API.
+///
+/// Up to 23 ASCII letters, digits, `.`, `_` and `-`, stored inline, so that
+/// it is as cheap to copy around as the address.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct BanReason {
+    len: NonZeroU8,
+    /// Zero after `len`, so that the derived comparisons work.
+    bytes: [u8; MAX_LEN],
+}
+
+impl BanReason {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is accepted.
+        std::str::from_utf8(&self.bytes[..usize::from(self.len.get())]).unwrap_or_default()
+    }
+
+    pub(crate) fn from_bytes(s: &[u8]) -> Result<BanReason, InvalidBanReason> {
+        if s.len() > MAX_LEN
+            || !s
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b'-'))
+        {
+            return Err(InvalidBanReason);
+        }
+        let len = u8::try_from(s.len())
+            .ok()
+            .and_then(NonZeroU8::new)
+            .ok_or(InvalidBanReason)?;
+        let mut bytes = [0; MAX_LEN];
+        bytes[..s.len()].copy_from_slice(s);
+        Ok(BanReason { len, bytes })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidBanReason;
+
+impl fmt::Display for InvalidBanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid ban reason, expected up to {MAX_LEN} letters, digits, '.', '_' or '-'"
+        )
+    }
+}
+
+impl std::error::Error for InvalidBanReason {}
+
+impl FromStr for BanReason {
+    type Err = InvalidBanReason;
+
+    fn from_str(s: &str) -> Result<BanReason, InvalidBanReason> {
+        BanReason::from_bytes(s.as_bytes())
+    }
+}
+
+impl fmt::Display for BanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for BanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for BanReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for BanReason {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<BanReason, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,274 @@
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
+
+use crate::{
+    ip_family::ByIpFamily, Algorithm, Backend, Clock, Escalation, EventLogFormat, Leroy,
+    LeroyConfig, LeroyError, MaxBannedPolicy, QueueOverflow, ReconcileMissing,
+};
+
+/// Builds a [`Leroy`] for use as a library, starting from the defaults of
+/// the command line flags. Settings without a setter can be changed on the
+/// [`LeroyConfig`] from [`LeroyBuilder::into_config`].
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// let leroy = leroyjenkins::Leroy::builder()
+///     .ipsets("leroy4", "leroy6")
+///     .rate_limit(100, Duration::from_secs(10))
+///     .ban_time(Duration::from_secs(60))
+///     .build()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct LeroyBuilder {
+    config: LeroyConfig,
+    backends: Option<ByIpFamily<Box<dyn Backend + Send>>>,
+}
+
+impl LeroyBuilder {
+    pub(crate) fn new() -> LeroyBuilder {
+        LeroyBuilder {
+            config: LeroyConfig::default(),
+            backends: None,
+        }
+    }
+
+    /// The names of the ipsets to add bans to. Required unless in dry run.
+    pub fn ipsets(mut self, ipv4: impl Into<String>, ipv6: impl Into<String>) -> LeroyBuilder {
+        self.config.ipset_ipv4_name = ipv4.into();
+        self.config.ipset_ipv6_name = ipv6.into();
+        self
+    }
+
+    /// Sends bans to these backends instead of the ipsets, for example a
+    /// `MockBackend` (with the `mock` feature) in tests. The ipset names are
+    /// then not required. Watch ipsets are not affected.
+    pub fn backends(
+        mut self,
+        ipv4: impl Backend + Send + 'static,
+        ipv6: impl Backend + Send + 'static,
+    ) -> LeroyBuilder {
+        self.backends = Some(ByIpFamily {
+            ipv4: Box::new(ipv4),
+            ipv6: Box::new(ipv6),
+        });
+        self
+    }
+
+    /// Ban after more than `threshold` events, replenished over `period`.
+    /// Without a rate limit, only manual bans are made.
+    pub fn rate_limit(mut self, threshold: u32, period: Duration) -> LeroyBuilder {
+        self.config.bl_threshold = threshold;
+        self.config.bl_period = period;
+        self.config.bl_rate = None;
+        self
+    }
+
+    pub fn algorithm(mut self, algorithm: Algorithm) -> LeroyBuilder {
+        self.config.algorithm = algorithm;
+        self
+    }
+
+    /// The time of the first ban. Required.
+    pub fn ban_time(mut self, base_time: Duration) -> LeroyBuilder {
+        self.config.ipset_base_time = base_time;
+        self
+    }
+
+    pub fn max_ban_time(mut self, max_time: Duration) -> LeroyBuilder {
+        self.config.ipset_max_time = Some(max_time);
+        self
+    }
+
+    /// Add the time of a new ban to the remaining time of an IP that
+    /// exceeds the rate limit while banned.
+    pub fn extend_bans(mut self, extend: bool) -> LeroyBuilder {
+        self.config.extend_bans = extend;
+        self
+    }
+
+    /// Report bans that run out to the event log and the unban hook.
+    pub fn expiry_events(mut self, expiry_events: bool) -> LeroyBuilder {
+        self.config.expiry_events = expiry_events;
+        self
+    }
+
+    pub fn escalation(mut self, escalation: Escalation) -> LeroyBuilder {
+        self.config.escalation = escalation;
+        self
+    }
+
+    /// How long previous bans count towards longer bans. By default they
+    /// are forgotten right away.
+    pub fn recidivism_ttl(mut self, ttl: Duration) -> LeroyBuilder {
+        self.config.ipset_ban_ttl = ttl;
+        self
+    }
+
+    pub fn recidivism_decay(mut self, decay: bool) -> LeroyBuilder {
+        self.config.recidivism_decay = decay;
+        self
+    }
+
+    /// Randomize ban times by up to this fraction, like `0.1` for 10%.
+    pub fn ban_jitter(mut self, jitter: f64) -> LeroyBuilder {
+        self.config.ban_jitter = jitter;
+        self
+    }
+
+    /// Rate limit and ban networks of these prefix lengths instead of single
+    /// addresses.
+    pub fn ban_prefixes(mut self, ipv4: u8, ipv6: u8) -> LeroyBuilder {
+        self.config.ban_prefix_v4 = ipv4;
+        self.config.ban_prefix_v6 = ipv6;
+        self
+    }
+
+    /// Additionally ban whole networks of these prefix lengths, after more
+    /// than `threshold` bans within them per `period`.
+    pub fn subnet_rate_limit(
+        mut self,
+        prefix_v4: u8,
+        prefix_v6: u8,
+        threshold: u32,
+        period: Duration,
+    ) -> LeroyBuilder {
+        self.config.subnet_prefix_v4 = Some(prefix_v4);
+        self.config.subnet_prefix_v6 = Some(prefix_v6);
+        self.config.subnet_threshold = threshold;
+        self.config.subnet_period = period;
+        self
+    }
+
+    pub fn allowlist_file(mut self, path: impl Into<PathBuf>) -> LeroyBuilder {
+        self.config.allowlist_file = Some(path.into());
+        self
+    }
+
+    pub fn ban_private_ranges(mut self, ban: bool) -> LeroyBuilder {
+        self.config.ban_private_ranges = ban;
+        self
+    }
+
+    pub fn max_banned(mut self, limit: usize, policy: MaxBannedPolicy) -> LeroyBuilder {
+        self.config.max_banned = Some(limit);
+        self.config.max_banned_policy = policy;
+        self
+    }
+
+    /// Changes the ipsets (or backends) from a separate thread, through a
+    /// queue of `capacity` changes.
+    pub fn netlink_queue(mut self, capacity: usize, overflow: QueueOverflow) -> LeroyBuilder {
+        self.config.netlink_queue = Some(capacity);
+        self.config.netlink_queue_overflow = overflow;
+        self
+    }
+
+    /// Checks `sample` cached bans of each ipset every `interval`, to notice
+    /// when the sets were flushed or changed by someone else.
+    pub fn verify_bans(mut self, interval: Duration, sample: u32) -> LeroyBuilder {
+        self.config.verify_bans_interval = Some(interval);
+        self.config.verify_bans_sample = sample;
+        self
+    }
+
+    /// Compares all entries of each ipset with the cached bans every
+    /// `interval`, handling missing bans according to `missing`, and caching
+    /// entries added by someone else if `adopt` is set.
+    pub fn reconcile(
+        mut self,
+        interval: Duration,
+        missing: ReconcileMissing,
+        adopt: bool,
+    ) -> LeroyBuilder {
+        self.config.reconcile_interval = Some(interval);
+        self.config.reconcile_missing = missing;
+        self.config.reconcile_adopt = adopt;
+        self
+    }
+
+    /// The initial and maximum number of entries of the ban, recidivism
+    /// and rate limiter tables.
+    pub fn cache_size(mut self, initial_capacity: usize, max_size: u64) -> LeroyBuilder {
+        self.config.cache_initial_capacity = initial_capacity;
+        self.config.cache_max_size = max_size;
+        self
+    }
+
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> LeroyBuilder {
+        self.config.state_file = Some(path.into());
+        self
+    }
+
+    pub fn event_log(mut self, path: impl Into<PathBuf>, format: EventLogFormat) -> LeroyBuilder {
+        self.config.event_log = Some(path.into());
+        self.config.event_log_format = format;
+        self
+    }
+
+    pub fn admin_socket(mut self, path: impl Into<PathBuf>) -> LeroyBuilder {
+        self.config.admin_socket = Some(path.into());
+        self
+    }
+
+    pub fn health_listen(mut self, addr: SocketAddr) -> LeroyBuilder {
+        self.config.health_listen = Some(addr);
+        self
+    }
+
+    /// Count but do not ban for this long after starting.
+    pub fn warmup(mut self, warmup: Duration) -> LeroyBuilder {
+        self.config.warmup = warmup;
+        self
+    }
+
+    /// Do not touch the ipsets at all, but keep the would-be bans for
+    /// [`Leroy::dry_run_bans`].
+    pub fn dry_run(mut self, dry_run: bool) -> LeroyBuilder {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    /// Log bans without adding them to the ipsets, until switched to enforce
+    /// with the admin commands.
+    pub fn monitor_only(mut self, monitor_only: bool) -> LeroyBuilder {
+        self.config.monitor_only = monitor_only;
+        self
+    }
+
+    /// Drives rate limits, ban expiry and periodic work with `clock`, for
+    /// example a [`Clock::fake`] to test without sleeping.
+    pub fn clock(mut self, clock: Clock) -> LeroyBuilder {
+        self.config.clock = clock;
+        self
+    }
+
+    /// The settings so far, to change those without a setter.
+    pub fn into_config(self) -> LeroyConfig {
+        self.config
+    }
+
+    pub fn build(self) -> Result<Leroy, LeroyError> {
+        let config = self.config;
+        if config.manages_ipsets()
+            && self.backends.is_none()
+            && (config.ipset_ipv4_name.is_empty() || config.ipset_ipv6_name.is_empty())
+        {
+            return Err("ipset names are required, see LeroyBuilder::ipsets".into());
+        }
+        if config.ipset_base_time.is_zero() {
+            return Err("ban time is required, see LeroyBuilder::ban_time".into());
+        }
+        if !(0.0..=1.0).contains(&config.ban_jitter) {
+            return Err("ban jitter is not between 0 and 1".into());
+        }
+        if config.ban_prefix_v4 > 32 || config.ban_prefix_v6 > 128 {
+            return Err("ban prefix is longer than the address".into());
+        }
+        if config.subnet_prefix_v4.is_some_and(|prefix| prefix > 32)
+            || config.subnet_prefix_v6.is_some_and(|prefix| prefix > 128)
+        {
+            return Err("subnet prefix is longer than the address".into());
+        }
+        Leroy::with_backends(config, self.backends)
+    }
+}
@@ -0,0 +1,340 @@
+use std::{
+    error::Error,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{
+    allowlist::Allowlist, anonymize::IpAnonymizer, asn::AsnDatabase, asn_rate_limiter,
+    attack_rate_limiters, backend, ban_rate_limiters, country_rate_limiters,
+    error::is_permission_error, geoip::GeoIp, ip_family::IpFamily, key_banner::KeyType,
+    leroy_config::LeroyConfig, mac::MacAddr, masked_ip::MaskedIpAddr, port_rate_limiters,
+    subnet_rate_limiters, tenant_rate_limiters, watch_rate_limiters,
+};
+
+/// The result of [`check`]: what was found to be fine, and the problems,
+/// each with what to do about it.
+#[derive(Debug, Default)]
+pub struct CheckReport {
+    pub passed: Vec<String>,
+    pub problems: Vec<String>,
+}
+
+impl CheckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    fn pass(&mut self, message: impl Into<String>) {
+        self.passed.push(message.into());
+    }
+
+    /// Reports each problem once, even if several checks find it.
+    fn fail(&mut self, message: impl Into<String>) {
+        let message = message.into();
+        if !self.problems.contains(&message) {
+            self.problems.push(message);
+        }
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for message in &self.passed {
+            writeln!(f, "ok: {message}")?;
+        }
+        for message in &self.problems {
+            writeln!(f, "error: {message}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the flags are consistent, that the files they name can be
+/// loaded, and that the ipsets exist, without starting or changing
+/// anything. Unlike [`Leroy::new`](crate::Leroy::new), finds all problems
+/// at once, for `leroyjenkins check`.
+pub fn check(config: impl Into<LeroyConfig>) -> CheckReport {
+    let config = config.into();
+    let mut report = CheckReport::default();
+    check_rate_limits(&config, &mut report);
+    check_ban_times(&config, &mut report);
+    check_caches(&config, &mut report);
+    check_files(&config, &mut report);
+    if config.key_type == KeyType::Mac {
+        check_mac_set(&config, &mut report);
+    } else if config.manages_ipsets() {
+        check_ipsets(&config, &mut report);
+    } else {
+        report.pass("Not checking the ipsets, because they are not changed with --dry-run or --forward-bans");
+    }
+    report
+}
+
+fn check_rate_limits(config: &LeroyConfig, report: &mut CheckReport) {
+    // Only to validate the quotas, so without preallocating.
+    let config = &LeroyConfig {
+        cache_initial_capacity: 0,
+        ..config.clone()
+    };
+    let problems = report.problems.len();
+    if let Err(err) = ban_rate_limiters(config, |family| config.bl_threshold_for(family)) {
+        report.fail(err.to_string());
+    }
+    if let Err(err) = attack_rate_limiters(config) {
+        report.fail(format!("{err} for --attack-bl-threshold"));
+    }
+    if let Err(err) = country_rate_limiters(config) {
+        report.fail(format!("{err} for --country-bl-threshold"));
+    }
+    if let Err(err) = port_rate_limiters(config) {
+        report.fail(format!("{err} for --port-bl-threshold"));
+    }
+    if let Err(err) = tenant_rate_limiters(config) {
+        report.fail(format!("{err} for --tenant-bl-threshold"));
+    }
+    if let Err(err) = watch_rate_limiters(config) {
+        report.fail(err.to_string());
+    }
+    if let Err(err) = subnet_rate_limiters(config) {
+        report.fail(err.to_string());
+    }
+    if let Err(err) = asn_rate_limiter(config) {
+        report.fail(err.to_string());
+    }
+    if let Some(watch_threshold) = config.watch_threshold {
+        for family in [IpFamily::V4, IpFamily::V6] {
+            let bl_threshold = config.bl_threshold_for(family);
+            if bl_threshold != 0 && watch_threshold >= bl_threshold {
+                report.fail(format!(
+                    "--watch-threshold {watch_threshold} is not below the IP{family} --bl-threshold {bl_threshold}, so addresses are banned before they are watched. Lower it."
+                ));
+            }
+        }
+    }
+    if config.approve_wide_bans && config.admin_socket.is_none() && config.admin_listen.is_none() {
+        report.fail(
+            "--approve-wide-bans needs --admin-socket or --admin-listen to approve bans. Add one of them.",
+        );
+    }
+    if report.problems.len() == problems {
+        report.pass("Rate limits are valid");
+    }
+}
+
+/// Bans shorter than a second would be added to the ipsets with a timeout of
+/// 0, which is forever.
+fn check_ban_times(config: &LeroyConfig, report: &mut CheckReport) {
+    let problems = report.problems.len();
+    let mut base_times = vec![("--ipset-base-time", config.ipset_base_time)];
+    base_times.extend(
+        config
+            .ipset_base_time_ipv4
+            .map(|base_time| ("--ipset-base-time-ipv4", base_time)),
+    );
+    base_times.extend(
+        config
+            .ipset_base_time_ipv6
+            .map(|base_time| ("--ipset-base-time-ipv6", base_time)),
+    );
+    base_times.extend(
+        config
+            .attack_ipset_base_time
+            .map(|base_time| ("--attack-ipset-base-time", base_time)),
+    );
+    base_times.extend(
+        config
+            .country_ipset_base_time
+            .iter()
+            .map(|(_, base_time)| ("--country-ipset-base-time", *base_time)),
+    );
+    base_times.extend(
+        config
+            .port_ipset_base_time
+            .iter()
+            .map(|(_, base_time)| ("--port-ipset-base-time", *base_time)),
+    );
+    base_times.extend(
+        config
+            .tenant_ipset_base_time
+            .iter()
+            .map(|(_, base_time)| ("--tenant-ipset-base-time", *base_time)),
+    );
+    base_times.extend(
+        config
+            .ipset_max_time
+            .map(|max_time| ("--ipset-max-time", max_time)),
+    );
+    for (flag, time) in base_times {
+        if time < Duration::from_secs(1) {
+            report.fail(format!(
+                "{flag} is {time:?}, which would ban forever, because ipset timeouts of 0 never expire. Use at least 1s."
+            ));
+        }
+    }
+    if config.ipset_ban_ttl < config.ipset_base_time {
+        report.fail(format!(
+            "--ipset-ban-ttl {:?} is shorter than --ipset-base-time {:?}, so previous bans are forgotten before a ban ends, and repeat offenders are never banned longer. Raise it.",
+            config.ipset_ban_ttl, config.ipset_base_time
+        ));
+    }
+    if report.problems.len() == problems {
+        report.pass("Ban times are valid");
+    }
+}
+
+fn check_caches(config: &LeroyConfig, report: &mut CheckReport) {
+    let problems = report.problems.len();
+    if config.cache_max_size == 0 {
+        report
+            .fail("--cache-max-size is 0, so no bans or repeat offenses are remembered. Raise it.");
+    }
+    if config.cache_initial_capacity as u64 > config.cache_max_size {
+        report.fail(format!(
+            "--cache-initial-capacity {} exceeds --cache-max-size {}, which allocates memory that is never used. Lower it.",
+            config.cache_initial_capacity, config.cache_max_size
+        ));
+    }
+    if config.resolve_min_ttl > config.resolve_max_ttl {
+        report.fail(format!(
+            "--resolve-min-ttl {:?} exceeds --resolve-max-ttl {:?}. Lower it.",
+            config.resolve_min_ttl, config.resolve_max_ttl
+        ));
+    }
+    if report.problems.len() == problems {
+        report.pass("Cache sizes are valid");
+    }
+}
+
+fn check_files(config: &LeroyConfig, report: &mut CheckReport) {
+    type Load = fn(&Path) -> Result<(), Box<dyn Error>>;
+    let files: [(&str, &Option<PathBuf>, Load); 3] = [
+        ("--allowlist-file", &config.allowlist_file, |path| {
+            Allowlist::from_file(path).map(drop)
+        }),
+        ("--geoip-file", &config.geoip_file, |path| {
+            GeoIp::open(path).map(drop)
+        }),
+        ("--asn-file", &config.asn_file, |path| {
+            AsnDatabase::from_file(path).map(drop)
+        }),
+    ];
+    for (flag, path, load) in files {
+        let Some(path) = path else {
+            continue;
+        };
+        match load(path) {
+            Ok(()) => report.pass(format!("Loaded {flag} {path:?}")),
+            Err(err) => report.fail(format!("Failed to load {flag} {path:?}: {err}")),
+        }
+    }
+    match IpAnonymizer::open(config.anonymize_ips, config.anonymize_key_file.as_deref()) {
+        Ok(_) => {
+            if let Some(ref path) = config.anonymize_key_file {
+                report.pass(format!("Loaded --anonymize-key-file {path:?}"));
+            }
+        }
+        Err(err) => report.fail(format!("{err}")),
+    }
+    if config.anonymize_ips.is_some() && config.event_log_reverse_dns.is_some() {
+        report.fail("--event-log-reverse-dns would log host names of the addresses that --anonymize-ips hides. Drop one of them.");
+    }
+}
+
+/// Tests whether each ipset contains the self-test address of its family,
+/// which fails if the set does not exist, is of the other family, or can not
+/// be reached over netlink.
+fn check_ipsets(config: &LeroyConfig, report: &mut CheckReport) {
+    let mut sets = vec![
+        (
+            config.ipset_ipv4_name.as_str(),
+            IpAddr::V4(config.self_test_ipv4),
+        ),
+        (
+            config.ipset_ipv6_name.as_str(),
+            IpAddr::V6(config.self_test_ipv6),
+        ),
+    ];
+    sets.extend(
+        config
+            .ipset_watch_ipv4_name
+            .as_deref()
+            .map(|name| (name, IpAddr::V4(config.self_test_ipv4))),
+    );
+    sets.extend(
+        config
+            .ipset_watch_ipv6_name
+            .as_deref()
+            .map(|name| (name, IpAddr::V6(config.self_test_ipv6))),
+    );
+    for port_sets in &config.port_ipset {
+        sets.push((
+            port_sets.ipv4_name.as_str(),
+            IpAddr::V4(config.self_test_ipv4),
+        ));
+        sets.push((
+            port_sets.ipv6_name.as_str(),
+            IpAddr::V6(config.self_test_ipv6),
+        ));
+    }
+    for tenant_sets in &config.tenant_ipset {
+        sets.push((
+            tenant_sets.ipv4_name.as_str(),
+            IpAddr::V4(config.self_test_ipv4),
+        ));
+        sets.push((
+            tenant_sets.ipv6_name.as_str(),
+            IpAddr::V6(config.self_test_ipv6),
+        ));
+    }
+    for (name, test) in sets {
+        let (family, inet) = if test.is_ipv4() {
+            ("IPv4", "inet")
+        } else {
+            ("IPv6", "inet6")
+        };
+        let result = backend::open(name, None)
+            .map_err(|err| err.to_string())
+            .and_then(|mut set| {
+                set.test(MaskedIpAddr::from(test))
+                    .map_err(|err| err.to_string())
+            });
+        match result {
+            Ok(_) => report.pass(format!("ipset {name:?} exists and holds {family} addresses")),
+            Err(err) if is_permission_error(&err) => report.fail(format!(
+                "Not permitted to test ipset {name:?}: {err}. Run as root or with CAP_NET_ADMIN."
+            )),
+            Err(err) => report.fail(format!(
+                "Failed to test ipset {name:?}: {err}. Create it as an {family} set, for example with `ipset create {name} hash:net family {inet} timeout 0`."
+            )),
+        }
+    }
+}
+
+/// Like [`check_ipsets`], for --key-type mac.
+fn check_mac_set(config: &LeroyConfig, report: &mut CheckReport) {
+    let Some(ref name) = config.ipset_mac_name else {
+        report.fail("--key-type mac needs --ipset-mac-name. Add it.");
+        return;
+    };
+    if config.dry_run || config.monitor_only {
+        report.pass(
+            "Not checking the MAC set, because it is not changed with --dry-run or --monitor-only",
+        );
+        return;
+    }
+    match backend::MacSet::new(name)
+        .test(MacAddr([0; 6]))
+        .map_err(|err| err.to_string())
+    {
+        Ok(_) => report.pass(format!("ipset {name:?} exists and holds MAC addresses")),
+        Err(err) if is_permission_error(&err) => report.fail(format!(
+            "Not permitted to test ipset {name:?}: {err}. Run as root or with CAP_NET_ADMIN."
+        )),
+        Err(err) => report.fail(format!(
+            "Failed to test ipset {name:?}: {err}. Create it as a MAC set, for example with `ipset create {name} hash:mac timeout 0`."
+        )),
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use governor::clock::{Clock as _, QuantaClock, QuantaInstant, Reference};
+
+/// How far the monotonic clock may drift from the wall clock between two
+/// checks, before it is considered to have jumped. After long pauses, NTP
+/// may have slewed the wall clock by up to 0.05% of the time.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(5);
+const MAX_CLOCK_SKEW_RATIO: u32 = 1000;
+
+#[derive(Debug)]
+struct FakeTime {
+    start: Instant,
+    system_start: SystemTime,
+    /// Nanoseconds since the start.
+    offset: AtomicU64,
+}
+
+#[derive(Debug, Clone)]
+enum Source {
+    /// Rate limiters read the time with quanta, which is cheaper than
+    /// [`Instant::now`].
+    Real {
+        quanta: QuantaClock,
+        epoch: QuantaInstant,
+    },
+    Fake(Arc<FakeTime>),
+}
+
+/// The time source of rate limiters, bans and periodic work. Either the
+/// real time, or a fake time for tests and replays, which only moves when
+/// advanced. Clones of a fake clock share its time, so one can be kept to
+/// advance the time of a [`Leroy`](crate::Leroy) built with another.
+///
+/// The ban and recidivism caches expire entries by this clock. The other
+/// caches still evict entries by real time, but only to bound memory:
+/// whether a ban or a previous ban is still active is always checked with
+/// this clock.
+#[derive(Debug, Clone)]
+pub struct Clock {
+    source: Source,
+}
+
+impl Default for Clock {
+    fn default() -> Clock {
+        let quanta = QuantaClock::default();
+        Clock {
+            source: Source::Real {
+                epoch: quanta.now(),
+                quanta,
+            },
+        }
+    }
+}
+
+impl Clock {
+    /// A clock that starts at the current time and stands still.
+    pub fn fake() -> Clock {
+        Clock {
+            source: Source::Fake(Arc::new(FakeTime {
+                start: Instant::now(),
+                system_start: SystemTime::now(),
+                offset: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    /// Moves a fake clock forward. Does nothing to the real clock.
+    pub fn advance(&self, duration: Duration) {
+        if let Source::Fake(ref fake) = self.source {
+            let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+            fake.offset.fetch_add(nanos, Ordering::Relaxed);
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        match self.source {
+            Source::Real { .. } => Instant::now(),
+            Source::Fake(ref fake) => fake.start + fake.offset(),
+        }
+    }
+
+    pub fn system_now(&self) -> SystemTime {
+        match self.source {
+            Source::Real { .. } => SystemTime::now(),
+            Source::Fake(ref fake) => fake.system_start + fake.offset(),
+        }
+    }
+
+    /// Like [`Instant::elapsed`].
+    pub fn elapsed(&self, earlier: Instant) -> Duration {
+        self.now().saturating_duration_since(earlier)
+    }
+}
+
+impl FakeTime {
+    fn offset(&self) -> Duration {
+        Duration::from_nanos(self.offset.load(Ordering::Relaxed))
+    }
+}
+
+/// For rate limiters, as the time since an arbitrary epoch.
+impl governor::clock::Clock for Clock {
+    type Instant = Duration;
+
+    fn now(&self) -> Duration {
+        match self.source {
+            Source::Real { ref quanta, epoch } => quanta.now().duration_since(epoch).into(),
+            Source::Fake(ref fake) => fake.offset(),
+        }
+    }
+}
+
+/// A disagreement of the monotonic clock with the wall clock, see
+/// [`JumpDetector`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ClockJump {
+    /// The monotonic clock stood still for this long, while the wall clock
+    /// went on, like during suspend.
+    Stalled(Duration),
+    /// The monotonic clock leapt this far ahead of the wall clock, like
+    /// after a VM migration.
+    Ahead(Duration),
+}
+
+/// Notices when the monotonic time of rate limiters stops or leaps, by
+/// comparing it with the wall clock. Otherwise all rate limits would
+/// suddenly be replenished, or not replenish for the time that was missed.
+/// Steps of the wall clock itself, like by NTP, look the same, but are rare
+/// and small.
+pub struct JumpDetector {
+    clock: Clock,
+    last: Duration,
+    last_system: SystemTime,
+}
+
+impl JumpDetector {
+    pub fn new(clock: Clock) -> JumpDetector {
+        JumpDetector {
+            last: governor::clock::Clock::now(&clock),
+            last_system: clock.system_now(),
+            clock,
+        }
+    }
+
+    /// Compares how far both clocks moved since the previous check.
+    pub fn check(&mut self) -> Option<ClockJump> {
+        let now = governor::clock::Clock::now(&self.clock);
+        let system_now = self.clock.system_now();
+        let elapsed = now.saturating_sub(self.last);
+        // Ignored if the wall clock was set back.
+        let system_elapsed = system_now.duration_since(self.last_system).ok();
+        self.last = now;
+        self.last_system = system_now;
+        let system_elapsed = system_elapsed?;
+        let max_skew = MAX_CLOCK_SKEW.max(system_elapsed / MAX_CLOCK_SKEW_RATIO);
+        if system_elapsed > elapsed + max_skew {
+            Some(ClockJump::Stalled(system_elapsed - elapsed))
+        } else if elapsed > system_elapsed + max_skew {
+            Some(ClockJump::Ahead(elapsed - system_elapsed))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fake_clock_moves_only_when_advanced() {
+        let clock = Clock::fake();
+        let start = clock.now();
+        let system_start = clock.system_now();
+        let mut jump_detector = JumpDetector::new(clock.clone());
+        assert_eq!(clock.now(), start);
+
+        // Clones share the time.
+        clock.clone().advance(Duration::from_secs(90));
+        assert_eq!(clock.elapsed(start), Duration::from_secs(90));
+        assert_eq!(clock.system_now(), system_start + Duration::from_secs(90));
+        assert_eq!(governor::clock::Clock::now(&clock), Duration::from_secs(90));
+        assert_eq!(jump_detector.check(), None);
+    }
+}
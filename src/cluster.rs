@@ -0,0 +1,310 @@
+use std::{
+    ffi::CStr,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    os::unix::{
+        io::{AsRawFd, RawFd},
+        net::UnixStream,
+    },
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin_http::{constant_time_eq, ConnectionLimit},
+    ban_reason::BanReason,
+    event_log::BanCategory,
+    masked_ip::MaskedIpAddr,
+};
+
+/// How long to drop bans for a peer after failing to connect to it.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a peer has to send the token after connecting.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a peer connection may be idle before it is closed. Senders
+/// reconnect after half of it, so that no ban is written to a connection
+/// that is being closed.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The longest line that is read from a peer, which is plenty for the token
+/// and a ban.
+const MAX_LINE_BYTES: u64 = 4096;
+
+/// How many peer connections are handled at once. Further connections are
+/// closed right away.
+const MAX_CONNECTIONS: usize = 64;
+
+/// A ban decision, sent as one JSON line to each peer.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PeerBan {
+    /// The --cluster-node-id of the instance that made the decision.
+    pub origin: String,
+    pub ip: MaskedIpAddr,
+    pub timeout: u32,
+    pub category: BanCategory,
+    /// Not sent by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BanReason>,
+}
+
+/// Shares ban decisions with other instances. Every instance sends its own
+/// decisions directly to all peers, and never forwards the ones it received,
+/// so that bans do not loop around.
+pub struct Cluster {
+    node_id: String,
+    peers: Vec<SyncSender<String>>,
+    receiver: Receiver<PeerBan>,
+    wakeup_receiver: UnixStream,
+}
+
+impl Cluster {
+    pub fn new(
+        node_id: String,
+        listener: Option<TcpListener>,
+        peers: &[String],
+        token: String,
+    ) -> io::Result<Cluster> {
+        let (sender, receiver) = mpsc::sync_channel(1024);
+        let (wakeup_sender, wakeup_receiver) = UnixStream::pair()?;
+        wakeup_receiver.set_nonblocking(true)?;
+
+        if let Some(listener) = listener {
+            let addr = listener.local_addr()?;
+            info!("Accepting bans from peers on {addr}");
+            let node_id = node_id.clone();
+            let token = token.clone();
+            let limit = ConnectionLimit::new(MAX_CONNECTIONS);
+            thread::Builder::new()
+                .name("cluster".to_owned())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        let result = stream.and_then(|stream| {
+                            let Some(slot) = limit.acquire() else {
+                                warn!("Closed peer connection, too many open");
+                                return Ok(());
+                            };
+                            let sender = sender.clone();
+                            let wakeup = wakeup_sender.try_clone()?;
+                            let node_id = node_id.clone();
+                            let token = token.clone();
+                            thread::Builder::new()
+                                .name("cluster-connection".to_owned())
+                                .spawn(move || {
+                                    if let Err(err) =
+                                        receive(stream, &token, &node_id, &sender, wakeup)
+                                    {
+                                        debug!("Peer connection failed: {err}");
+                                    }
+                                    drop(slot);
+                                })
+                                .map(drop)
+                        });
+                        if let Err(err) = result {
+                            warn!("Failed to accept peer connection: {err}");
+                        }
+                    }
+                })?;
+        }
+
+        let peers = peers
+            .iter()
+            .map(|addr| {
+                let (sender, receiver) = mpsc::sync_channel(1024);
+                let addr = addr.clone();
+                let token = token.clone();
+                thread::Builder::new()
+                    .name(format!("cluster-peer-{addr}"))
+                    .spawn(move || send(&addr, &token, receiver))?;
+                Ok(sender)
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Cluster {
+            node_id,
+            peers,
+            receiver,
+            wakeup_receiver,
+        })
+    }
+
+    /// Sends a ban decision of this instance to all peers.
+    pub fn broadcast(
+        &self,
+        ip: MaskedIpAddr,
+        timeout: u32,
+        category: BanCategory,
+        reason: Option<BanReason>,
+    ) {
+        let ban = PeerBan {
+            origin: self.node_id.clone(),
+            ip,
+            timeout,
+            category,
+            reason,
+        };
+        let Ok(mut line) = serde_json::to_string(&ban) else {
+            return;
+        };
+        line.push('\n');
+        for peer in &self.peers {
+            match peer.try_send(line.clone()) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => debug!("Not sharing ban of {ip}, too many pending"),
+                Err(TrySendError::Disconnected(_)) => warn!("Peer sender has stopped"),
+            }
+        }
+    }
+
+    /// Returns the bans received from peers since the last call.
+    pub fn take_bans(&mut self) -> Vec<PeerBan> {
+        let mut buf = [0; 64];
+        while matches!(self.wakeup_receiver.read(&mut buf), Ok(n) if n > 0) {}
+        self.receiver.try_iter().collect()
+    }
+}
+
+impl AsRawFd for Cluster {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup_receiver.as_raw_fd()
+    }
+}
+
+/// The hostname, which is the default --cluster-node-id.
+pub fn hostname() -> String {
+    let mut buf = [0; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return "localhost".to_owned();
+    }
+    let hostname = unsafe { CStr::from_ptr(buf.as_ptr()) };
+    hostname.to_string_lossy().into_owned()
+}
+
+/// Keeps a connection to a peer, and writes the queued bans to it.
+fn send(addr: &str, token: &str, receiver: Receiver<String>) {
+    let mut stream = None;
+    let mut retry_at = None;
+    let mut last_write = Instant::now();
+    for line in receiver {
+        if stream.is_some() && last_write.elapsed() > IDLE_TIMEOUT / 2 {
+            debug!("Reconnecting to idle peer {addr}");
+            stream = None;
+        }
+        // Reconnect at most once per message, because the peer may have
+        // closed an idle connection.
+        for _ in 0..2 {
+            if stream.is_none() {
+                if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                    debug!("Not sharing ban with {addr}, not connected");
+                    break;
+                }
+                match connect(addr, token) {
+                    Ok(connected) => {
+                        info!("Connected to peer {addr}");
+                        stream = Some(connected);
+                        retry_at = None;
+                    }
+                    Err(err) => {
+                        warn!("Failed to connect to peer {addr}: {err}");
+                        retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                        break;
+                    }
+                }
+            }
+            let Some(ref mut connected) = stream else {
+                break;
+            };
+            match connected.write_all(line.as_bytes()) {
+                Ok(()) => {
+                    last_write = Instant::now();
+                    break;
+                }
+                Err(err) => {
+                    debug!("Lost connection to peer {addr}: {err}");
+                    stream = None;
+                }
+            }
+        }
+    }
+}
+
+fn connect(addr: &str, token: &str) -> io::Result<TcpStream> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_nodelay(true)?;
+    writeln!(stream, "{token}")?;
+    Ok(stream)
+}
+
+/// Reads the token, followed by one ban per line.
+fn receive(
+    stream: TcpStream,
+    token: &str,
+    node_id: &str,
+    sender: &SyncSender<PeerBan>,
+    mut wakeup: UnixStream,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    stream.set_read_timeout(Some(TOKEN_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let authorized = match read_line(&mut reader)? {
+        Some(line) => constant_time_eq(line.trim(), token),
+        None => false,
+    };
+    if !authorized {
+        warn!("Rejected peer {peer} with a wrong token");
+        return Ok(());
+    }
+    debug!("Peer {peer} connected");
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    while let Some(line) = read_line(&mut reader)? {
+        let ban: PeerBan = match serde_json::from_str(&line) {
+            Ok(ban) => ban,
+            Err(err) => {
+                warn!("Invalid ban from peer {peer}: {err}");
+                continue;
+            }
+        };
+        if ban.origin == node_id {
+            debug!("Ignoring own ban of {} from peer {peer}", ban.ip);
+            continue;
+        }
+        match sender.try_send(ban) {
+            Ok(()) => wakeup.write_all(&[0])?,
+            Err(TrySendError::Full(ban)) => {
+                debug!(
+                    "Dropped ban of {} from peer {peer}, too many pending",
+                    ban.ip
+                )
+            }
+            Err(TrySendError::Disconnected(_)) => return Ok(()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads a line of up to [`MAX_LINE_BYTES`], or `None` at the end of the
+/// stream.
+fn read_line(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut line = String::new();
+    let len = reader.by_ref().take(MAX_LINE_BYTES).read_line(&mut line)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') && len as u64 == MAX_LINE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("line over {MAX_LINE_BYTES} bytes"),
+        ));
+    }
+    Ok(Some(line))
+}
@@ -0,0 +1,140 @@
+use std::{
+    env,
+    error::Error,
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, CommandFactory};
+use toml::Value;
+
+use crate::Args;
+
+/// Prefix of the environment variables that set flags, like
+/// `LEROY_BL_THRESHOLD` for `--bl-threshold`.
+const ENV_PREFIX: &str = "LEROY_";
+
+fn env_var_name(long: &str) -> String {
+    format!(
+        "{ENV_PREFIX}{}",
+        long.to_ascii_uppercase().replace('-', "_")
+    )
+}
+
+/// Finds `--config PATH` or `--config=PATH` on the command line, or else
+/// `LEROY_CONFIG`.
+fn config_path(argv: &[OsString]) -> Option<PathBuf> {
+    command_line_config_path(argv)
+        .map(Path::to_owned)
+        .or_else(|| env::var_os(env_var_name("config")).map(PathBuf::from))
+}
+
+fn command_line_config_path(argv: &[OsString]) -> Option<&Path> {
+    let mut argv = argv.iter();
+    while let Some(arg) = argv.next() {
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return argv.next().map(Path::new);
+        }
+        if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(Path::new(path));
+        }
+    }
+    None
+}
+
+/// Inserts the flags from the --config file (if any) and from `LEROY_*`
+/// environment variables right after the program name, so that the
+/// environment overrides the file, and flags given on the command line
+/// override both.
+pub fn args_with_config(argv: Vec<OsString>) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut config_args = match config_path(&argv) {
+        Some(path) => file_args(&path)?,
+        None => Vec::new(),
+    };
+    config_args.extend(env_args()?);
+
+    let mut argv = argv.into_iter();
+    Ok(argv
+        .next()
+        .into_iter()
+        .chain(config_args)
+        .chain(argv)
+        .collect())
+}
+
+/// Flags from a TOML file. Keys are the long flag names, like
+/// `bl-threshold = 100`.
+fn file_args(path: &Path) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let config =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read config {path:?}: {err}"))?;
+    let table: toml::Table = config
+        .parse()
+        .map_err(|err| format!("Failed to parse config {path:?}: {err}"))?;
+
+    let command = Args::command();
+    let mut config_args = Vec::new();
+    for (key, value) in table {
+        let known = command
+            .get_arguments()
+            .any(|arg| arg.get_long() == Some(key.as_str()) && key != "config");
+        if !known {
+            return Err(format!("Unknown key {key:?} in config {path:?}").into());
+        }
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                Value::Boolean(true) => config_args.push(format!("--{key}")),
+                Value::Boolean(false) => {}
+                Value::String(s) => config_args.push(format!("--{key}={s}")),
+                Value::Integer(n) => config_args.push(format!("--{key}={n}")),
+                Value::Float(x) => config_args.push(format!("--{key}={x}")),
+                value => {
+                    return Err(
+                        format!("Unsupported value {value} for {key:?} in config {path:?}").into(),
+                    )
+                }
+            }
+        }
+    }
+    Ok(config_args.into_iter().map(OsString::from).collect())
+}
+
+/// Flags from `LEROY_*` environment variables. Switches take `true` or
+/// `false`, and flags that can be repeated take comma separated values.
+fn env_args() -> Result<Vec<OsString>, Box<dyn Error>> {
+    let mut env_args = Vec::new();
+    for arg in Args::command().get_arguments() {
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let name = env_var_name(long);
+        let Some(value) = env::var_os(&name) else {
+            continue;
+        };
+        let value = value
+            .into_string()
+            .map_err(|value| format!("Invalid unicode in {name}: {value:?}"))?;
+        match arg.get_action() {
+            ArgAction::SetTrue => match value.as_str() {
+                "true" | "1" => env_args.push(format!("--{long}")),
+                "false" | "0" | "" => {}
+                _ => return Err(format!("Expected true or false in {name}, got {value:?}").into()),
+            },
+            ArgAction::Append => env_args.extend(
+                value
+                    .split(',')
+                    .filter(|value| !value.is_empty())
+                    .map(|value| format!("--{long}={value}")),
+            ),
+            _ => env_args.push(format!("--{long}={value}")),
+        }
+    }
+    Ok(env_args.into_iter().map(OsString::from).collect())
+}
@@ -0,0 +1,31 @@
+/// What [`Leroy::handle_line`](crate::Leroy::handle_line) did with a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    /// Not rate limited: input is paused, or the line is a good event, or
+    /// the address is allowlisted, private or in an allowed country.
+    Ignored,
+    /// The line is not an IP address, or an empty key.
+    ParseError,
+    /// Counted, but still within the rate limit.
+    UnderLimit,
+    /// A host name, which is counted once it is resolved, see
+    /// --resolve-hostnames.
+    Resolving,
+    /// Newly banned for `timeout` seconds, for the `recidivism`th time.
+    Banned { timeout: u32, recidivism: u32 },
+    /// Over the rate limit while banned, so the ban now lasts `timeout`
+    /// seconds, see --extend-bans.
+    Extended { timeout: u32 },
+    /// Over the rate limit, but already banned.
+    AlreadyBanned,
+    /// Over the rate limit, but not banned because of --warmup,
+    /// --max-banned, an overlap with the allowlist, a failed ipset operation
+    /// or a full --netlink-queue.
+    NotBanned,
+}
+
+impl Decision {
+    pub fn is_banned(self) -> bool {
+        matches!(self, Decision::Banned { .. })
+    }
+}
@@ -0,0 +1,61 @@
+use std::{error::Error, io};
+
+use thiserror::Error;
+
+/// Errors of [`Leroy`](crate::Leroy), by what can be done about them.
+#[derive(Error, Debug)]
+pub enum LeroyError {
+    /// Invalid or inconsistent settings.
+    #[error("{0}")]
+    Config(String),
+    /// Not allowed to change the ipsets, which will not go away by retrying.
+    #[error("{0}")]
+    Permission(String),
+    /// An ipset operation failed, possibly only temporarily, for example
+    /// because netlink buffers were full.
+    #[error("{0}")]
+    Netlink(String),
+    /// A file, like the allowlist or a database, has invalid contents.
+    #[error("{0}")]
+    Parse(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl LeroyError {
+    /// Classifies a failed ipset operation by the error it reported.
+    pub(crate) fn netlink(message: String) -> LeroyError {
+        if is_permission_error(&message) {
+            LeroyError::Permission(message)
+        } else {
+            LeroyError::Netlink(message)
+        }
+    }
+}
+
+/// Whether an ipset operation failed for lack of permission, by its message.
+pub(crate) fn is_permission_error(message: &str) -> bool {
+    message.contains("Operation not permitted") || message.contains("Permission denied")
+}
+
+impl From<&str> for LeroyError {
+    fn from(message: &str) -> LeroyError {
+        LeroyError::Config(message.to_owned())
+    }
+}
+
+impl From<String> for LeroyError {
+    fn from(message: String) -> LeroyError {
+        LeroyError::Config(message)
+    }
+}
+
+/// For loaders of files, which fail either to read or to parse them.
+impl From<Box<dyn Error>> for LeroyError {
+    fn from(err: Box<dyn Error>) -> LeroyError {
+        match err.downcast::<io::Error>() {
+            Ok(err) => LeroyError::Io(*err),
+            Err(err) => LeroyError::Parse(err.to_string()),
+        }
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::SystemTime,
+};
+
+use clap::ValueEnum;
+use log::error;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    anonymize::IpAnonymizer, ban_reason::BanReason, ip_family::IpFamily, masked_ip::MaskedIpAddr,
+    rdns, siem,
+};
+
+/// Why something was banned.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanCategory {
+    /// The IP (or --ban-prefix network) exceeded its rate limit.
+    RateLimit,
+    /// Too many bans within --subnet-prefix.
+    Subnet,
+    /// Too many bans within an autonomous system.
+    Asn,
+    /// Banned through the admin socket.
+    Manual,
+    /// Banned by another instance of the --cluster-peer mesh.
+    Peer,
+}
+
+impl fmt::Display for BanCategory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BanCategory::RateLimit => "rate_limit",
+            BanCategory::Subnet => "subnet",
+            BanCategory::Asn => "asn",
+            BanCategory::Manual => "manual",
+            BanCategory::Peer => "peer",
+        })
+    }
+}
+
+/// Why a ban ended.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnbanReason {
+    /// Ran out, reported with --expiry-events.
+    Expired,
+    Allowlisted,
+    Evicted,
+    /// Unbanned through the admin socket.
+    Manual,
+    /// Removed with all other bans through the admin socket.
+    Flushed,
+}
+
+impl fmt::Display for UnbanReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            UnbanReason::Expired => "expired",
+            UnbanReason::Allowlisted => "allowlisted",
+            UnbanReason::Evicted => "evicted",
+            UnbanReason::Manual => "manual",
+            UnbanReason::Flushed => "flushed",
+        })
+    }
+}
+
+/// How events are written to --event-log, one per line.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum EventLogFormat {
+    Json,
+    /// ArcSight Common Event Format.
+    Cef,
+    /// IBM QRadar Log Event Extended Format.
+    Leef,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Ban {
+        ip: MaskedIpAddr,
+        family: IpFamily,
+        timeout: u32,
+        recidivism: u32,
+        category: BanCategory,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<BanReason>,
+        #[serde(with = "rfc3339")]
+        timestamp: SystemTime,
+        /// From the PTR record, with --event-log-reverse-dns.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        hostname: Option<String>,
+    },
+    Unban {
+        ip: MaskedIpAddr,
+        family: IpFamily,
+        reason: UnbanReason,
+        #[serde(with = "rfc3339")]
+        timestamp: SystemTime,
+    },
+}
+
+impl Event {
+    pub fn ip(&self) -> MaskedIpAddr {
+        match self {
+            Event::Ban { ip, .. } | Event::Unban { ip, .. } => *ip,
+        }
+    }
+}
+
+mod rfc3339 {
+    use std::time::SystemTime;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_rfc3339_millis(*time))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        humantime::parse_rfc3339(&String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Writes one event per line on a background thread, so that a slow
+/// disk never stalls banning. Events are dropped if the thread can not
+/// keep up.
+pub struct EventLog {
+    sender: Option<SyncSender<Event>>,
+    thread: Option<JoinHandle<()>>,
+    dropped: u64,
+}
+
+impl EventLog {
+    /// Appends to the file, or writes to stdout if the path is `-`. With
+    /// `reverse_dns_threads`, hostnames are added to ban events before they
+    /// are written, which may reorder events. With an `anonymizer`, events
+    /// are written with anonymized addresses.
+    pub fn open(
+        path: &Path,
+        format: EventLogFormat,
+        capacity: usize,
+        reverse_dns_threads: Option<usize>,
+        anonymizer: Option<IpAnonymizer>,
+    ) -> io::Result<EventLog> {
+        let writer: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+        };
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let (sender, receiver) = match reverse_dns_threads {
+            Some(threads) => {
+                let (resolved_sender, resolved_receiver) = mpsc::sync_channel(capacity);
+                rdns::spawn_resolvers(threads, receiver, resolved_sender)?;
+                (sender, resolved_receiver)
+            }
+            None => (sender, receiver),
+        };
+        Ok(EventLog {
+            sender: Some(sender),
+            thread: Some(
+                thread::Builder::new()
+                    .name("event-log".to_owned())
+                    .spawn(move || {
+                        write_events(
+                            receiver,
+                            format,
+                            anonymizer.as_ref(),
+                            BufWriter::new(writer),
+                        )
+                    })?,
+            ),
+            dropped: 0,
+        })
+    }
+
+    pub fn log(&mut self, event: Event) {
+        if let Some(ref sender) = self.sender {
+            match sender.try_send(event) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => self.dropped += 1,
+                Err(TrySendError::Disconnected(_)) => {
+                    error!("Event log writer has stopped");
+                    self.sender = None;
+                }
+            }
+        }
+    }
+
+    /// The number of events dropped since the previous call.
+    pub fn take_dropped(&mut self) -> u64 {
+        std::mem::take(&mut self.dropped)
+    }
+}
+
+impl Drop for EventLog {
+    /// Waits until all pending events are written.
+    fn drop(&mut self) {
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write_events<W: Write>(
+    receiver: Receiver<Event>,
+    format: EventLogFormat,
+    anonymizer: Option<&IpAnonymizer>,
+    mut writer: W,
+) {
+    while let Ok(event) = receiver.recv() {
+        // Write everything that is already queued before flushing.
+        let result = [event]
+            .into_iter()
+            .chain(receiver.try_iter())
+            .try_for_each(|event| {
+                match (format, anonymizer) {
+                    (EventLogFormat::Json, None) => serde_json::to_writer(&mut writer, &event)?,
+                    (EventLogFormat::Json, Some(anonymizer)) => {
+                        let ip = event.ip();
+                        let json = serde_json::to_string(&event)?.replacen(
+                            &format!(r#""ip":"{ip}""#),
+                            &format!(r#""ip":"{}""#, anonymizer.anonymize(ip)),
+                            1,
+                        );
+                        writer.write_all(json.as_bytes())?;
+                    }
+                    (EventLogFormat::Cef, _) => siem::write_cef(&mut writer, &event, anonymizer)?,
+                    (EventLogFormat::Leef, _) => siem::write_leef(&mut writer, &event, anonymizer)?,
+                }
+                writer.write_all(b"\n")
+            })
+            .and_then(|()| writer.flush());
+        if let Err(err) = result {
+            error!("Failed to write event log: {err}");
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::{error::Error, fmt, net::IpAddr, path::Path, str::FromStr};
+
+use maxminddb::{Mmap, Reader};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// An ISO 3166-1 alpha-2 country code like `DE`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CountryCode([u8; 2]);
+
+impl FromStr for CountryCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CountryCode, String> {
+        match s.as_bytes() {
+            [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => Ok(CountryCode([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+            ])),
+            _ => Err(format!("invalid country code {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for CountryCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", char::from(self.0[0]), char::from(self.0[1]))
+    }
+}
+
+impl Serialize for CountryCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CountryCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<CountryCode, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Only the fields we need, so that lookups do not have to allocate for
+/// all the localized names in the record.
+#[derive(Deserialize)]
+struct CountryRecord<'a> {
+    #[serde(borrow)]
+    country: Option<Country<'a>>,
+}
+
+#[derive(Deserialize)]
+struct Country<'a> {
+    iso_code: Option<&'a str>,
+}
+
+/// A memory mapped MaxMind GeoLite2 Country (or City) database.
+pub struct GeoIp {
+    reader: Reader<Mmap>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, Box<dyn Error>> {
+        Ok(GeoIp {
+            reader: Reader::open_mmap(path)
+                .map_err(|err| format!("Failed to open GeoIP database {path:?}: {err}"))?,
+        })
+    }
+
+    pub fn country(&self, ip: IpAddr) -> Option<CountryCode> {
+        let record: CountryRecord = self.reader.lookup(ip).ok()?;
+        record.country?.iso_code?.parse().ok()
+    }
+}
+
+/// Parses `CC=VALUE`, e.g. `CN=5`.
+pub fn parse_country_value<T>(s: &str) -> Result<(CountryCode, T), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let (country, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected COUNTRY=VALUE, got {s:?}"))?;
+    Ok((
+        country.parse()?,
+        value
+            .parse()
+            .map_err(|err| format!("invalid value {value:?}: {err}"))?,
+    ))
+}
@@ -0,0 +1,166 @@
+use std::{
+    io,
+    net::IpAddr,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, SyncSender},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use log::{error, info};
+
+use crate::{
+    admin::AdminReply, parse_line, resolve::Hostname, AdminCommand, BanReason, InputLine, Leroy,
+    LeroyError, LineError, Tenant,
+};
+
+/// How often admin commands and peer bans are handled without input.
+const IDLE_POLL: Duration = Duration::from_millis(100);
+
+/// The thread that owns the Leroy, returning the result of its shutdown, or
+/// the fatal error that stopped it.
+pub type LeroyThread = JoinHandle<io::Result<()>>;
+
+pub(crate) type ReplyFn = Box<dyn FnOnce(AdminReply) + Send>;
+
+pub(crate) enum Input {
+    Ip(IpAddr, Option<Tenant>, Option<u16>, Option<BanReason>),
+    /// The line is kept for the parse error without --resolve-hostnames.
+    Host(
+        Vec<u8>,
+        Hostname,
+        Option<Tenant>,
+        Option<u16>,
+        Option<BanReason>,
+    ),
+    Good(IpAddr),
+    ParseError(Vec<u8>, LineError),
+    Command(AdminCommand, ReplyFn),
+}
+
+impl Input {
+    pub(crate) fn from_line(line: &[u8]) -> Input {
+        match parse_line(line) {
+            Ok(InputLine::Bad(ip, tenant, port, reason)) => Input::Ip(ip, tenant, port, reason),
+            Ok(InputLine::BadHost(name, tenant, port, reason)) => {
+                Input::Host(line.to_vec(), name, tenant, port, reason)
+            }
+            Ok(InputLine::Good(ip)) => Input::Good(ip),
+            Err(err) => Input::ParseError(line.to_vec(), err),
+        }
+    }
+}
+
+/// Feeds one [`Leroy`] from many threads. Lines are parsed on the calling
+/// thread, so that parsing scales with the number of ingestion threads, and
+/// only the addresses are sent to the thread that owns the rate limiters and
+/// ipsets.
+#[derive(Clone)]
+pub struct LeroyHandle {
+    pub(crate) sender: SyncSender<Input>,
+}
+
+impl LeroyHandle {
+    /// Builds a [`Leroy`] with `make` on a new thread, which then handles
+    /// the input of all clones of the returned handle, along with admin
+    /// commands and peer bans. Up to `capacity` inputs are queued. Once all
+    /// handles are dropped, the thread calls [`Leroy::shutdown`] and returns
+    /// its result.
+    pub fn spawn(
+        make: impl FnOnce() -> Result<Leroy, LeroyError> + Send + 'static,
+        capacity: usize,
+    ) -> Result<(LeroyHandle, LeroyThread), LeroyError> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let (ready_sender, ready_receiver) = mpsc::sync_channel(1);
+        let thread = thread::Builder::new()
+            .name("leroy".to_owned())
+            .spawn(move || {
+                // Leroy is not Send, so it lives on this thread only.
+                let leroy = match make() {
+                    Ok(leroy) => leroy,
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                        return Ok(());
+                    }
+                };
+                let _ = ready_sender.send(Ok(()));
+                let (leroy, fatal_error) = run(leroy, receiver);
+                leroy.shutdown()?;
+                match fatal_error {
+                    Some(err) => Err(io::Error::new(io::ErrorKind::PermissionDenied, err)),
+                    None => Ok(()),
+                }
+            })?;
+        ready_receiver
+            .recv()
+            .map_err(|_| io::Error::other("Leroy thread panicked"))??;
+        Ok((LeroyHandle { sender }, thread))
+    }
+
+    /// Like [`Leroy::handle_line`], but without the decision. Blocks while
+    /// the owning thread is behind. Fails if that thread has stopped.
+    pub fn handle_line(&self, line: &[u8]) -> Result<(), SendError<()>> {
+        self.send(Input::from_line(line))
+    }
+
+    /// Like [`Leroy::handle_ip`], but without the decision.
+    pub fn handle_ip(&self, ip: IpAddr) -> Result<(), SendError<()>> {
+        self.send(Input::Ip(ip, None, None, None))
+    }
+
+    /// Runs an admin command, like those of --admin-socket, and waits for
+    /// the reply.
+    pub fn blocking_command(&self, command: AdminCommand) -> Result<AdminReply, SendError<()>> {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let reply = Box::new(move |reply| {
+            let _ = sender.send(reply);
+        });
+        self.send(Input::Command(command, reply))?;
+        receiver.recv().map_err(|_| SendError(()))
+    }
+
+    fn send(&self, input: Input) -> Result<(), SendError<()>> {
+        self.sender.send(input).map_err(|_| SendError(()))
+    }
+}
+
+/// Returns once all handles are dropped, or with a fatal error, see
+/// [`Leroy::take_fatal_error`].
+fn run(mut leroy: Leroy, receiver: Receiver<Input>) -> (Leroy, Option<LeroyError>) {
+    loop {
+        match receiver.recv_timeout(IDLE_POLL) {
+            Ok(input) => {
+                // Handle everything that is already queued, before checking
+                // the admin and peer sockets.
+                for input in [input].into_iter().chain(receiver.try_iter()) {
+                    match input {
+                        Input::Ip(ip, tenant, port, reason) => {
+                            leroy.handle_bad_ip(ip, tenant, port, reason);
+                        }
+                        Input::Host(line, name, tenant, port, reason) => {
+                            leroy.handle_bad_host(&line, name, tenant, port, reason);
+                        }
+                        Input::Good(ip) => {
+                            leroy.handle_good_ip(ip);
+                        }
+                        Input::ParseError(line, err) => {
+                            leroy.handle_parse_error(&line, err);
+                        }
+                        Input::Command(command, reply) => {
+                            info!("Admin command: {command}");
+                            reply(leroy.handle_admin_command(command));
+                        }
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return (leroy, None),
+        }
+        leroy.handle_admin_requests();
+        leroy.handle_peer_bans();
+        leroy.handle_resolved();
+        if let Some(err) = leroy.take_fatal_error() {
+            error!("Stopping, because bans can not be made anymore: {err}");
+            return (leroy, Some(err));
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    convert::Infallible,
+    env,
+    io::{self, Read},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixStream,
+        process::CommandExt,
+    },
+    process::{self, Command},
+    ptr,
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    admin::PendingBan, keyed_limiter::KeyState, listen_fds::SD_LISTEN_FDS_START,
+    masked_ip::MaskedIpAddr, state::Recidivism,
+};
+
+/// The socket that the new process reads the handover from.
+const HANDOVER_FD: &str = "LEROY_HANDOVER_FD";
+/// The child process that writes to it, to be reaped by the new process.
+const HANDOVER_PID: &str = "LEROY_HANDOVER_PID";
+
+/// What a running leroyjenkins hands over to the process that replaces it,
+/// see [`Leroy::handover`](crate::Leroy::handover).
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Handover {
+    /// Rate limiter states by table, `ip`, `attack`, `subnet`, `watch` or
+    /// `country:<code>`.
+    pub rate_limiters: Vec<(String, Vec<(MaskedIpAddr, KeyState)>)>,
+    pub asn_rate_limiter: Vec<(u32, KeyState)>,
+    pub recidivism: Vec<(MaskedIpAddr, Recidivism)>,
+    /// Active bans and when they expire.
+    pub bans: Vec<(MaskedIpAddr, SystemTime)>,
+    pub watched: Vec<MaskedIpAddr>,
+    pub asn_decisions: Vec<(u32, bool)>,
+    pub pending_bans: Vec<PendingBan>,
+    pub paused: bool,
+    /// The start of an input line that was not complete yet.
+    pub input: Vec<u8>,
+}
+
+/// Replaces the process with the same program and arguments, which reads
+/// `handover` from a unix socket at startup. Stdin is kept, and the
+/// `listeners` are passed like with socket activation, so that no input or
+/// connection is lost. Only returns on failure, after which the descriptors
+/// may be mixed up, so the process should exit.
+pub fn exec(handover: &Handover, listeners: &[RawFd]) -> io::Error {
+    match try_exec(handover, listeners) {
+        Ok(never) => match never {},
+        Err(err) => err,
+    }
+}
+
+fn try_exec(handover: &Handover, listeners: &[RawFd]) -> io::Result<Infallible> {
+    let (reader, writer_pid) = spawn_writer(serde_json::to_vec(handover)?)?;
+    let mut args = env::args_os();
+    let program = args
+        .next()
+        .ok_or_else(|| io::Error::other("the program name is missing"))?;
+
+    // The listeners go to 3, 4, ..., like with socket activation, followed
+    // by the handover socket. Copies above all of those first, so that none
+    // is overwritten before it is moved.
+    let fds: Vec<RawFd> = listeners
+        .iter()
+        .copied()
+        .chain([reader.as_raw_fd()])
+        .collect();
+    let above = SD_LISTEN_FDS_START + fds.len() as RawFd;
+    let copies = fds
+        .iter()
+        .map(
+            |&fd| match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, above) } {
+                -1 => Err(io::Error::last_os_error()),
+                copy => Ok(unsafe { OwnedFd::from_raw_fd(copy) }),
+            },
+        )
+        .collect::<io::Result<Vec<OwnedFd>>>()?;
+    // Without FD_CLOEXEC, unlike the copies.
+    for (fd, copy) in (SD_LISTEN_FDS_START..).zip(&copies) {
+        if unsafe { libc::dup2(copy.as_raw_fd(), fd) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let err = Command::new(program)
+        .args(args)
+        .env("LISTEN_PID", process::id().to_string())
+        .env("LISTEN_FDS", listeners.len().to_string())
+        .env_remove("LISTEN_FDNAMES")
+        .env(HANDOVER_FD, (above - 1).to_string())
+        .env(HANDOVER_PID, writer_pid.to_string())
+        .exec();
+    Err(err)
+}
+
+/// Forks a process that writes `data` to a unix socket and exits, so that
+/// the handover can be larger than the socket buffer. Returns the reading
+/// end and the pid of the writer.
+fn spawn_writer(data: Vec<u8>) -> io::Result<(OwnedFd, libc::pid_t)> {
+    let (reader, writer) = UnixStream::pair()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            // Only async-signal-safe calls in the child of a process with
+            // threads.
+            let mut written = 0;
+            while written < data.len() {
+                let n = unsafe {
+                    libc::write(
+                        writer.as_raw_fd(),
+                        data[written..].as_ptr().cast(),
+                        data.len() - written,
+                    )
+                };
+                match n {
+                    n if n > 0 => written += n as usize,
+                    _ if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted => {}
+                    _ => unsafe { libc::_exit(1) },
+                }
+            }
+            unsafe { libc::_exit(0) }
+        }
+        pid => Ok((reader.into(), pid)),
+    }
+}
+
+/// Reads the handover of the process that this one replaced, if any.
+pub fn receive() -> io::Result<Option<Handover>> {
+    let Ok(fd) = env::var(HANDOVER_FD) else {
+        return Ok(None);
+    };
+    let writer_pid = env::var(HANDOVER_PID)
+        .ok()
+        .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+    // Not inherited by child processes.
+    env::remove_var(HANDOVER_FD);
+    env::remove_var(HANDOVER_PID);
+    let fd: RawFd = fd
+        .parse()
+        .map_err(|_| io::Error::other(format!("{HANDOVER_FD} is not a file descriptor")))?;
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut data = Vec::new();
+    let result = UnixStream::from(unsafe { OwnedFd::from_raw_fd(fd) }).read_to_end(&mut data);
+    if let Some(pid) = writer_pid {
+        unsafe { libc::waitpid(pid, ptr::null_mut(), 0) };
+    }
+    result?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
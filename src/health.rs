@@ -0,0 +1,122 @@
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+use serde::Serialize;
+
+/// Liveness information shared with the health endpoint thread. Nothing is
+/// recorded unless enabled, to keep the cost off the hot path.
+#[derive(Debug)]
+pub struct Health {
+    enabled: bool,
+    /// Unix time in seconds, or 0 if never.
+    last_line: AtomicU64,
+    last_ban: AtomicU64,
+    netlink_ok: AtomicBool,
+}
+
+impl Health {
+    pub fn new(enabled: bool) -> Health {
+        Health {
+            enabled,
+            last_line: AtomicU64::new(0),
+            last_ban: AtomicU64::new(0),
+            netlink_ok: AtomicBool::new(true),
+        }
+    }
+
+    pub fn record_line(&self) {
+        if self.enabled {
+            self.last_line.store(unix_secs(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_ban(&self) {
+        if self.enabled {
+            self.last_ban.store(unix_secs(), Ordering::Relaxed);
+        }
+    }
+
+    /// Records the outcome of the latest ipset operation.
+    pub fn record_netlink(&self, ok: bool) {
+        self.netlink_ok.store(ok, Ordering::Relaxed);
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[derive(Serialize)]
+struct Status {
+    healthy: bool,
+    netlink_ok: bool,
+    last_ban: Option<u64>,
+    seconds_since_last_line: Option<u64>,
+}
+
+/// Serves the health status as JSON on every HTTP request, with status 503
+/// if the latest ipset operation failed, or if no line has been read for
+/// longer than `max_idle`.
+pub fn serve(
+    listener: TcpListener,
+    health: Arc<Health>,
+    max_idle: Option<Duration>,
+) -> io::Result<()> {
+    let addr = listener.local_addr()?;
+    info!("Serving health endpoint on http://{addr}/");
+    thread::Builder::new()
+        .name("health".to_owned())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|stream| respond(stream, &health, max_idle));
+                if let Err(err) = result {
+                    debug!("Health endpoint error: {err}");
+                }
+            }
+        })?;
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, health: &Health, max_idle: Option<Duration>) -> io::Result<()> {
+    // The request itself does not matter.
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    let _ = stream.read(&mut [0; 1024])?;
+
+    let netlink_ok = health.netlink_ok.load(Ordering::Relaxed);
+    let since = |unix_time: u64| (unix_time != 0).then(|| unix_secs().saturating_sub(unix_time));
+    let seconds_since_last_line = since(health.last_line.load(Ordering::Relaxed));
+    let last_ban = Some(health.last_ban.load(Ordering::Relaxed)).filter(|t| *t != 0);
+    let idle = max_idle.is_some_and(|max_idle| {
+        seconds_since_last_line.is_none_or(|idle| idle > max_idle.as_secs())
+    });
+    let status = Status {
+        healthy: netlink_ok && !idle,
+        netlink_ok,
+        last_ban,
+        seconds_since_last_line,
+    };
+
+    let body = serde_json::to_string(&status)?;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        if status.healthy {
+            "200 OK"
+        } else {
+            "503 Service Unavailable"
+        },
+        body.len()
+    )
+}
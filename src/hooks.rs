@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ban_reason::BanReason,
+    error::LeroyError,
+    event_log::{BanCategory, UnbanReason},
+    masked_ip::MaskedIpAddr,
+};
+
+/// A ban decision, passed to the hook of [`Leroy::set_ban_hook`](crate::Leroy::set_ban_hook).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BanEvent {
+    pub ip: MaskedIpAddr,
+    pub category: BanCategory,
+    /// The reason given with the input line that led to the ban.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<BanReason>,
+    /// In seconds.
+    pub timeout: u32,
+    /// The number of bans of this address, including this one.
+    pub recidivism: u32,
+}
+
+/// The end of a ban, passed to the hook of
+/// [`Leroy::set_unban_hook`](crate::Leroy::set_unban_hook).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct UnbanEvent {
+    pub ip: MaskedIpAddr,
+    pub reason: UnbanReason,
+}
+
+type BanHook = Box<dyn FnMut(BanEvent) + Send>;
+type UnbanHook = Box<dyn FnMut(UnbanEvent) + Send>;
+type ErrorHook = Box<dyn FnMut(&LeroyError) + Send>;
+
+/// Callbacks of library embedders.
+#[derive(Default)]
+pub struct Hooks {
+    pub ban: Option<BanHook>,
+    pub unban: Option<UnbanHook>,
+    pub error: Option<ErrorHook>,
+}
+
+impl Hooks {
+    pub fn ban(&mut self, event: BanEvent) {
+        if let Some(ref mut hook) = self.ban {
+            hook(event);
+        }
+    }
+
+    pub fn unban(&mut self, event: UnbanEvent) {
+        if let Some(ref mut hook) = self.unban {
+            hook(event);
+        }
+    }
+
+    pub fn error(&mut self, err: &LeroyError) {
+        if let Some(ref mut hook) = self.error {
+            hook(err);
+        }
+    }
+}
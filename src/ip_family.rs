@@ -1,3 +1,7 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 #[derive(Debug, Copy, Clone)]
 pub enum IpFamily {
     V4,
@@ -14,7 +18,42 @@ impl IpFamily {
     }
 }
 
-#[derive(Debug)]
+impl fmt::Display for IpFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IpFamily::V4 => "v4",
+            IpFamily::V6 => "v6",
+        })
+    }
+}
+
+impl FromStr for IpFamily {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpFamily, String> {
+        match s {
+            "v4" => Ok(IpFamily::V4),
+            "v6" => Ok(IpFamily::V6),
+            _ => Err(format!("invalid ip family {s:?}")),
+        }
+    }
+}
+
+impl Serialize for IpFamily {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpFamily {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<IpFamily, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
 pub struct ByIpFamily<T> {
     pub ipv4: T,
     pub ipv6: T,
@@ -31,6 +70,13 @@ impl<T> ByIpFamily<T> {
         })
     }
 
+    pub fn by_family(&self, family: IpFamily) -> &T {
+        match family {
+            IpFamily::V4 => &self.ipv4,
+            IpFamily::V6 => &self.ipv6,
+        }
+    }
+
     pub fn by_family_mut(&mut self, family: IpFamily) -> &mut T {
         match family {
             IpFamily::V4 => &mut self.ipv4,
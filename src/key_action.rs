@@ -0,0 +1,168 @@
+use std::{
+    ffi::OsStr,
+    io,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    process::Command,
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, error, warn};
+use serde_json::json;
+
+use crate::{
+    backend::{self, MacSet},
+    mac::MacAddr,
+    LeroyError,
+};
+
+/// What to do when a [`KeyBanner`](crate::KeyBanner) bans a key, instead of
+/// adding an address to the ipsets. Also implemented by closures.
+pub trait KeyAction {
+    /// Called once per ban, with the ban time in seconds and the number of
+    /// bans of the key, including this one.
+    fn ban(&mut self, key: &[u8], timeout: u32, recidivism: u32);
+}
+
+impl<F: FnMut(&[u8], u32, u32)> KeyAction for F {
+    fn ban(&mut self, key: &[u8], timeout: u32, recidivism: u32) {
+        self(key, timeout, recidivism)
+    }
+}
+
+struct KeyBan {
+    key: Vec<u8>,
+    timeout: u32,
+    recidivism: u32,
+}
+
+/// Runs actions on a background thread, so that slow programs or servers do
+/// not stall the input.
+struct Worker {
+    name: &'static str,
+    sender: SyncSender<KeyBan>,
+}
+
+impl Worker {
+    fn spawn(
+        name: &'static str,
+        mut run: impl FnMut(KeyBan) + Send + 'static,
+    ) -> io::Result<Worker> {
+        let (sender, receiver) = mpsc::sync_channel::<KeyBan>(1024);
+        thread::Builder::new()
+            .name(name.to_owned())
+            .spawn(move || receiver.into_iter().for_each(&mut run))?;
+        Ok(Worker { name, sender })
+    }
+
+    fn send(&self, key: &[u8], timeout: u32, recidivism: u32) {
+        let ban = KeyBan {
+            key: key.to_vec(),
+            timeout,
+            recidivism,
+        };
+        match self.sender.try_send(ban) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Skipped {}, too many pending", self.name),
+            Err(TrySendError::Disconnected(_)) => warn!("{} has stopped", self.name),
+        }
+    }
+}
+
+/// Runs a program for every ban, with the key, the ban time in seconds and
+/// the ban count as arguments.
+pub struct ExecAction {
+    worker: Worker,
+}
+
+impl ExecAction {
+    pub fn new(program: PathBuf) -> io::Result<ExecAction> {
+        let worker = Worker::spawn("key-exec", move |ban| {
+            let status = Command::new(&program)
+                .arg(OsStr::from_bytes(&ban.key))
+                .arg(ban.timeout.to_string())
+                .arg(ban.recidivism.to_string())
+                .status();
+            match status {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("{} exited with {status}", program.display()),
+                Err(err) => warn!("Failed to run {}: {err}", program.display()),
+            }
+        })?;
+        Ok(ExecAction { worker })
+    }
+}
+
+impl KeyAction for ExecAction {
+    fn ban(&mut self, key: &[u8], timeout: u32, recidivism: u32) {
+        self.worker.send(key, timeout, recidivism);
+    }
+}
+
+/// POSTs every ban as JSON like `{"key": ..., "timeout": ..., "recidivism":
+/// ...}`. Keys that are not UTF-8 are sent lossily.
+pub struct WebhookAction {
+    worker: Worker,
+}
+
+impl WebhookAction {
+    pub fn new(url: String) -> io::Result<WebhookAction> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(10))
+            .build();
+        let worker = Worker::spawn("key-webhook", move |ban| {
+            let body = json!({
+                "key": String::from_utf8_lossy(&ban.key),
+                "timeout": ban.timeout,
+                "recidivism": ban.recidivism,
+            });
+            if let Err(err) = agent
+                .post(&url)
+                .set("Content-Type", "application/json")
+                .send_string(&body.to_string())
+            {
+                warn!("Failed to post ban to {url}: {err}");
+            }
+        })?;
+        Ok(WebhookAction { worker })
+    }
+}
+
+impl KeyAction for WebhookAction {
+    fn ban(&mut self, key: &[u8], timeout: u32, recidivism: u32) {
+        self.worker.send(key, timeout, recidivism);
+    }
+}
+
+/// Adds every ban to an ipset of type hash:mac, for --key-type mac. Keys
+/// that are not MAC addresses are skipped.
+pub struct MacSetAction {
+    set: MacSet,
+}
+
+impl MacSetAction {
+    /// Checks that the set exists if `test` is set, like the self-test of
+    /// the IP sets.
+    pub fn open(name: &str, test: bool) -> Result<MacSetAction, LeroyError> {
+        Ok(MacSetAction {
+            set: backend::open_mac(name, test)?,
+        })
+    }
+}
+
+impl KeyAction for MacSetAction {
+    fn ban(&mut self, key: &[u8], timeout: u32, _recidivism: u32) {
+        let Some(mac) = MacAddr::from_bytes(key) else {
+            warn!(
+                "Not banning {}, which is not a MAC address",
+                key.escape_ascii()
+            );
+            return;
+        };
+        if let Err(err) = self.set.add(mac, timeout) {
+            error!("Unable to add {mac} to set: {err}");
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    hash::BuildHasherDefault,
+    num::NonZeroU32,
+    time::{Duration, Instant, SystemTime},
+};
+
+use clap::ValueEnum;
+use governor::Quota;
+use log::{debug, info};
+use mini_moka::unsync::Cache;
+use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    key_action::KeyAction, keyed_limiter::KeyedLimiter, leroy_config::LeroyConfig, mac::MacAddr,
+    state::Recidivism, Decision, LeroyError,
+};
+
+type Key = Box<[u8]>;
+
+/// What the lines of input are.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyType {
+    /// IP addresses, or arbitrary keys with --key-exec or --key-webhook-url.
+    Ip,
+    /// MAC addresses, like `02:00:5e:10:00:01`, banned in the hash:mac
+    /// ipset --ipset-mac-name.
+    Mac,
+}
+
+/// Rate limits and bans arbitrary keys, like user IDs or API tokens, with a
+/// [`KeyAction`] instead of ipsets. Uses the rate limit, ban time,
+/// escalation and recidivism settings of a [`LeroyConfig`], but none of
+/// those specific to IP addresses.
+pub struct KeyBanner {
+    config: LeroyConfig,
+    /// `None` to ban on sight.
+    rate_limiter: Option<KeyedLimiter<Key, BuildHasherDefault<FxHasher>>>,
+    /// When the active bans expire.
+    bans: Cache<Key, SystemTime, BuildHasherDefault<FxHasher>>,
+    recidivism_counts: Cache<Key, Recidivism, BuildHasherDefault<FxHasher>>,
+    action: Box<dyn KeyAction>,
+    start: Instant,
+}
+
+impl KeyBanner {
+    pub fn new(
+        config: impl Into<LeroyConfig>,
+        action: impl KeyAction + 'static,
+    ) -> Result<KeyBanner, LeroyError> {
+        let config = config.into();
+        if config.ipset_base_time.is_zero() {
+            return Err("--ipset-base-time must be non-zero".into());
+        }
+        let rate_limiter = match NonZeroU32::new(config.bl_threshold) {
+            Some(threshold) => Some(KeyedLimiter::with_clock(
+                config.algorithm,
+                Quota::with_period(config.bl_rate.unwrap_or(config.bl_period))
+                    .ok_or("--bl-period must be non-zero")?
+                    .allow_burst(threshold),
+                config.cache_initial_capacity,
+                BuildHasherDefault::default(),
+                config.clock.clone(),
+            )),
+            None => None,
+        };
+        let recidivism_counts = {
+            let builder = Cache::builder()
+                .initial_capacity(config.cache_initial_capacity)
+                .max_capacity(config.cache_max_size);
+            if config.recidivism_decay {
+                builder
+            } else {
+                builder.time_to_live(config.ipset_ban_ttl)
+            }
+            .build_with_hasher(Default::default())
+        };
+        Ok(KeyBanner {
+            rate_limiter,
+            bans: Cache::builder()
+                .initial_capacity(config.cache_initial_capacity)
+                .max_capacity(config.cache_max_size)
+                .build_with_hasher(Default::default()),
+            recidivism_counts,
+            action: Box::new(action),
+            start: config.clock.now(),
+            config,
+        })
+    }
+
+    /// Counts an event of the key, like [`Leroy::handle_line`](crate::Leroy::handle_line)
+    /// does for an address. A line like `+key` is a good event, which gives
+    /// back --good-credit events to the key.
+    pub fn handle_line(&mut self, line: &[u8]) -> Decision {
+        match line.strip_prefix(b"+") {
+            Some(key) => self.handle_good_key(key),
+            None => self.handle_key(line),
+        }
+    }
+
+    pub fn handle_key(&mut self, key: &[u8]) -> Decision {
+        let Some(key) = self.parse_key(key) else {
+            return Decision::ParseError;
+        };
+        let over_limit = self
+            .rate_limiter
+            .as_mut()
+            .is_none_or(|l| l.check_key(&key).is_err());
+        if !over_limit {
+            return Decision::UnderLimit;
+        }
+        self.ban(key)
+    }
+
+    pub fn handle_good_key(&mut self, key: &[u8]) -> Decision {
+        let Some(key) = self.parse_key(key) else {
+            return Decision::ParseError;
+        };
+        if let Some(ref mut rate_limiter) = self.rate_limiter {
+            rate_limiter.credit(&key, self.config.good_credit);
+        }
+        Decision::Ignored
+    }
+
+    /// Writes MAC addresses in one way, so that all spellings of an address
+    /// share its rate limit and recidivism.
+    fn parse_key(&self, key: &[u8]) -> Option<Key> {
+        match self.config.key_type {
+            _ if key.is_empty() => None,
+            KeyType::Ip => Some(Key::from(key)),
+            KeyType::Mac => MacAddr::from_bytes(key).map(|mac| mac.to_string().into_bytes().into()),
+        }
+    }
+
+    fn ban(&mut self, key: Key) -> Decision {
+        let now = self.config.clock.system_now();
+        if self.bans.get(&key).is_some_and(|expires| *expires > now) {
+            return Decision::AlreadyBanned;
+        }
+        if self.config.clock.elapsed(self.start) < self.config.warmup {
+            debug!("Not banning {} during --warmup", key.escape_ascii());
+            return Decision::NotBanned;
+        }
+
+        let previous_bans = match self.recidivism_counts.get(&key) {
+            Some(recidivism) => self.config.previous_bans(recidivism),
+            None => 0,
+        };
+        let recidivism = previous_bans.saturating_add(1);
+        let timeout = self
+            .config
+            .escalate(self.config.ipset_base_time, recidivism);
+
+        if self.config.dry_run || self.config.monitor_only {
+            info!(
+                "Would ban {} for {timeout}s (recidivism: {recidivism})",
+                key.escape_ascii()
+            );
+        } else {
+            info!(
+                "Banned {} for {timeout}s (recidivism: {recidivism})",
+                key.escape_ascii()
+            );
+            self.action.ban(&key, timeout, recidivism);
+        }
+        self.bans
+            .insert(key.clone(), now + Duration::from_secs(u64::from(timeout)));
+        self.recidivism_counts.insert(
+            key,
+            Recidivism {
+                count: recidivism,
+                last_ban: now,
+                reason: None,
+            },
+        );
+        Decision::Banned {
+            timeout,
+            recidivism,
+        }
+    }
+}
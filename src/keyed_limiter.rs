@@ -1,18 +1,25 @@
 use std::{
     cell::{Cell, RefCell},
     cmp::max,
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     hash::{BuildHasher, Hash},
-    num::NonZeroU64,
+    iter,
+    num::{NonZeroU32, NonZeroU64},
+    rc::Rc,
+    time::{Duration, Instant},
 };
 
 use governor::{
-    clock::{DefaultClock, QuantaInstant},
+    middleware::NoOpMiddleware,
     nanos::Nanos,
     state::{keyed::ShrinkableKeyedStateStore, StateStore},
-    NotUntil, Quota, RateLimiter,
+    Quota, RateLimiter,
 };
 use log::debug;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{clock::Clock, Algorithm};
 
 #[derive(Default)]
 struct UnsyncInMemoryState {
@@ -33,20 +40,31 @@ impl UnsyncInMemoryState {
     fn is_older_than(&self, nanos: Nanos) -> bool {
         self.value.get() <= nanos.into()
     }
-}
 
-struct UnsyncHashMapStateStore<K, S> {
-    buckets: RefCell<HashMap<K, UnsyncInMemoryState, S>>,
-}
+    /// Moves the theoretical arrival time back, as if the cells had never
+    /// been used.
+    fn credit(&self, nanos: u64) {
+        self.value.set(self.value.get().saturating_sub(nanos));
+    }
 
-impl<K, S> UnsyncHashMapStateStore<K, S> {
-    fn with_capacity_and_hasher(capacity: usize, hasher: S) -> UnsyncHashMapStateStore<K, S> {
-        UnsyncHashMapStateStore {
-            buckets: RefCell::new(HashMap::with_capacity_and_hasher(capacity, hasher)),
+    /// Moves the theoretical arrival time forward. States that were never
+    /// used stay that way.
+    fn debit(&self, nanos: u64) {
+        let value = self.value.get();
+        if value != 0 {
+            self.value.set(value.saturating_add(nanos));
         }
     }
 }
 
+type Buckets<K, S> = Rc<RefCell<HashMap<K, UnsyncInMemoryState, S>>>;
+
+/// Shares the buckets with [`KeyedLimiter`], because the rate limiter does
+/// not give access to its state store.
+struct UnsyncHashMapStateStore<K, S> {
+    buckets: Buckets<K, S>,
+}
+
 impl<K, S> StateStore for UnsyncHashMapStateStore<K, S>
 where
     K: Hash + Eq + Clone,
@@ -91,14 +109,146 @@ where
     }
 }
 
+/// Exact counts over a sliding window, by remembering when each of the
+/// most recent events happened.
+struct SlidingWindows<K, S> {
+    windows: HashMap<K, VecDeque<Instant>, S>,
+    limit: usize,
+    period: Duration,
+    clock: Clock,
+}
+
+impl<K, S> SlidingWindows<K, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    fn check_key_n(&mut self, key: &K, n: usize) -> Result<(), RateLimited> {
+        let now = self.clock.now();
+        if let Some(window) = self.windows.get_mut(key) {
+            while window
+                .front()
+                .is_some_and(|event| now.duration_since(*event) >= self.period)
+            {
+                window.pop_front();
+            }
+            if window.len() + n > self.limit {
+                return Err(RateLimited);
+            }
+            window.extend(iter::repeat_n(now, n));
+            return Ok(());
+        }
+        if n > self.limit {
+            return Err(RateLimited);
+        }
+        self.windows
+            .entry(key.clone())
+            .or_insert_with(|| VecDeque::with_capacity(self.limit))
+            .extend(iter::repeat_n(now, n));
+        Ok(())
+    }
+
+    fn credit(&mut self, key: &K, n: u32) {
+        if let Some(window) = self.windows.get_mut(key) {
+            window.drain(..window.len().min(n as usize));
+        }
+    }
+
+    /// Moves all events in time, and forgets those that would be before
+    /// the start of the clock.
+    fn shift(&mut self, f: impl Fn(Instant) -> Option<Instant>) {
+        self.windows.retain(|_, window| {
+            *window = window.iter().filter_map(|event| f(*event)).collect();
+            !window.is_empty()
+        });
+    }
+
+    fn retain_recent(&mut self) {
+        let now = self.clock.now();
+        self.windows.retain(|_, window| {
+            window
+                .back()
+                .is_some_and(|event| now.duration_since(*event) < self.period)
+        });
+    }
+}
+
+enum Strategy<K, S>
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    Gcra {
+        rate_limiter:
+            RateLimiter<K, UnsyncHashMapStateStore<K, S>, Clock, NoOpMiddleware<Duration>>,
+        buckets: Buckets<K, S>,
+        replenish_interval: Nanos,
+        burst_size: u32,
+        /// Approximately when the rate limiter started its clock, which the
+        /// states are relative to.
+        created: Instant,
+        clock: Clock,
+    },
+    SlidingWindow(SlidingWindows<K, S>),
+}
+
+/// The events were not allowed, and were not counted.
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq)]
+#[error("rate limited")]
+pub struct RateLimited;
+
+/// The state of one key relative to now, which can be moved to a limiter
+/// with another clock, see [`KeyedLimiter::export`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum KeyState {
+    /// How far the theoretical arrival time is ahead of now.
+    Gcra { ahead: Duration },
+    /// How long ago each of the recent events happened, oldest first.
+    SlidingWindow { ages: Vec<Duration> },
+}
+
+/// Garbage collections of the table since it was created.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct GcStats {
+    pub runs: u64,
+    /// Entries that were removed, because their state was no longer
+    /// relevant.
+    pub removed: u64,
+}
+
+/// Rate limits events by key, like a keyed [`governor::RateLimiter`], but
+/// for a single thread: the states are plain cells in a [`HashMap`] with
+/// the hasher `S`, without atomics or locks.
+///
+/// The table grows as new keys are checked. Whenever it has doubled since
+/// the last garbage collection (starting at `initial_capacity`), keys whose
+/// state no longer matters are removed, as if they had never been checked.
+///
+/// ```
+/// use std::{collections::hash_map::RandomState, num::NonZeroU32};
+///
+/// use governor::Quota;
+/// use leroyjenkins::{Algorithm, KeyedLimiter};
+///
+/// let quota = Quota::per_minute(NonZeroU32::new(2).unwrap());
+/// let mut limiter = KeyedLimiter::new(Algorithm::Gcra, quota, 1024, RandomState::new());
+/// assert!(limiter.check_key(&"alice").is_ok());
+/// assert!(limiter.check_key(&"alice").is_ok());
+/// assert!(limiter.check_key(&"alice").is_err());
+/// limiter.credit(&"alice", 1);
+/// assert!(limiter.check_key(&"alice").is_ok());
+/// assert!(limiter.check_key_n(&"bob", NonZeroU32::new(3).unwrap()).is_err());
+/// assert_eq!(limiter.len(), 1);
+/// ```
 pub struct KeyedLimiter<K, S>
 where
     K: Hash + Eq + Clone,
     S: BuildHasher,
 {
-    rate_limiter: RateLimiter<K, UnsyncHashMapStateStore<K, S>, DefaultClock>,
+    strategy: Strategy<K, S>,
     initial_capacity: usize,
     next_gc_len: usize,
+    gc_stats: GcStats,
 }
 
 impl<K, S> KeyedLimiter<K, S>
@@ -106,30 +256,361 @@ where
     K: Hash + Eq + Clone,
     S: BuildHasher,
 {
-    pub fn new(quota: Quota, initial_capacity: usize, hasher: S) -> KeyedLimiter<K, S> {
+    /// With [`Algorithm::SlidingWindow`], more than the burst size of the
+    /// quota within its replenish interval are rate limited.
+    pub fn new(
+        algorithm: Algorithm,
+        quota: Quota,
+        initial_capacity: usize,
+        hasher: S,
+    ) -> KeyedLimiter<K, S> {
+        KeyedLimiter::with_clock(algorithm, quota, initial_capacity, hasher, Clock::default())
+    }
+
+    pub fn with_clock(
+        algorithm: Algorithm,
+        quota: Quota,
+        initial_capacity: usize,
+        hasher: S,
+        clock: Clock,
+    ) -> KeyedLimiter<K, S> {
         KeyedLimiter {
-            rate_limiter: RateLimiter::new(
-                quota,
-                UnsyncHashMapStateStore::with_capacity_and_hasher(initial_capacity, hasher),
-                &DefaultClock::default(),
-            ),
+            strategy: match algorithm {
+                Algorithm::Gcra => {
+                    let buckets = Rc::new(RefCell::new(HashMap::with_capacity_and_hasher(
+                        initial_capacity,
+                        hasher,
+                    )));
+                    Strategy::Gcra {
+                        rate_limiter: RateLimiter::new(
+                            quota,
+                            UnsyncHashMapStateStore {
+                                buckets: Rc::clone(&buckets),
+                            },
+                            &clock,
+                        ),
+                        buckets,
+                        replenish_interval: quota.replenish_interval().into(),
+                        burst_size: quota.burst_size().get(),
+                        created: clock.now(),
+                        clock,
+                    }
+                }
+                Algorithm::SlidingWindow => Strategy::SlidingWindow(SlidingWindows {
+                    windows: HashMap::with_capacity_and_hasher(initial_capacity, hasher),
+                    limit: quota.burst_size().get() as usize,
+                    period: quota.replenish_interval(),
+                    clock,
+                }),
+            },
             initial_capacity,
             next_gc_len: initial_capacity,
+            gc_stats: GcStats::default(),
+        }
+    }
+
+    /// Counts one event of the key, unless it is rate limited.
+    pub fn check_key(&mut self, key: &K) -> Result<(), RateLimited> {
+        self.maybe_gc();
+        match self.strategy {
+            Strategy::Gcra {
+                ref rate_limiter, ..
+            } => rate_limiter.check_key(key).map_err(|_| RateLimited),
+            Strategy::SlidingWindow(ref mut windows) => windows.check_key_n(key, 1),
         }
     }
 
-    pub fn check_key(&mut self, key: &K) -> Result<(), NotUntil<QuantaInstant>> {
+    /// Counts `n` events of the key at once, unless that would exceed the
+    /// quota, in which case none of them are counted. More events than the
+    /// burst size of the quota are never allowed.
+    pub fn check_key_n(&mut self, key: &K, n: NonZeroU32) -> Result<(), RateLimited> {
         self.maybe_gc();
-        self.rate_limiter.check_key(key)
+        match self.strategy {
+            Strategy::Gcra {
+                ref rate_limiter, ..
+            } => match rate_limiter.check_key_n(key, n) {
+                Ok(Ok(())) => Ok(()),
+                Ok(Err(_)) | Err(_) => Err(RateLimited),
+            },
+            Strategy::SlidingWindow(ref mut windows) => windows.check_key_n(key, n.get() as usize),
+        }
+    }
+
+    /// Gives back up to `n` events to the key, for example after it proved
+    /// to be legitimate. Keys that are not tracked are left alone.
+    pub fn credit(&mut self, key: &K, n: u32) {
+        match self.strategy {
+            Strategy::Gcra {
+                ref buckets,
+                replenish_interval,
+                ..
+            } => {
+                if let Some(state) = buckets.borrow().get(key) {
+                    state.credit(u64::from(replenish_interval).saturating_mul(u64::from(n)));
+                }
+            }
+            Strategy::SlidingWindow(ref mut windows) => windows.credit(key, n),
+        }
+    }
+
+    /// The number of tracked keys, including those that garbage collection
+    /// would remove.
+    pub fn len(&self) -> usize {
+        match self.strategy {
+            Strategy::Gcra {
+                ref rate_limiter, ..
+            } => rate_limiter.len(),
+            Strategy::SlidingWindow(ref windows) => windows.windows.len(),
+        }
+    }
+
+    /// The number of events that each tracked key can still have right now,
+    /// before it is rate limited.
+    pub fn remaining(&self) -> Vec<(K, u32)> {
+        match self.strategy {
+            Strategy::Gcra {
+                ref buckets,
+                replenish_interval,
+                burst_size,
+                created,
+                ref clock,
+                ..
+            } => {
+                // Mirrors the check of the rate limiter: an event is allowed
+                // if its theoretical arrival time is at most the burst
+                // tolerance ahead of now.
+                let now = u64::try_from(clock.elapsed(created).as_nanos()).unwrap_or(u64::MAX);
+                let interval = max(u64::from(replenish_interval), 1);
+                let tolerance = interval.saturating_mul(u64::from(burst_size));
+                buckets
+                    .borrow()
+                    .iter()
+                    .map(|(key, state)| {
+                        let ahead = state.value.get().saturating_sub(now);
+                        let remaining = match tolerance.checked_sub(ahead) {
+                            Some(slack) => slack / interval + 1,
+                            None => 0,
+                        };
+                        (key.clone(), u32::try_from(remaining).unwrap_or(u32::MAX))
+                    })
+                    .collect()
+            }
+            Strategy::SlidingWindow(ref windows) => {
+                let now = windows.clock.now();
+                windows
+                    .windows
+                    .iter()
+                    .map(|(key, window)| {
+                        let recent = window
+                            .iter()
+                            .filter(|event| now.duration_since(**event) < windows.period)
+                            .count();
+                        let remaining = windows.limit.saturating_sub(recent);
+                        (key.clone(), u32::try_from(remaining).unwrap_or(u32::MAX))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Takes over the states of a limiter with a previous quota, for example
+    /// after reloading the configuration. States can not be converted
+    /// between algorithms, so they are dropped if the algorithm changed.
+    pub fn inherit(&mut self, previous: KeyedLimiter<K, S>) {
+        self.gc_stats.runs += previous.gc_stats.runs;
+        self.gc_stats.removed += previous.gc_stats.removed;
+        match (&mut self.strategy, previous.strategy) {
+            (
+                Strategy::Gcra {
+                    buckets, created, ..
+                },
+                Strategy::Gcra {
+                    buckets: previous_buckets,
+                    created: previous_created,
+                    ..
+                },
+            ) => {
+                // Rebase the theoretical arrival times onto the new clock.
+                let offset = created.saturating_duration_since(previous_created);
+                let offset = u64::try_from(offset.as_nanos()).unwrap_or(u64::MAX);
+                let mut buckets = buckets.borrow_mut();
+                std::mem::swap(&mut *buckets, &mut *previous_buckets.borrow_mut());
+                for state in buckets.values() {
+                    state.credit(offset);
+                }
+            }
+            (Strategy::SlidingWindow(windows), Strategy::SlidingWindow(previous_windows)) => {
+                windows.windows = previous_windows.windows;
+            }
+            _ => debug!("Dropped rate limiter states, because the algorithm changed"),
+        }
+        self.next_gc_len = max(self.initial_capacity, self.len() * 2);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The states of the keys that are not back to a full quota, for
+    /// example to hand them over to a new process.
+    pub fn export(&self) -> Vec<(K, KeyState)> {
+        match self.strategy {
+            Strategy::Gcra {
+                ref buckets,
+                created,
+                ref clock,
+                ..
+            } => {
+                let now = u64::try_from(clock.elapsed(created).as_nanos()).unwrap_or(u64::MAX);
+                buckets
+                    .borrow()
+                    .iter()
+                    .filter_map(|(key, state)| {
+                        let ahead = state
+                            .value
+                            .get()
+                            .checked_sub(now)
+                            .filter(|&ahead| ahead > 0)?;
+                        Some((
+                            key.clone(),
+                            KeyState::Gcra {
+                                ahead: Duration::from_nanos(ahead),
+                            },
+                        ))
+                    })
+                    .collect()
+            }
+            Strategy::SlidingWindow(ref windows) => {
+                let now = windows.clock.now();
+                windows
+                    .windows
+                    .iter()
+                    .filter_map(|(key, window)| {
+                        let ages: Vec<Duration> = window
+                            .iter()
+                            .map(|event| now.duration_since(*event))
+                            .filter(|age| *age < windows.period)
+                            .collect();
+                        (!ages.is_empty()).then(|| (key.clone(), KeyState::SlidingWindow { ages }))
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Takes over states from [`KeyedLimiter::export`]. Like with
+    /// [`KeyedLimiter::inherit`], states of the other algorithm are dropped.
+    pub fn import(&mut self, states: impl IntoIterator<Item = (K, KeyState)>) {
+        match self.strategy {
+            Strategy::Gcra {
+                ref buckets,
+                created,
+                ref clock,
+                ..
+            } => {
+                let now = u64::try_from(clock.elapsed(created).as_nanos()).unwrap_or(u64::MAX);
+                let mut buckets = buckets.borrow_mut();
+                for (key, state) in states {
+                    if let KeyState::Gcra { ahead } = state {
+                        let ahead = u64::try_from(ahead.as_nanos()).unwrap_or(u64::MAX);
+                        let value = UnsyncInMemoryState::default();
+                        value.value.set(now.saturating_add(ahead));
+                        buckets.insert(key, value);
+                    }
+                }
+            }
+            Strategy::SlidingWindow(ref mut windows) => {
+                let now = windows.clock.now();
+                for (key, state) in states {
+                    if let KeyState::SlidingWindow { ages } = state {
+                        let window: VecDeque<Instant> = ages
+                            .into_iter()
+                            .filter_map(|age| now.checked_sub(age))
+                            .collect();
+                        if !window.is_empty() {
+                            windows.windows.insert(key, window);
+                        }
+                    }
+                }
+            }
+        }
+        self.next_gc_len = max(self.next_gc_len, self.len() * 2);
+    }
+
+    /// Moves the states on, as if `duration` had passed without events,
+    /// for example after the clock stood still during suspend.
+    pub fn advance(&mut self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        match self.strategy {
+            Strategy::Gcra { ref buckets, .. } => {
+                for state in buckets.borrow().values() {
+                    state.credit(nanos);
+                }
+            }
+            Strategy::SlidingWindow(ref mut windows) => {
+                windows.shift(|event| event.checked_sub(duration));
+            }
+        }
+    }
+
+    /// Moves the states back, as if `duration` had not passed, for example
+    /// after the clock leapt ahead.
+    pub fn rewind(&mut self, duration: Duration) {
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        match self.strategy {
+            Strategy::Gcra { ref buckets, .. } => {
+                for state in buckets.borrow().values() {
+                    state.debit(nanos);
+                }
+            }
+            Strategy::SlidingWindow(ref mut windows) => {
+                windows.shift(|event| event.checked_add(duration));
+            }
+        }
+    }
+
+    /// Fills the table up to its capacity with `keys` and clears it again,
+    /// so that its memory is faulted in before it is needed. Only for an
+    /// empty limiter.
+    pub fn prewarm(&mut self, keys: impl Iterator<Item = K>) {
+        match self.strategy {
+            Strategy::Gcra { ref buckets, .. } => {
+                let mut buckets = buckets.borrow_mut();
+                let capacity = buckets.capacity();
+                buckets.extend(keys.take(capacity).map(|key| (key, Default::default())));
+                buckets.clear();
+            }
+            Strategy::SlidingWindow(ref mut windows) => {
+                let capacity = windows.windows.capacity();
+                windows
+                    .windows
+                    .extend(keys.take(capacity).map(|key| (key, VecDeque::new())));
+                windows.windows.clear();
+            }
+        }
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.gc_stats
     }
 
+    /// Garbage collects the table if it has doubled since the last time.
+    /// This happens on every check anyway, but can be called when the
+    /// limiter is idle, to free memory.
     pub fn maybe_gc(&mut self) {
-        if self.rate_limiter.len() >= self.next_gc_len {
-            let old_len = self.rate_limiter.len();
-            self.rate_limiter.retain_recent();
-            let new_len = self.rate_limiter.len();
+        if self.len() >= self.next_gc_len {
+            let old_len = self.len();
+            match self.strategy {
+                Strategy::Gcra {
+                    ref rate_limiter, ..
+                } => rate_limiter.retain_recent(),
+                Strategy::SlidingWindow(ref mut windows) => windows.retain_recent(),
+            }
+            let new_len = self.len();
 
             debug!("Garbage collected rate limiter table: {old_len} -> {new_len} entries");
+            self.gc_stats.runs += 1;
+            self.gc_stats.removed += (old_len - new_len) as u64;
 
             self.next_gc_len = max(self.initial_capacity, new_len * 2);
         }
@@ -0,0 +1,725 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Algorithm, Args, Clock, CountryCode, EmitTarget, Escalation, EventLogFormat, IpAnonymization,
+    KeyType, MaxBannedPolicy, PortIpsets, QueueOverflow, ReconcileMissing, SketchAlgorithm, Tenant,
+    TenantIpsets, WebhookFormat,
+};
+
+/// The settings of a [`Leroy`](crate::Leroy), independent of how they were
+/// given. Each field is documented at the flag of the same name in [`Args`].
+///
+/// The default has the defaults of the flags, no rate limit, no ban time and
+/// no ipset names, so at least those have to be set.
+///
+/// Serializes with the flag names and values, like a --config file, so that
+/// `toml::to_string(&config)` can be used as one. Missing keys take the
+/// default.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct LeroyConfig {
+    pub bl_threshold: u32,
+    pub bl_threshold_ipv4: Option<u32>,
+    pub bl_threshold_ipv6: Option<u32>,
+    /// Left out when zero, the default, which conflicts with `bl_rate`.
+    #[serde(with = "duration", skip_serializing_if = "Duration::is_zero")]
+    pub bl_period: Duration,
+    #[serde(with = "rate")]
+    pub bl_rate: Option<Duration>,
+    #[serde(with = "option_duration")]
+    pub bl_period_ipv4: Option<Duration>,
+    #[serde(with = "option_duration")]
+    pub bl_period_ipv6: Option<Duration>,
+    pub algorithm: Algorithm,
+    pub sketch_width: Option<usize>,
+    pub sketch_algorithm: SketchAlgorithm,
+    pub sketch_promote_threshold: u32,
+    #[serde(with = "duration")]
+    pub sketch_window: Duration,
+    #[serde(with = "duration")]
+    pub ipset_ban_ttl: Duration,
+    pub recidivism_decay: bool,
+    #[serde(with = "duration")]
+    pub ipset_base_time: Duration,
+    #[serde(with = "option_duration")]
+    pub ipset_base_time_ipv4: Option<Duration>,
+    #[serde(with = "option_duration")]
+    pub ipset_base_time_ipv6: Option<Duration>,
+    pub escalation: Escalation,
+    #[serde(with = "option_duration")]
+    pub ipset_max_time: Option<Duration>,
+    pub extend_bans: bool,
+    #[serde(with = "percentage")]
+    pub ban_jitter: f64,
+    pub ban_prefix_v4: u8,
+    pub ban_prefix_v6: u8,
+    pub subnet_prefix_v4: Option<u8>,
+    pub subnet_prefix_v6: Option<u8>,
+    pub subnet_threshold: u32,
+    #[serde(with = "duration")]
+    pub subnet_period: Duration,
+    pub asn_file: Option<PathBuf>,
+    pub asn_threshold: u32,
+    #[serde(with = "duration")]
+    pub asn_period: Duration,
+    pub asn_max_prefixes: usize,
+    pub approve_wide_bans: bool,
+    #[serde(with = "duration")]
+    pub approval_timeout: Duration,
+    pub geoip_file: Option<PathBuf>,
+    pub geoip_allow_countries: Vec<CountryCode>,
+    #[serde(with = "country_values")]
+    pub country_bl_threshold: Vec<(CountryCode, u32)>,
+    #[serde(with = "country_durations")]
+    pub country_ipset_base_time: Vec<(CountryCode, Duration)>,
+    #[serde(with = "port_values")]
+    pub port_bl_threshold: Vec<(u16, u32)>,
+    #[serde(with = "port_durations")]
+    pub port_ipset_base_time: Vec<(u16, Duration)>,
+    pub port_ipset: Vec<PortIpsets>,
+    pub tenant_ipset: Vec<TenantIpsets>,
+    #[serde(with = "tenant_values")]
+    pub tenant_bl_threshold: Vec<(Tenant, u32)>,
+    #[serde(with = "tenant_durations")]
+    pub tenant_ipset_base_time: Vec<(Tenant, Duration)>,
+    pub resolve_hostnames: Option<usize>,
+    pub resolve_max_addrs: usize,
+    #[serde(with = "duration")]
+    pub resolve_min_ttl: Duration,
+    #[serde(with = "duration")]
+    pub resolve_max_ttl: Duration,
+    pub allowlist_file: Option<PathBuf>,
+    pub ban_private_ranges: bool,
+    pub good_credit: u32,
+    pub good_recidivism_credit: u32,
+    pub max_banned: Option<usize>,
+    pub expiry_events: bool,
+    pub max_banned_policy: MaxBannedPolicy,
+    pub ipset_ipv4_name: String,
+    pub ipset_ipv6_name: String,
+    pub ipset_comments: bool,
+    pub skip_self_test: bool,
+    pub self_test_ipv4: Ipv4Addr,
+    pub self_test_ipv6: Ipv6Addr,
+    #[serde(with = "option_duration")]
+    pub verify_bans_interval: Option<Duration>,
+    pub verify_bans_sample: u32,
+    #[serde(with = "option_duration")]
+    pub reconcile_interval: Option<Duration>,
+    pub reconcile_missing: ReconcileMissing,
+    pub reconcile_adopt: bool,
+    pub watch_threshold: Option<u32>,
+    pub ipset_watch_ipv4_name: Option<String>,
+    pub ipset_watch_ipv6_name: Option<String>,
+    #[serde(with = "duration")]
+    pub ipset_watch_time: Duration,
+    pub netlink_queue: Option<usize>,
+    pub netlink_queue_overflow: QueueOverflow,
+    pub netlink_pin_cpu: Option<usize>,
+    pub attack_line_rate: Option<u64>,
+    pub attack_ban_rate: Option<u64>,
+    #[serde(with = "duration")]
+    pub attack_window: Duration,
+    pub attack_bl_threshold: Option<u32>,
+    #[serde(with = "option_duration")]
+    pub attack_ipset_base_time: Option<Duration>,
+    #[serde(with = "duration")]
+    pub reporting_ban_time_period: Duration,
+    #[serde(with = "duration")]
+    pub reporting_ip_time_period: Duration,
+    pub parse_error_examples: u64,
+    pub cache_initial_capacity: usize,
+    pub cache_max_size: u64,
+    pub lock_memory: bool,
+    pub pin_cpu: Option<usize>,
+    pub sched_fifo: Option<u8>,
+    pub nice: Option<i8>,
+    pub drop_capabilities: bool,
+    pub user: Option<String>,
+    pub chroot: Option<PathBuf>,
+    pub seccomp: bool,
+    pub health_listen: Option<SocketAddr>,
+    #[serde(with = "option_duration")]
+    pub health_max_idle: Option<Duration>,
+    pub admin_socket: Option<PathBuf>,
+    pub admin_listen: Option<SocketAddr>,
+    pub admin_token_file: Option<PathBuf>,
+    pub cluster_listen: Option<SocketAddr>,
+    pub cluster_peer: Vec<String>,
+    pub cluster_token_file: Option<PathBuf>,
+    pub cluster_node_id: Option<String>,
+    pub forward_bans: bool,
+    pub event_log: Option<PathBuf>,
+    pub event_log_format: EventLogFormat,
+    pub event_log_capacity: usize,
+    pub event_log_reverse_dns: Option<usize>,
+    pub anonymize_ips: Option<IpAnonymization>,
+    pub anonymize_key_file: Option<PathBuf>,
+    pub emit_bans: Option<EmitTarget>,
+    pub emit_bans_json: bool,
+    pub passthrough: bool,
+    pub webhook_url: Option<String>,
+    pub webhook_ban_threshold: Option<u64>,
+    pub banned_events_threshold: Option<u64>,
+    pub webhook_format: WebhookFormat,
+    pub key_exec: Option<PathBuf>,
+    pub key_webhook_url: Option<String>,
+    pub key_type: KeyType,
+    pub ipset_mac_name: Option<String>,
+    pub abuseipdb_key_file: Option<PathBuf>,
+    pub abuseipdb_categories: Vec<u8>,
+    pub threat_intel_url: Option<String>,
+    pub abuse_report_max_per_day: u32,
+    pub statsd_addr: Option<String>,
+    pub statsd_prefix: String,
+    pub statsd_tags: Vec<String>,
+    #[serde(with = "duration")]
+    pub statsd_period: Duration,
+    pub otlp_endpoint: Option<String>,
+    #[serde(with = "duration")]
+    pub otlp_period: Duration,
+    pub state_file: Option<PathBuf>,
+    #[serde(with = "duration")]
+    pub state_save_period: Duration,
+    #[serde(with = "duration")]
+    pub warmup: Duration,
+    pub dry_run: bool,
+    pub dry_run_capture: usize,
+    pub dry_run_json: bool,
+    pub monitor_only: bool,
+    /// Not a flag. The real time by default, or a [`Clock::fake`] for tests
+    /// and replays. Kept on reload.
+    #[serde(skip)]
+    pub clock: Clock,
+}
+
+impl Default for LeroyConfig {
+    fn default() -> LeroyConfig {
+        LeroyConfig {
+            bl_threshold: 0,
+            bl_threshold_ipv4: None,
+            bl_threshold_ipv6: None,
+            bl_period: Duration::ZERO,
+            bl_rate: None,
+            bl_period_ipv4: None,
+            bl_period_ipv6: None,
+            algorithm: Algorithm::Gcra,
+            sketch_width: None,
+            sketch_algorithm: SketchAlgorithm::CountMin,
+            sketch_promote_threshold: 10,
+            sketch_window: Duration::from_secs(60),
+            ipset_ban_ttl: Duration::ZERO,
+            recidivism_decay: false,
+            ipset_base_time: Duration::ZERO,
+            ipset_base_time_ipv4: None,
+            ipset_base_time_ipv6: None,
+            escalation: Escalation::Linear,
+            ipset_max_time: None,
+            extend_bans: false,
+            ban_jitter: 0.0,
+            ban_prefix_v4: 32,
+            ban_prefix_v6: 128,
+            subnet_prefix_v4: None,
+            subnet_prefix_v6: None,
+            subnet_threshold: 10,
+            subnet_period: Duration::from_secs(60),
+            asn_file: None,
+            asn_threshold: 100,
+            asn_period: Duration::from_secs(600),
+            asn_max_prefixes: 64,
+            approve_wide_bans: false,
+            approval_timeout: Duration::from_secs(3600),
+            geoip_file: None,
+            geoip_allow_countries: Vec::new(),
+            country_bl_threshold: Vec::new(),
+            country_ipset_base_time: Vec::new(),
+            port_bl_threshold: Vec::new(),
+            port_ipset_base_time: Vec::new(),
+            port_ipset: Vec::new(),
+            tenant_ipset: Vec::new(),
+            tenant_bl_threshold: Vec::new(),
+            tenant_ipset_base_time: Vec::new(),
+            resolve_hostnames: None,
+            resolve_max_addrs: 4,
+            resolve_min_ttl: Duration::from_secs(60),
+            resolve_max_ttl: Duration::from_secs(3600),
+            allowlist_file: None,
+            ban_private_ranges: false,
+            good_credit: 0,
+            good_recidivism_credit: 0,
+            max_banned: None,
+            expiry_events: false,
+            max_banned_policy: MaxBannedPolicy::Stop,
+            ipset_ipv4_name: String::new(),
+            ipset_ipv6_name: String::new(),
+            ipset_comments: false,
+            skip_self_test: false,
+            self_test_ipv4: Ipv4Addr::LOCALHOST,
+            self_test_ipv6: Ipv6Addr::LOCALHOST,
+            verify_bans_interval: None,
+            verify_bans_sample: 20,
+            reconcile_interval: None,
+            reconcile_missing: ReconcileMissing::Forget,
+            reconcile_adopt: false,
+            watch_threshold: None,
+            ipset_watch_ipv4_name: None,
+            ipset_watch_ipv6_name: None,
+            ipset_watch_time: Duration::from_secs(600),
+            netlink_queue: None,
+            netlink_queue_overflow: QueueOverflow::Block,
+            netlink_pin_cpu: None,
+            attack_line_rate: None,
+            attack_ban_rate: None,
+            attack_window: Duration::from_secs(60),
+            attack_bl_threshold: None,
+            attack_ipset_base_time: None,
+            reporting_ban_time_period: Duration::from_secs(10),
+            reporting_ip_time_period: Duration::from_secs(10),
+            parse_error_examples: 5,
+            cache_initial_capacity: 100000,
+            cache_max_size: 500000,
+            lock_memory: false,
+            pin_cpu: None,
+            sched_fifo: None,
+            nice: None,
+            drop_capabilities: false,
+            user: None,
+            chroot: None,
+            seccomp: false,
+            health_listen: None,
+            health_max_idle: None,
+            admin_socket: None,
+            admin_listen: None,
+            admin_token_file: None,
+            cluster_listen: None,
+            cluster_peer: Vec::new(),
+            cluster_token_file: None,
+            cluster_node_id: None,
+            forward_bans: false,
+            event_log: None,
+            event_log_format: EventLogFormat::Json,
+            event_log_capacity: 10000,
+            event_log_reverse_dns: None,
+            anonymize_ips: None,
+            anonymize_key_file: None,
+            emit_bans: None,
+            emit_bans_json: false,
+            passthrough: false,
+            webhook_url: None,
+            webhook_ban_threshold: None,
+            banned_events_threshold: None,
+            webhook_format: WebhookFormat::Slack,
+            key_exec: None,
+            key_webhook_url: None,
+            key_type: KeyType::Ip,
+            ipset_mac_name: None,
+            abuseipdb_key_file: None,
+            abuseipdb_categories: vec![4],
+            threat_intel_url: None,
+            abuse_report_max_per_day: 1000,
+            statsd_addr: None,
+            statsd_prefix: "leroyjenkins".to_owned(),
+            statsd_tags: Vec::new(),
+            statsd_period: Duration::from_secs(10),
+            otlp_endpoint: None,
+            otlp_period: Duration::from_secs(10),
+            state_file: None,
+            state_save_period: Duration::from_secs(60),
+            warmup: Duration::ZERO,
+            dry_run: false,
+            dry_run_capture: 1000,
+            dry_run_json: false,
+            monitor_only: false,
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl From<Args> for LeroyConfig {
+    fn from(args: Args) -> LeroyConfig {
+        LeroyConfig {
+            bl_threshold: args.bl_threshold,
+            bl_threshold_ipv4: args.bl_threshold_ipv4,
+            bl_threshold_ipv6: args.bl_threshold_ipv6,
+            bl_period: args.bl_period,
+            bl_rate: args.bl_rate,
+            bl_period_ipv4: args.bl_period_ipv4,
+            bl_period_ipv6: args.bl_period_ipv6,
+            algorithm: args.algorithm,
+            sketch_width: args.sketch_width,
+            sketch_algorithm: args.sketch_algorithm,
+            sketch_promote_threshold: args.sketch_promote_threshold,
+            sketch_window: args.sketch_window,
+            ipset_ban_ttl: args.ipset_ban_ttl,
+            recidivism_decay: args.recidivism_decay,
+            ipset_base_time: args.ipset_base_time,
+            ipset_base_time_ipv4: args.ipset_base_time_ipv4,
+            ipset_base_time_ipv6: args.ipset_base_time_ipv6,
+            escalation: args.escalation,
+            ipset_max_time: args.ipset_max_time,
+            extend_bans: args.extend_bans,
+            ban_jitter: args.ban_jitter,
+            ban_prefix_v4: args.ban_prefix_v4,
+            ban_prefix_v6: args.ban_prefix_v6,
+            subnet_prefix_v4: args.subnet_prefix_v4,
+            subnet_prefix_v6: args.subnet_prefix_v6,
+            subnet_threshold: args.subnet_threshold,
+            subnet_period: args.subnet_period,
+            asn_file: args.asn_file,
+            asn_threshold: args.asn_threshold,
+            asn_period: args.asn_period,
+            asn_max_prefixes: args.asn_max_prefixes,
+            approve_wide_bans: args.approve_wide_bans,
+            approval_timeout: args.approval_timeout,
+            geoip_file: args.geoip_file,
+            geoip_allow_countries: args.geoip_allow_countries,
+            country_bl_threshold: args.country_bl_threshold,
+            country_ipset_base_time: args.country_ipset_base_time,
+            port_bl_threshold: args.port_bl_threshold,
+            port_ipset_base_time: args.port_ipset_base_time,
+            port_ipset: args.port_ipset,
+            tenant_ipset: args.tenant_ipset,
+            tenant_bl_threshold: args.tenant_bl_threshold,
+            tenant_ipset_base_time: args.tenant_ipset_base_time,
+            resolve_hostnames: args.resolve_hostnames,
+            resolve_max_addrs: args.resolve_max_addrs,
+            resolve_min_ttl: args.resolve_min_ttl,
+            resolve_max_ttl: args.resolve_max_ttl,
+            allowlist_file: args.allowlist_file,
+            ban_private_ranges: args.ban_private_ranges,
+            good_credit: args.good_credit,
+            good_recidivism_credit: args.good_recidivism_credit,
+            max_banned: args.max_banned,
+            expiry_events: args.expiry_events,
+            max_banned_policy: args.max_banned_policy,
+            ipset_ipv4_name: args.ipset_ipv4_name,
+            ipset_ipv6_name: args.ipset_ipv6_name,
+            ipset_comments: args.ipset_comments,
+            skip_self_test: args.skip_self_test,
+            self_test_ipv4: args.self_test_ipv4,
+            self_test_ipv6: args.self_test_ipv6,
+            verify_bans_interval: args.verify_bans_interval,
+            verify_bans_sample: args.verify_bans_sample,
+            reconcile_interval: args.reconcile_interval,
+            reconcile_missing: args.reconcile_missing,
+            reconcile_adopt: args.reconcile_adopt,
+            watch_threshold: args.watch_threshold,
+            ipset_watch_ipv4_name: args.ipset_watch_ipv4_name,
+            ipset_watch_ipv6_name: args.ipset_watch_ipv6_name,
+            ipset_watch_time: args.ipset_watch_time,
+            netlink_queue: args.netlink_queue,
+            netlink_queue_overflow: args.netlink_queue_overflow,
+            netlink_pin_cpu: args.netlink_pin_cpu,
+            attack_line_rate: args.attack_line_rate,
+            attack_ban_rate: args.attack_ban_rate,
+            attack_window: args.attack_window,
+            attack_bl_threshold: args.attack_bl_threshold,
+            attack_ipset_base_time: args.attack_ipset_base_time,
+            reporting_ban_time_period: args.reporting_ban_time_period,
+            reporting_ip_time_period: args.reporting_ip_time_period,
+            parse_error_examples: args.parse_error_examples,
+            cache_initial_capacity: args.cache_initial_capacity,
+            cache_max_size: args.cache_max_size,
+            lock_memory: args.lock_memory,
+            pin_cpu: args.pin_cpu,
+            sched_fifo: args.sched_fifo,
+            nice: args.nice,
+            drop_capabilities: args.drop_capabilities,
+            user: args.user,
+            chroot: args.chroot,
+            seccomp: args.seccomp,
+            health_listen: args.health_listen,
+            health_max_idle: args.health_max_idle,
+            admin_socket: args.admin_socket,
+            admin_listen: args.admin_listen,
+            admin_token_file: args.admin_token_file,
+            cluster_listen: args.cluster_listen,
+            cluster_peer: args.cluster_peer,
+            cluster_token_file: args.cluster_token_file,
+            cluster_node_id: args.cluster_node_id,
+            forward_bans: args.forward_bans,
+            event_log: args.event_log,
+            event_log_format: args.event_log_format,
+            event_log_capacity: args.event_log_capacity,
+            event_log_reverse_dns: args.event_log_reverse_dns,
+            anonymize_ips: args.anonymize_ips,
+            anonymize_key_file: args.anonymize_key_file,
+            emit_bans: args.emit_bans,
+            emit_bans_json: args.emit_bans_json,
+            passthrough: args.passthrough,
+            webhook_url: args.webhook_url,
+            webhook_ban_threshold: args.webhook_ban_threshold,
+            banned_events_threshold: args.banned_events_threshold,
+            webhook_format: args.webhook_format,
+            key_exec: args.key_exec,
+            key_webhook_url: args.key_webhook_url,
+            key_type: args.key_type,
+            ipset_mac_name: args.ipset_mac_name,
+            abuseipdb_key_file: args.abuseipdb_key_file,
+            abuseipdb_categories: args.abuseipdb_categories,
+            threat_intel_url: args.threat_intel_url,
+            abuse_report_max_per_day: args.abuse_report_max_per_day,
+            statsd_addr: args.statsd_addr,
+            statsd_prefix: args.statsd_prefix,
+            statsd_tags: args.statsd_tags,
+            statsd_period: args.statsd_period,
+            otlp_endpoint: args.otlp_endpoint,
+            otlp_period: args.otlp_period,
+            state_file: args.state_file,
+            state_save_period: args.state_save_period,
+            warmup: args.warmup,
+            dry_run: args.dry_run,
+            dry_run_capture: args.dry_run_capture,
+            dry_run_json: args.dry_run_json,
+            monitor_only: args.monitor_only,
+            clock: Clock::default(),
+        }
+    }
+}
+
+/// Durations like `1h 30m`.
+mod duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*duration))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        crate::parse_duration(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+mod option_duration {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::duration::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| crate::parse_duration(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Rates like `10/s`, kept as the time per event.
+mod rate {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        per_event: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match per_event {
+            Some(per_event) => serializer.collect_str(&format_args!(
+                "1/{}",
+                humantime::format_duration(*per_event)
+            )),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|s| crate::parse_rate(&s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+/// Fractions as percentages like `10%`.
+mod percentage {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(fraction: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{}%", fraction * 100.0))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        crate::parse_percentage(&String::deserialize(deserializer)?)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Lists like `["CN=5"]`.
+mod country_values {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::{geoip::parse_country_value, CountryCode};
+
+    pub fn serialize<S: Serializer>(
+        values: &[(CountryCode, u32)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            values
+                .iter()
+                .map(|(country, value)| format!("{country}={value}")),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(CountryCode, u32)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_country_value(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Lists like `["CN=1h"]`.
+mod country_durations {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::CountryCode;
+
+    pub fn serialize<S: Serializer>(
+        durations: &[(CountryCode, Duration)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(durations.iter().map(|(country, duration)| {
+            format!("{country}={}", humantime::format_duration(*duration))
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(CountryCode, Duration)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| crate::parse_country_duration(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Lists like `["22=3"]`.
+mod port_values {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::port_policy::parse_port_value;
+
+    pub fn serialize<S: Serializer>(
+        values: &[(u16, u32)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(values.iter().map(|(port, value)| format!("{port}={value}")))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(u16, u32)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_port_value(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Lists like `["22=1d"]`.
+mod port_durations {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::port_policy::parse_port_duration;
+
+    pub fn serialize<S: Serializer>(
+        durations: &[(u16, Duration)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            durations.iter().map(|(port, duration)| {
+                format!("{port}={}", humantime::format_duration(*duration))
+            }),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(u16, Duration)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_port_duration(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Lists like `["shop=3"]`.
+mod tenant_values {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::{tenant::parse_tenant_value, Tenant};
+
+    pub fn serialize<S: Serializer>(
+        values: &[(Tenant, u32)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(
+            values
+                .iter()
+                .map(|(tenant, value)| format!("{tenant}={value}")),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Tenant, u32)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_tenant_value(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
+
+/// Lists like `["shop=1d"]`.
+mod tenant_durations {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::{tenant::parse_tenant_duration, Tenant};
+
+    pub fn serialize<S: Serializer>(
+        durations: &[(Tenant, Duration)],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(durations.iter().map(|(tenant, duration)| {
+            format!("{tenant}={}", humantime::format_duration(*duration))
+        }))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<(Tenant, Duration)>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|s| parse_tenant_duration(s).map_err(serde::de::Error::custom))
+            .collect()
+    }
+}
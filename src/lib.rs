@@ -1,40 +1,186 @@
-#![feature(addr_parse_ascii)]
-
+mod abuse_report;
+mod admin;
+mod admin_http;
+mod allowlist;
+mod anonymize;
+mod approval;
+mod asn;
+#[cfg(feature = "tokio")]
+mod async_handle;
+mod attack;
+mod backend;
+mod ban_reason;
+mod builder;
+mod check;
+mod clock;
+mod cluster;
+mod config;
+mod decision;
+mod error;
+mod event_log;
+mod geoip;
+mod handle;
+mod handover;
+mod health;
+mod hooks;
 mod ip_family;
+mod key_action;
+mod key_banner;
 mod keyed_limiter;
+mod leroy_config;
+mod listen_fds;
+mod live_bans;
+mod log_limiter;
+mod mac;
+mod masked_ip;
+mod memory;
+mod metrics;
+#[cfg(any(test, feature = "mock"))]
+#[cfg_attr(not(feature = "mock"), allow(dead_code))]
+mod mock_backend;
+mod netlink_queue;
+mod otlp;
+mod output;
+mod parse_errors;
+mod port_policy;
+mod rdns;
+mod resolve;
+mod sandbox;
+mod sched;
+mod siem;
+mod sketch;
+mod space_saving;
+mod state;
+mod statsd;
+mod tenant;
+mod ttl_map;
+mod webhook;
 
 use std::{
-    error::Error,
-    hash::BuildHasherDefault,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs,
+    hash::{BuildHasher, BuildHasherDefault, Hash},
+    io, iter, mem,
+    net::{AddrParseError, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::NonZeroU32,
-    time::{Duration, Instant},
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    str,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use governor::Quota;
-use ipset::{
-    types::{AddOption, HashIp},
-    Session,
-};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use mini_moka::unsync::Cache;
 use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
 
+#[cfg(feature = "mock")]
+pub use crate::mock_backend::{MockBackend, MockOp};
 use crate::{
+    abuse_report::{AbuseIpDb, AbuseReport, AbuseReporter},
+    admin::{
+        ActiveBan, AdminQueue, Budget, DaemonStatus, IpStatus, RateLimiterDump, RecidivismDump,
+        StateDump, Timestamp,
+    },
+    allowlist::Allowlist,
+    approval::{ApprovalQueue, Queued},
+    asn::AsnDatabase,
+    attack::AttackDetector,
+    backend::add_with_retry,
+    clock::{ClockJump, JumpDetector},
+    cluster::Cluster,
+    event_log::{Event, EventLog},
+    geoip::{parse_country_value, GeoIp},
+    handover::Handover,
+    health::Health,
+    hooks::Hooks,
     ip_family::{ByIpFamily, IpFamily},
-    keyed_limiter::KeyedLimiter,
+    listen_fds::ListenFds,
+    live_bans::LiveBans,
+    log_limiter::LogLimiter,
+    metrics::{hit_rate, BanCounts, Metrics},
+    netlink_queue::{Backends, NetlinkQueue, QueueFull, Report, Sets},
+    otlp::Otlp,
+    output::Output,
+    parse_errors::ParseErrors,
+    port_policy::{parse_port_duration, parse_port_value},
+    resolve::{Hostname, Resolved, Resolver},
+    sched::Scheduling,
+    sketch::Sketch,
+    state::Recidivism,
+    statsd::Statsd,
+    tenant::{parse_tenant_duration, parse_tenant_value},
+    ttl_map::{insert_with_ttl, prewarm_cache, ttl_cache, TtlCache},
+    webhook::Webhook,
 };
+pub use crate::{
+    admin::{request as admin_request, AdminCommand, AdminReply},
+    anonymize::{AnonymizedIp, IpAnonymization, IpAnonymizer},
+    backend::{Backend, BackendError},
+    ban_reason::{BanReason, InvalidBanReason},
+    builder::LeroyBuilder,
+    check::{check, CheckReport},
+    clock::Clock,
+    config::args_with_config,
+    decision::Decision,
+    error::LeroyError,
+    event_log::{BanCategory, EventLogFormat, UnbanReason},
+    geoip::CountryCode,
+    handle::{LeroyHandle, LeroyThread},
+    hooks::{BanEvent, UnbanEvent},
+    key_action::{ExecAction, KeyAction, MacSetAction, WebhookAction},
+    key_banner::{KeyBanner, KeyType},
+    keyed_limiter::{GcStats, KeyState, KeyedLimiter, RateLimited},
+    leroy_config::LeroyConfig,
+    masked_ip::MaskedIpAddr,
+    netlink_queue::QueueOverflow,
+    output::EmitTarget,
+    port_policy::PortIpsets,
+    sketch::SketchAlgorithm,
+    tenant::{InvalidTenant, Tenant, TenantIpsets},
+    webhook::WebhookFormat,
+};
+
+/// How long a new ban may take to reach the firewall, and lines already
+/// written to reach leroyjenkins, before events of the address count for
+/// --banned-events-threshold.
+const BANNED_EVENT_GRACE: Duration = Duration::from_secs(10);
+
+/// Cached bans that end within this time may be gone from the ipset already,
+/// and are not missing for --reconcile-interval.
+const RECONCILE_MARGIN: Duration = Duration::from_secs(2);
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_override_self = true)]
 pub struct Args {
-    /// The number of events that has to be exceeded before a ban decision.
-    /// Combines with `bl_period` to determine the exact rate limit.
-    /// see: https://github.com/antifuchs/governor/blob/master/governor/src/quota.rs#L9
+    /// Read flags from this TOML file, with the long flag names as keys,
+    /// like `bl-threshold = 100`. Flags on the command line take precedence.
+    /// Send `SIGHUP` to reload it.
+    ///
+    /// Every flag can also be set with an environment variable like
+    /// `LEROY_BL_THRESHOLD`, which overrides the file but not the command
+    /// line.
     #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// The number of events that has to be exceeded before a ban decision,
+    /// i.e. the burst size. Combines with `bl_period` (or `bl_rate`) to
+    /// determine the exact rate limit.
+    /// see: https://github.com/antifuchs/governor/blob/master/governor/src/quota.rs#L9
+    #[arg(long, visible_alias = "bl-burst")]
     pub bl_threshold: u32,
 
+    /// Overrides `bl_threshold` for IPv4 addresses.
+    #[arg(long)]
+    pub bl_threshold_ipv4: Option<u32>,
+
+    /// Overrides `bl_threshold` for IPv6 addresses.
+    #[arg(long)]
+    pub bl_threshold_ipv6: Option<u32>,
+
     /// The amount of time before the rate limiter is fully replenished.
     /// Uses humantime to parse the duration.
     /// See: https://docs.rs/humantime/latest/humantime/ for details
@@ -44,6 +190,48 @@ pub struct Args {
     #[arg(long, default_value = "0s", value_parser = parse_duration)]
     pub bl_period: Duration,
 
+    /// The sustained rate, like `10/s` or `100/5m`, as an alternative to
+    /// `bl_period`. Internally this is the time to replenish one event.
+    #[arg(long, value_parser = parse_rate, conflicts_with = "bl_period")]
+    pub bl_rate: Option<Duration>,
+
+    /// Overrides `bl_period` for IPv4 addresses.
+    #[arg(long, value_parser = parse_duration)]
+    pub bl_period_ipv4: Option<Duration>,
+
+    /// Overrides `bl_period` for IPv6 addresses.
+    #[arg(long, value_parser = parse_duration)]
+    pub bl_period_ipv6: Option<Duration>,
+
+    /// How rate limits are counted.
+    #[arg(long, value_enum, default_value_t = Algorithm::Gcra)]
+    pub algorithm: Algorithm,
+
+    /// Pre-filter IPs with a sketch of this many counters (per row), see
+    /// --sketch-algorithm. Only IPs with more than
+    /// --sketch-promote-threshold estimated events are tracked by the exact
+    /// rate limiters, which bounds memory during attacks from very many
+    /// distinct IPs. Those first events do not count towards `bl_threshold`,
+    /// and estimates may promote IPs early or late, depending on the
+    /// algorithm.
+    #[arg(long)]
+    pub sketch_width: Option<usize>,
+
+    /// How the --sketch-width pre-filter estimates event counts.
+    #[arg(long, value_enum, default_value_t = SketchAlgorithm::CountMin)]
+    pub sketch_algorithm: SketchAlgorithm,
+
+    /// See --sketch-width.
+    #[arg(long, default_value = "10")]
+    pub sketch_promote_threshold: u32,
+
+    /// How often estimated counts in the sketch are halved.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "1m", value_parser = parse_duration)]
+    pub sketch_window: Duration,
+
     /// Recidivists get banned longer for their subsequent bans.
     /// This reperesents the amount of time we'll keep the history around.
     /// Everytime we :hammer-time: them, it will reset this countdown.
@@ -55,14 +243,260 @@ pub struct Args {
     #[arg(long, value_parser = parse_duration)]
     pub ipset_ban_ttl: Duration,
 
-    /// The time of the first ban. Each subsequent ban will be increased
-    /// linearly by this amount (resulting in --ipset-base-time * ban count).
+    /// Instead of forgetting all previous bans at once, decrease the ban
+    /// count by one for every --ipset-ban-ttl without a ban.
+    #[arg(long)]
+    pub recidivism_decay: bool,
+
+    /// The time of the first ban. Subsequent bans are increased according
+    /// to --escalation.
     ///
     /// Uses humantime to parse the duration.
     /// See: https://docs.rs/humantime/latest/humantime/ for details
     #[arg(long, value_parser = parse_duration)]
     pub ipset_base_time: Duration,
 
+    /// Overrides --ipset-base-time for IPv4 addresses.
+    #[arg(long, value_parser = parse_duration)]
+    pub ipset_base_time_ipv4: Option<Duration>,
+
+    /// Overrides --ipset-base-time for IPv6 addresses.
+    #[arg(long, value_parser = parse_duration)]
+    pub ipset_base_time_ipv6: Option<Duration>,
+
+    /// How ban times grow for recidivists. `linear` bans for
+    /// --ipset-base-time * ban count, `exponential` for
+    /// --ipset-base-time * 2^(ban count - 1).
+    #[arg(long, value_enum, default_value_t = Escalation::Linear)]
+    pub escalation: Escalation,
+
+    /// The maximum time of a single ban, no matter how often the IP has
+    /// been banned before.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, value_parser = parse_duration)]
+    pub ipset_max_time: Option<Duration>,
+
+    /// When a banned IP exceeds the rate limit again, for example through
+    /// events that do not pass the firewall, add the time of the new ban to
+    /// the remaining time of its ban, up to --ipset-max-time. Each ban is
+    /// extended at most once per --bl-period. By default, such events are
+    /// ignored until the ban expires.
+    #[arg(long)]
+    pub extend_bans: bool,
+
+    /// Randomly lengthen or shorten each ban by up to this percentage, so
+    /// that IPs banned at the same time do not all return at the same time.
+    /// Bans stay within --ipset-max-time and last at least a second.
+    #[arg(long, default_value = "0%", value_parser = parse_percentage)]
+    pub ban_jitter: f64,
+
+    /// Rate limit and ban whole IPv4 networks of this prefix length instead
+    /// of single addresses. The ipsets must be of type hash:net when using
+    /// prefixes shorter than 32.
+    #[arg(long, default_value = "32", value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub ban_prefix_v4: u8,
+
+    /// Rate limit and ban whole IPv6 networks of this prefix length instead
+    /// of single addresses. /64 is a good choice, because hosts can freely
+    /// rotate addresses within their /64. The ipsets must be of type
+    /// hash:net when using prefixes shorter than 128.
+    #[arg(long, default_value = "128", value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub ban_prefix_v6: u8,
+
+    /// Additionally ban the entire IPv4 network of this prefix length (e.g.
+    /// 24), once `subnet_threshold` bans within it have been exceeded.
+    /// Defeats attackers rotating addresses within one provider's block.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=32))]
+    pub subnet_prefix_v4: Option<u8>,
+
+    /// Additionally ban the entire IPv6 network of this prefix length (e.g.
+    /// 48), once `subnet_threshold` bans within it have been exceeded.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=128))]
+    pub subnet_prefix_v6: Option<u8>,
+
+    /// The number of distinct bans within a network that has to be exceeded
+    /// before banning the entire network. Combines with `subnet_period` just
+    /// like `bl_threshold` and `bl_period`.
+    #[arg(long, default_value = "10")]
+    pub subnet_threshold: u32,
+
+    /// The amount of time before the subnet rate limiter is fully
+    /// replenished.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "1m", value_parser = parse_duration)]
+    pub subnet_period: Duration,
+
+    /// An ip2asn TSV database (e.g. from https://iptoasn.com/), to ban all
+    /// networks of an autonomous system once `asn_threshold` bans within it
+    /// have been exceeded. Requires hash:net ipsets.
+    #[arg(long)]
+    pub asn_file: Option<PathBuf>,
+
+    /// The number of distinct bans within an autonomous system that has to
+    /// be exceeded before banning all its networks. Combines with
+    /// `asn_period` just like `bl_threshold` and `bl_period`.
+    #[arg(long, default_value = "100")]
+    pub asn_threshold: u32,
+
+    /// The amount of time before the autonomous system rate limiter is
+    /// fully replenished.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "10m", value_parser = parse_duration)]
+    pub asn_period: Duration,
+
+    /// Never ban autonomous systems that announce more networks than this.
+    #[arg(long, default_value = "64")]
+    pub asn_max_prefixes: usize,
+
+    /// Instead of banning whole subnets and autonomous systems right away,
+    /// queue them for approval with the `approve` admin command. Needs
+    /// --admin-socket or --admin-listen.
+    #[arg(long)]
+    pub approve_wide_bans: bool,
+
+    /// How long a wide ban waits for approval before it is dropped. It is
+    /// queued again at its next event over the limit. Rejected bans are
+    /// not queued again for this long either.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "1h", value_parser = parse_duration)]
+    pub approval_timeout: Duration,
+
+    /// A MaxMind GeoLite2 Country (or City) database, to apply per-country
+    /// policies.
+    #[arg(long)]
+    pub geoip_file: Option<PathBuf>,
+
+    /// Comma separated country codes that are never rate limited or banned.
+    #[arg(long, value_delimiter = ',', requires = "geoip_file")]
+    pub geoip_allow_countries: Vec<CountryCode>,
+
+    /// Overrides `bl_threshold` for a country, given as `COUNTRY=THRESHOLD`,
+    /// e.g. `CN=5`. A threshold of 0 bans on sight. Can be repeated.
+    #[arg(long, value_parser = parse_country_value::<u32>, requires = "geoip_file")]
+    pub country_bl_threshold: Vec<(CountryCode, u32)>,
+
+    /// Overrides --ipset-base-time for a country, given as
+    /// `COUNTRY=DURATION`, e.g. `CN=1h`. Can be repeated.
+    #[arg(long, value_parser = parse_country_duration, requires = "geoip_file")]
+    pub country_ipset_base_time: Vec<(CountryCode, Duration)>,
+
+    /// Overrides `bl_threshold` for events on a destination port, given as
+    /// `PORT=THRESHOLD`, e.g. `22=3`. Lines carry the port after the
+    /// address, like `192.0.2.1:22` or `[2001:db8::1]:22`. Takes precedence
+    /// over --country-bl-threshold. Can be repeated.
+    #[arg(long, value_parser = parse_port_value::<u32>)]
+    pub port_bl_threshold: Vec<(u16, u32)>,
+
+    /// Overrides --ipset-base-time for bans caused by events on a
+    /// destination port, given as `PORT=DURATION`, e.g. `22=1d`. Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_port_duration)]
+    pub port_ipset_base_time: Vec<(u16, Duration)>,
+
+    /// Adds bans caused by events on a destination port to other ipsets
+    /// than --ipset-ipv4-name and --ipset-ipv6-name, given as
+    /// `PORT=IPV4_SET,IPV6_SET`, e.g. `22=leroy-ssh4,leroy-ssh6`, for
+    /// example to block only that service. Can be repeated.
+    #[arg(long)]
+    pub port_ipset: Vec<PortIpsets>,
+
+    /// Adds bans of a tenant to other ipsets than --ipset-ipv4-name and
+    /// --ipset-ipv6-name, given as `TENANT=IPV4_SET,IPV6_SET`, e.g.
+    /// `shop=leroy-shop4,leroy-shop6`. Lines carry the tenant before the
+    /// address, like `shop@192.0.2.1`, so that one instance can protect
+    /// several sites or customers. Each tenant counts events with rate
+    /// limits of its own. Lines of other tenants are handled like untagged
+    /// ones. Can be repeated.
+    #[arg(long)]
+    pub tenant_ipset: Vec<TenantIpsets>,
+
+    /// Overrides `bl_threshold` for a tenant, given as `TENANT=THRESHOLD`,
+    /// e.g. `shop=3`. Takes precedence over --port-bl-threshold and
+    /// --country-bl-threshold. Can be repeated.
+    #[arg(long, value_parser = parse_tenant_value::<u32>)]
+    pub tenant_bl_threshold: Vec<(Tenant, u32)>,
+
+    /// Overrides --ipset-base-time for bans of a tenant, given as
+    /// `TENANT=DURATION`, e.g. `shop=1d`. Takes precedence over
+    /// --port-ipset-base-time and --country-ipset-base-time. Can be
+    /// repeated.
+    #[arg(long, value_parser = parse_tenant_duration)]
+    pub tenant_ipset_base_time: Vec<(Tenant, Duration)>,
+
+    /// Accept host names instead of addresses, like `bot.example.com` or
+    /// `bot.example.com:22`, for feeds that only give names, and ban all of
+    /// their addresses. Names are resolved with this many threads, and
+    /// cached as long as their records allow, within --resolve-min-ttl and
+    /// --resolve-max-ttl. Events of a name are counted once it is resolved.
+    #[arg(long)]
+    pub resolve_hostnames: Option<usize>,
+
+    /// Ban none of the addresses of a name that has more than this many,
+    /// like a CDN or a name pointed at someone else's network, so that a
+    /// single line can not ban large parts of the internet.
+    #[arg(long, default_value = "4")]
+    pub resolve_max_addrs: usize,
+
+    /// Cache names for at least this long, even if their records have a
+    /// shorter TTL, so that a hostile name can not make every line a lookup.
+    #[arg(long, default_value = "1m", value_parser = parse_duration)]
+    pub resolve_min_ttl: Duration,
+
+    /// Cache names for at most this long, even if their records have a
+    /// longer TTL.
+    #[arg(long, default_value = "1h", value_parser = parse_duration)]
+    pub resolve_max_ttl: Duration,
+
+    /// File with addresses or networks in CIDR notation (one per line) that
+    /// are never rate limited or banned. Reloaded on SIGHUP. Exact entries
+    /// are removed from the ipsets when loading the file.
+    #[arg(long)]
+    pub allowlist_file: Option<PathBuf>,
+
+    /// Also rate limit and ban private, loopback, link-local and
+    /// carrier-grade NAT addresses. These are ignored by default, because
+    /// they usually come from a misconfigured proxy rather than an attacker.
+    #[arg(long)]
+    pub ban_private_ranges: bool,
+
+    /// The number of events given back to an IP for each good event, i.e.
+    /// a line like `+192.0.2.1` (for example after a successful login or a
+    /// solved captcha), so that busy but legitimate clients are less likely
+    /// to trip the threshold.
+    #[arg(long, default_value = "0")]
+    pub good_credit: u32,
+
+    /// The number of previous bans forgiven for each good event.
+    #[arg(long, default_value = "0")]
+    pub good_recidivism_credit: u32,
+
+    /// The maximum number of active bans per ipset, to protect the kernel
+    /// from running out of memory during floods from spoofed addresses.
+    #[arg(long)]
+    pub max_banned: Option<usize>,
+
+    /// What to do when --max-banned is reached. `stop` does not ban any
+    /// more IPs until some bans expire, `evict` lifts the bans that would
+    /// expire next.
+    #[arg(long, value_enum, default_value_t = MaxBannedPolicy::Stop)]
+    pub max_banned_policy: MaxBannedPolicy,
+
+    /// Keep track of when each ban in the ipsets runs out, and report it as
+    /// an unban with the reason `expired` to --event-log, the unban hook and
+    /// the `expired_bans` metric, so that systems that mirror the bans can
+    /// lift them in sync. Bans in the --port-ipset and --tenant-ipset sets
+    /// are not tracked.
+    #[arg(long)]
+    pub expiry_events: bool,
+
     /// The name of the ipset for IPv4.
     #[arg(long)]
     pub ipset_ipv4_name: String,
@@ -71,6 +505,137 @@ pub struct Args {
     #[arg(long)]
     pub ipset_ipv6_name: String,
 
+    /// Store the category and reason of each ban, like `rate_limit:login`,
+    /// as the comment of its ipset entry. The ipsets must be created with
+    /// the `comment` option.
+    #[arg(long)]
+    pub ipset_comments: bool,
+
+    /// Do not check at startup that the ipsets exist. The check only tests
+    /// whether they contain --self-test-ipv4 and --self-test-ipv6, without
+    /// adding anything.
+    #[arg(long)]
+    pub skip_self_test: bool,
+
+    /// The address that the startup check looks up in the IPv4 ipset.
+    #[arg(long, default_value_t = Ipv4Addr::LOCALHOST)]
+    pub self_test_ipv4: Ipv4Addr,
+
+    /// The address that the startup check looks up in the IPv6 ipset.
+    #[arg(long, default_value_t = Ipv6Addr::LOCALHOST)]
+    pub self_test_ipv6: Ipv6Addr,
+
+    /// Every this often, check whether a sample of the cached bans of each
+    /// ipset is still in it. If none are, the set was probably flushed or
+    /// recreated, and all cached bans are added again with their remaining
+    /// time. Bans that were removed individually are forgotten, so that they
+    /// are made again at the next event over the limit.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, value_parser = parse_duration)]
+    pub verify_bans_interval: Option<Duration>,
+
+    /// How many cached bans of each ipset to check every
+    /// --verify-bans-interval.
+    #[arg(long, default_value_t = 20, value_parser = clap::value_parser!(u32).range(1..))]
+    pub verify_bans_sample: u32,
+
+    /// Every this often, list all entries of the ipsets and compare them
+    /// with the cached bans, in both directions. Cached bans that are
+    /// missing from a set are handled according to --reconcile-missing, and
+    /// entries that leroyjenkins did not add are adopted with
+    /// --reconcile-adopt. Unlike --verify-bans-interval, this looks at every
+    /// entry, which takes a while for large sets.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, value_parser = parse_duration)]
+    pub reconcile_interval: Option<Duration>,
+
+    /// What to do with cached bans that are missing from an ipset at
+    /// --reconcile-interval.
+    #[arg(long, value_enum, default_value_t = ReconcileMissing::Forget)]
+    pub reconcile_missing: ReconcileMissing,
+
+    /// Treat the entries that leroyjenkins did not add, found at
+    /// --reconcile-interval, as banned until a later reconciliation finds
+    /// them gone, so that events of those addresses are not sent to the
+    /// kernel again. They are never removed by the `flush` admin command.
+    #[arg(long, requires = "reconcile_interval")]
+    pub reconcile_adopt: bool,
+
+    /// The number of events that has to be exceeded before adding an IP to
+    /// the watch ipsets (e.g. to log it or show a captcha), before it is
+    /// eventually banned after `bl_threshold` events. Combines with
+    /// `bl_period`.
+    #[arg(
+        long,
+        requires = "ipset_watch_ipv4_name",
+        requires = "ipset_watch_ipv6_name"
+    )]
+    pub watch_threshold: Option<u32>,
+
+    /// The name of the watch ipset for IPv4.
+    #[arg(long)]
+    pub ipset_watch_ipv4_name: Option<String>,
+
+    /// The name of the watch ipset for IPv6.
+    #[arg(long)]
+    pub ipset_watch_ipv6_name: Option<String>,
+
+    /// How long IPs stay in the watch ipsets.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "10m", value_parser = parse_duration)]
+    pub ipset_watch_time: Duration,
+
+    /// Change the ipsets from a separate thread, through a queue of this
+    /// many changes, so that reading lines never waits for the kernel. Bans
+    /// then count as made when queued, and failures are reported later.
+    /// Removing entries still waits for the queue. By default, changes are
+    /// made inline.
+    #[arg(long)]
+    pub netlink_queue: Option<usize>,
+
+    /// What to do with a ban when the --netlink-queue is full.
+    #[arg(long, value_enum, default_value_t = QueueOverflow::Block)]
+    pub netlink_queue_overflow: QueueOverflow,
+
+    /// Pin the --netlink-queue writer thread to this CPU.
+    #[arg(long)]
+    pub netlink_pin_cpu: Option<usize>,
+
+    /// Enter attack mode when more than this many lines are seen within
+    /// --attack-window. Attack mode ends once the rate drops below half.
+    #[arg(long)]
+    pub attack_line_rate: Option<u64>,
+
+    /// Enter attack mode when more than this many bans happen within
+    /// --attack-window. Attack mode ends once the rate drops below half.
+    #[arg(long)]
+    pub attack_ban_rate: Option<u64>,
+
+    /// The window in which --attack-line-rate and --attack-ban-rate are
+    /// measured.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "1m", value_parser = parse_duration)]
+    pub attack_window: Duration,
+
+    /// Replaces `bl_threshold` in attack mode.
+    #[arg(long)]
+    pub attack_bl_threshold: Option<u32>,
+
+    /// Replaces --ipset-base-time in attack mode.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, value_parser = parse_duration)]
+    pub attack_ipset_base_time: Option<Duration>,
+
     /// The number of seconds to accumulate ban counts before reporting and
     /// resetting.
     ///
@@ -87,6 +652,11 @@ pub struct Args {
     #[arg(long, default_value = "10s", value_parser = parse_duration)]
     pub reporting_ip_time_period: Duration,
 
+    /// The number of unparseable lines to log per --reporting-ip-time-period.
+    /// Further ones are only counted.
+    #[arg(long, default_value_t = 5)]
+    pub parse_error_examples: u64,
+
     /// Initial capacity of the rate limiter table and recidivism cache.
     /// Choose a value large enough for a typical DDOS, to avoid gc and memory
     /// allocation when under attack.
@@ -97,152 +667,4161 @@ pub struct Args {
     #[arg(long, default_value = "500000")]
     pub cache_max_size: u64,
 
-    /// Do not actually actually test or manage ipsets. Useful for test runs
-    /// without privileges.
+    /// Lock all memory with mlockall once started, so that leroy never waits
+    /// for page faults or swap when the machine is busiest. This also faults
+    /// in the caches allocated with --cache-initial-capacity. Needs
+    /// CAP_IPC_LOCK or a large enough RLIMIT_MEMLOCK, because allocations
+    /// beyond the limit fail.
     #[arg(long)]
-    pub dry_run: bool,
-}
+    pub lock_memory: bool,
 
-impl Args {
-    fn seconds_to_ban(&self, ban_count: u32) -> u32 {
-        self.ipset_base_time
-            .checked_mul(ban_count)
-            .and_then(|time| u32::try_from(time.as_secs()).ok())
-            .unwrap_or(u32::MAX)
-    }
-}
+    /// Pin the thread that reads lines to this CPU, for example away from
+    /// the cores that handle NIC interrupts, to reduce jitter during
+    /// attacks. See also --netlink-pin-cpu.
+    #[arg(long)]
+    pub pin_cpu: Option<usize>,
 
-fn parse_duration(s: &str) -> Result<Duration, humantime::DurationError> {
-    s.parse::<humantime::Duration>().map(Into::into)
-}
+    /// Schedule the thread that reads lines and the --netlink-queue writer
+    /// thread with the SCHED_FIFO real-time policy at this priority. Needs
+    /// CAP_SYS_NICE. Best combined with --pin-cpu, because a busy real-time
+    /// thread can starve everything else on its CPU.
+    #[arg(long, conflicts_with = "nice", value_parser = clap::value_parser!(u8).range(1..=99))]
+    pub sched_fifo: Option<u8>,
 
-pub struct Leroy {
-    sessions: ByIpFamily<Session<HashIp>>,
+    /// The niceness of the thread that reads lines and the --netlink-queue
+    /// writer thread. Negative values need CAP_SYS_NICE.
+    #[arg(long, allow_negative_numbers = true, value_parser = clap::value_parser!(i8).range(-20..=19))]
+    pub nice: Option<i8>,
 
-    ip_rate_limiters: Option<KeyedLimiter<Vec<u8>, BuildHasherDefault<FxHasher>>>,
-    ipset_cache: Cache<IpAddr, (), BuildHasherDefault<FxHasher>>,
-    recidivism_counts: Cache<IpAddr, u32, BuildHasherDefault<FxHasher>>,
+    /// Before starting, drop all capabilities except CAP_NET_ADMIN for the
+    /// ipsets and the ones that other flags need, like
+    /// CAP_IPC_LOCK for --lock-memory, CAP_SYS_NICE for --sched-fifo and
+    /// CAP_NET_BIND_SERVICE for listening on ports below 1024.
+    #[arg(long)]
+    pub drop_capabilities: bool,
 
-    line_count: u64,
-    line_count_start: Instant,
+    /// Change to this user before starting, keeping only the capabilities of
+    /// --drop-capabilities. Files are opened as this user afterwards.
+    #[arg(long)]
+    pub user: Option<String>,
 
-    ban_count: u64,
-    ban_count_start: Instant,
+    /// Change the root directory to this one before starting, like
+    /// --user. The other paths, like --state-file and --admin-socket, are
+    /// then inside it.
+    #[arg(long)]
+    pub chroot: Option<PathBuf>,
 
-    args: Args,
-}
+    /// Once started, restrict all threads to the syscalls that reading lines
+    /// and banning need, with a seccomp filter. Other syscalls, like those
+    /// to start programs, fail. Only on Linux on x86_64 and aarch64.
+    #[arg(long)]
+    pub seccomp: bool,
 
-impl Leroy {
-    pub fn new(args: Args) -> Result<Leroy, Box<dyn Error>> {
-        Ok(Leroy {
-            sessions: ByIpFamily::try_new_with::<_, Box<dyn Error>>(|family| {
-                let (name, localhost) = match family {
-                    IpFamily::V4 => (&args.ipset_ipv4_name, IpAddr::V4(Ipv4Addr::LOCALHOST)),
-                    IpFamily::V6 => (&args.ipset_ipv6_name, IpAddr::V6(Ipv6Addr::LOCALHOST)),
-                };
-                let mut session = Session::<HashIp>::new(name.clone());
-                if !args.dry_run {
-                    session.test(localhost).map_err(|err| {
-                        format!("Failed to test set {name:?}: {err}. Please create before running.")
-                    })?;
-                }
-                Ok(session)
-            })?,
-            ip_rate_limiters: match NonZeroU32::new(args.bl_threshold) {
-                Some(bl_threshold) => Some(KeyedLimiter::new(
-                    Quota::with_period(args.bl_period)
-                        .ok_or_else(|| format!("--bl-period must be non-zero"))?
-                        .allow_burst(bl_threshold),
-                    args.cache_initial_capacity,
-                    BuildHasherDefault::default(),
-                )),
-                None => None, // ban on sight
-            },
-            ipset_cache: Cache::builder()
-                .initial_capacity(args.cache_initial_capacity)
-                .max_capacity(args.cache_max_size)
-                .time_to_live(args.ipset_base_time.saturating_sub(Duration::from_secs(1)))
-                .build_with_hasher(Default::default()),
-            recidivism_counts: Cache::builder()
-                .initial_capacity(args.cache_initial_capacity)
-                .max_capacity(args.cache_max_size)
-                .time_to_live(args.ipset_ban_ttl)
-                .build_with_hasher(Default::default()),
-            line_count: 0,
-            ban_count: 0,
-            line_count_start: Instant::now(),
-            ban_count_start: Instant::now(),
-            args,
-        })
-    }
+    /// Serve a JSON health status over HTTP on this address, like
+    /// `127.0.0.1:9090`, with the time of the last ban, the seconds since
+    /// the last line, and whether the latest ipset operation succeeded.
+    #[arg(long)]
+    pub health_listen: Option<SocketAddr>,
 
-    pub fn handle_line(&mut self, line: &Vec<u8>) {
-        self.line_count += 1;
+    /// Report unhealthy if no line has been read for this long, to detect a
+    /// dead input pipe.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, value_parser = parse_duration)]
+    pub health_max_idle: Option<Duration>,
 
-        if self
-            .ip_rate_limiters
-            .as_mut()
-            .map_or(true, |l| l.check_key(line).is_err())
-        {
-            match IpAddr::parse_ascii(line) {
-                Ok(ip) => self.ban(ip),
-                Err(err) => error!(
-                    "Error parsing IP from {:?}: {}",
-                    String::from_utf8_lossy(line),
-                    err
-                ),
-            }
-        }
+    /// Accept admin commands on this unix socket, like `ban <ip> [duration]`,
+    /// `unban <ip>`, `query <ip>`, `stats`, `pause` and `resume`.
+    #[arg(long)]
+    pub admin_socket: Option<PathBuf>,
 
-        if self.line_count % 10 == 0
-            && self.line_count_start.elapsed() > self.args.reporting_ip_time_period
-        {
-            info!(
-                "Seen {} lines since {:?}",
-                self.line_count,
-                self.line_count_start.elapsed()
-            );
-            self.line_count = 0;
-            self.line_count_start = Instant::now();
-        }
-    }
+    /// Serve the admin commands as a JSON API over HTTP on this address,
+    /// like `127.0.0.1:9091`.
+    #[arg(long, requires = "admin_token_file")]
+    pub admin_listen: Option<SocketAddr>,
 
-    fn ban(&mut self, ip: IpAddr) {
-        if self.ipset_cache.contains_key(&ip) {
-            debug!("{ip} already banned");
-            return;
-        }
+    /// The bearer token for --admin-listen is read from this file.
+    #[arg(long)]
+    pub admin_token_file: Option<PathBuf>,
 
-        let recidivism: u32 = *self.recidivism_counts.get(&ip).unwrap_or(&0) + 1;
-        let timeout = self.args.seconds_to_ban(recidivism);
+    /// Accept bans from other instances on this address, like
+    /// `0.0.0.0:9092`. The token and the bans are sent in plain text, so
+    /// only listen on a trusted network.
+    #[arg(long, requires = "cluster_token_file")]
+    pub cluster_listen: Option<SocketAddr>,
 
-        let ban_result = if self.args.dry_run {
-            Ok(true)
-        } else {
-            self.sessions
-                .by_family_mut(IpFamily::from_ipv4(ip.is_ipv4()))
-                .add(ip, vec![AddOption::Timeout(timeout)])
-        };
+    /// Send bans to another instance, given as its --cluster-listen address
+    /// like `edge2.example.com:9092`. Can be repeated. Received bans are not
+    /// forwarded, so every instance has to list all others.
+    #[arg(long, requires = "cluster_token_file")]
+    pub cluster_peer: Vec<String>,
 
-        match ban_result {
-            Ok(false) => debug!("{ip} already banned, but was no longer cached"),
-            Ok(true) => {
-                info!("Banned {ip} for {timeout}s (recidivism: {recidivism})");
-                self.ban_count += 1;
-                self.ipset_cache.insert(ip, ());
-                self.recidivism_counts.insert(ip, recidivism);
-            }
-            Err(err) => error!("Unable to add {ip} to set: {err}"),
-        }
+    /// The shared secret of the --cluster-peer mesh is read from this file.
+    #[arg(long)]
+    pub cluster_token_file: Option<PathBuf>,
 
-        if self.ban_count_start.elapsed() > self.args.reporting_ban_time_period {
-            info!(
-                "Banned {} ips in the past {:?}",
-                self.ban_count,
-                self.ban_count_start.elapsed()
-            );
-            self.ban_count = 0;
-            self.ban_count_start = Instant::now();
-        }
+    /// Identifies the bans of this instance within the cluster. Defaults to
+    /// the hostname.
+    #[arg(long)]
+    pub cluster_node_id: Option<String>,
+
+    /// Do not manage ipsets on this host, but only send bans to the
+    /// --cluster-peer instances, like a central firewall host with
+    /// --cluster-listen. Rate limits, caches and recidivism are still tracked
+    /// here. The ipset names are not used.
+    #[arg(long, requires = "cluster_peer")]
+    pub forward_bans: bool,
+
+    /// Write one record per ban or early unban to this file (or `-` for
+    /// stdout), for consumption by SIEMs and audit pipelines. With
+    /// --expiry-events, also when bans run out.
+    #[arg(long)]
+    pub event_log: Option<PathBuf>,
+
+    /// The format of --event-log.
+    #[arg(long, value_enum, default_value_t = EventLogFormat::Json)]
+    pub event_log_format: EventLogFormat,
+
+    /// The maximum number of events waiting to be written to --event-log.
+    /// More are dropped rather than stalling bans.
+    #[arg(long, default_value = "10000")]
+    pub event_log_capacity: usize,
+
+    /// Add hostnames from PTR records to ban events in --event-log, using
+    /// this many resolver threads. Lookups never delay bans, but may delay
+    /// and reorder events.
+    #[arg(long, requires = "event_log")]
+    pub event_log_reverse_dns: Option<usize>,
+
+    /// Hide addresses in the log, --event-log and --webhook-url reports:
+    /// `truncate` shows only their /24 or /48 network, `hash` a keyed hash,
+    /// which still lets events of the same address be correlated. Bans, the
+    /// admin API, --emit-bans and abuse reports keep the real addresses.
+    #[arg(long, value_enum, conflicts_with = "event_log_reverse_dns")]
+    pub anonymize_ips: Option<IpAnonymization>,
+
+    /// The secret key of --anonymize-ips hash. Changing it breaks the
+    /// correlation with earlier hashes.
+    #[arg(long, requires = "anonymize_ips")]
+    pub anonymize_key_file: Option<PathBuf>,
+
+    /// Write every ban to `stdout` or to this inherited file descriptor,
+    /// like `3` for `3>bans.txt`, one address or network per line, so that
+    /// other tools can follow them.
+    #[arg(long)]
+    pub emit_bans: Option<EmitTarget>,
+
+    /// Write --emit-bans as JSON lines like
+    /// `{"ip":"192.0.2.1","category":"rate_limit","timeout":60,"recidivism":1}`.
+    #[arg(long, requires = "emit_bans")]
+    pub emit_bans_json: bool,
+
+    /// Echo every input line that did not lead to a ban to stdout, so that
+    /// leroyjenkins can sit inline in an existing pipeline.
+    #[arg(long)]
+    pub passthrough: bool,
+
+    /// Post a summary to this chat webhook when there are more than
+    /// --webhook-ban-threshold bans within --reporting-ban-time-period.
+    #[arg(long, requires = "webhook_ban_threshold")]
+    pub webhook_url: Option<String>,
+
+    /// See --webhook-url.
+    #[arg(long)]
+    pub webhook_ban_threshold: Option<u64>,
+
+    /// The payload format of --webhook-url.
+    #[arg(long, value_enum, default_value_t = WebhookFormat::Slack)]
+    pub webhook_format: WebhookFormat,
+
+    /// Warn, and post to --webhook-url, when there are more than this many
+    /// events of addresses that were banned for a while already within
+    /// --reporting-ban-time-period. Such events mean that the firewall does
+    /// not drop the traffic of the banned addresses, for example because
+    /// the rule is on the wrong interface or missing for IPv6.
+    #[arg(long)]
+    pub banned_events_threshold: Option<u64>,
+
+    /// Treat input lines as arbitrary keys, like user IDs or API tokens,
+    /// instead of IP addresses, and run this program for every ban, with
+    /// the key, the ban time in seconds and the ban count as arguments.
+    /// Bans are only remembered, not enforced by leroyjenkins. The ipset
+    /// names and IP specific flags are not used, nor is the admin socket.
+    #[arg(long, conflicts_with = "key_webhook_url")]
+    pub key_exec: Option<PathBuf>,
+
+    /// Like --key-exec, but POST every ban as JSON like
+    /// `{"key": ..., "timeout": ..., "recidivism": ...}` to this URL.
+    #[arg(long)]
+    pub key_webhook_url: Option<String>,
+
+    /// With `mac`, treat input lines as MAC addresses, like
+    /// `02:00:5e:10:00:01`, to quarantine misbehaving devices on a local
+    /// network, and ban them in the hash:mac ipset --ipset-mac-name. Uses
+    /// the rate limit, ban time and recidivism flags like --key-exec.
+    #[arg(long, value_enum, default_value_t = KeyType::Ip, conflicts_with_all = ["key_exec", "key_webhook_url"])]
+    pub key_type: KeyType,
+
+    /// The ipset of type hash:mac for --key-type mac, created for example
+    /// with `ipset create leroy-mac hash:mac timeout 0`.
+    #[arg(long, required_if_eq("key_type", "mac"))]
+    pub ipset_mac_name: Option<String>,
+
+    /// Report banned IPs to AbuseIPDB, with the API key read from this
+    /// file.
+    #[arg(long)]
+    pub abuseipdb_key_file: Option<PathBuf>,
+
+    /// Comma separated AbuseIPDB categories for reports.
+    /// See: https://www.abuseipdb.com/categories
+    #[arg(long, value_delimiter = ',', default_value = "4")]
+    pub abuseipdb_categories: Vec<u8>,
+
+    /// Report bans to this generic threat intelligence API, with one JSON
+    /// POST request per ban.
+    #[arg(long)]
+    pub threat_intel_url: Option<String>,
+
+    /// The maximum number of reports per day to --abuseipdb-key-file and
+    /// --threat-intel-url. Further reports are dropped.
+    #[arg(long, default_value = "1000")]
+    pub abuse_report_max_per_day: u32,
+
+    /// Send metrics to this statsd server, like `127.0.0.1:8125`.
+    #[arg(long)]
+    pub statsd_addr: Option<String>,
+
+    /// Prefix for statsd metric names.
+    #[arg(long, default_value = "leroyjenkins")]
+    pub statsd_prefix: String,
+
+    /// Comma separated DogStatsD tags like `host:edge1,env:prod`.
+    #[arg(long, value_delimiter = ',')]
+    pub statsd_tags: Vec<String>,
+
+    /// How often to send metrics to statsd.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub statsd_period: Duration,
+
+    /// Export metrics to an OpenTelemetry collector with OTLP/HTTP (JSON),
+    /// like `http://localhost:4318/v1/metrics`.
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+
+    /// How often to export metrics with OTLP.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub otlp_period: Duration,
+
+    /// File to periodically save recidivism counts and active bans to, and
+    /// to restore them from at startup, so that restarts neither give repeat
+    /// offenders a clean slate nor reset the timeouts of active bans.
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+
+    /// How often to save --state-file.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "1m", value_parser = parse_duration)]
+    pub state_save_period: Duration,
+
+    /// Count and report, but do not ban for this long after startup, so that
+    /// log lines replayed after a restart do not cause a burst of bans.
+    ///
+    /// Uses humantime to parse the duration.
+    /// See: https://docs.rs/humantime/latest/humantime/ for details
+    #[arg(long, default_value = "0s", value_parser = parse_duration)]
+    pub warmup: Duration,
+
+    /// Do not actually actually test or manage ipsets. Useful for test runs
+    /// without privileges.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// With --dry-run, keep the latest this many would-be bans in memory,
+    /// for library users to check with `Leroy::dry_run_bans`.
+    #[arg(long, default_value = "1000")]
+    pub dry_run_capture: usize,
+
+    /// With --dry-run, also print every would-be ban as a JSON line like
+    /// `{"ip":"192.0.2.1","category":"rate_limit","timeout":60,"recidivism":1}`
+    /// to stdout.
+    #[arg(long, requires = "dry_run")]
+    pub dry_run_json: bool,
+
+    /// Count, report and log bans without adding them to the ipsets, while
+    /// keeping all state. Unlike --dry-run, this can be toggled at runtime
+    /// through the admin socket. Manual bans are still added.
+    #[arg(long)]
+    pub monitor_only: bool,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    /// Allows bursts of `bl_threshold` events, replenishing one event per
+    /// `bl_period`.
+    Gcra,
+    /// Counts events exactly, allowing `bl_threshold` events within any
+    /// `bl_period`. Uses more memory.
+    SlidingWindow,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Escalation {
+    Linear,
+    Exponential,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaxBannedPolicy {
+    Stop,
+    Evict,
+}
+
+/// What --reconcile-interval does with cached bans that are missing from an
+/// ipset.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReconcileMissing {
+    /// Forget them, so that they are made again at the next event over the
+    /// limit.
+    Forget,
+    /// Add them again with their remaining time.
+    Reban,
+}
+
+impl LeroyConfig {
+    /// Whether this instance adds to and removes from the ipsets itself.
+    fn manages_ipsets(&self) -> bool {
+        !self.dry_run && !self.forward_bans
+    }
+
+    /// Whether the bans are kept in [`LiveBans`], for --max-banned or
+    /// --expiry-events.
+    fn tracks_live_bans(&self) -> bool {
+        self.max_banned.is_some() || self.expiry_events
+    }
+
+    /// Checks the combinations of flags that clap can not, at startup and on
+    /// reload.
+    fn validate(&self) -> Result<(), LeroyError> {
+        if self.approve_wide_bans && self.admin_socket.is_none() && self.admin_listen.is_none() {
+            return Err("--approve-wide-bans needs --admin-socket or --admin-listen".into());
+        }
+        if self.anonymize_ips.is_some() && self.event_log_reverse_dns.is_some() {
+            return Err("--anonymize-ips can not be used with --event-log-reverse-dns".into());
+        }
+        if self.resolve_min_ttl > self.resolve_max_ttl {
+            return Err("--resolve-min-ttl must not exceed --resolve-max-ttl".into());
+        }
+        Ok(())
+    }
+
+    fn bl_threshold_for(&self, family: IpFamily) -> u32 {
+        match family {
+            IpFamily::V4 => self.bl_threshold_ipv4,
+            IpFamily::V6 => self.bl_threshold_ipv6,
+        }
+        .unwrap_or(self.bl_threshold)
+    }
+
+    fn bl_period_for(&self, family: IpFamily) -> Duration {
+        match family {
+            IpFamily::V4 => self.bl_period_ipv4,
+            IpFamily::V6 => self.bl_period_ipv6,
+        }
+        .or(self.bl_rate)
+        .unwrap_or(self.bl_period)
+    }
+
+    fn ipset_base_time_for(&self, family: IpFamily) -> Duration {
+        match family {
+            IpFamily::V4 => self.ipset_base_time_ipv4,
+            IpFamily::V6 => self.ipset_base_time_ipv6,
+        }
+        .unwrap_or(self.ipset_base_time)
+    }
+
+    fn country_ipset_base_time_for(&self, country: CountryCode) -> Option<Duration> {
+        self.country_ipset_base_time
+            .iter()
+            .find(|(c, _)| *c == country)
+            .map(|(_, base_time)| *base_time)
+    }
+
+    fn port_ipset_base_time_for(&self, port: u16) -> Option<Duration> {
+        self.port_ipset_base_time
+            .iter()
+            .find(|(p, _)| *p == port)
+            .map(|(_, base_time)| *base_time)
+    }
+
+    fn tenant_ipset_base_time_for(&self, tenant: Tenant) -> Option<Duration> {
+        self.tenant_ipset_base_time
+            .iter()
+            .find(|(t, _)| *t == tenant)
+            .map(|(_, base_time)| *base_time)
+    }
+
+    /// The sets that bans caused by events of `tenant` on `port` go to. The
+    /// sets of the tenant come first.
+    fn sets_for(&self, tenant: Option<Tenant>, port: Option<u16>) -> Sets {
+        match (tenant, port) {
+            (Some(tenant), _) if self.tenant_ipset.iter().any(|sets| sets.tenant == tenant) => {
+                Sets::Tenant(tenant)
+            }
+            (_, Some(port)) if self.port_ipset.iter().any(|sets| sets.port == port) => {
+                Sets::Port(port)
+            }
+            _ => Sets::Bans,
+        }
+    }
+
+    fn ban_prefix_for(&self, family: IpFamily) -> u8 {
+        match family {
+            IpFamily::V4 => self.ban_prefix_v4,
+            IpFamily::V6 => self.ban_prefix_v6,
+        }
+    }
+
+    fn subnet_prefix_for(&self, family: IpFamily) -> Option<u8> {
+        match family {
+            IpFamily::V4 => self.subnet_prefix_v4,
+            IpFamily::V6 => self.subnet_prefix_v6,
+        }
+    }
+
+    /// How long the recidivism of an IP is cached after its last ban, i.e.
+    /// `since` ago. With --recidivism-decay, until pruned in periodic_work.
+    fn recidivism_ttl(&self, since: Duration) -> Duration {
+        if self.recidivism_decay {
+            Duration::MAX
+        } else {
+            self.ipset_ban_ttl.saturating_sub(since)
+        }
+    }
+
+    /// The number of previous bans that still count against an IP.
+    fn previous_bans(&self, recidivism: &Recidivism) -> u32 {
+        let elapsed = self
+            .clock
+            .system_now()
+            .duration_since(recidivism.last_ban)
+            .unwrap_or_default();
+        if self.recidivism_decay {
+            let periods = elapsed.as_nanos() / self.ipset_ban_ttl.as_nanos().max(1);
+            recidivism
+                .count
+                .saturating_sub(u32::try_from(periods).unwrap_or(u32::MAX))
+        } else if elapsed < self.ipset_ban_ttl {
+            recidivism.count
+        } else {
+            0
+        }
+    }
+
+    fn seconds_to_ban(
+        &self,
+        family: IpFamily,
+        country: Option<CountryCode>,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        ban_count: u32,
+        under_attack: bool,
+    ) -> u32 {
+        let base_time = match self.attack_ipset_base_time {
+            Some(attack_base_time) if under_attack => attack_base_time,
+            _ => tenant
+                .and_then(|tenant| self.tenant_ipset_base_time_for(tenant))
+                .or_else(|| port.and_then(|port| self.port_ipset_base_time_for(port)))
+                .or_else(|| country.and_then(|country| self.country_ipset_base_time_for(country)))
+                .unwrap_or_else(|| self.ipset_base_time_for(family)),
+        };
+        self.escalate(base_time, ban_count)
+    }
+
+    /// The ban time of a ban that is extended by `timeout` seconds with
+    /// --extend-bans, up to --ipset-max-time.
+    fn extend(&self, remaining: Duration, timeout: u32) -> u32 {
+        let time = remaining.saturating_add(Duration::from_secs(u64::from(timeout)));
+        let time = self
+            .ipset_max_time
+            .map_or(time, |max_time| time.min(max_time));
+        // Never shorter than the new ban alone, even with --ban-jitter.
+        u32::try_from(time.as_secs())
+            .unwrap_or(u32::MAX)
+            .max(timeout)
+    }
+
+    /// The ban time for the `ban_count`th ban, given the time of the first,
+    /// according to --escalation, --ipset-max-time and --ban-jitter.
+    fn escalate(&self, base_time: Duration, ban_count: u32) -> u32 {
+        let multiplier = match self.escalation {
+            Escalation::Linear => Some(ban_count),
+            Escalation::Exponential => ban_count
+                .checked_sub(1)
+                .and_then(|exponent| 1u32.checked_shl(exponent)),
+        };
+        let time = multiplier
+            .and_then(|multiplier| base_time.checked_mul(multiplier))
+            .unwrap_or(Duration::MAX);
+        let time = if self.ban_jitter > 0.0 {
+            let factor = 1.0 + self.ban_jitter * (2.0 * fastrand::f64() - 1.0);
+            Duration::try_from_secs_f64(time.as_secs_f64() * factor).unwrap_or(Duration::MAX)
+        } else {
+            time
+        };
+        let time = self
+            .ipset_max_time
+            .map_or(time, |max_time| time.min(max_time));
+        // A timeout of 0 would keep the entry forever.
+        u32::try_from(time.as_secs()).unwrap_or(u32::MAX).max(1)
+    }
+}
+
+fn parse_duration(s: &str) -> Result<Duration, humantime::DurationError> {
+    s.parse::<humantime::Duration>().map(Into::into)
+}
+
+fn parse_country_duration(s: &str) -> Result<(CountryCode, Duration), String> {
+    parse_country_value::<humantime::Duration>(s).map(|(country, time)| (country, time.into()))
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || (a == 100 && b & 0b1100_0000 == 0b0100_0000) // 100.64.0.0/10
+        }
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unicast_link_local() || ip.is_unique_local(),
+    }
+}
+
+fn parse_percentage(s: &str) -> Result<f64, String> {
+    let percentage = s
+        .strip_suffix('%')
+        .ok_or_else(|| format!("expected a percentage like 10%, got {s:?}"))?
+        .parse::<f64>()
+        .map_err(|err| err.to_string())?;
+    if !(0.0..=100.0).contains(&percentage) {
+        return Err(format!("{percentage}% is not between 0% and 100%"));
+    }
+    Ok(percentage / 100.0)
+}
+
+/// Parses a rate like `10/s` or `100/5m` into the time per event.
+fn parse_rate(s: &str) -> Result<Duration, String> {
+    let (count, per) = s
+        .split_once('/')
+        .ok_or_else(|| format!("expected a rate like 10/s, got {s:?}"))?;
+    let count = count
+        .parse::<u32>()
+        .ok()
+        .filter(|count| *count > 0)
+        .ok_or_else(|| format!("invalid event count {count:?}"))?;
+    let per = if per.starts_with(|c: char| c.is_ascii_digit()) {
+        parse_duration(per)
+    } else {
+        parse_duration(&format!("1{per}"))
+    }
+    .map_err(|err| err.to_string())?;
+    Ok(per / count)
+}
+
+/// Parses an address from bytes. Input that is not UTF-8 is no address
+/// either. The zone index of a link-local IPv6 address, like `eth0` in
+/// `fe80::1%eth0`, is ignored: the address is banned on all interfaces.
+pub(crate) fn parse_ip(s: &[u8]) -> Result<IpAddr, AddrParseError> {
+    let s = str::from_utf8(s).unwrap_or_default();
+    if let Some((addr, zone)) = s.split_once('%') {
+        if let Ok(addr) = addr.parse::<Ipv6Addr>() {
+            if addr.is_unicast_link_local() && !zone.is_empty() {
+                return Ok(IpAddr::V6(addr));
+            }
+        }
+    }
+    s.parse()
+}
+
+/// Parses an address, optionally with a destination port, like
+/// `192.0.2.1:22` or `[2001:db8::1]:22`.
+fn parse_addr(s: &[u8]) -> Result<(IpAddr, Option<u16>), AddrParseError> {
+    match parse_ip(s) {
+        Ok(ip) => Ok((ip, None)),
+        Err(err) => str::from_utf8(s)
+            .ok()
+            .and_then(|s| s.parse::<SocketAddr>().ok())
+            .map(|addr| (addr.ip(), Some(addr.port())))
+            .ok_or(err),
+    }
+}
+
+/// A line of input, see [`Leroy::handle_line`].
+pub(crate) enum InputLine {
+    Bad(IpAddr, Option<Tenant>, Option<u16>, Option<BanReason>),
+    /// See --resolve-hostnames.
+    BadHost(Hostname, Option<Tenant>, Option<u16>, Option<BanReason>),
+    Good(IpAddr),
+}
+
+#[derive(Debug)]
+pub(crate) enum LineError {
+    Ip(AddrParseError),
+    Reason(InvalidBanReason),
+    Tenant(InvalidTenant),
+    /// A host name without --resolve-hostnames.
+    Hostname,
+}
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LineError::Ip(err) => err.fmt(f),
+            LineError::Reason(err) => err.fmt(f),
+            LineError::Tenant(err) => err.fmt(f),
+            LineError::Hostname => {
+                f.write_str("host names are only resolved with --resolve-hostnames")
+            }
+        }
+    }
+}
+
+/// Parses an address or host name with an optional port, optionally
+/// prefixed with a tenant and `@`, and optionally followed by a space and a
+/// ban reason, or such an address prefixed with `+` for a good event. The
+/// tenant, port and reason of a good event are ignored.
+pub(crate) fn parse_line(line: &[u8]) -> Result<InputLine, LineError> {
+    let (good, line) = match line.strip_prefix(b"+") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+    let (ip, reason) = match memchr::memchr(b' ', line) {
+        Some(space) => (&line[..space], Some(&line[space + 1..])),
+        None => (line, None),
+    };
+    let (tenant, ip) = match memchr::memchr(b'@', ip) {
+        Some(at) => (Some(&ip[..at]), &ip[at + 1..]),
+        None => (None, ip),
+    };
+    let addr = parse_addr(ip);
+    if good {
+        return Ok(InputLine::Good(addr.map_err(LineError::Ip)?.0));
+    }
+    let tenant = tenant
+        .map(Tenant::from_bytes)
+        .transpose()
+        .map_err(LineError::Tenant)?;
+    let reason = || {
+        reason
+            .map(BanReason::from_bytes)
+            .transpose()
+            .map_err(LineError::Reason)
+    };
+    match addr {
+        Ok((ip, port)) => Ok(InputLine::Bad(ip, tenant, port, reason()?)),
+        Err(err) => {
+            let (host, port) = parse_host(ip).ok_or(LineError::Ip(err))?;
+            Ok(InputLine::BadHost(host, tenant, port, reason()?))
+        }
+    }
+}
+
+/// Parses a host name, optionally with a port, like `bot.example.com:22`.
+fn parse_host(s: &[u8]) -> Option<(Hostname, Option<u16>)> {
+    match memchr::memrchr(b':', s) {
+        Some(colon) => Some((
+            Hostname::from_bytes(&s[..colon])?,
+            Some(str::from_utf8(&s[colon + 1..]).ok()?.parse().ok()?),
+        )),
+        None => Some((Hostname::from_bytes(s)?, None)),
+    }
+}
+
+type RateLimiters = ByIpFamily<Option<KeyedLimiter<MaskedIpAddr, BuildHasherDefault<FxHasher>>>>;
+
+/// Rate limiters that trigger bans, with `threshold` events per
+/// `bl_period`.
+fn ban_rate_limiters(
+    config: &LeroyConfig,
+    threshold: impl Fn(IpFamily) -> u32,
+) -> Result<RateLimiters, LeroyError> {
+    ByIpFamily::try_new_with(|family| {
+        Ok(match NonZeroU32::new(threshold(family)) {
+            Some(threshold) => Some(KeyedLimiter::with_clock(
+                config.algorithm,
+                Quota::with_period(config.bl_period_for(family))
+                    .ok_or("--bl-period must be non-zero")?
+                    .allow_burst(threshold),
+                config.cache_initial_capacity,
+                BuildHasherDefault::default(),
+                config.clock.clone(),
+            )),
+            None => None, // ban on sight
+        })
+    })
+}
+
+fn attack_rate_limiters(config: &LeroyConfig) -> Result<Option<RateLimiters>, LeroyError> {
+    match config.attack_bl_threshold {
+        Some(attack_bl_threshold) => Ok(Some(ban_rate_limiters(config, |_| attack_bl_threshold)?)),
+        None => Ok(None),
+    }
+}
+
+type CountryRateLimiters = HashMap<CountryCode, RateLimiters, BuildHasherDefault<FxHasher>>;
+
+fn country_rate_limiters(config: &LeroyConfig) -> Result<CountryRateLimiters, LeroyError> {
+    config
+        .country_bl_threshold
+        .iter()
+        .map(|(country, bl_threshold)| {
+            Ok((*country, ban_rate_limiters(config, |_| *bl_threshold)?))
+        })
+        .collect()
+}
+
+type PortRateLimiters = HashMap<u16, RateLimiters, BuildHasherDefault<FxHasher>>;
+
+fn port_rate_limiters(config: &LeroyConfig) -> Result<PortRateLimiters, LeroyError> {
+    config
+        .port_bl_threshold
+        .iter()
+        .map(|(port, bl_threshold)| Ok((*port, ban_rate_limiters(config, |_| *bl_threshold)?)))
+        .collect()
+}
+
+type TenantRateLimiters = HashMap<Tenant, RateLimiters, BuildHasherDefault<FxHasher>>;
+
+/// Rate limiters for each tenant of --tenant-ipset and
+/// --tenant-bl-threshold, which keep the events of tenants apart even
+/// without a threshold of their own.
+fn tenant_rate_limiters(config: &LeroyConfig) -> Result<TenantRateLimiters, LeroyError> {
+    config
+        .tenant_ipset
+        .iter()
+        .map(|sets| sets.tenant)
+        .chain(config.tenant_bl_threshold.iter().map(|(tenant, _)| *tenant))
+        .map(|tenant| {
+            let bl_threshold = config
+                .tenant_bl_threshold
+                .iter()
+                .find(|(t, _)| *t == tenant)
+                .map(|(_, bl_threshold)| *bl_threshold);
+            let rate_limiters = ban_rate_limiters(config, |family| {
+                bl_threshold.unwrap_or_else(|| config.bl_threshold_for(family))
+            })?;
+            Ok((tenant, rate_limiters))
+        })
+        .collect()
+}
+
+fn watch_rate_limiters(config: &LeroyConfig) -> Result<RateLimiters, LeroyError> {
+    ByIpFamily::try_new_with(|family| {
+        let Some(watch_threshold) = config.watch_threshold else {
+            return Ok(None);
+        };
+        Ok(Some(KeyedLimiter::with_clock(
+            config.algorithm,
+            Quota::with_period(config.bl_period_for(family))
+                .ok_or("--bl-period must be non-zero")?
+                .allow_burst(
+                    NonZeroU32::new(watch_threshold).ok_or("--watch-threshold must be non-zero")?,
+                ),
+            config.cache_initial_capacity,
+            BuildHasherDefault::default(),
+            config.clock.clone(),
+        )))
+    })
+}
+
+fn subnet_rate_limiters(config: &LeroyConfig) -> Result<RateLimiters, LeroyError> {
+    ByIpFamily::try_new_with(|family| {
+        let Some(subnet_prefix) = config.subnet_prefix_for(family) else {
+            return Ok(None);
+        };
+        if subnet_prefix >= config.ban_prefix_for(family) {
+            return Err(format!(
+                "--subnet-prefix-{family} must be shorter than --ban-prefix-{family}"
+            )
+            .into());
+        }
+        Ok(match NonZeroU32::new(config.subnet_threshold) {
+            Some(subnet_threshold) => Some(KeyedLimiter::with_clock(
+                config.algorithm,
+                Quota::with_period(config.subnet_period)
+                    .ok_or("--subnet-period must be non-zero")?
+                    .allow_burst(subnet_threshold),
+                config.cache_initial_capacity,
+                BuildHasherDefault::default(),
+                config.clock.clone(),
+            )),
+            None => None, // ban on sight
+        })
+    })
+}
+
+type AsnRateLimiter = KeyedLimiter<u32, BuildHasherDefault<FxHasher>>;
+
+fn asn_rate_limiter(config: &LeroyConfig) -> Result<Option<AsnRateLimiter>, LeroyError> {
+    Ok(match NonZeroU32::new(config.asn_threshold) {
+        Some(asn_threshold) => Some(KeyedLimiter::with_clock(
+            config.algorithm,
+            Quota::with_period(config.asn_period)
+                .ok_or("--asn-period must be non-zero")?
+                .allow_burst(asn_threshold),
+            1024,
+            BuildHasherDefault::default(),
+            config.clock.clone(),
+        )),
+        None => None, // ban on sight
+    })
+}
+
+/// Keeps the states of the previous rate limiter, if both exist. Otherwise
+/// the garbage collection statistics of the dropped one are added to
+/// `retired`, so that the exported counters do not go backwards.
+fn inherit_rate_limiter<K, S>(
+    rate_limiter: Option<&mut KeyedLimiter<K, S>>,
+    previous: Option<KeyedLimiter<K, S>>,
+    retired: &mut GcStats,
+) where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    match (rate_limiter, previous) {
+        (Some(rate_limiter), Some(previous)) => rate_limiter.inherit(previous),
+        (None, Some(previous)) => {
+            retired.runs += previous.gc_stats().runs;
+            retired.removed += previous.gc_stats().removed;
+        }
+        (_, None) => {}
+    }
+}
+
+fn inherit_rate_limiters(
+    rate_limiters: Option<&mut RateLimiters>,
+    previous: Option<RateLimiters>,
+    retired: &mut GcStats,
+) {
+    let (ipv4, ipv6) = match rate_limiters {
+        Some(rate_limiters) => (rate_limiters.ipv4.as_mut(), rate_limiters.ipv6.as_mut()),
+        None => (None, None),
+    };
+    let (previous_ipv4, previous_ipv6) = match previous {
+        Some(previous) => (previous.ipv4, previous.ipv6),
+        None => (None, None),
+    };
+    inherit_rate_limiter(ipv4, previous_ipv4, retired);
+    inherit_rate_limiter(ipv6, previous_ipv6, retired);
+}
+
+/// Opens the ipsets, or takes `backends` instead, and the watch ipsets. With
+/// --netlink-queue, they are opened on the writer thread, and the returned
+/// backends queue to it.
+fn open_backends(
+    config: &LeroyConfig,
+    backends: Option<ByIpFamily<Box<dyn Backend + Send>>>,
+) -> Result<(Backends, Option<NetlinkQueue>), LeroyError> {
+    let self_test = ByIpFamily {
+        ipv4: IpAddr::V4(config.self_test_ipv4),
+        ipv6: IpAddr::V6(config.self_test_ipv6),
+    };
+    let test = (config.manages_ipsets() && !config.skip_self_test).then_some(self_test);
+    let names = ByIpFamily {
+        ipv4: config.ipset_ipv4_name.clone(),
+        ipv6: config.ipset_ipv6_name.clone(),
+    };
+    let watch_names = match (&config.ipset_watch_ipv4_name, &config.ipset_watch_ipv6_name) {
+        (Some(ipv4_name), Some(ipv6_name)) => Some(ByIpFamily {
+            ipv4: ipv4_name.clone(),
+            ipv6: ipv6_name.clone(),
+        }),
+        _ => None,
+    };
+    let port_names: Vec<_> = config
+        .port_ipset
+        .iter()
+        .map(|sets| {
+            (
+                sets.port,
+                ByIpFamily {
+                    ipv4: sets.ipv4_name.clone(),
+                    ipv6: sets.ipv6_name.clone(),
+                },
+            )
+        })
+        .collect();
+    let tenant_names: Vec<_> = config
+        .tenant_ipset
+        .iter()
+        .map(|sets| {
+            (
+                sets.tenant,
+                ByIpFamily {
+                    ipv4: sets.ipv4_name.clone(),
+                    ipv6: sets.ipv6_name.clone(),
+                },
+            )
+        })
+        .collect();
+    let open = move || -> Result<Backends, LeroyError> {
+        let open_sets = |names: &ByIpFamily<String>| {
+            ByIpFamily::try_new_with(|family| {
+                backend::open(
+                    names.by_family(family),
+                    test.as_ref().map(|test| *test.by_family(family)),
+                )
+            })
+        };
+        Ok(Backends {
+            bans: match backends {
+                Some(backends) => ByIpFamily {
+                    ipv4: backends.ipv4,
+                    ipv6: backends.ipv6,
+                },
+                None => open_sets(&names)?,
+            },
+            watch: watch_names.as_ref().map(open_sets).transpose()?,
+            ports: port_names
+                .iter()
+                .map(|(port, names)| Ok((*port, open_sets(names)?)))
+                .collect::<Result<_, LeroyError>>()?,
+            tenants: tenant_names
+                .iter()
+                .map(|(tenant, names)| Ok((*tenant, open_sets(names)?)))
+                .collect::<Result<_, LeroyError>>()?,
+        })
+    };
+    match config.netlink_queue {
+        Some(capacity) => {
+            let (queue, backends) = NetlinkQueue::spawn(
+                open,
+                capacity,
+                config.netlink_queue_overflow,
+                Scheduling::for_netlink(config),
+            )?;
+            Ok((backends, Some(queue)))
+        }
+        None => Ok((open()?, None)),
+    }
+}
+
+/// Inserts into the cache with its own time to live, and returns whether it
+/// was full, so that another entry was evicted (or the new one not admitted).
+fn insert_counting_eviction<K, V>(
+    cache: &mut TtlCache<K, V>,
+    key: K,
+    value: V,
+    ttl: Duration,
+) -> bool
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    let is_new = !cache.contains_key(&key);
+    let entry_count = cache.entry_count();
+    insert_with_ttl(cache, key, value, ttl);
+    is_new && cache.entry_count() <= entry_count
+}
+
+/// The key of a would-be ban of `ip` in `sets` in `Leroy::monitor_bans`.
+/// The watch sets share the cached bans with the ban sets.
+fn monitor_key(ip: MaskedIpAddr, sets: Sets) -> (Sets, MaskedIpAddr) {
+    match sets {
+        Sets::Watch => (Sets::Bans, ip),
+        sets => (sets, ip),
+    }
+}
+
+/// The cached bans that have not expired yet.
+fn active_bans(
+    cache: &TtlCache<MaskedIpAddr, SystemTime>,
+    now: SystemTime,
+) -> impl Iterator<Item = (MaskedIpAddr, SystemTime)> + '_ {
+    cache
+        .iter()
+        .filter(move |(_, expires)| **expires > now)
+        .map(|(net, expires)| (*net, *expires))
+}
+
+fn resync_rate_limiter<K, S>(rate_limiter: &mut KeyedLimiter<K, S>, jump: ClockJump)
+where
+    K: Hash + Eq + Clone,
+    S: BuildHasher,
+{
+    match jump {
+        ClockJump::Stalled(duration) => rate_limiter.advance(duration),
+        ClockJump::Ahead(duration) => rate_limiter.rewind(duration),
+    }
+}
+
+/// Keeps the first error after which bans can not be made anymore.
+fn record_fatal_error(fatal_error: &mut Option<LeroyError>, err: &LeroyError) {
+    if let LeroyError::Permission(ref message) = *err {
+        fatal_error.get_or_insert_with(|| LeroyError::Permission(message.clone()));
+    }
+}
+
+/// The tenant, destination port and reason of an event of a host name.
+type HostEvent = (Option<Tenant>, Option<u16>, Option<BanReason>);
+
+pub struct Leroy {
+    sessions: ByIpFamily<Box<dyn Backend>>,
+    watch_sessions: Option<ByIpFamily<Box<dyn Backend>>>,
+    /// With --port-ipset.
+    port_sessions: Vec<(u16, ByIpFamily<Box<dyn Backend>>)>,
+    /// With --tenant-ipset.
+    tenant_sessions: Vec<(Tenant, ByIpFamily<Box<dyn Backend>>)>,
+    /// With --netlink-queue. Declared after the sessions, which queue to it,
+    /// so that they are dropped before it waits for the queue to drain.
+    netlink_queue: Option<NetlinkQueue>,
+
+    sketch: Option<Sketch<MaskedIpAddr>>,
+    ip_rate_limiters: RateLimiters,
+    attack_rate_limiters: Option<RateLimiters>,
+    attack_detector: AttackDetector,
+    country_rate_limiters: CountryRateLimiters,
+    port_rate_limiters: PortRateLimiters,
+    tenant_rate_limiters: TenantRateLimiters,
+    geoip: Option<GeoIp>,
+    subnet_rate_limiters: RateLimiters,
+    /// Recently banned IPs and when their ban expires.
+    ipset_cache: ByIpFamily<TtlCache<MaskedIpAddr, SystemTime>>,
+    /// Recently banned IPs in the --port-ipset sets, and when their ban
+    /// expires.
+    port_ipset_cache: TtlCache<(u16, MaskedIpAddr), SystemTime>,
+    /// Recently banned IPs in the --tenant-ipset sets, and when their ban
+    /// expires.
+    tenant_ipset_cache: TtlCache<(Tenant, MaskedIpAddr), SystemTime>,
+    /// Would-be bans of monitor-only mode, and when they expire. Kept apart
+    /// from the cached bans, which are in the kernel, and dropped on
+    /// `enforce`.
+    monitor_bans: TtlCache<(Sets, MaskedIpAddr), SystemTime>,
+    /// Only tracked with --max-banned or --expiry-events.
+    live_bans: ByIpFamily<LiveBans>,
+    recidivism_counts: TtlCache<MaskedIpAddr, Recidivism>,
+    watch_rate_limiters: RateLimiters,
+    watch_cache: Cache<MaskedIpAddr, (), BuildHasherDefault<FxHasher>>,
+    asn_database: Option<AsnDatabase>,
+    asn_rate_limiter: Option<AsnRateLimiter>,
+    /// Garbage collection statistics of rate limiters dropped on reload.
+    retired_gc_stats: GcStats,
+    /// Whether all networks of the autonomous system have been banned.
+    asn_decisions: Cache<u32, bool, BuildHasherDefault<FxHasher>>,
+    approvals: ApprovalQueue,
+    allowlist: Allowlist,
+
+    metrics: Metrics,
+    statsd: Option<Statsd>,
+    otlp: Option<Otlp>,
+    webhook: Option<Webhook>,
+    abuse_reporter: Option<AbuseReporter>,
+    event_log: Option<EventLog>,
+    anonymizer: Option<IpAnonymizer>,
+    output: Output,
+    /// The listening sockets, which are passed on in a handover.
+    listen_fds: Vec<RawFd>,
+    /// The incomplete line from the previous process, see
+    /// [`Leroy::take_handover_input`].
+    handover_input: Vec<u8>,
+    health: Arc<Health>,
+    admin: Option<AdminQueue>,
+    cluster: Option<Cluster>,
+    /// With --resolve-hostnames.
+    resolver: Option<Resolver>,
+    /// The addresses of resolved host names, none for names that are not
+    /// banned.
+    hostnames: TtlCache<Hostname, Vec<IpAddr>>,
+    /// The events of host names that are being resolved.
+    resolving: HashMap<Hostname, Vec<HostEvent>, BuildHasherDefault<FxHasher>>,
+    hooks: Hooks,
+    /// The latest would-be bans of --dry-run.
+    dry_run_bans: VecDeque<BanEvent>,
+    /// Input lines are ignored while paused through the admin socket.
+    paused: bool,
+    /// Bans are not added to the ipsets, see --monitor-only.
+    monitor_only: bool,
+
+    line_count: u64,
+    line_count_start: Instant,
+    parse_errors: ParseErrors,
+    /// Limits the errors logged per line, parse errors and netlink errors.
+    error_log: LogLimiter,
+    /// See [`Leroy::take_fatal_error`].
+    fatal_error: Option<LeroyError>,
+    clock_jumps: JumpDetector,
+    ban_verify_start: Instant,
+    reconcile_start: Instant,
+    /// Entries of the sets that leroyjenkins did not add, with
+    /// --reconcile-adopt. Kept apart from the cached bans, so that they are
+    /// not flushed, saved or handed over as if leroyjenkins had made them.
+    adopted_bans: ByIpFamily<HashSet<MaskedIpAddr>>,
+    /// Where the next sample of cached bans starts, see [`Leroy::verify_bans`].
+    ban_verify_offsets: ByIpFamily<usize>,
+
+    ban_counts: BanCounts,
+    max_banned_skips: u64,
+    /// Events of banned addresses, see --banned-events-threshold, and the
+    /// address of the latest one.
+    banned_events: u64,
+    banned_event_ip: Option<MaskedIpAddr>,
+    warmup_skips: u64,
+    ban_count_start: Instant,
+
+    start: Instant,
+
+    state_save_start: Instant,
+    recidivism_prune_start: Instant,
+
+    config: LeroyConfig,
+}
+
+impl Leroy {
+    /// Typed construction for library users, see [`LeroyBuilder`].
+    pub fn builder() -> LeroyBuilder {
+        LeroyBuilder::new()
+    }
+
+    pub fn new(config: impl Into<LeroyConfig>) -> Result<Leroy, LeroyError> {
+        Leroy::with_backends(config.into(), None)
+    }
+
+    /// Like [`Leroy::new`], but with `backends` instead of the ipsets.
+    pub(crate) fn with_backends(
+        config: LeroyConfig,
+        backends: Option<ByIpFamily<Box<dyn Backend + Send>>>,
+    ) -> Result<Leroy, LeroyError> {
+        config.validate()?;
+        // Before --chroot.
+        let anonymizer =
+            IpAnonymizer::open(config.anonymize_ips, config.anonymize_key_file.as_deref())?;
+        let mut listen_fds = ListenFds::from_env();
+        let handover = handover::receive().unwrap_or_else(|err| {
+            error!("Failed to take over from the previous process, starting afresh: {err}");
+            None
+        });
+        // Outside of --chroot.
+        let nameservers = config.resolve_hostnames.map(|_| resolve::nameservers());
+        // Before spawning any threads, starting with the --netlink-queue
+        // writer, because capabilities are per thread. The ipsets still
+        // work with the CAP_NET_ADMIN that is kept.
+        if config.drop_capabilities || config.user.is_some() || config.chroot.is_some() {
+            sandbox::drop_privileges(&config)?;
+        }
+        let (backends, netlink_queue) = open_backends(&config, backends)?;
+        let mut leroy = Leroy {
+            sessions: backends.bans,
+            watch_sessions: backends.watch,
+            port_sessions: backends.ports,
+            tenant_sessions: backends.tenants,
+            netlink_queue,
+            watch_rate_limiters: watch_rate_limiters(&config)?,
+            watch_cache: Cache::builder()
+                .initial_capacity(config.cache_initial_capacity)
+                .max_capacity(config.cache_max_size)
+                .time_to_live(
+                    config
+                        .ipset_watch_time
+                        .saturating_sub(Duration::from_secs(1)),
+                )
+                .build_with_hasher(Default::default()),
+            sketch: config.sketch_width.map(|width| {
+                Sketch::new(
+                    config.sketch_algorithm,
+                    width,
+                    config.sketch_window,
+                    config.clock.clone(),
+                )
+            }),
+            ip_rate_limiters: ban_rate_limiters(&config, |family| config.bl_threshold_for(family))?,
+            attack_rate_limiters: attack_rate_limiters(&config)?,
+            country_rate_limiters: country_rate_limiters(&config)?,
+            port_rate_limiters: port_rate_limiters(&config)?,
+            tenant_rate_limiters: tenant_rate_limiters(&config)?,
+            geoip: match config.geoip_file {
+                Some(ref path) => Some(GeoIp::open(path)?),
+                None => None,
+            },
+            attack_detector: AttackDetector::new(
+                config.attack_line_rate,
+                config.attack_ban_rate,
+                config.attack_window,
+                config.clock.clone(),
+            ),
+            asn_database: match config.asn_file {
+                Some(ref path) => Some(AsnDatabase::from_file(path)?),
+                None => None,
+            },
+            asn_rate_limiter: asn_rate_limiter(&config)?,
+            retired_gc_stats: GcStats::default(),
+            asn_decisions: Cache::builder()
+                .time_to_live(
+                    config
+                        .ipset_base_time
+                        .saturating_sub(Duration::from_secs(1)),
+                )
+                .build_with_hasher(Default::default()),
+            subnet_rate_limiters: subnet_rate_limiters(&config)?,
+            approvals: ApprovalQueue::new(config.clock.clone()),
+            ipset_cache: ByIpFamily::try_new_with::<_, LeroyError>(|family| {
+                Ok(ttl_cache(
+                    config.cache_initial_capacity,
+                    config.cache_max_size,
+                    Some(
+                        config
+                            .ipset_base_time_for(family)
+                            .saturating_sub(Duration::from_secs(1)),
+                    ),
+                    &config.clock,
+                ))
+            })?,
+            port_ipset_cache: ttl_cache(
+                config.cache_initial_capacity,
+                config.cache_max_size,
+                None,
+                &config.clock,
+            ),
+            tenant_ipset_cache: ttl_cache(
+                config.cache_initial_capacity,
+                config.cache_max_size,
+                None,
+                &config.clock,
+            ),
+            monitor_bans: ttl_cache(
+                config.cache_initial_capacity,
+                config.cache_max_size,
+                None,
+                &config.clock,
+            ),
+            recidivism_counts: ttl_cache(
+                config.cache_initial_capacity,
+                config.cache_max_size,
+                // Pruned in periodic_work with --recidivism-decay.
+                (!config.recidivism_decay).then_some(config.ipset_ban_ttl),
+                &config.clock,
+            ),
+            live_bans: ByIpFamily {
+                ipv4: LiveBans::new(config.clock.clone(), config.expiry_events),
+                ipv6: LiveBans::new(config.clock.clone(), config.expiry_events),
+            },
+            allowlist: match config.allowlist_file {
+                Some(ref path) => Allowlist::from_file(path)?,
+                None => Allowlist::default(),
+            },
+            line_count: 0,
+            ban_counts: BanCounts::default(),
+            metrics: Metrics::default(),
+            statsd: match config.statsd_addr {
+                Some(ref addr) => Some(Statsd::new(
+                    addr,
+                    &config.statsd_prefix,
+                    &config.statsd_tags,
+                    config.statsd_period,
+                )?),
+                None => None,
+            },
+            otlp: match config.otlp_endpoint {
+                Some(ref endpoint) => Some(Otlp::new(endpoint.clone(), config.otlp_period)?),
+                None => None,
+            },
+            webhook: match config.webhook_url {
+                Some(ref url) => Some(Webhook::new(url.clone(), config.webhook_format)?),
+                None => None,
+            },
+            abuse_reporter: match (&config.abuseipdb_key_file, &config.threat_intel_url) {
+                (None, None) => None,
+                (key_file, url) => Some(AbuseReporter::new(
+                    match key_file {
+                        Some(key_file) => {
+                            Some(AbuseIpDb::new(key_file, &config.abuseipdb_categories)?)
+                        }
+                        None => None,
+                    },
+                    url.clone(),
+                    config.abuse_report_max_per_day,
+                )?),
+            },
+            event_log: match config.event_log {
+                Some(ref path) => Some(
+                    EventLog::open(
+                        path,
+                        config.event_log_format,
+                        config.event_log_capacity,
+                        config.event_log_reverse_dns,
+                        anonymizer.clone(),
+                    )
+                    .map_err(|err| format!("Failed to open event log {path:?}: {err}"))?,
+                ),
+                None => None,
+            },
+            output: Output::open(&config)?,
+            listen_fds: Vec::new(),
+            handover_input: Vec::new(),
+            anonymizer,
+            health: {
+                let health = Arc::new(Health::new(config.health_listen.is_some()));
+                if let Some(addr) = config.health_listen {
+                    listen_fds
+                        .tcp_listener(addr)
+                        .and_then(|listener| {
+                            health::serve(listener, Arc::clone(&health), config.health_max_idle)
+                        })
+                        .map_err(|err| {
+                            format!("Failed to serve health endpoint on {addr}: {err}")
+                        })?;
+                }
+                health
+            },
+            admin: if config.admin_socket.is_some() || config.admin_listen.is_some() {
+                let admin = AdminQueue::new()?;
+                if let Some(ref path) = config.admin_socket {
+                    listen_fds
+                        .unix_listener(path)
+                        .and_then(|listener| admin::serve(listener, &admin))
+                        .map_err(|err| {
+                            format!("Failed to accept admin commands on {path:?}: {err}")
+                        })?;
+                }
+                if let (Some(addr), Some(token_file)) =
+                    (config.admin_listen, &config.admin_token_file)
+                {
+                    let token = fs::read_to_string(token_file)
+                        .map_err(|err| format!("Failed to read admin token {token_file:?}: {err}"))?
+                        .trim()
+                        .to_owned();
+                    if token.is_empty() {
+                        return Err(format!("Admin token {token_file:?} is empty").into());
+                    }
+                    listen_fds
+                        .tcp_listener(addr)
+                        .and_then(|listener| admin_http::serve(listener, token, admin.client()?))
+                        .map_err(|err| format!("Failed to serve admin API on {addr}: {err}"))?;
+                }
+                Some(admin)
+            } else {
+                None
+            },
+            resolver: match (config.resolve_hostnames, nameservers) {
+                (Some(threads), Some(nameservers)) => Some(Resolver::spawn(threads, nameservers)?),
+                _ => None,
+            },
+            hostnames: ttl_cache(
+                0,
+                config.cache_max_size,
+                Some(config.resolve_max_ttl),
+                &config.clock,
+            ),
+            resolving: HashMap::default(),
+            cluster: if config.cluster_listen.is_some() || !config.cluster_peer.is_empty() {
+                let token_file = config
+                    .cluster_token_file
+                    .as_ref()
+                    .ok_or("--cluster-token-file is required")?;
+                let token = fs::read_to_string(token_file)
+                    .map_err(|err| format!("Failed to read cluster token {token_file:?}: {err}"))?
+                    .trim()
+                    .to_owned();
+                if token.is_empty() {
+                    return Err(format!("Cluster token {token_file:?} is empty").into());
+                }
+                let node_id = config
+                    .cluster_node_id
+                    .clone()
+                    .unwrap_or_else(cluster::hostname);
+                let listener = config
+                    .cluster_listen
+                    .map(|addr| {
+                        listen_fds.tcp_listener(addr).map_err(|err| {
+                            format!("Failed to accept bans from peers on {addr}: {err}")
+                        })
+                    })
+                    .transpose()?;
+                Some(
+                    Cluster::new(node_id, listener, &config.cluster_peer, token)
+                        .map_err(|err| format!("Failed to set up cluster: {err}"))?,
+                )
+            } else {
+                None
+            },
+            paused: false,
+            monitor_only: config.monitor_only,
+            max_banned_skips: 0,
+            banned_events: 0,
+            banned_event_ip: None,
+            warmup_skips: 0,
+            line_count_start: config.clock.now(),
+            parse_errors: ParseErrors::new(config.parse_error_examples),
+            error_log: LogLimiter::new(config.clock.clone()),
+            fatal_error: None,
+            clock_jumps: JumpDetector::new(config.clock.clone()),
+            ban_verify_start: config.clock.now(),
+            reconcile_start: config.clock.now(),
+            adopted_bans: ByIpFamily::default(),
+            ban_verify_offsets: ByIpFamily { ipv4: 0, ipv6: 0 },
+            ban_count_start: config.clock.now(),
+            start: config.clock.now(),
+            state_save_start: config.clock.now(),
+            recidivism_prune_start: config.clock.now(),
+            hooks: Hooks::default(),
+            dry_run_bans: VecDeque::new(),
+            config,
+        };
+        leroy.listen_fds = listen_fds.close_unused();
+        leroy.sweep_allowlist();
+        leroy.restore_state()?;
+        if let Some(handover) = handover {
+            leroy.restore_handover(handover);
+        }
+        // After spawning the other threads, so that they do not inherit it.
+        Scheduling::for_lines(&leroy.config).apply("line reader")?;
+        if leroy.config.lock_memory {
+            leroy.prewarm();
+            memory::lock_all().map_err(|err| {
+                io::Error::new(err.kind(), format!("Failed to lock memory: {err}"))
+            })?;
+            match memory::resident_bytes() {
+                Some(bytes) => info!("Locked memory, {} MiB resident", bytes >> 20),
+                None => info!("Locked memory"),
+            }
+        }
+        if leroy.config.seccomp {
+            sandbox::install_seccomp()?;
+        }
+        Ok(leroy)
+    }
+
+    /// Faults in the rate limiters and caches allocated with
+    /// --cache-initial-capacity, for --lock-memory.
+    fn prewarm(&mut self) {
+        let keys = || (0..).map(|i| MaskedIpAddr::from(IpAddr::V6(Ipv6Addr::from_bits(i))));
+        let rate_limiters = [
+            &mut self.ip_rate_limiters,
+            &mut self.subnet_rate_limiters,
+            &mut self.watch_rate_limiters,
+        ];
+        for rate_limiters in rate_limiters
+            .into_iter()
+            .chain(self.attack_rate_limiters.as_mut())
+        {
+            for rate_limiter in [&mut rate_limiters.ipv4, &mut rate_limiters.ipv6]
+                .into_iter()
+                .flatten()
+            {
+                rate_limiter.prewarm(keys());
+            }
+        }
+        for cache in [&mut self.ipset_cache.ipv4, &mut self.ipset_cache.ipv6] {
+            prewarm_cache(cache, keys(), SystemTime::UNIX_EPOCH);
+        }
+        let recidivism = Recidivism {
+            count: 0,
+            last_ban: SystemTime::UNIX_EPOCH,
+            reason: None,
+        };
+        prewarm_cache(&mut self.recidivism_counts, keys(), recidivism);
+    }
+
+    fn restore_state(&mut self) -> Result<(), LeroyError> {
+        if let Some(path) = self.config.state_file.clone() {
+            let state = state::load(&path).map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("Failed to load state file {path:?}: {err}"),
+                )
+            })?;
+            let now = self.config.clock.system_now();
+            for (net, recidivism) in state.recidivism {
+                self.restore_recidivism(net, recidivism, now);
+            }
+            for (net, expires) in state.bans {
+                self.restore_ban(net, expires, now);
+            }
+            info!(
+                "Restored {} recidivism counts and {} active bans from {path:?}",
+                self.recidivism_counts.entry_count(),
+                self.ipset_cache.ipv4.entry_count() + self.ipset_cache.ipv6.entry_count()
+            );
+        }
+        Ok(())
+    }
+
+    fn restore_recidivism(&mut self, net: MaskedIpAddr, recidivism: Recidivism, now: SystemTime) {
+        if self.config.previous_bans(&recidivism) > 0 {
+            let since = now.duration_since(recidivism.last_ban).unwrap_or_default();
+            insert_with_ttl(
+                &mut self.recidivism_counts,
+                net,
+                recidivism,
+                self.config.recidivism_ttl(since),
+            );
+        }
+    }
+
+    fn restore_ban(&mut self, net: MaskedIpAddr, expires: SystemTime, now: SystemTime) {
+        if expires > now && !self.allowlist.overlaps(&net) {
+            let remaining = expires.duration_since(now).unwrap_or_default();
+            insert_with_ttl(
+                self.ipset_cache.by_family_mut(net.family()),
+                net,
+                expires,
+                remaining.saturating_sub(Duration::from_secs(1)),
+            );
+            if self.config.tracks_live_bans() {
+                self.live_bans
+                    .by_family_mut(net.family())
+                    .insert(net, expires);
+            }
+        }
+    }
+
+    /// Takes over from the previous process, after the state file, which
+    /// the handover is newer than.
+    fn restore_handover(&mut self, handover: Handover) {
+        let mut limiter_states = 0;
+        for (table, states) in handover.rate_limiters {
+            let rate_limiters = match table.as_str() {
+                "ip" => Some(&mut self.ip_rate_limiters),
+                "attack" => self.attack_rate_limiters.as_mut(),
+                "subnet" => Some(&mut self.subnet_rate_limiters),
+                "watch" => Some(&mut self.watch_rate_limiters),
+                table => table
+                    .strip_prefix("country:")
+                    .and_then(|country| country.parse::<CountryCode>().ok())
+                    .and_then(|country| self.country_rate_limiters.get_mut(&country))
+                    .or_else(|| {
+                        table
+                            .strip_prefix("port:")
+                            .and_then(|port| port.parse::<u16>().ok())
+                            .and_then(|port| self.port_rate_limiters.get_mut(&port))
+                    })
+                    .or_else(|| {
+                        table
+                            .strip_prefix("tenant:")
+                            .and_then(|tenant| tenant.parse::<Tenant>().ok())
+                            .and_then(|tenant| self.tenant_rate_limiters.get_mut(&tenant))
+                    }),
+            };
+            // Dropped, like on reload, if the table is no longer configured.
+            let Some(rate_limiters) = rate_limiters else {
+                continue;
+            };
+            let (ipv4, ipv6): (Vec<_>, Vec<_>) = states
+                .into_iter()
+                .partition(|(net, _)| net.addr().is_ipv4());
+            for (family, states) in [(IpFamily::V4, ipv4), (IpFamily::V6, ipv6)] {
+                if let Some(rate_limiter) = rate_limiters.by_family_mut(family) {
+                    limiter_states += states.len();
+                    rate_limiter.import(states);
+                }
+            }
+        }
+        if let Some(ref mut rate_limiter) = self.asn_rate_limiter {
+            limiter_states += handover.asn_rate_limiter.len();
+            rate_limiter.import(handover.asn_rate_limiter);
+        }
+        let now = self.config.clock.system_now();
+        let (recidivism, bans) = (handover.recidivism.len(), handover.bans.len());
+        for (net, recidivism) in handover.recidivism {
+            self.restore_recidivism(net, recidivism, now);
+        }
+        for (net, expires) in handover.bans {
+            self.restore_ban(net, expires, now);
+        }
+        for net in handover.watched {
+            self.watch_cache.insert(net, ());
+        }
+        for (asn, banned) in handover.asn_decisions {
+            self.asn_decisions.insert(asn, banned);
+        }
+        let pending_bans = handover.pending_bans.len();
+        if self.config.approve_wide_bans {
+            for ban in handover.pending_bans {
+                self.approvals.restore(ban);
+            }
+        }
+        self.paused = handover.paused;
+        self.handover_input = handover.input;
+        info!(
+            "Took over {limiter_states} rate limiter states, {recidivism} recidivism counts, {bans} active bans and {pending_bans} bans waiting for approval from the previous process{}",
+            if self.paused { ", paused" } else { "" }
+        );
+    }
+
+    /// The start of an incomplete input line that the previous process
+    /// handed over, to be prepended to the next input.
+    pub fn take_handover_input(&mut self) -> Vec<u8> {
+        mem::take(&mut self.handover_input)
+    }
+
+    /// Whether [`Leroy::handover`] is possible with the configuration.
+    pub fn check_handover(&self) -> Result<(), LeroyError> {
+        let config = &self.config;
+        if config.drop_capabilities
+            || config.user.is_some()
+            || config.chroot.is_some()
+            || config.seccomp
+        {
+            return Err("A handover is not possible with --drop-capabilities, --user, --chroot or --seccomp, because the new process could not apply them again".into());
+        }
+        if let Some(EmitTarget::Fd(fd)) = config.emit_bans {
+            return Err(format!(
+                "A handover is not possible with --emit-bans {fd}, because the passed sockets take the place of file descriptors from 3"
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Replaces the process with a new one of the same program and
+    /// arguments, for example after an upgrade, which carries on with the
+    /// rate limiter states, bans and bans waiting for approval, and
+    /// `pending_input`, the start of an incomplete line. Stdin and the
+    /// listening sockets are kept, so that nothing is dropped. Saves the
+    /// state and waits for background work like [`Leroy::shutdown`] first.
+    /// Only returns if replacing the process failed, after which it should
+    /// exit.
+    pub fn handover(mut self, pending_input: &[u8]) -> LeroyError {
+        if let Err(err) = self.check_handover() {
+            return err;
+        }
+        let handover = self.handover_state(pending_input);
+        let listen_fds = mem::take(&mut self.listen_fds);
+        info!("Handing over to a new process");
+        // Errors are logged.
+        let _ = self.shutdown();
+        handover::exec(&handover, &listen_fds).into()
+    }
+
+    fn handover_state(&self, pending_input: &[u8]) -> Handover {
+        let mut rate_limiters = Vec::new();
+        let mut export = |table: String, limiters: &RateLimiters| {
+            let states: Vec<_> = [&limiters.ipv4, &limiters.ipv6]
+                .into_iter()
+                .flatten()
+                .flat_map(|rate_limiter| rate_limiter.export())
+                .collect();
+            if !states.is_empty() {
+                rate_limiters.push((table, states));
+            }
+        };
+        export("ip".to_owned(), &self.ip_rate_limiters);
+        if let Some(ref attack_rate_limiters) = self.attack_rate_limiters {
+            export("attack".to_owned(), attack_rate_limiters);
+        }
+        export("subnet".to_owned(), &self.subnet_rate_limiters);
+        export("watch".to_owned(), &self.watch_rate_limiters);
+        for (country, country_rate_limiters) in &self.country_rate_limiters {
+            export(format!("country:{country}"), country_rate_limiters);
+        }
+        for (port, port_rate_limiters) in &self.port_rate_limiters {
+            export(format!("port:{port}"), port_rate_limiters);
+        }
+        for (tenant, tenant_rate_limiters) in &self.tenant_rate_limiters {
+            export(format!("tenant:{tenant}"), tenant_rate_limiters);
+        }
+        let now = self.config.clock.system_now();
+        Handover {
+            rate_limiters,
+            asn_rate_limiter: self
+                .asn_rate_limiter
+                .as_ref()
+                .map(|rate_limiter| rate_limiter.export())
+                .unwrap_or_default(),
+            recidivism: self
+                .recidivism_counts
+                .iter()
+                .map(|(net, recidivism)| (*net, *recidivism))
+                .collect(),
+            bans: active_bans(&self.ipset_cache.ipv4, now)
+                .chain(active_bans(&self.ipset_cache.ipv6, now))
+                .collect(),
+            watched: self.watch_cache.iter().map(|(net, _)| *net).collect(),
+            asn_decisions: self
+                .asn_decisions
+                .iter()
+                .map(|(asn, banned)| (*asn, *banned))
+                .collect(),
+            pending_bans: self.approvals.list(),
+            paused: self.paused,
+            input: pending_input.to_vec(),
+        }
+    }
+
+    /// Calls `hook` after every ban, including those in dry run or
+    /// monitor-only mode.
+    pub fn set_ban_hook(&mut self, hook: impl FnMut(BanEvent) + Send + 'static) {
+        self.hooks.ban = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` whenever a ban is lifted early, and with
+    /// --expiry-events when one runs out.
+    pub fn set_unban_hook(&mut self, hook: impl FnMut(UnbanEvent) + Send + 'static) {
+        self.hooks.unban = Some(Box::new(hook));
+    }
+
+    /// Calls `hook` with every error that is logged while running, like
+    /// failed ipset operations.
+    pub fn set_error_hook(&mut self, hook: impl FnMut(&LeroyError) + Send + 'static) {
+        self.hooks.error = Some(Box::new(hook));
+    }
+
+    /// Writes recidivism counts and active bans to --state-file, if
+    /// configured.
+    pub fn save_state(&mut self) -> io::Result<()> {
+        self.state_save_start = self.config.clock.now();
+        // Would-be bans of --dry-run have no kernel entries, so a later real
+        // run must not restore them as active.
+        let bans = (!self.config.dry_run).then(|| {
+            self.ipset_cache
+                .ipv4
+                .iter()
+                .chain(self.ipset_cache.ipv6.iter())
+        });
+        match self.config.state_file {
+            Some(ref path) => state::save(
+                path,
+                self.recidivism_counts.iter(),
+                bans.into_iter().flatten(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies reloaded arguments, keeping rate limiter states, caches and
+    /// bans. Settings that are only used at startup, like ipset names, cache
+    /// sizes and listen addresses, keep their previous values until restart.
+    pub fn reload(&mut self, config: impl Into<LeroyConfig>) -> Result<(), LeroyError> {
+        let mut config = config.into();
+        config.validate()?;
+        if config.dry_run != self.config.dry_run {
+            warn!("Ignoring changed --dry-run until restart");
+            config.dry_run = self.config.dry_run;
+        }
+        if config.forward_bans != self.config.forward_bans {
+            warn!("Ignoring changed --forward-bans until restart");
+            config.forward_bans = self.config.forward_bans;
+        }
+        if config.port_ipset != self.config.port_ipset {
+            warn!("Ignoring changed --port-ipset until restart");
+            config.port_ipset = mem::take(&mut self.config.port_ipset);
+        }
+        if config.tenant_ipset != self.config.tenant_ipset {
+            warn!("Ignoring changed --tenant-ipset until restart");
+            config.tenant_ipset = mem::take(&mut self.config.tenant_ipset);
+        }
+        config.clock = self.config.clock.clone();
+        if config.monitor_only != self.config.monitor_only {
+            self.monitor_only = config.monitor_only;
+        }
+
+        // Build everything first, so that nothing changes on errors.
+        let mut ip_rate_limiters =
+            ban_rate_limiters(&config, |family| config.bl_threshold_for(family))?;
+        let mut attack_rate_limiters = attack_rate_limiters(&config)?;
+        let mut country_rate_limiters = country_rate_limiters(&config)?;
+        let mut port_rate_limiters = port_rate_limiters(&config)?;
+        let mut tenant_rate_limiters = tenant_rate_limiters(&config)?;
+        let mut watch_rate_limiters = watch_rate_limiters(&config)?;
+        let mut subnet_rate_limiters = subnet_rate_limiters(&config)?;
+        let mut asn_rate_limiter = asn_rate_limiter(&config)?;
+        let allowlist = match config.allowlist_file {
+            Some(ref path) => Allowlist::from_file(path)?,
+            None => Allowlist::default(),
+        };
+
+        let retired = &mut self.retired_gc_stats;
+        for (rate_limiters, previous) in [
+            (&mut ip_rate_limiters, &mut self.ip_rate_limiters),
+            (&mut watch_rate_limiters, &mut self.watch_rate_limiters),
+            (&mut subnet_rate_limiters, &mut self.subnet_rate_limiters),
+        ] {
+            inherit_rate_limiters(Some(rate_limiters), Some(mem::take(previous)), retired);
+        }
+        inherit_rate_limiters(
+            attack_rate_limiters.as_mut(),
+            self.attack_rate_limiters.take(),
+            retired,
+        );
+        for (country, previous) in self.country_rate_limiters.drain() {
+            inherit_rate_limiters(
+                country_rate_limiters.get_mut(&country),
+                Some(previous),
+                retired,
+            );
+        }
+        for (port, previous) in self.port_rate_limiters.drain() {
+            inherit_rate_limiters(port_rate_limiters.get_mut(&port), Some(previous), retired);
+        }
+        for (tenant, previous) in self.tenant_rate_limiters.drain() {
+            inherit_rate_limiters(
+                tenant_rate_limiters.get_mut(&tenant),
+                Some(previous),
+                retired,
+            );
+        }
+        inherit_rate_limiter(
+            asn_rate_limiter.as_mut(),
+            self.asn_rate_limiter.take(),
+            retired,
+        );
+
+        self.ip_rate_limiters = ip_rate_limiters;
+        self.attack_rate_limiters = attack_rate_limiters;
+        self.country_rate_limiters = country_rate_limiters;
+        self.port_rate_limiters = port_rate_limiters;
+        self.tenant_rate_limiters = tenant_rate_limiters;
+        self.watch_rate_limiters = watch_rate_limiters;
+        self.subnet_rate_limiters = subnet_rate_limiters;
+        self.asn_rate_limiter = asn_rate_limiter;
+        self.attack_detector.set_trip_points(
+            config.attack_line_rate,
+            config.attack_ban_rate,
+            config.attack_window,
+        );
+        self.allowlist = allowlist;
+        self.config = config;
+        info!(
+            "Reloaded configuration with an allowlist of {} entries",
+            self.allowlist.len()
+        );
+        self.sweep_allowlist();
+        Ok(())
+    }
+
+    /// Reloads --allowlist-file. Keeps the previous allowlist if the file
+    /// can not be loaded.
+    pub fn reload_allowlist(&mut self) -> Result<(), LeroyError> {
+        if let Some(ref path) = self.config.allowlist_file {
+            self.allowlist = Allowlist::from_file(path)?;
+            info!("Reloaded allowlist with {} entries", self.allowlist.len());
+            self.sweep_allowlist();
+        }
+        Ok(())
+    }
+
+    fn sweep_allowlist(&mut self) {
+        let entries: Vec<MaskedIpAddr> = self.allowlist.entries().copied().collect();
+        for entry in &entries {
+            let family = entry.family();
+            self.ipset_cache.by_family_mut(family).invalidate(entry);
+            self.live_bans.by_family_mut(family).remove(*entry);
+            if self.config.manages_ipsets() {
+                match self.sessions.by_family_mut(family).del(*entry) {
+                    Ok(true) => {
+                        info!("Removed allowlisted {entry} from set");
+                        self.record_unban(*entry, UnbanReason::Allowlisted);
+                    }
+                    Ok(false) => {}
+                    Err(err) => {
+                        let err = LeroyError::netlink(format!(
+                            "Unable to remove allowlisted {entry} from set: {err}"
+                        ));
+                        self.netlink_error(&err);
+                        self.health.record_netlink(false);
+                    }
+                }
+            }
+        }
+    }
+
+    /// The file descriptor that becomes readable when admin requests are
+    /// waiting, if --admin-socket is enabled.
+    pub fn admin_fd(&self) -> Option<RawFd> {
+        self.admin.as_ref().map(|admin| admin.as_raw_fd())
+    }
+
+    /// The file descriptor that becomes readable when bans from peers are
+    /// waiting, if --cluster-listen is enabled.
+    pub fn cluster_fd(&self) -> Option<RawFd> {
+        self.cluster
+            .as_ref()
+            .filter(|_| self.config.cluster_listen.is_some())
+            .map(|cluster| cluster.as_raw_fd())
+    }
+
+    /// The file descriptor that becomes readable when host names have been
+    /// resolved, if --resolve-hostnames is enabled.
+    pub fn resolver_fd(&self) -> Option<RawFd> {
+        self.resolver.as_ref().map(|resolver| resolver.as_raw_fd())
+    }
+
+    /// Counts the events of the host names that have been resolved since
+    /// the last call, against each of their addresses.
+    pub fn handle_resolved(&mut self) {
+        let Some(ref mut resolver) = self.resolver else {
+            return;
+        };
+        for (name, result) in resolver.take_results() {
+            let events = self.resolving.remove(&name).unwrap_or_default();
+            let addrs = match result {
+                Ok(resolved) => self.resolved_addrs(&name, resolved),
+                Err(err) => {
+                    debug!("Failed to resolve {name}: {err}");
+                    insert_with_ttl(
+                        &mut self.hostnames,
+                        name.clone(),
+                        Vec::new(),
+                        resolve::NEGATIVE_TTL,
+                    );
+                    continue;
+                }
+            };
+            for (tenant, port, reason) in events {
+                self.check_addrs(&name, &addrs, tenant, port, reason);
+            }
+        }
+        self.output.flush();
+    }
+
+    /// Caches the addresses of `name`, unless they are too many, or some are
+    /// allowlisted or private, which a hostile name could use to ban others.
+    fn resolved_addrs(&mut self, name: &Hostname, resolved: Resolved) -> Vec<IpAddr> {
+        let ttl = resolved
+            .ttl
+            .clamp(self.config.resolve_min_ttl, self.config.resolve_max_ttl);
+        let mut addrs = resolved.addrs;
+        if addrs.len() > self.config.resolve_max_addrs {
+            warn!(
+                "Not banning {name}, because it has {} addresses (--resolve-max-addrs is {})",
+                addrs.len(),
+                self.config.resolve_max_addrs
+            );
+            addrs.clear();
+        } else if let Some(ip) = addrs.iter().find(|ip| {
+            self.allowlist.contains(**ip) || (!self.config.ban_private_ranges && is_private(**ip))
+        }) {
+            warn!("Not banning {name}, because its address {ip} is allowlisted or private");
+            addrs.clear();
+        } else {
+            debug!("Resolved {name} to {addrs:?} for {ttl:?}");
+        }
+        insert_with_ttl(&mut self.hostnames, name.clone(), addrs.clone(), ttl);
+        addrs
+    }
+
+    /// Counts an event against each address of a resolved host name.
+    fn check_addrs(
+        &mut self,
+        name: &Hostname,
+        addrs: &[IpAddr],
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        let mut decision = Decision::Ignored;
+        for ip in addrs {
+            let ip_decision = self.check_ip(*ip, tenant, port, reason);
+            if ip_decision.is_banned() {
+                debug!("Banned {ip} as an address of {name}");
+            }
+            if !decision.is_banned() {
+                decision = ip_decision;
+            }
+        }
+        decision
+    }
+
+    /// Counts an event of a host name, right away if it was resolved
+    /// recently, or else once it is.
+    fn check_host(
+        &mut self,
+        line: &[u8],
+        name: Hostname,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        let Some(ref resolver) = self.resolver else {
+            return self.record_parse_error(line, LineError::Hostname);
+        };
+        if let Some(addrs) = self.hostnames.get(&name).cloned() {
+            return self.check_addrs(&name, &addrs, tenant, port, reason);
+        }
+        match self.resolving.get_mut(&name) {
+            Some(events) => {
+                if events.len() < resolve::MAX_PENDING_EVENTS {
+                    events.push((tenant, port, reason));
+                }
+            }
+            None => {
+                if !resolver.resolve(name.clone()) {
+                    debug!("Not resolving {name}, because too many names are being resolved");
+                    self.metrics.resolver_drops += 1;
+                    return Decision::Ignored;
+                }
+                self.resolving.insert(name, vec![(tenant, port, reason)]);
+            }
+        }
+        Decision::Resolving
+    }
+
+    /// Applies the bans from peers. Bans that are already active here are
+    /// skipped, just like repeated decisions of this instance.
+    pub fn handle_peer_bans(&mut self) {
+        let Some(ref mut cluster) = self.cluster else {
+            return;
+        };
+        for ban in cluster.take_bans() {
+            debug!(
+                "Ban of {} for {}s ({}) from peer {}",
+                ban.ip, ban.timeout, ban.category, ban.origin
+            );
+            // A timeout of 0 would keep the entry forever, and wider
+            // networks than this instance bans could block anyone.
+            let family = ban.ip.family();
+            let min_prefix = self
+                .config
+                .subnet_prefix_for(family)
+                .unwrap_or(u8::MAX)
+                .min(self.config.ban_prefix_for(family));
+            if ban.timeout == 0 || ban.ip.prefix_len() < min_prefix {
+                warn!(
+                    "Ignoring ban of {} for {}s from peer {}, because it is forever or wider than /{min_prefix}",
+                    ban.ip, ban.timeout, ban.origin
+                );
+                continue;
+            }
+            self.ban(ban.ip, BanCategory::Peer, Some(ban.timeout), ban.reason);
+        }
+        self.output.flush();
+    }
+
+    pub fn handle_admin_requests(&mut self) {
+        let Some(ref mut admin) = self.admin else {
+            return;
+        };
+        for request in admin.take_requests() {
+            info!("Admin command: {}", request.command);
+            let reply = self.handle_admin_command(request.command);
+            // The connection may have given up waiting.
+            let _ = request.reply.try_send(reply);
+        }
+        self.output.flush();
+    }
+
+    pub(crate) fn handle_admin_command(&mut self, command: AdminCommand) -> AdminReply {
+        match command {
+            AdminCommand::Ban(ip, duration) => {
+                if self.allowlist.overlaps(&ip) {
+                    return AdminReply::error(format!("{ip} overlaps the allowlist"));
+                }
+                if self.is_banned_net(ip) {
+                    return AdminReply::message(format!("{ip} is already banned"));
+                }
+                // A timeout of 0 would keep the entry forever.
+                if duration.is_some_and(|duration| duration.as_secs() == 0) {
+                    return AdminReply::error("a ban lasts at least 1s");
+                }
+                let timeout =
+                    duration.map(|duration| u32::try_from(duration.as_secs()).unwrap_or(u32::MAX));
+                if self.ban(ip, BanCategory::Manual, timeout, None).is_banned() {
+                    AdminReply::message(format!("banned {ip}"))
+                } else {
+                    AdminReply::error(format!("failed to ban {ip}, see the log for details"))
+                }
+            }
+            AdminCommand::Unban(ip) => self.unban(ip),
+            AdminCommand::Query(ip) => AdminReply::Status(self.ip_status(ip)),
+            AdminCommand::Status => AdminReply::Daemon(DaemonStatus {
+                paused: self.paused,
+                monitor_only: self.monitor_only,
+                uptime_seconds: self.config.clock.elapsed(self.start).as_secs(),
+                lines: self.metrics.lines,
+                active_bans: self.ipset_cache.ipv4.entry_count()
+                    + self.ipset_cache.ipv6.entry_count(),
+                attack_mode: self.attack_detector.under_attack(),
+            }),
+            AdminCommand::List => AdminReply::Bans {
+                bans: self.active_bans(),
+            },
+            AdminCommand::Stats => AdminReply::Stats {
+                stats: self.stats(),
+            },
+            AdminCommand::Pause => {
+                self.paused = true;
+                AdminReply::message("paused, input lines are ignored")
+            }
+            AdminCommand::Resume => {
+                self.paused = false;
+                AdminReply::message("resumed")
+            }
+            AdminCommand::Monitor => {
+                self.monitor_only = true;
+                AdminReply::message("monitor only, bans are not added to the sets")
+            }
+            AdminCommand::Enforce => {
+                self.monitor_only = false;
+                // Banned for real at their next event over the limit.
+                self.monitor_bans.invalidate_entries_if(|_, _| true);
+                AdminReply::message("enforcing bans")
+            }
+            AdminCommand::Dump(filter) => AdminReply::Dump(self.dump_state(filter)),
+            AdminCommand::Flush => self.flush_bans(),
+            AdminCommand::Pending => AdminReply::Pending {
+                pending: self.approvals.list(),
+            },
+            AdminCommand::Approve(id) => self.approve_ban(id),
+            AdminCommand::Reject(id) => match self.approvals.reject(id) {
+                Some(pending) => {
+                    info!(
+                        "Rejected {} ban #{id} of {}",
+                        pending.category,
+                        pending.target()
+                    );
+                    AdminReply::message(format!("rejected #{id}"))
+                }
+                None => AdminReply::error(format!("no pending ban #{id}")),
+            },
+        }
+    }
+
+    /// Removes `ip` from the set and forgets its rate limits, so that it is
+    /// not banned again right away. Recidivism is kept.
+    fn unban(&mut self, ip: MaskedIpAddr) -> AdminReply {
+        match self.remove_ban(ip, UnbanReason::Manual) {
+            Ok(true) => AdminReply::message(format!("unbanned {ip}")),
+            Ok(false) => AdminReply::message(format!("{ip} was not in the set")),
+            Err(err) => AdminReply::error(err.to_string()),
+        }
+    }
+
+    /// Removes every ban that is still cached, i.e. only elements that were
+    /// added by this instance, for example to roll back after a bad input
+    /// feed. Bans that were evicted from the cache are not known anymore.
+    fn flush_bans(&mut self) -> AdminReply {
+        let bans: Vec<MaskedIpAddr> = self
+            .ipset_cache
+            .ipv4
+            .iter()
+            .chain(self.ipset_cache.ipv6.iter())
+            .map(|(ip, _)| *ip)
+            .collect();
+        let (mut removed, mut missing, mut failed) = (0, 0, 0);
+        for ip in bans {
+            match self.remove_ban(ip, UnbanReason::Flushed) {
+                Ok(true) => removed += 1,
+                Ok(false) => missing += 1,
+                Err(_) => failed += 1,
+            }
+        }
+        // Ban decisions of autonomous systems refer to the removed prefixes.
+        self.asn_decisions.invalidate_all();
+        info!("Flushed {removed} bans, {missing} had already expired, {failed} failed");
+        if failed > 0 {
+            AdminReply::error(format!(
+                "flushed {removed} bans, but failed to remove {failed}, see the log for details"
+            ))
+        } else {
+            AdminReply::message(format!(
+                "flushed {removed} bans, {missing} had already expired"
+            ))
+        }
+    }
+
+    /// Forgets the ban and rate limits of `ip`, and removes it from the set
+    /// and the --port-ipset and --tenant-ipset sets. Returns `true` if it was
+    /// in any of them.
+    fn remove_ban(&mut self, ip: MaskedIpAddr, reason: UnbanReason) -> Result<bool, LeroyError> {
+        let family = ip.family();
+        self.ipset_cache.by_family_mut(family).invalidate(&ip);
+        self.live_bans.by_family_mut(family).remove(ip);
+        self.adopted_bans.by_family_mut(family).remove(&ip);
+        self.monitor_bans
+            .invalidate_entries_if(|(_, net), _| *net == ip);
+        self.credit_rate_limiters(ip, u32::MAX);
+        let mut result = if !self.config.manages_ipsets() {
+            Ok(true)
+        } else {
+            self.sessions.by_family_mut(family).del(ip).map_err(|err| {
+                LeroyError::netlink(format!("Unable to remove {ip} from set: {err}"))
+            })
+        };
+        for (port, port_sessions) in &mut self.port_sessions {
+            self.port_ipset_cache.invalidate(&(*port, ip));
+            if !self.config.manages_ipsets() {
+                continue;
+            }
+            let port_result = port_sessions.by_family_mut(family).del(ip).map_err(|err| {
+                LeroyError::netlink(format!(
+                    "Unable to remove {ip} from set of port {port}: {err}"
+                ))
+            });
+            result = match (result, port_result) {
+                (Err(err), _) | (_, Err(err)) => Err(err),
+                (Ok(removed), Ok(port_removed)) => Ok(removed || port_removed),
+            };
+        }
+        for (tenant, tenant_sessions) in &mut self.tenant_sessions {
+            self.tenant_ipset_cache.invalidate(&(*tenant, ip));
+            if !self.config.manages_ipsets() {
+                continue;
+            }
+            let tenant_result = tenant_sessions
+                .by_family_mut(family)
+                .del(ip)
+                .map_err(|err| {
+                    LeroyError::netlink(format!(
+                        "Unable to remove {ip} from set of tenant {tenant}: {err}"
+                    ))
+                });
+            result = match (result, tenant_result) {
+                (Err(err), _) | (_, Err(err)) => Err(err),
+                (Ok(removed), Ok(tenant_removed)) => Ok(removed || tenant_removed),
+            };
+        }
+        self.health.record_netlink(result.is_ok());
+        match result {
+            Ok(true) => {
+                info!("Unbanned {ip}");
+                self.record_unban(ip, reason);
+            }
+            Ok(false) => {}
+            Err(ref err) => {
+                self.netlink_error(err);
+            }
+        }
+        result
+    }
+
+    /// Reports the end of the ban of `ip` to the event log and the unban
+    /// hook.
+    fn record_unban(&mut self, ip: MaskedIpAddr, reason: UnbanReason) {
+        if let Some(ref mut event_log) = self.event_log {
+            event_log.log(Event::Unban {
+                ip,
+                family: ip.family(),
+                reason,
+                timestamp: self.config.clock.system_now(),
+            });
+        }
+        self.hooks.unban(UnbanEvent { ip, reason });
+    }
+
+    /// Reports the bans that ran out, with --expiry-events.
+    fn report_expired_bans(&mut self) {
+        if !self.config.expiry_events {
+            return;
+        }
+        for family in [IpFamily::V4, IpFamily::V6] {
+            for ip in self.live_bans.by_family_mut(family).take_expired() {
+                debug!("Ban of {ip} expired");
+                self.metrics.expired_bans += 1;
+                self.record_unban(ip, UnbanReason::Expired);
+            }
+        }
+    }
+
+    fn ip_status(&mut self, ip: MaskedIpAddr) -> IpStatus {
+        let banned_until = self.ban_expiry(ip);
+        let recidivism = self.recidivism_counts.get(&ip).copied();
+        IpStatus {
+            ip,
+            banned_until: banned_until.map(Timestamp),
+            banned: banned_until.is_some() || self.is_banned_net(ip),
+            previous_bans: recidivism
+                .map_or(0, |recidivism| self.config.previous_bans(&recidivism)),
+            last_ban: recidivism.map(|recidivism| Timestamp(recidivism.last_ban)),
+            last_reason: recidivism.and_then(|recidivism| recidivism.reason),
+            allowlisted: self.allowlist.overlaps(&ip),
+        }
+    }
+
+    /// How much longer `ip` is banned, as a single address (or network of
+    /// --ban-prefix-v4 and --ban-prefix-v6), or as part of a subnet or
+    /// autonomous system ban. Only bans made or restored since startup are
+    /// known.
+    pub fn is_banned(&mut self, ip: &IpAddr) -> Option<Duration> {
+        let net = MaskedIpAddr::new(
+            *ip,
+            self.config
+                .ban_prefix_for(IpFamily::from_ipv4(ip.is_ipv4())),
+        );
+        self.ban_expiry(net)?
+            .duration_since(self.config.clock.system_now())
+            .ok()
+    }
+
+    /// The number of previous bans that still count against `ip`, i.e. one
+    /// less than its next ban would be.
+    pub fn recidivism(&mut self, ip: &IpAddr) -> u32 {
+        let net = MaskedIpAddr::new(
+            *ip,
+            self.config
+                .ban_prefix_for(IpFamily::from_ipv4(ip.is_ipv4())),
+        );
+        self.recidivism_counts
+            .get(&net)
+            .map_or(0, |recidivism| self.config.previous_bans(recidivism))
+    }
+
+    /// The latest expiry of the active bans that cover `net`.
+    fn ban_expiry(&mut self, net: MaskedIpAddr) -> Option<SystemTime> {
+        let family = net.family();
+        let subnet = self
+            .config
+            .subnet_prefix_for(family)
+            .map(|subnet_prefix| MaskedIpAddr::new(net.addr(), subnet_prefix));
+        let asn_prefixes = match self.asn_database {
+            Some(ref asn_database) => match asn_database.lookup(net.addr()) {
+                // Scans the database, but only for banned autonomous systems.
+                Some(asn) if self.asn_decisions.get(&asn).is_some_and(|banned| *banned) => {
+                    asn_database.prefixes(asn)
+                }
+                _ => Vec::new(),
+            },
+            None => Vec::new(),
+        };
+        let ipset_cache = self.ipset_cache.by_family_mut(family);
+        let now = self.config.clock.system_now();
+        iter::once(net)
+            .chain(subnet)
+            .chain(
+                asn_prefixes
+                    .into_iter()
+                    .filter(|prefix| prefix.overlaps(&net)),
+            )
+            .filter_map(|net| ipset_cache.get(&net).copied())
+            .filter(|expires| *expires > now)
+            .max()
+    }
+
+    /// The bans that are still cached, soonest expiry first.
+    fn active_bans(&self) -> Vec<ActiveBan> {
+        let now = self.config.clock.system_now();
+        let reasons: HashMap<MaskedIpAddr, BanReason> = self
+            .recidivism_counts
+            .iter()
+            .filter_map(|(ip, recidivism)| Some((*ip, recidivism.reason?)))
+            .collect();
+        let mut bans: Vec<ActiveBan> = self
+            .ipset_cache
+            .ipv4
+            .iter()
+            .chain(self.ipset_cache.ipv6.iter())
+            .filter_map(|(ip, expires)| {
+                Some(ActiveBan {
+                    ip: *ip,
+                    expires: Timestamp(*expires),
+                    remaining_seconds: expires.duration_since(now).ok()?.as_secs(),
+                    reason: reasons.get(ip).copied(),
+                })
+            })
+            .collect();
+        bans.sort_by_key(|ban| ban.expires.0);
+        bans
+    }
+
+    /// Rate limiter budgets, recidivism and cached bans, only for keys that
+    /// overlap `filter` if given.
+    fn dump_state(&self, filter: Option<MaskedIpAddr>) -> StateDump {
+        let matches = |ip: &MaskedIpAddr| filter.is_none_or(|filter| filter.overlaps(ip));
+
+        let mut named_rate_limiters = vec![
+            ("ip", &self.ip_rate_limiters),
+            ("watch", &self.watch_rate_limiters),
+            ("subnet", &self.subnet_rate_limiters),
+        ];
+        if let Some(ref attack_rate_limiters) = self.attack_rate_limiters {
+            named_rate_limiters.push(("attack", attack_rate_limiters));
+        }
+        let mut country_rate_limiters: Vec<_> = self.country_rate_limiters.iter().collect();
+        country_rate_limiters.sort_by_key(|(country, _)| country.to_string());
+        let mut port_rate_limiters: Vec<_> = self.port_rate_limiters.iter().collect();
+        port_rate_limiters.sort_by_key(|(port, _)| **port);
+        let mut tenant_rate_limiters: Vec<_> = self.tenant_rate_limiters.iter().collect();
+        tenant_rate_limiters.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+
+        let mut rate_limiters = Vec::new();
+        for (name, limiters) in named_rate_limiters
+            .into_iter()
+            .map(|(name, limiters)| (name.to_owned(), limiters))
+            .chain(
+                country_rate_limiters
+                    .into_iter()
+                    .map(|(country, limiters)| (format!("country {country}"), limiters)),
+            )
+            .chain(
+                port_rate_limiters
+                    .into_iter()
+                    .map(|(port, limiters)| (format!("port {port}"), limiters)),
+            )
+            .chain(
+                tenant_rate_limiters
+                    .into_iter()
+                    .map(|(tenant, limiters)| (format!("tenant {tenant}"), limiters)),
+            )
+        {
+            for family in [IpFamily::V4, IpFamily::V6] {
+                let Some(rate_limiter) = limiters.by_family(family) else {
+                    continue;
+                };
+                let mut budgets: Vec<_> = rate_limiter
+                    .remaining()
+                    .into_iter()
+                    .filter(|(ip, _)| matches(ip))
+                    .collect();
+                budgets.sort_by_key(|(ip, remaining)| (*remaining, *ip));
+                rate_limiters.push(RateLimiterDump {
+                    name: format!("{name} {family}"),
+                    budgets: budgets
+                        .into_iter()
+                        .map(|(ip, remaining)| Budget {
+                            key: ip.to_string(),
+                            remaining,
+                        })
+                        .collect(),
+                });
+            }
+        }
+        if let Some(ref asn_rate_limiter) = self.asn_rate_limiter {
+            // Networks can not be matched against AS numbers without a
+            // lookup, so they are only included without a filter.
+            let mut budgets = if filter.is_none() {
+                asn_rate_limiter.remaining()
+            } else {
+                Vec::new()
+            };
+            budgets.sort_unstable_by_key(|(asn, remaining)| (*remaining, *asn));
+            rate_limiters.push(RateLimiterDump {
+                name: "asn".to_owned(),
+                budgets: budgets
+                    .into_iter()
+                    .map(|(asn, remaining)| Budget {
+                        key: format!("AS{asn}"),
+                        remaining,
+                    })
+                    .collect(),
+            });
+        }
+
+        let mut recidivism: Vec<_> = self
+            .recidivism_counts
+            .iter()
+            .filter(|(ip, _)| matches(ip))
+            .map(|(ip, recidivism)| RecidivismDump {
+                ip: *ip,
+                count: recidivism.count,
+                last_ban: Timestamp(recidivism.last_ban),
+                reason: recidivism.reason,
+            })
+            .collect();
+        recidivism.sort_by_key(|recidivism| recidivism.ip);
+
+        let mut bans = self.active_bans();
+        bans.retain(|ban| matches(&ban.ip));
+
+        StateDump {
+            rate_limiters,
+            recidivism,
+            bans,
+        }
+    }
+
+    /// Handles a line of input: an IP address, optionally with a
+    /// destination port like `192.0.2.1:22`, prefixed with a [`Tenant`] like
+    /// `shop@192.0.2.1` and followed by a space and a [`BanReason`], or an
+    /// IP address prefixed with `+` for a good event. With --passthrough,
+    /// the line is echoed unless it led to a ban.
+    pub fn handle_line(&mut self, line: &[u8]) -> Decision {
+        let decision = self.handle(|leroy| leroy.check_line(line));
+        if !decision.is_banned() {
+            self.output.pass_through(line);
+            self.output.flush();
+        }
+        decision
+    }
+
+    /// Handles lines like [`Leroy::handle_line`], but does the periodic
+    /// work, like reporting, exporting metrics and saving state, only once
+    /// for all of them. Meant for input that arrives in batches anyway, like
+    /// datagrams or messages from a queue. Returns the number of new bans.
+    pub fn handle_lines<'a>(&mut self, lines: impl IntoIterator<Item = &'a [u8]>) -> usize {
+        let mut bans = 0;
+        for line in lines {
+            if self.count_line() && self.check_line(line).is_banned() {
+                bans += 1;
+            } else {
+                self.output.pass_through(line);
+            }
+        }
+        self.output.flush();
+        self.health.record_line();
+        self.periodic_work();
+        bans
+    }
+
+    /// Handles newline terminated lines, straight from a read buffer, like
+    /// [`Leroy::handle_lines`]. A final line without newline is handled as
+    /// well, so the buffer should end at a line boundary. Returns the number
+    /// of new bans.
+    pub fn handle_bytes(&mut self, bytes: &[u8]) -> usize {
+        let mut rest = bytes;
+        self.handle_lines(iter::from_fn(|| {
+            if rest.is_empty() {
+                return None;
+            }
+            let end = memchr::memchr(b'\n', rest).unwrap_or(rest.len());
+            let line = &rest[..end];
+            rest = rest.get(end + 1..).unwrap_or_default();
+            Some(line)
+        }))
+    }
+
+    /// Handles an event of an already parsed address, like a line of input
+    /// without the parsing.
+    pub fn handle_ip(&mut self, ip: IpAddr) -> Decision {
+        self.handle(|leroy| leroy.check_ip(ip, None, None, None))
+    }
+
+    pub(crate) fn handle_bad_ip(
+        &mut self,
+        ip: IpAddr,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        self.handle(|leroy| leroy.check_ip(ip, tenant, port, reason))
+    }
+
+    pub(crate) fn handle_bad_host(
+        &mut self,
+        line: &[u8],
+        name: Hostname,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        self.handle(|leroy| leroy.check_host(line, name, tenant, port, reason))
+    }
+
+    pub(crate) fn handle_good_ip(&mut self, ip: IpAddr) -> Decision {
+        self.handle(|leroy| {
+            leroy.credit(ip);
+            Decision::Ignored
+        })
+    }
+
+    pub(crate) fn handle_parse_error(&mut self, line: &[u8], err: LineError) -> Decision {
+        self.handle(|leroy| leroy.record_parse_error(line, err))
+    }
+
+    /// Counts a line, handles it unless paused, and does periodic work.
+    fn handle(&mut self, f: impl FnOnce(&mut Leroy) -> Decision) -> Decision {
+        self.health.record_line();
+        if !self.count_line() {
+            return Decision::Ignored;
+        }
+        let decision = f(self);
+        self.finish_line();
+        self.output.flush();
+        decision
+    }
+
+    /// Counts a line. Returns `false` if it is to be ignored, because input
+    /// is paused.
+    fn count_line(&mut self) -> bool {
+        self.line_count += 1;
+        self.metrics.lines += 1;
+        if self.paused {
+            return false;
+        }
+        self.attack_detector.record_line();
+        true
+    }
+
+    fn check_line(&mut self, line: &[u8]) -> Decision {
+        match parse_line(line) {
+            Ok(InputLine::Bad(ip, tenant, port, reason)) => self.check_ip(ip, tenant, port, reason),
+            Ok(InputLine::BadHost(name, tenant, port, reason)) => {
+                self.check_host(line, name, tenant, port, reason)
+            }
+            Ok(InputLine::Good(ip)) => {
+                self.credit(ip);
+                Decision::Ignored
+            }
+            Err(err) => self.record_parse_error(line, err),
+        }
+    }
+
+    fn record_parse_error(&mut self, line: &[u8], err: LineError) -> Decision {
+        self.metrics.parse_errors += 1;
+        self.parse_errors.record(line, err, &mut self.error_log);
+        Decision::ParseError
+    }
+
+    fn check_ip(
+        &mut self,
+        ip: IpAddr,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        if self.allowlist.contains(ip) {
+            debug!("{ip} is allowlisted");
+            return Decision::Ignored;
+        }
+        if !self.config.ban_private_ranges && is_private(ip) {
+            debug!("{ip} is in a private range");
+            return Decision::Ignored;
+        }
+        let country = self.country(ip);
+        match country {
+            Some(country) if self.config.geoip_allow_countries.contains(&country) => {
+                debug!("{ip} is in allowed country {country}");
+                Decision::Ignored
+            }
+            _ => self.rate_limit_ip(ip, country, tenant, port, reason),
+        }
+    }
+
+    fn finish_line(&mut self) {
+        if self.line_count.is_multiple_of(10) {
+            self.periodic_work();
+        }
+    }
+
+    fn expire_approvals(&mut self) {
+        if self.approvals.is_empty() {
+            return;
+        }
+        for pending in self.approvals.expire() {
+            warn!(
+                "Dropped {} ban #{} of {}, because it was not approved within --approval-timeout",
+                pending.category,
+                pending.id,
+                pending.target()
+            );
+        }
+    }
+
+    /// Reports, exports, saves and prunes whatever is due.
+    fn periodic_work(&mut self) {
+        if let Some(jump) = self.clock_jumps.check() {
+            self.resync_rate_limiters(jump);
+        }
+        self.process_netlink_reports();
+        self.report_expired_bans();
+        self.expire_approvals();
+        self.attack_detector.maybe_update();
+        self.maybe_report_bans();
+        if let Some(ref mut sketch) = self.sketch {
+            sketch.maybe_decay();
+        }
+        if self.statsd.as_ref().is_some_and(|statsd| statsd.is_due())
+            || self.otlp.as_ref().is_some_and(|otlp| otlp.is_due())
+        {
+            self.export_metrics();
+        }
+
+        if self.config.clock.elapsed(self.line_count_start) > self.config.reporting_ip_time_period {
+            info!(
+                "Seen {} lines since {:?}",
+                self.line_count,
+                self.config.clock.elapsed(self.line_count_start)
+            );
+            if self.parse_errors.suppressed() > 0 {
+                error!(
+                    "Failed to parse {} lines in the past {:?} ({}), only logged the first {}",
+                    self.parse_errors.total(),
+                    self.config.clock.elapsed(self.line_count_start),
+                    self.parse_errors,
+                    self.config.parse_error_examples
+                );
+            }
+            self.parse_errors.reset();
+            let suppressed = self.error_log.take_suppressed();
+            if suppressed > 0 {
+                error!(
+                    "Suppressed {suppressed} error messages in the past {:?}",
+                    self.config.clock.elapsed(self.line_count_start)
+                );
+            }
+            self.line_count = 0;
+            self.line_count_start = self.config.clock.now();
+        }
+
+        if self.config.state_file.is_some()
+            && self.config.clock.elapsed(self.state_save_start) > self.config.state_save_period
+        {
+            if let Err(err) = self.save_state() {
+                let err = LeroyError::Io(io::Error::new(
+                    err.kind(),
+                    format!("Failed to save state: {err}"),
+                ));
+                error!("{err}");
+                self.hooks.error(&err);
+            }
+        }
+
+        if self
+            .config
+            .verify_bans_interval
+            .is_some_and(|interval| self.config.clock.elapsed(self.ban_verify_start) > interval)
+        {
+            self.verify_bans();
+            self.ban_verify_start = self.config.clock.now();
+        }
+
+        if self
+            .config
+            .reconcile_interval
+            .is_some_and(|interval| self.config.clock.elapsed(self.reconcile_start) > interval)
+        {
+            self.reconcile();
+            self.reconcile_start = self.config.clock.now();
+        }
+
+        if self.config.recidivism_decay
+            && self.config.clock.elapsed(self.recidivism_prune_start) > self.config.ipset_ban_ttl
+        {
+            let config = &self.config;
+            self.recidivism_counts
+                .invalidate_entries_if(|_, recidivism| config.previous_bans(recidivism) == 0);
+            self.recidivism_prune_start = self.config.clock.now();
+        }
+    }
+
+    /// The `reason` of the event that exceeds the rate limit becomes the
+    /// reason of the ban, and of the subnet and ASN bans it leads to. The
+    /// `tenant` and destination `port` only pick the rate limit, ban time
+    /// and ipsets of the address itself.
+    fn rate_limit_ip(
+        &mut self,
+        ip: IpAddr,
+        country: Option<CountryCode>,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        let family = IpFamily::from_ipv4(ip.is_ipv4());
+        let net = MaskedIpAddr::new(ip, self.config.ban_prefix_for(family));
+        if self.config.banned_events_threshold.is_some() {
+            self.record_banned_event(net, tenant, port);
+        }
+        if self
+            .sketch
+            .as_mut()
+            .is_some_and(|sketch| sketch.increment(&net) <= self.config.sketch_promote_threshold)
+        {
+            return Decision::UnderLimit;
+        }
+        if self
+            .watch_rate_limiters
+            .by_family_mut(family)
+            .as_mut()
+            .is_some_and(|l| l.check_key(&net).is_err())
+        {
+            self.watch(net);
+        }
+        let ip_rate_limiters = match self.attack_rate_limiters {
+            Some(ref mut attack_rate_limiters) if self.attack_detector.under_attack() => {
+                attack_rate_limiters
+            }
+            _ => tenant
+                .and_then(|tenant| self.tenant_rate_limiters.get_mut(&tenant))
+                .or_else(|| port.and_then(|port| self.port_rate_limiters.get_mut(&port)))
+                .or_else(|| {
+                    country.and_then(|country| self.country_rate_limiters.get_mut(&country))
+                })
+                .unwrap_or(&mut self.ip_rate_limiters),
+        };
+        if ip_rate_limiters
+            .by_family_mut(family)
+            .as_mut()
+            .is_some_and(|l| l.check_key(&net).is_ok())
+        {
+            return Decision::UnderLimit;
+        }
+        let decision = self.ban_in_sets(net, tenant, port, BanCategory::RateLimit, None, reason);
+        if decision.is_banned() {
+            self.maybe_ban_subnet(net, reason);
+            self.maybe_ban_asn(net, reason);
+        }
+        decision
+    }
+
+    fn credit(&mut self, ip: IpAddr) {
+        self.metrics.good_events += 1;
+
+        let family = IpFamily::from_ipv4(ip.is_ipv4());
+        let net = MaskedIpAddr::new(ip, self.config.ban_prefix_for(family));
+
+        if self.config.good_credit > 0 {
+            self.credit_rate_limiters(net, self.config.good_credit);
+        }
+
+        if self.config.good_recidivism_credit > 0 {
+            if let Some(recidivism) = self.recidivism_counts.get(&net).copied() {
+                match recidivism
+                    .count
+                    .saturating_sub(self.config.good_recidivism_credit)
+                {
+                    0 => self.recidivism_counts.invalidate(&net),
+                    count => {
+                        // Keeps the countdown of the last ban.
+                        let since = self
+                            .config
+                            .clock
+                            .system_now()
+                            .duration_since(recidivism.last_ban)
+                            .unwrap_or_default();
+                        insert_with_ttl(
+                            &mut self.recidivism_counts,
+                            net,
+                            Recidivism {
+                                count,
+                                ..recidivism
+                            },
+                            self.config.recidivism_ttl(since),
+                        );
+                    }
+                }
+            }
+        }
+
+        debug!("Credited good event of {net}");
+    }
+
+    /// Gives back up to `n` events to `net` in all rate limiters that lead to
+    /// bans or watching.
+    fn credit_rate_limiters(&mut self, net: MaskedIpAddr, n: u32) {
+        let family = net.family();
+        let country = self.country(net.addr());
+        let country_rate_limiters =
+            country.and_then(|country| self.country_rate_limiters.get_mut(&country));
+        for rate_limiters in [
+            Some(&mut self.ip_rate_limiters),
+            self.attack_rate_limiters.as_mut(),
+            country_rate_limiters,
+            Some(&mut self.watch_rate_limiters),
+        ]
+        .into_iter()
+        .flatten()
+        .chain(self.port_rate_limiters.values_mut())
+        .chain(self.tenant_rate_limiters.values_mut())
+        {
+            if let Some(rate_limiter) = rate_limiters.by_family_mut(family) {
+                rate_limiter.credit(&net, n);
+            }
+        }
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<CountryCode> {
+        self.geoip.as_ref().and_then(|geoip| geoip.country(ip))
+    }
+
+    fn previous_bans(&mut self, ip: MaskedIpAddr) -> u32 {
+        // Entries cached before a reload that shortened --ipset-ban-ttl live
+        // longer, so always check the actual time of the last ban.
+        match self.recidivism_counts.get(&ip).copied() {
+            Some(recidivism) => {
+                self.metrics.recidivism_cache_hits += 1;
+                self.config.previous_bans(&recidivism)
+            }
+            None => {
+                self.metrics.recidivism_cache_misses += 1;
+                0
+            }
+        }
+    }
+
+    /// Keeps rate limits in line with the wall clock after a jump of the
+    /// monotonic clock.
+    fn resync_rate_limiters(&mut self, jump: ClockJump) {
+        match jump {
+            ClockJump::Stalled(duration) => warn!(
+                "Monotonic clock stood still for {duration:?} (suspended?), moving rate limits on"
+            ),
+            ClockJump::Ahead(duration) => warn!(
+                "Monotonic clock leapt {duration:?} ahead of the wall clock, moving rate limits back"
+            ),
+        }
+        for rate_limiters in [
+            &mut self.ip_rate_limiters,
+            &mut self.watch_rate_limiters,
+            &mut self.subnet_rate_limiters,
+        ]
+        .into_iter()
+        .chain(&mut self.attack_rate_limiters)
+        .chain(self.country_rate_limiters.values_mut())
+        .chain(self.port_rate_limiters.values_mut())
+        .chain(self.tenant_rate_limiters.values_mut())
+        {
+            for rate_limiter in [&mut rate_limiters.ipv4, &mut rate_limiters.ipv6]
+                .into_iter()
+                .flatten()
+            {
+                resync_rate_limiter(rate_limiter, jump);
+            }
+        }
+        if let Some(ref mut rate_limiter) = self.asn_rate_limiter {
+            resync_rate_limiter(rate_limiter, jump);
+        }
+    }
+
+    fn limiter_gc_stats(&self) -> GcStats {
+        let limiters = [
+            &self.ip_rate_limiters,
+            &self.watch_rate_limiters,
+            &self.subnet_rate_limiters,
+        ]
+        .into_iter()
+        .chain(&self.attack_rate_limiters)
+        .chain(self.country_rate_limiters.values())
+        .chain(self.port_rate_limiters.values())
+        .chain(self.tenant_rate_limiters.values())
+        .flat_map(|rate_limiters| [&rate_limiters.ipv4, &rate_limiters.ipv6])
+        .flatten()
+        .map(|rate_limiter| rate_limiter.gc_stats())
+        .chain(
+            self.asn_rate_limiter
+                .as_ref()
+                .map(|rate_limiter| rate_limiter.gc_stats()),
+        );
+        let mut total = self.retired_gc_stats;
+        for gc_stats in limiters {
+            total.runs += gc_stats.runs;
+            total.removed += gc_stats.removed;
+        }
+        total
+    }
+
+    fn watch(&mut self, net: MaskedIpAddr) {
+        if self.watch_cache.contains_key(&net) || self.is_banned_net(net) {
+            return;
+        }
+
+        let timeout = u32::try_from(self.config.ipset_watch_time.as_secs()).unwrap_or(u32::MAX);
+        let watch_result = match self.watch_sessions {
+            Some(ref mut watch_sessions) if self.config.manages_ipsets() && !self.monitor_only => {
+                let start = Instant::now();
+                let result = watch_sessions.by_family_mut(net.family()).add(net, timeout);
+                if self.netlink_queue.is_none() {
+                    self.metrics.ipset_latency.record(start.elapsed());
+                }
+                result
+            }
+            _ => Ok(true),
+        };
+
+        match watch_result {
+            Ok(_) => {
+                info!("Watching {net} for {timeout}s");
+                self.watch_cache.insert(net, ());
+            }
+            Err(err) if err.is::<QueueFull>() => {
+                debug!("Not watching {net}, because the netlink queue is full");
+                self.metrics.netlink_queue_drops += 1;
+            }
+            Err(err) => {
+                let err = LeroyError::netlink(format!("Unable to add {net} to watch set: {err}"));
+                self.netlink_error(&err);
+                self.health.record_netlink(false);
+            }
+        }
+    }
+
+    fn maybe_ban_subnet(&mut self, net: MaskedIpAddr, reason: Option<BanReason>) {
+        let family = net.family();
+        let Some(subnet_prefix) = self.config.subnet_prefix_for(family) else {
+            return;
+        };
+        let subnet = MaskedIpAddr::new(net.addr(), subnet_prefix);
+        if self
+            .subnet_rate_limiters
+            .by_family_mut(family)
+            .as_mut()
+            .is_none_or(|l| l.check_key(&subnet).is_err())
+        {
+            if self.config.approve_wide_bans {
+                self.queue_for_approval(BanCategory::Subnet, None, vec![subnet], reason);
+            } else {
+                self.ban(subnet, BanCategory::Subnet, None, reason);
+            }
+        }
+    }
+
+    fn maybe_ban_asn(&mut self, net: MaskedIpAddr, reason: Option<BanReason>) {
+        let Some(ref asn_database) = self.asn_database else {
+            return;
+        };
+        let Some(asn) = asn_database.lookup(net.addr()) else {
+            return;
+        };
+        if self.asn_decisions.contains_key(&asn)
+            || self
+                .asn_rate_limiter
+                .as_mut()
+                .is_some_and(|l| l.check_key(&asn).is_ok())
+        {
+            return;
+        }
+
+        let prefixes = asn_database.prefixes(asn);
+        if prefixes.len() > self.config.asn_max_prefixes {
+            self.asn_decisions.insert(asn, false);
+            warn!(
+                "Not banning AS{asn} with {} networks (--asn-max-prefixes is {})",
+                prefixes.len(),
+                self.config.asn_max_prefixes
+            );
+            return;
+        }
+        if self.config.approve_wide_bans {
+            self.queue_for_approval(BanCategory::Asn, Some(asn), prefixes, reason);
+            return;
+        }
+        self.asn_decisions.insert(asn, true);
+        info!("Banning {} networks of AS{asn}", prefixes.len());
+        for prefix in prefixes {
+            self.ban(prefix, BanCategory::Asn, None, reason);
+        }
+    }
+
+    fn queue_for_approval(
+        &mut self,
+        category: BanCategory,
+        asn: Option<u32>,
+        networks: Vec<MaskedIpAddr>,
+        reason: Option<BanReason>,
+    ) {
+        match self.approvals.queue(
+            category,
+            asn,
+            networks,
+            reason,
+            self.config.approval_timeout,
+        ) {
+            Queued::New(pending) => warn!(
+                "Waiting for approval of {category} ban #{} of {}",
+                pending.id,
+                pending.target()
+            ),
+            Queued::Known => {}
+            Queued::Full => debug!("Not queueing {category} ban, because too many are pending"),
+        }
+    }
+
+    /// Applies a wide ban that was waiting for approval.
+    fn approve_ban(&mut self, id: u64) -> AdminReply {
+        let Some(pending) = self.approvals.approve(id) else {
+            return AdminReply::error(format!("no pending ban #{id}"));
+        };
+        if let Some(asn) = pending.asn {
+            self.asn_decisions.insert(asn, true);
+        }
+        let banned = pending
+            .networks
+            .iter()
+            .filter(|net| {
+                self.ban(**net, pending.category, None, pending.reason)
+                    .is_banned()
+            })
+            .count();
+        info!(
+            "Approved {} ban #{id} of {}, banned {banned} networks",
+            pending.category,
+            pending.target()
+        );
+        AdminReply::message(format!(
+            "banned {banned} of {} networks",
+            pending.networks.len()
+        ))
+    }
+
+    fn is_banned_net(&mut self, ip: MaskedIpAddr) -> bool {
+        let family = ip.family();
+        let subnet = self
+            .config
+            .subnet_prefix_for(family)
+            .map(|subnet_prefix| MaskedIpAddr::new(ip.addr(), subnet_prefix));
+        let ipset_cache = self.ipset_cache.by_family_mut(family);
+        let monitor_bans = &mut self.monitor_bans;
+        let now = self.config.clock.system_now();
+        let mut is_active = |net| {
+            ipset_cache
+                .get(&net)
+                .or_else(|| monitor_bans.get(&(Sets::Bans, net)))
+                .is_some_and(|expires| *expires > now)
+        };
+        is_active(ip)
+            || subnet.is_some_and(is_active)
+            || self.adopted_bans.by_family(family).contains(&ip)
+            || self.asn_database.as_ref().is_some_and(|asn_database| {
+                asn_database
+                    .lookup(ip.addr())
+                    .and_then(|asn| self.asn_decisions.get(&asn))
+                    .is_some_and(|banned| *banned)
+            })
+    }
+
+    /// Returns `true` if `ip` was newly added to the ipset. The timeout is
+    /// computed from the recidivism, unless given. The `reason` is recorded
+    /// with the ban wherever it goes.
+    fn ban(
+        &mut self,
+        ip: MaskedIpAddr,
+        category: BanCategory,
+        timeout: Option<u32>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        self.ban_in_sets(ip, None, None, category, timeout, reason)
+    }
+
+    /// Counts an event of `net` if it has been banned for longer than
+    /// [`BANNED_EVENT_GRACE`], in the sets that events of `tenant` on `port`
+    /// go to. Bans that are not in the ipsets, like those of --dry-run, do
+    /// not count.
+    fn record_banned_event(
+        &mut self,
+        net: MaskedIpAddr,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+    ) {
+        if !self.config.manages_ipsets() || self.monitor_only {
+            return;
+        }
+        let now = self.config.clock.system_now();
+        let expires = self.cached_expiry(net, self.config.sets_for(tenant, port));
+        if expires.is_none_or(|expires| expires <= now) {
+            return;
+        }
+        let banned_recently = self.recidivism_counts.get(&net).is_some_and(|recidivism| {
+            now.duration_since(recidivism.last_ban).unwrap_or_default() < BANNED_EVENT_GRACE
+        });
+        if banned_recently {
+            return;
+        }
+        self.banned_events += 1;
+        self.banned_event_ip = Some(net);
+        self.metrics.banned_events += 1;
+    }
+
+    /// With --extend-bans, the remaining time of the ban of `ip` itself in
+    /// `sets`, if it was banned at least --bl-period ago. Bans of covering
+    /// subnets and ASNs are not extended.
+    fn extendable_ban(&mut self, ip: MaskedIpAddr, sets: Sets) -> Option<Duration> {
+        if !self.config.extend_bans {
+            return None;
+        }
+        let now = self.config.clock.system_now();
+        let last_ban = self.recidivism_counts.get(&ip)?.last_ban;
+        if now.duration_since(last_ban).unwrap_or_default() < self.config.bl_period_for(ip.family())
+        {
+            return None;
+        }
+        let expires = self.cached_expiry(ip, sets)?;
+        expires.duration_since(now).ok()
+    }
+
+    /// When the cached ban of `ip` itself in `sets` expires.
+    fn cached_expiry(&mut self, ip: MaskedIpAddr, sets: Sets) -> Option<SystemTime> {
+        if let Some(expires) = self.monitor_bans.get(&monitor_key(ip, sets)) {
+            return Some(*expires);
+        }
+        match sets {
+            Sets::Port(port) => self.port_ipset_cache.get(&(port, ip)).copied(),
+            Sets::Tenant(tenant) => self.tenant_ipset_cache.get(&(tenant, ip)).copied(),
+            Sets::Bans | Sets::Watch => self
+                .ipset_cache
+                .by_family_mut(ip.family())
+                .get(&ip)
+                .copied(),
+        }
+    }
+
+    /// Whether `ip` is banned in the --port-ipset or --tenant-ipset sets of
+    /// `sets`.
+    fn is_banned_in(&mut self, ip: MaskedIpAddr, sets: Sets) -> bool {
+        if !matches!(sets, Sets::Port(_) | Sets::Tenant(_)) {
+            return false;
+        }
+        let now = self.config.clock.system_now();
+        self.cached_expiry(ip, sets)
+            .is_some_and(|expires| expires > now)
+    }
+
+    /// Like [`Leroy::ban`], for events of a `tenant` or on a destination
+    /// `port`, which may have their own ban time and ipsets. Bans in the
+    /// --port-ipset and --tenant-ipset sets do not count towards
+    /// --max-banned and are not shared with the cluster, because they only
+    /// block one service or tenant.
+    fn ban_in_sets(
+        &mut self,
+        ip: MaskedIpAddr,
+        tenant: Option<Tenant>,
+        port: Option<u16>,
+        category: BanCategory,
+        timeout: Option<u32>,
+        reason: Option<BanReason>,
+    ) -> Decision {
+        let family = ip.family();
+        let sets = self.config.sets_for(tenant, port);
+
+        let mut remaining = None;
+        if self.is_banned_net(ip) || self.is_banned_in(ip, sets) {
+            self.metrics.ban_cache_hits += 1;
+            remaining = timeout
+                .is_none()
+                .then(|| self.extendable_ban(ip, sets))
+                .flatten();
+            if remaining.is_none() {
+                debug!("{ip} already banned");
+                return Decision::AlreadyBanned;
+            }
+        } else {
+            self.metrics.ban_cache_misses += 1;
+        }
+
+        if self.allowlist.overlaps(&ip) {
+            info!("Not banning {ip}, because it overlaps the allowlist");
+            return Decision::NotBanned;
+        }
+
+        if category != BanCategory::Manual
+            && self.config.clock.elapsed(self.start) < self.config.warmup
+        {
+            debug!("Not banning {ip} during --warmup");
+            self.warmup_skips += 1;
+            self.metrics.skipped_bans += 1;
+            return Decision::NotBanned;
+        }
+
+        if sets == Sets::Bans && remaining.is_none() && !self.make_room_for_ban(family) {
+            debug!("Not banning {ip}, because --max-banned is reached");
+            self.max_banned_skips += 1;
+            self.metrics.skipped_bans += 1;
+            return Decision::NotBanned;
+        }
+
+        let recidivism = self.previous_bans(ip).saturating_add(1);
+        let timeout = timeout.unwrap_or_else(|| {
+            self.config.seconds_to_ban(
+                family,
+                self.country(ip.addr()),
+                tenant,
+                port,
+                recidivism,
+                self.attack_detector.under_attack(),
+            )
+        });
+        let monitor_only = self.monitor_only && category != BanCategory::Manual;
+        let comment = self.config.ipset_comments.then(|| match reason {
+            Some(reason) => format!("{category}:{reason}"),
+            None => category.to_string(),
+        });
+        if let Some(remaining) = remaining {
+            let timeout = self.config.extend(remaining, timeout);
+            return self.extend_ban(ip, sets, remaining, timeout, comment, monitor_only);
+        }
+
+        let ban_result = if !self.config.manages_ipsets() || monitor_only {
+            Ok(true)
+        } else {
+            self.add_to_ipset(ip, sets, timeout, comment)
+        };
+
+        if let Err(ref err) = ban_result {
+            if err.is::<QueueFull>() {
+                debug!("Not banning {ip}, because the netlink queue is full");
+                self.metrics.netlink_queue_drops += 1;
+                self.metrics.skipped_bans += 1;
+                return Decision::NotBanned;
+            }
+        }
+        if self.netlink_queue.is_none() {
+            self.health.record_netlink(ban_result.is_ok());
+        }
+        match ban_result {
+            Ok(false) => {
+                debug!("{ip} already banned, but was no longer cached");
+                Decision::AlreadyBanned
+            }
+            Ok(true) => {
+                let because = reason.map_or(String::new(), |reason| format!(", reason: {reason}"));
+                let of_tenant =
+                    tenant.map_or(String::new(), |tenant| format!(", tenant: {tenant}"));
+                let on_port = port.map_or(String::new(), |port| format!(", port: {port}"));
+                if monitor_only {
+                    info!(
+                        "Would ban {ip} for {timeout}s (recidivism: {recidivism}{of_tenant}{on_port}{because})"
+                    );
+                } else if self.config.forward_bans {
+                    info!(
+                        "Forwarding ban of {ip} for {timeout}s (recidivism: {recidivism}{of_tenant}{on_port}{because})"
+                    );
+                } else {
+                    info!(
+                        "Banned {ip} for {timeout}s (recidivism: {recidivism}{of_tenant}{on_port}{because})"
+                    );
+                }
+                self.ban_counts.record(ip, category, recidivism);
+                if let Some(reason) = reason {
+                    self.metrics.bans_by_reason.record(reason);
+                }
+                *self.metrics.bans.by_family_mut(family) += 1;
+                self.health.record_ban();
+                self.attack_detector.record_ban();
+                self.cache_ban(ip, sets, timeout, monitor_only);
+                if insert_counting_eviction(
+                    &mut self.recidivism_counts,
+                    ip,
+                    Recidivism {
+                        count: recidivism,
+                        last_ban: self.config.clock.system_now(),
+                        reason,
+                    },
+                    // Every ban restarts the countdown, with the
+                    // --ipset-ban-ttl of the current configuration.
+                    self.config.recidivism_ttl(Duration::ZERO),
+                ) {
+                    self.metrics.recidivism_cache_evictions += 1;
+                }
+                if category != BanCategory::Peer
+                    && sets == Sets::Bans
+                    && !self.config.dry_run
+                    && !monitor_only
+                {
+                    if let Some(ref cluster) = self.cluster {
+                        cluster.broadcast(ip, timeout, category, reason);
+                    }
+                }
+                // A report cannot be taken back, so only bans that happened
+                // are reported.
+                if !self.config.dry_run && !monitor_only {
+                    if let Some(ref abuse_reporter) = self.abuse_reporter {
+                        abuse_reporter.report(AbuseReport {
+                            ip,
+                            category,
+                            recidivism,
+                            timeout,
+                        });
+                    }
+                }
+                if let Some(ref mut event_log) = self.event_log {
+                    event_log.log(Event::Ban {
+                        ip,
+                        family,
+                        timeout,
+                        recidivism,
+                        category,
+                        reason,
+                        timestamp: self.config.clock.system_now(),
+                        hostname: None,
+                    });
+                }
+                let event = BanEvent {
+                    ip,
+                    category,
+                    reason,
+                    timeout,
+                    recidivism,
+                };
+                if self.config.dry_run {
+                    self.capture_dry_run_ban(event);
+                }
+                self.output.ban(&event);
+                self.hooks.ban(event);
+                Decision::Banned {
+                    timeout,
+                    recidivism,
+                }
+            }
+            Err(err) => {
+                let err = LeroyError::netlink(format!("Unable to add {ip} to set: {err}"));
+                self.netlink_error(&err);
+                self.metrics.skipped_bans += 1;
+                Decision::NotBanned
+            }
+        }
+    }
+
+    /// Replaces the ban of `ip` in `sets`, which has `remaining` time left,
+    /// with one of `timeout` seconds, see --extend-bans. An extension is not
+    /// a new ban, so it is only counted in the `extended_bans` metric, and
+    /// not shared, reported or logged to --event-log like one.
+    fn extend_ban(
+        &mut self,
+        ip: MaskedIpAddr,
+        sets: Sets,
+        remaining: Duration,
+        timeout: u32,
+        comment: Option<String>,
+        monitor_only: bool,
+    ) -> Decision {
+        if self.config.manages_ipsets() && !monitor_only {
+            if let Err(err) = self.replace_in_ipset(ip, sets, remaining, timeout, comment) {
+                if err.is::<QueueFull>() {
+                    debug!("Not extending ban of {ip}, because the netlink queue is full");
+                    self.metrics.netlink_queue_drops += 1;
+                } else {
+                    let err = LeroyError::netlink(format!("Unable to extend ban of {ip}: {err}"));
+                    self.netlink_error(&err);
+                }
+                return Decision::AlreadyBanned;
+            }
+        }
+        let verb = if monitor_only {
+            "Would extend"
+        } else {
+            "Extended"
+        };
+        info!("{verb} ban of {ip} to {timeout}s");
+        self.metrics.extended_bans += 1;
+        self.cache_ban(ip, sets, timeout, monitor_only);
+        // Restarts the --bl-period until the next extension, without
+        // counting as another offense.
+        if let Some(recidivism) = self.recidivism_counts.get(&ip).copied() {
+            if insert_counting_eviction(
+                &mut self.recidivism_counts,
+                ip,
+                Recidivism {
+                    last_ban: self.config.clock.system_now(),
+                    ..recidivism
+                },
+                self.config.recidivism_ttl(Duration::ZERO),
+            ) {
+                self.metrics.recidivism_cache_evictions += 1;
+            }
+        }
+        Decision::Extended { timeout }
+    }
+
+    /// Caches a ban of `ip` in `sets` for `timeout` seconds, as long as the
+    /// kernel keeps the entry, so that long bans of recidivists are not sent
+    /// again while active. Would-be bans of monitor-only mode go to
+    /// `monitor_bans` instead.
+    fn cache_ban(&mut self, ip: MaskedIpAddr, sets: Sets, timeout: u32, monitor_only: bool) {
+        let family = ip.family();
+        let ban_time = Duration::from_secs(u64::from(timeout));
+        let expires = self.config.clock.system_now() + ban_time;
+        let ttl = ban_time.saturating_sub(Duration::from_secs(1));
+        let evicted = match sets {
+            _ if monitor_only => insert_counting_eviction(
+                &mut self.monitor_bans,
+                monitor_key(ip, sets),
+                expires,
+                ttl,
+            ),
+            Sets::Port(port) => {
+                insert_counting_eviction(&mut self.port_ipset_cache, (port, ip), expires, ttl)
+            }
+            Sets::Tenant(tenant) => {
+                insert_counting_eviction(&mut self.tenant_ipset_cache, (tenant, ip), expires, ttl)
+            }
+            Sets::Bans | Sets::Watch => {
+                insert_counting_eviction(self.ipset_cache.by_family_mut(family), ip, expires, ttl)
+            }
+        };
+        if evicted {
+            self.metrics.ban_cache_evictions += 1;
+        }
+        if self.config.tracks_live_bans() && sets == Sets::Bans && !monitor_only {
+            self.live_bans.by_family_mut(family).insert(ip, expires);
+        }
+    }
+
+    fn capture_dry_run_ban(&mut self, event: BanEvent) {
+        if self.config.dry_run_json {
+            self.output.dry_run_ban(&event);
+        }
+        if self.config.dry_run_capture == 0 {
+            return;
+        }
+        if self.dry_run_bans.len() >= self.config.dry_run_capture {
+            self.dry_run_bans.pop_front();
+        }
+        self.dry_run_bans.push_back(event);
+    }
+
+    /// The latest would-be bans of --dry-run, oldest first, up to
+    /// --dry-run-capture of them.
+    pub fn dry_run_bans(&self) -> Vec<BanEvent> {
+        self.dry_run_bans.iter().copied().collect()
+    }
+
+    /// Like [`Leroy::dry_run_bans`], but also clears them, so that the next
+    /// call only returns newer ones.
+    pub fn take_dry_run_bans(&mut self) -> Vec<BanEvent> {
+        self.dry_run_bans.drain(..).collect()
+    }
+
+    /// Records the outcomes of the adds made by the --netlink-queue. Failed
+    /// bans are forgotten, so that they are made again at the next event
+    /// over the limit.
+    fn process_netlink_reports(&mut self) {
+        let Some(ref netlink_queue) = self.netlink_queue else {
+            return;
+        };
+        let reports: Vec<Report> = netlink_queue.reports().collect();
+        for report in reports {
+            self.metrics.ipset_latency.record(report.latency);
+            self.metrics.netlink_retries += u64::from(report.retries);
+            self.health.record_netlink(report.result.is_ok());
+            let net = report.net;
+            match report.result {
+                Ok(true) => {}
+                Ok(false) => debug!("{net} was already in the set"),
+                Err(err) => {
+                    let err = match report.sets {
+                        Sets::Watch => {
+                            self.watch_cache.invalidate(&net);
+                            LeroyError::netlink(format!("Unable to add {net} to watch set: {err}"))
+                        }
+                        Sets::Port(port) => {
+                            self.port_ipset_cache.invalidate(&(port, net));
+                            LeroyError::netlink(format!(
+                                "Unable to add {net} to set of port {port}: {err}"
+                            ))
+                        }
+                        Sets::Tenant(tenant) => {
+                            self.tenant_ipset_cache.invalidate(&(tenant, net));
+                            LeroyError::netlink(format!(
+                                "Unable to add {net} to set of tenant {tenant}: {err}"
+                            ))
+                        }
+                        Sets::Bans => {
+                            self.ipset_cache
+                                .by_family_mut(net.family())
+                                .invalidate(&net);
+                            self.live_bans.by_family_mut(net.family()).remove(net);
+                            LeroyError::netlink(format!("Unable to add {net} to set: {err}"))
+                        }
+                    };
+                    self.netlink_error(&err);
+                }
+            }
+        }
+    }
+
+    /// Logs and counts a failed ipset operation. Missing permissions are
+    /// fatal, see [`Leroy::take_fatal_error`].
+    fn netlink_error(&mut self, err: &LeroyError) {
+        self.error_log.error(err);
+        self.hooks.error(err);
+        self.metrics.netlink_errors += 1;
+        record_fatal_error(&mut self.fatal_error, err);
+    }
+
+    /// The first error after which bans can not be made anymore, like
+    /// missing permission to change the ipsets. Transient failures are
+    /// retried, logged and counted instead. The daemon exits on it, and an
+    /// embedder should stop too.
+    pub fn take_fatal_error(&mut self) -> Option<LeroyError> {
+        self.fatal_error.take()
+    }
+
+    fn export_metrics(&mut self) {
+        let gc_stats = self.limiter_gc_stats();
+        self.metrics.limiter_gc_runs = gc_stats.runs;
+        self.metrics.limiter_gc_removed = gc_stats.removed;
+        let gauges = [
+            (
+                "attack_mode",
+                u64::from(self.attack_detector.under_attack()),
+            ),
+            (
+                "active_bans",
+                self.ipset_cache.ipv4.entry_count() + self.ipset_cache.ipv6.entry_count(),
+            ),
+            (
+                "netlink_queue_depth",
+                self.netlink_queue
+                    .as_ref()
+                    .map_or(0, |queue| queue.depth() as u64),
+            ),
+        ];
+        if let Some(ref mut statsd) = self.statsd {
+            if statsd.is_due() {
+                statsd.flush(&self.metrics, &gauges);
+            }
+        }
+        if let Some(ref mut otlp) = self.otlp {
+            if otlp.is_due() {
+                otlp.export(&self.metrics, &gauges);
+            }
+        }
+    }
+
+    /// Saves the state and logs a final summary. Background threads that
+    /// write the event log finish when it is dropped.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        let result = self.save_state();
+        match result {
+            Ok(()) if self.config.state_file.is_some() => info!("Saved state"),
+            Ok(()) => {}
+            Err(ref err) => error!("Failed to save state: {err}"),
+        }
+        info!(
+            "Shutting down after {}",
+            humantime::format_duration(Duration::from_secs(
+                self.config.clock.elapsed(self.start).as_secs()
+            ))
+        );
+        self.log_stats();
+        result
+    }
+
+    /// Logs a snapshot of the internal state, for example on SIGUSR1.
+    pub fn log_stats(&self) {
+        for line in self.stats() {
+            info!("Stats: {line}");
+        }
+    }
+
+    /// A snapshot of the internal state, one line per topic.
+    fn stats(&self) -> Vec<String> {
+        let metrics = &self.metrics;
+        let mut stats = Vec::new();
+        stats.push(format!(
+            "{} lines, {} parse errors, {} good events since startup",
+            metrics.lines, metrics.parse_errors, metrics.good_events
+        ));
+        stats.push(format!(
+            "{} v4 and {} v6 bans, {} skipped bans, {} netlink errors, {} netlink retries since startup",
+            metrics.bans.ipv4,
+            metrics.bans.ipv6,
+            metrics.skipped_bans,
+            metrics.netlink_errors,
+            metrics.netlink_retries
+        ));
+        stats.push(format!(
+            "banned {} in the past {:?}: {}",
+            self.ban_counts.total(),
+            self.config.clock.elapsed(self.ban_count_start),
+            self.ban_counts
+        ));
+        let limiter_len = |rate_limiters: &RateLimiters| {
+            rate_limiters.ipv4.as_ref().map_or(0, |l| l.len())
+                + rate_limiters.ipv6.as_ref().map_or(0, |l| l.len())
+        };
+        stats.push(format!(
+            "rate limiters track {} ips, {} ips in attack mode, {} ips in countries, {} ips on ports, {} ips of tenants, {} watched ips, {} subnets, {} asns",
+            limiter_len(&self.ip_rate_limiters),
+            self.attack_rate_limiters.as_ref().map_or(0, limiter_len),
+            self.country_rate_limiters.values().map(limiter_len).sum::<usize>(),
+            self.port_rate_limiters.values().map(limiter_len).sum::<usize>(),
+            self.tenant_rate_limiters.values().map(limiter_len).sum::<usize>(),
+            limiter_len(&self.watch_rate_limiters),
+            limiter_len(&self.subnet_rate_limiters),
+            self.asn_rate_limiter.as_ref().map_or(0, |l| l.len())
+        ));
+        stats.push(format!(
+            "ipset add latency since startup: {}",
+            metrics.ipset_latency
+        ));
+        if let Some(ref netlink_queue) = self.netlink_queue {
+            stats.push(format!(
+                "netlink queue has {} pending changes, dropped {} bans since startup",
+                netlink_queue.depth(),
+                metrics.netlink_queue_drops
+            ));
+        }
+        let gc_stats = self.limiter_gc_stats();
+        stats.push(format!(
+            "rate limiters were garbage collected {} times, removing {} entries",
+            gc_stats.runs, gc_stats.removed
+        ));
+        stats.push(format!(
+            "ban cache has {} v4 and {} v6 entries, {:.1}% hit rate over {} lookups, {} evictions",
+            self.ipset_cache.ipv4.entry_count(),
+            self.ipset_cache.ipv6.entry_count(),
+            hit_rate(metrics.ban_cache_hits, metrics.ban_cache_misses),
+            metrics.ban_cache_hits + metrics.ban_cache_misses,
+            metrics.ban_cache_evictions
+        ));
+        stats.push(format!(
+            "recidivism cache has {} entries, {:.1}% hit rate over {} lookups, {} evictions",
+            self.recidivism_counts.entry_count(),
+            hit_rate(
+                metrics.recidivism_cache_hits,
+                metrics.recidivism_cache_misses
+            ),
+            metrics.recidivism_cache_hits + metrics.recidivism_cache_misses,
+            metrics.recidivism_cache_evictions
+        ));
+        stats.push(format!(
+            "watch cache has {} entries",
+            self.watch_cache.entry_count()
+        ));
+        stats.push(format!(
+            "attack mode is {}",
+            if self.attack_detector.under_attack() {
+                "on"
+            } else {
+                "off"
+            }
+        ));
+        stats
+    }
+
+    fn maybe_report_bans(&mut self) {
+        if self.config.clock.elapsed(self.ban_count_start) > self.config.reporting_ban_time_period {
+            info!(
+                "Banned {} in the past {:?}: {}",
+                self.ban_counts.total(),
+                self.config.clock.elapsed(self.ban_count_start),
+                self.ban_counts
+            );
+            self.maybe_notify_ban_spike();
+            if self.max_banned_skips > 0 {
+                warn!(
+                    "Skipped {} bans in the past {:?}, because --max-banned was reached",
+                    self.max_banned_skips,
+                    self.config.clock.elapsed(self.ban_count_start)
+                );
+            }
+            if self.warmup_skips > 0 {
+                info!(
+                    "Skipped {} bans in the past {:?}, because of --warmup",
+                    self.warmup_skips,
+                    self.config.clock.elapsed(self.ban_count_start)
+                );
+            }
+            let dropped_events = self
+                .event_log
+                .as_mut()
+                .map_or(0, |event_log| event_log.take_dropped());
+            if dropped_events > 0 {
+                warn!("Dropped {dropped_events} events, because --event-log could not keep up");
+            }
+            self.maybe_warn_banned_events();
+            self.ban_counts = BanCounts::default();
+            self.max_banned_skips = 0;
+            self.banned_events = 0;
+            self.warmup_skips = 0;
+            self.ban_count_start = self.config.clock.now();
+        }
+    }
+
+    fn maybe_warn_banned_events(&self) {
+        let (Some(threshold), Some(ip)) =
+            (self.config.banned_events_threshold, self.banned_event_ip)
+        else {
+            return;
+        };
+        if self.banned_events <= threshold {
+            return;
+        }
+        let ip = match self.anonymizer {
+            Some(ref anonymizer) => anonymizer.anonymize(ip).to_string(),
+            None => ip.to_string(),
+        };
+        let message = format!(
+            "Got {} events of addresses banned for over {:?} in the past {:?}, like {ip}. Check that the firewall drops the traffic of the ipsets, on all interfaces and for IPv6.",
+            self.banned_events,
+            BANNED_EVENT_GRACE,
+            self.config.clock.elapsed(self.ban_count_start)
+        );
+        warn!("{message}");
+        if let Some(ref webhook) = self.webhook {
+            webhook.notify(format!("leroyjenkins: {message}"));
+        }
+    }
+
+    fn maybe_notify_ban_spike(&self) {
+        let (Some(webhook), Some(threshold)) = (&self.webhook, self.config.webhook_ban_threshold)
+        else {
+            return;
+        };
+        if self.ban_counts.total() <= threshold {
+            return;
+        }
+        let top_offenders = self
+            .ban_counts
+            .top_offenders
+            .iter()
+            .map(|(recidivism, ip)| match self.anonymizer {
+                Some(ref anonymizer) => format!("{} ({recidivism}x)", anonymizer.anonymize(*ip)),
+                None => format!("{ip} ({recidivism}x)"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        webhook.notify(format!(
+            "leroyjenkins banned {} in the past {:?}: {}\n\
+             Top offenders: {top_offenders}\n\
+             Policy: --bl-threshold {} --bl-period {:?} --ipset-base-time {:?}, attack mode {}",
+            self.ban_counts.total(),
+            self.config.clock.elapsed(self.ban_count_start),
+            self.ban_counts,
+            self.config.bl_threshold,
+            self.config.bl_rate.unwrap_or(self.config.bl_period),
+            self.config.ipset_base_time,
+            if self.attack_detector.under_attack() {
+                "on"
+            } else {
+                "off"
+            }
+        ));
+    }
+
+    /// Adds to the ipset, through the --netlink-queue if there is one, and
+    /// otherwise with retries.
+    /// The `comment` is only given with --ipset-comments.
+    fn add_to_ipset(
+        &mut self,
+        net: MaskedIpAddr,
+        sets: Sets,
+        timeout: u32,
+        comment: Option<String>,
+    ) -> Result<bool, BackendError> {
+        let queued = self.netlink_queue.is_some();
+        let backend = self.backend_for(net, sets);
+        if queued {
+            // Retried by the writer thread.
+            match comment {
+                Some(comment) => backend.add_with_comment(net, timeout, comment),
+                None => backend.add(net, timeout),
+            }
+        } else {
+            let start = Instant::now();
+            let (result, retries) = add_with_retry(backend, net, timeout, comment.as_deref());
+            self.metrics.ipset_latency.record(start.elapsed());
+            self.metrics.netlink_retries += u64::from(retries);
+            result
+        }
+    }
+
+    /// Sets the timeout of `net` in `sets`, which has `remaining` time left,
+    /// to `timeout`. Adding an entry that is in the set keeps its timeout,
+    /// so it is removed and added again, and restored with its remaining
+    /// time if adding fails.
+    fn replace_in_ipset(
+        &mut self,
+        net: MaskedIpAddr,
+        sets: Sets,
+        remaining: Duration,
+        timeout: u32,
+        comment: Option<String>,
+    ) -> Result<(), BackendError> {
+        self.backend_for(net, sets).del(net)?;
+        let Err(err) = self.add_to_ipset(net, sets, timeout, comment.clone()) else {
+            return Ok(());
+        };
+        let remaining = u32::try_from(remaining.as_secs())
+            .unwrap_or(u32::MAX)
+            .max(1);
+        if let Err(restore_err) = self.add_to_ipset(net, sets, remaining, comment) {
+            // Made again at the next event over the limit.
+            warn!("Unable to restore the ban of {net} after failing to extend it: {restore_err}");
+            self.forget_ban_in(net, sets);
+        }
+        Err(err)
+    }
+
+    /// The set of the family of `net` among `sets`.
+    fn backend_for(&mut self, net: MaskedIpAddr, sets: Sets) -> &mut dyn Backend {
+        let sessions = match sets {
+            Sets::Port(port) => self
+                .port_sessions
+                .iter_mut()
+                .find(|(p, _)| *p == port)
+                .map(|(_, sessions)| sessions),
+            Sets::Tenant(tenant) => self
+                .tenant_sessions
+                .iter_mut()
+                .find(|(t, _)| *t == tenant)
+                .map(|(_, sessions)| sessions),
+            Sets::Bans | Sets::Watch => None,
+        }
+        .unwrap_or(&mut self.sessions);
+        sessions.by_family_mut(net.family()).as_mut()
+    }
+
+    /// Checks a sample of the cached bans of each ipset against the kernel,
+    /// see --verify-bans-interval. Consecutive samples cover all cached bans
+    /// over time.
+    fn verify_bans(&mut self) {
+        // Like bans, repairs wait for `enforce` in monitor-only mode.
+        if !self.config.manages_ipsets() || self.monitor_only {
+            return;
+        }
+        for family in [IpFamily::V4, IpFamily::V6] {
+            self.verify_family_bans(family);
+        }
+    }
+
+    fn verify_family_bans(&mut self, family: IpFamily) {
+        let name = match family {
+            IpFamily::V4 => self.config.ipset_ipv4_name.clone(),
+            IpFamily::V6 => self.config.ipset_ipv6_name.clone(),
+        };
+        let now = self.config.clock.system_now();
+        let cache = self.ipset_cache.by_family(family);
+        let len = usize::try_from(cache.entry_count()).unwrap_or(usize::MAX);
+        if len == 0 {
+            return;
+        }
+        let offset = self.ban_verify_offsets.by_family_mut(family);
+        let start = *offset % len;
+        let sample_size = (self.config.verify_bans_sample as usize).min(len);
+        let sample: Vec<MaskedIpAddr> = active_bans(cache, now)
+            .skip(start)
+            .chain(active_bans(cache, now))
+            .take(sample_size)
+            .map(|(net, _)| net)
+            .collect();
+        *offset = start + sample.len();
+
+        let mut missing = Vec::new();
+        for &net in &sample {
+            match self.sessions.by_family_mut(family).test(net) {
+                Ok(true) => {}
+                Ok(false) => missing.push(net),
+                Err(err) => {
+                    let err = LeroyError::netlink(format!(
+                        "Failed to verify bans in set {name:?}: {err}"
+                    ));
+                    self.netlink_error(&err);
+                    return;
+                }
+            }
+        }
+
+        if !missing.is_empty() && missing.len() == sample.len() {
+            let bans: Vec<_> = active_bans(self.ipset_cache.by_family(family), now).collect();
+            warn!(
+                "None of {} sampled bans are in set {name:?}, which was probably flushed or \
+                 recreated. Adding all {} cached bans again",
+                sample.len(),
+                bans.len()
+            );
+            for (net, expires) in bans {
+                let remaining = expires.duration_since(now).unwrap_or_default();
+                let timeout = u32::try_from(remaining.as_secs())
+                    .unwrap_or(u32::MAX)
+                    .max(1);
+                if let Err(err) = self.add_to_ipset(net, Sets::Bans, timeout, None) {
+                    self.forget_ban(net);
+                    if !err.is::<QueueFull>() {
+                        let err = LeroyError::netlink(format!("Unable to add {net} to set: {err}"));
+                        self.netlink_error(&err);
+                    }
+                }
+            }
+        } else if !missing.is_empty() {
+            info!(
+                "{} of {} sampled bans were removed from set {name:?} by someone else, \
+                 forgetting them",
+                missing.len(),
+                sample.len()
+            );
+            for net in missing {
+                self.forget_ban(net);
+            }
+        }
+    }
+
+    /// Compares all entries of each ipset with the cached bans, see
+    /// --reconcile-interval.
+    fn reconcile(&mut self) {
+        if !self.config.manages_ipsets() || self.monitor_only {
+            return;
+        }
+        for family in [IpFamily::V4, IpFamily::V6] {
+            self.reconcile_family(family);
+        }
+    }
+
+    fn reconcile_family(&mut self, family: IpFamily) {
+        let name = match family {
+            IpFamily::V4 => self.config.ipset_ipv4_name.clone(),
+            IpFamily::V6 => self.config.ipset_ipv6_name.clone(),
+        };
+        let entries: HashSet<MaskedIpAddr> = match self.sessions.by_family_mut(family).list() {
+            Ok(entries) => entries.into_iter().collect(),
+            Err(err) => {
+                let err = LeroyError::netlink(format!("Failed to list set {name:?}: {err}"));
+                self.netlink_error(&err);
+                return;
+            }
+        };
+        let now = self.config.clock.system_now();
+        let cached: HashMap<MaskedIpAddr, SystemTime> =
+            active_bans(self.ipset_cache.by_family(family), now).collect();
+        let missing: Vec<(MaskedIpAddr, SystemTime)> = cached
+            .iter()
+            .filter(|(net, expires)| {
+                !entries.contains(net)
+                    && expires
+                        .duration_since(now)
+                        .is_ok_and(|remaining| remaining > RECONCILE_MARGIN)
+            })
+            .map(|(net, expires)| (*net, *expires))
+            .collect();
+        let foreign: Vec<MaskedIpAddr> = entries
+            .into_iter()
+            .filter(|net| !cached.contains_key(net))
+            .collect();
+        // Replaced at every reconciliation, which drops the entries that
+        // are gone, because their remaining time is not listed.
+        let adopted = self.adopted_bans.by_family_mut(family);
+        let previously_adopted = mem::take(adopted);
+        if self.config.reconcile_adopt {
+            adopted.extend(foreign.iter().copied());
+        }
+        let foreign: Vec<MaskedIpAddr> = foreign
+            .into_iter()
+            .filter(|net| !previously_adopted.contains(net))
+            .collect();
+        self.metrics.reconcile_missing += missing.len() as u64;
+        self.metrics.reconcile_foreign += foreign.len() as u64;
+        if missing.is_empty() && foreign.is_empty() {
+            debug!("Set {name:?} matches the {} cached bans", cached.len());
+            return;
+        }
+        info!(
+            "Set {name:?} lacks {} of {} cached bans ({}), and has {} entries that were not cached{}",
+            missing.len(),
+            cached.len(),
+            match self.config.reconcile_missing {
+                ReconcileMissing::Forget => "forgetting them",
+                ReconcileMissing::Reban => "adding them again",
+            },
+            foreign.len(),
+            if self.config.reconcile_adopt {
+                " (adopting them)"
+            } else {
+                ""
+            }
+        );
+
+        for (net, expires) in missing {
+            match self.config.reconcile_missing {
+                ReconcileMissing::Forget => self.forget_ban(net),
+                ReconcileMissing::Reban => {
+                    let remaining = expires.duration_since(now).unwrap_or_default();
+                    let timeout = u32::try_from(remaining.as_secs())
+                        .unwrap_or(u32::MAX)
+                        .max(1);
+                    if let Err(err) = self.add_to_ipset(net, Sets::Bans, timeout, None) {
+                        self.forget_ban(net);
+                        if !err.is::<QueueFull>() {
+                            let err =
+                                LeroyError::netlink(format!("Unable to add {net} to set: {err}"));
+                            self.netlink_error(&err);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Like [`Leroy::forget_ban`], for the ban in `sets`.
+    fn forget_ban_in(&mut self, net: MaskedIpAddr, sets: Sets) {
+        match sets {
+            Sets::Port(port) => self.port_ipset_cache.invalidate(&(port, net)),
+            Sets::Tenant(tenant) => self.tenant_ipset_cache.invalidate(&(tenant, net)),
+            Sets::Bans | Sets::Watch => self.forget_ban(net),
+        }
+    }
+
+    /// Forgets a ban that is no longer in the ipset, so that it is made again
+    /// at the next event over the limit.
+    fn forget_ban(&mut self, net: MaskedIpAddr) {
+        self.ipset_cache
+            .by_family_mut(net.family())
+            .invalidate(&net);
+        self.live_bans.by_family_mut(net.family()).remove(net);
+    }
+
+    /// Returns `false` if --max-banned is reached and no room can be made.
+    fn make_room_for_ban(&mut self, family: IpFamily) -> bool {
+        let Some(max_banned) = self.config.max_banned else {
+            return true;
+        };
+        if self.live_bans.by_family_mut(family).len() < max_banned {
+            return true;
+        }
+        match self.config.max_banned_policy {
+            MaxBannedPolicy::Stop => false,
+            MaxBannedPolicy::Evict => {
+                while self.live_bans.by_family_mut(family).len() >= max_banned {
+                    let Some(evicted) = self.live_bans.by_family_mut(family).pop_soonest() else {
+                        break;
+                    };
+                    self.ipset_cache.by_family_mut(family).invalidate(&evicted);
+                    if self.config.manages_ipsets() && !self.monitor_only {
+                        if let Err(err) = self.sessions.by_family_mut(family).del(evicted) {
+                            let err = LeroyError::netlink(format!(
+                                "Unable to evict {evicted} from set: {err}"
+                            ));
+                            self.netlink_error(&err);
+                            self.health.record_netlink(false);
+                        }
+                    }
+                    info!("Evicted {evicted} to make room for new bans");
+                    self.record_unban(evicted, UnbanReason::Evicted);
+                }
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_backend::MockBackend;
+
+    fn ban(leroy: &mut Leroy, ip: IpAddr) -> Decision {
+        loop {
+            match leroy.handle_ip(ip) {
+                Decision::UnderLimit => continue,
+                decision => return decision,
+            }
+        }
+    }
+
+    #[test]
+    fn recidivism_countdown_restarts_on_every_ban() {
+        let clock = Clock::fake();
+        let ttl = Duration::from_secs(3600);
+        let mut leroy = Leroy::builder()
+            .backends(
+                MockBackend::with_clock(clock.clone()),
+                MockBackend::with_clock(clock.clone()),
+            )
+            .rate_limit(2, Duration::from_secs(10))
+            .ban_time(Duration::from_secs(60))
+            .recidivism_ttl(ttl)
+            .clock(clock.clone())
+            .build()
+            .unwrap();
+        let ip = "192.0.2.1".parse().unwrap();
+
+        assert!(matches!(
+            ban(&mut leroy, ip),
+            Decision::Banned { recidivism: 1, .. }
+        ));
+        let between_bans = Duration::from_secs(2400);
+        clock.advance(between_bans);
+        assert_eq!(leroy.recidivism(&ip), 1);
+        assert!(matches!(
+            ban(&mut leroy, ip),
+            Decision::Banned { recidivism: 2, .. }
+        ));
+
+        // Past the TTL of the first ban.
+        clock.advance(ttl - between_bans);
+        assert_eq!(leroy.recidivism(&ip), 2);
+        clock.advance(between_bans - Duration::from_secs(1));
+        assert_eq!(leroy.recidivism(&ip), 2);
+        // One TTL after the last ban.
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(leroy.recidivism(&ip), 0);
     }
 }
@@ -0,0 +1,114 @@
+use std::{
+    env, fs, io,
+    net::{SocketAddr, TcpListener},
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        net::UnixListener,
+    },
+    path::Path,
+    process,
+};
+
+use log::{info, warn};
+
+/// The first file descriptor passed by the service manager.
+pub(crate) const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Listening sockets passed by systemd socket activation (see
+/// `sd_listen_fds(3)`). They are matched to the configured addresses, so that
+/// the same flags work with and without activation, and a restart does not
+/// drop connections that are waiting to be accepted.
+#[derive(Default)]
+pub struct ListenFds {
+    fds: Vec<OwnedFd>,
+    /// The sockets that were handed out, passed or not.
+    in_use: Vec<RawFd>,
+}
+
+impl ListenFds {
+    /// Takes the sockets from `LISTEN_FDS`, if they are meant for this
+    /// process.
+    pub fn from_env() -> ListenFds {
+        let for_us = env::var("LISTEN_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_some_and(|pid| pid == process::id());
+        let count = env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse::<i32>().ok())
+            .unwrap_or(0);
+        // Not inherited by child processes.
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+        if !for_us || count <= 0 {
+            return ListenFds::default();
+        }
+        info!("Using {count} sockets from socket activation");
+        ListenFds {
+            fds: (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+                // Skip descriptors that are not open after all.
+                .filter(|&fd| unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == 0)
+                .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+                .collect(),
+            in_use: Vec::new(),
+        }
+    }
+
+    /// A passed TCP socket listening on `addr`, or else a newly bound one.
+    pub fn tcp_listener(&mut self, addr: SocketAddr) -> io::Result<TcpListener> {
+        let position = self.fds.iter().position(|fd| {
+            fd.try_clone()
+                .and_then(|fd| TcpListener::from(fd).local_addr())
+                .is_ok_and(|local| {
+                    local == addr
+                        || (addr.ip().is_unspecified()
+                            && local.ip().is_unspecified()
+                            && local.port() == addr.port())
+                })
+        });
+        let listener = match position {
+            Some(i) => TcpListener::from(self.fds.remove(i)),
+            None => TcpListener::bind(addr)?,
+        };
+        self.in_use.push(listener.as_raw_fd());
+        Ok(listener)
+    }
+
+    /// A passed unix socket listening on `path`, or else a newly bound one
+    /// that only the owner can connect to.
+    pub fn unix_listener(&mut self, path: &Path) -> io::Result<UnixListener> {
+        let position = self.fds.iter().position(|fd| {
+            fd.try_clone()
+                .and_then(|fd| UnixListener::from(fd).local_addr())
+                .is_ok_and(|local| local.as_pathname() == Some(path))
+        });
+        if let Some(i) = position {
+            let listener = UnixListener::from(self.fds.remove(i));
+            self.in_use.push(listener.as_raw_fd());
+            return Ok(listener);
+        }
+        // Remove the socket of a previous run.
+        if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        self.in_use.push(listener.as_raw_fd());
+        Ok(listener)
+    }
+
+    /// Closes the sockets that match none of the configured addresses, and
+    /// returns the ones in use, which are passed on in a handover. They stay
+    /// open as long as the threads that accept on them.
+    pub fn close_unused(self) -> Vec<RawFd> {
+        if !self.fds.is_empty() {
+            warn!(
+                "Closing {} sockets from socket activation that match no listen flag",
+                self.fds.len()
+            );
+        }
+        self.in_use
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    mem,
+    time::SystemTime,
+};
+
+use crate::{clock::Clock, masked_ip::MaskedIpAddr};
+
+/// Bans that are presumably still in the kernel set, ordered by expiry.
+pub struct LiveBans {
+    by_expiry: BTreeSet<(SystemTime, MaskedIpAddr)>,
+    expiries: HashMap<MaskedIpAddr, SystemTime>,
+    /// Bans that expired since the last [`LiveBans::take_expired`], if they
+    /// are kept for --expiry-events.
+    expired: Option<Vec<MaskedIpAddr>>,
+    clock: Clock,
+}
+
+impl LiveBans {
+    pub fn new(clock: Clock, keep_expired: bool) -> LiveBans {
+        LiveBans {
+            by_expiry: BTreeSet::new(),
+            expiries: HashMap::new(),
+            expired: keep_expired.then(Vec::new),
+            clock,
+        }
+    }
+
+    /// Replaces the expiry of an earlier ban of `net`, for example one that
+    /// was extended.
+    pub fn insert(&mut self, net: MaskedIpAddr, expires: SystemTime) {
+        if let Some(previous) = self.expiries.insert(net, expires) {
+            self.by_expiry.remove(&(previous, net));
+        }
+        self.by_expiry.insert((expires, net));
+    }
+
+    pub fn len(&mut self) -> usize {
+        self.remove_expired();
+        self.by_expiry.len()
+    }
+
+    pub fn remove(&mut self, net: MaskedIpAddr) {
+        if let Some(expires) = self.expiries.remove(&net) {
+            self.by_expiry.remove(&(expires, net));
+        }
+    }
+
+    /// Removes and returns the ban that would expire next.
+    pub fn pop_soonest(&mut self) -> Option<MaskedIpAddr> {
+        self.remove_expired();
+        let (_, net) = self.by_expiry.pop_first()?;
+        self.expiries.remove(&net);
+        Some(net)
+    }
+
+    /// The bans that expired since the previous call, if kept.
+    pub fn take_expired(&mut self) -> Vec<MaskedIpAddr> {
+        self.remove_expired();
+        self.expired.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    fn remove_expired(&mut self) {
+        let now = self.clock.system_now();
+        while self
+            .by_expiry
+            .first()
+            .is_some_and(|(expires, _)| *expires <= now)
+        {
+            if let Some((_, net)) = self.by_expiry.pop_first() {
+                self.expiries.remove(&net);
+                if let Some(ref mut expired) = self.expired {
+                    expired.push(net);
+                }
+            }
+        }
+    }
+}
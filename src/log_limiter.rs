@@ -0,0 +1,75 @@
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use log::error;
+
+use crate::clock::Clock;
+
+/// How many errors can be logged at once.
+const BURST: u32 = 20;
+/// How often another error can be logged after a burst.
+const REFILL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A token bucket for the errors logged per input line, so that a stream of
+/// garbage or a failing netlink socket cannot flood the journal of the host
+/// it protects. Errors over the limit are only counted, for the periodic
+/// report.
+pub struct LogLimiter {
+    tokens: u32,
+    last_refill: Instant,
+    suppressed: u64,
+    clock: Clock,
+}
+
+impl LogLimiter {
+    pub fn new(clock: Clock) -> LogLimiter {
+        LogLimiter {
+            tokens: BURST,
+            last_refill: clock.now(),
+            suppressed: 0,
+            clock,
+        }
+    }
+
+    /// Takes a token if there is one, and otherwise counts the error as
+    /// suppressed.
+    pub fn allow(&mut self) -> bool {
+        self.refill();
+        if self.tokens > 0 {
+            self.tokens -= 1;
+            true
+        } else {
+            self.suppressed += 1;
+            false
+        }
+    }
+
+    pub fn error(&mut self, err: impl fmt::Display) {
+        if self.allow() {
+            error!("{err}");
+        }
+    }
+
+    /// The number of errors suppressed since the previous call.
+    pub fn take_suppressed(&mut self) -> u64 {
+        std::mem::take(&mut self.suppressed)
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.now();
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        let refills = elapsed.as_nanos() / REFILL_INTERVAL.as_nanos();
+        if refills == 0 {
+            return;
+        }
+        let refills = u32::try_from(refills).unwrap_or(u32::MAX);
+        self.tokens = self.tokens.saturating_add(refills).min(BURST);
+        self.last_refill = if self.tokens == BURST {
+            now
+        } else {
+            self.last_refill + REFILL_INTERVAL * refills
+        };
+    }
+}
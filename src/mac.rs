@@ -0,0 +1,49 @@
+use std::{fmt, str::FromStr};
+
+/// A MAC address, like `02:00:5e:10:00:01`, see --key-type mac.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    /// Parses six pairs of hex digits, separated by `:` or `-`, in either
+    /// case.
+    pub fn from_bytes(s: &[u8]) -> Option<MacAddr> {
+        if s.len() != 17 {
+            return None;
+        }
+        let separator = s[2];
+        if !matches!(separator, b':' | b'-') {
+            return None;
+        }
+        let mut octets = [0; 6];
+        for (i, octet) in octets.iter_mut().enumerate() {
+            let pair = &s[i * 3..i * 3 + 2];
+            if i > 0 && s[i * 3 - 1] != separator {
+                return None;
+            }
+            *octet = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+        }
+        Some(MacAddr(octets))
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MacAddr, String> {
+        MacAddr::from_bytes(s.as_bytes()).ok_or_else(|| format!("invalid MAC address {s:?}"))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
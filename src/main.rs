@@ -1,33 +1,450 @@
-use std::{error::Error, io, io::BufRead};
+use std::{
+    env,
+    error::Error,
+    io,
+    io::{BufRead, BufReader, Read},
+    os::unix::io::{AsRawFd, RawFd},
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+};
 
-use clap::Parser;
-use leroyjenkins::{Args, Leroy};
-use log::info;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use leroyjenkins::{
+    admin_request, args_with_config, AdminCommand, Args, ExecAction, IpAnonymizer, KeyBanner,
+    KeyType, Leroy, MacSetAction, MaskedIpAddr, WebhookAction,
+};
+use log::{error, info, Log, Metadata, Record};
 use mimalloc::MiMalloc;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+#[derive(Parser)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Option<Args>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(flatten)]
+    Remote(Remote),
+    /// Check the flags and config file of the daemon, the files they name,
+    /// and that the ipsets exist, without starting it. Exits with 1 if
+    /// anything is wrong, for example in CI or ExecStartPre.
+    Check(Box<Args>),
+    /// Print a shell completion script.
+    Completions { shell: Shell },
+    /// Print the man page in roff format.
+    Man,
+}
+
+/// Commands for a running daemon, sent to its --admin-socket.
+#[derive(Subcommand)]
+enum Remote {
+    /// Show whether the daemon is paused, and some key numbers.
+    Status(Client),
+    /// Ban an address or network manually.
+    Ban {
+        /// An address like `192.0.2.1`, or a network like `192.0.2.0/24`.
+        ip: MaskedIpAddr,
+        /// Defaults to the ban time of a first offense.
+        ///
+        /// Uses humantime to parse the duration.
+        /// See: https://docs.rs/humantime/latest/humantime/ for details
+        duration: Option<humantime::Duration>,
+        #[command(flatten)]
+        client: Client,
+    },
+    /// Remove a ban and reset the rate limits of an address or network.
+    Unban {
+        ip: MaskedIpAddr,
+        #[command(flatten)]
+        client: Client,
+    },
+    /// Show the ban expiry and previous bans of an address or network.
+    Query {
+        ip: MaskedIpAddr,
+        #[command(flatten)]
+        client: Client,
+    },
+    /// List the active bans.
+    List(Client),
+    /// Show a snapshot of the internal state.
+    Stats(Client),
+    /// Ignore input lines until resumed.
+    Pause(Client),
+    /// Handle input lines again.
+    Resume(Client),
+    /// Stop adding bans to the ipsets, but keep counting and logging them.
+    Monitor(Client),
+    /// Add bans to the ipsets again.
+    Enforce(Client),
+    /// Remove all bans that this instance added from the ipsets, for example
+    /// after a bad input feed.
+    Flush(Client),
+    /// List the subnet and ASN bans waiting for approval.
+    Pending(Client),
+    /// Apply a subnet or ASN ban waiting for approval.
+    Approve {
+        /// The id shown by `pending`.
+        id: u64,
+        #[command(flatten)]
+        client: Client,
+    },
+    /// Drop a subnet or ASN ban waiting for approval.
+    Reject {
+        id: u64,
+        #[command(flatten)]
+        client: Client,
+    },
+    /// Print the rate limiter budgets, recidivism and cached bans as JSON.
+    Dump {
+        /// Only include keys that overlap this address or network.
+        ip: Option<MaskedIpAddr>,
+        #[command(flatten)]
+        client: Client,
+    },
+}
+
+#[derive(clap::Args)]
+struct Client {
+    /// The --admin-socket of the daemon.
+    #[arg(
+        long,
+        env = "LEROY_ADMIN_SOCKET",
+        default_value = "/run/leroyjenkins.sock"
+    )]
+    admin_socket: PathBuf,
+}
+
+impl Remote {
+    fn into_request(self) -> (AdminCommand, PathBuf) {
+        let (command, client) = match self {
+            Remote::Status(client) => (AdminCommand::Status, client),
+            Remote::Ban {
+                ip,
+                duration,
+                client,
+            } => (AdminCommand::Ban(ip, duration.map(Into::into)), client),
+            Remote::Unban { ip, client } => (AdminCommand::Unban(ip), client),
+            Remote::Query { ip, client } => (AdminCommand::Query(ip), client),
+            Remote::List(client) => (AdminCommand::List, client),
+            Remote::Stats(client) => (AdminCommand::Stats, client),
+            Remote::Pause(client) => (AdminCommand::Pause, client),
+            Remote::Resume(client) => (AdminCommand::Resume, client),
+            Remote::Monitor(client) => (AdminCommand::Monitor, client),
+            Remote::Enforce(client) => (AdminCommand::Enforce, client),
+            Remote::Dump { ip, client } => (AdminCommand::Dump(ip), client),
+            Remote::Flush(client) => (AdminCommand::Flush, client),
+            Remote::Pending(client) => (AdminCommand::Pending, client),
+            Remote::Approve { id, client } => (AdminCommand::Approve(id), client),
+            Remote::Reject { id, client } => (AdminCommand::Reject(id), client),
+        };
+        (command, client.admin_socket)
+    }
+}
+
+/// Set once the flags are parsed, for [`AnonymizingLogger`].
+static ANONYMIZER: OnceLock<IpAnonymizer> = OnceLock::new();
+
+/// Replaces the addresses in all log messages with --anonymize-ips.
+struct AnonymizingLogger {
+    inner: Box<dyn Log>,
+}
+
+impl AnonymizingLogger {
+    /// Like `pretty_env_logger::init`.
+    fn init() {
+        let mut builder = pretty_env_logger::formatted_builder();
+        if let Ok(filters) = env::var("RUST_LOG") {
+            builder.parse_filters(&filters);
+        }
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        let _ = log::set_boxed_logger(Box::new(AnonymizingLogger {
+            inner: Box::new(logger),
+        }));
+    }
+}
+
+impl Log for AnonymizingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let Some(anonymizer) = ANONYMIZER.get() else {
+            return self.inner.log(record);
+        };
+        if !self.inner.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        let message = anonymizer.anonymize_text(&message);
+        self.inner.log(
+            &Record::builder()
+                .args(format_args!("{message}"))
+                .metadata(record.metadata().clone())
+                .module_path(record.module_path())
+                .file(record.file())
+                .line(record.line())
+                .build(),
+        );
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
 fn main() -> Result<(), Box<dyn Error>> {
-    pretty_env_logger::init();
+    AnonymizingLogger::init();
 
-    let args = Args::parse();
+    let argv: Vec<_> = env::args_os().collect();
+    let command = argv.get(1).and_then(|arg| arg.to_str()).and_then(|arg| {
+        Cli::command()
+            .find_subcommand(arg)
+            .map(|command| command.get_name().to_owned())
+    });
+    let cli = match command.as_deref() {
+        // Checks the flags of the daemon, so with its config file and
+        // environment.
+        Some("check") => {
+            let mut argv = argv.clone();
+            let check = argv.remove(1);
+            let mut argv = args_with_config(argv)?;
+            argv.insert(1, check);
+            Cli::parse_from(argv)
+        }
+        // The config file and environment only apply to the daemon.
+        Some(_) => Cli::parse_from(&argv),
+        None => Cli::parse_from(args_with_config(argv.clone())?),
+    };
+    let command = match cli.command {
+        Some(Command::Check(args)) => {
+            let report = leroyjenkins::check(*args);
+            print!("{report}");
+            if !report.is_ok() {
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                shell,
+                &mut Cli::command(),
+                "leroyjenkins",
+                &mut io::stdout(),
+            );
+            return Ok(());
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(Cli::command()).render(&mut io::stdout())?;
+            return Ok(());
+        }
+        Some(Command::Remote(command)) => Some(command),
+        None => None,
+    };
+    if let Some(command) = command {
+        let (command, path) = command.into_request();
+        let reply = admin_request(&path, &command)
+            .map_err(|err| format!("Failed to send command to {path:?}: {err}"))?;
+        print!("{reply}");
+        if reply.starts_with("error: ") {
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    let Some(args) = cli.args else {
+        Cli::command().print_help()?;
+        process::exit(2);
+    };
+    if let Some(anonymizer) =
+        IpAnonymizer::open(args.anonymize_ips, args.anonymize_key_file.as_deref())
+            .map_err(|err| err.to_string())?
+    {
+        let _ = ANONYMIZER.set(anonymizer);
+    }
     info!(
         "🔨🪓🪖🥚LEEEEEEEERRRRRROOOOOYYYYYYYYYY JJEEEEEENNNNNNNKKKKKKKIIIIIIINNNNNSSSSSSS🥚🪖🪓🔨"
     );
     info!("{:?}", args);
 
-    let mut leroy = Leroy::new(args)?;
+    if args.key_exec.is_some() || args.key_webhook_url.is_some() || args.key_type == KeyType::Mac {
+        return handle_keys(args);
+    }
+
+    let has_config = args.config.is_some();
+    let mut leroy = Leroy::new(args).map_err(|err| err.to_string())?;
+
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload))?;
+    let dump_stats = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGUSR1, Arc::clone(&dump_stats))?;
+    let handover = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGUSR2, Arc::clone(&handover))?;
+    let terminate = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        // A second signal exits right away, in case shutting down hangs.
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(&terminate))?;
+        signal_hook::flag::register(signal, Arc::clone(&terminate))?;
+    }
 
+    // Reads larger than the buffer of stdin itself bypass that, so that
+    // waiting for input is never fooled by buffered lines. The start of
+    // `buf` holds the `pending` bytes of an incomplete line.
     let mut stdin = io::stdin().lock();
+    let mut buf = vec![0; 64 * 1024];
+    let handover_input = leroy.take_handover_input();
+    let mut pending = handover_input.len().min(buf.len());
+    buf[..pending].copy_from_slice(&handover_input[..pending]);
+    let wakeup_fds: Vec<RawFd> = leroy
+        .admin_fd()
+        .into_iter()
+        .chain(leroy.cluster_fd())
+        .chain(leroy.resolver_fd())
+        .collect();
+    let mut input_open = true;
+    loop {
+        if terminate.load(Ordering::Relaxed) {
+            info!("Stopping intake, because of a signal");
+            break;
+        }
+        if let Some(err) = leroy.take_fatal_error() {
+            error!("Stopping, because bans can not be made anymore: {err}");
+            leroy.shutdown()?;
+            return Err(err.into());
+        }
+        if reload.swap(false, Ordering::Relaxed) {
+            if has_config {
+                let result = args_with_config(argv.clone())
+                    .and_then(|argv| Ok(Args::try_parse_from(argv)?))
+                    .and_then(|args| Ok(leroy.reload(args)?));
+                if let Err(err) = result {
+                    error!("Failed to reload configuration: {err}");
+                }
+            } else if let Err(err) = leroy.reload_allowlist() {
+                error!("Failed to reload allowlist: {err}");
+            }
+        }
+        if dump_stats.swap(false, Ordering::Relaxed) {
+            leroy.log_stats();
+        }
+        if handover.swap(false, Ordering::Relaxed) {
+            match leroy.check_handover() {
+                Ok(()) => {
+                    let err = leroy.handover(&buf[..pending]);
+                    error!("Failed to hand over to a new process: {err}");
+                    return Err(err.into());
+                }
+                Err(err) => error!("Not handing over to a new process: {err}"),
+            }
+        }
+        // Unlike reads, waiting is interrupted by signals. Every read takes
+        // up to thousands of lines, so this costs little.
+        let stdin_ready = wait_for_input(input_open, &wakeup_fds)?;
+        leroy.handle_admin_requests();
+        leroy.handle_peer_bans();
+        leroy.handle_resolved();
+        if !stdin_ready {
+            continue;
+        }
+        let read = match stdin.read(&mut buf[pending..]) {
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if read == 0 {
+            if pending > 0 {
+                leroy.handle_bytes(&buf[..pending]);
+                pending = 0;
+            }
+            // An aggregator may have no input of its own.
+            if leroy.cluster_fd().is_none() {
+                break;
+            }
+            info!("Input closed, still accepting bans from peers");
+            input_open = false;
+            continue;
+        }
+        let filled = pending + read;
+        match memchr::memrchr(b'\n', &buf[..filled]) {
+            Some(end) => {
+                leroy.handle_bytes(&buf[..=end]);
+                buf.copy_within(end + 1..filled, 0);
+                pending = filled - end - 1;
+            }
+            // Too long to be an address, so it may as well be split.
+            None if filled == buf.len() => {
+                leroy.handle_bytes(&buf);
+                pending = 0;
+            }
+            None => pending = filled,
+        }
+    }
+
+    leroy.shutdown()?;
+
+    Ok(())
+}
+
+/// Reads keys instead of addresses, see --key-exec and --key-type.
+fn handle_keys(args: Args) -> Result<(), Box<dyn Error>> {
+    let mut banner = match (args.key_exec.clone(), args.key_webhook_url.clone()) {
+        (Some(program), _) => KeyBanner::new(args, ExecAction::new(program)?),
+        (None, Some(url)) => KeyBanner::new(args, WebhookAction::new(url)?),
+        (None, None) => {
+            let name = args.ipset_mac_name.clone().unwrap_or_default();
+            let test = !args.dry_run && !args.monitor_only && !args.skip_self_test;
+            KeyBanner::new(args, MacSetAction::open(&name, test)?)
+        }
+    }
+    .map_err(|err| err.to_string())?;
+    let mut stdin = BufReader::with_capacity(64 * 1024, io::stdin().lock());
     let mut line = Vec::with_capacity(40);
-    while stdin.read_until(b'\n', &mut line)? != 0 {
+    while stdin.read_until(b'\n', &mut line)? > 0 {
         if line[line.len() - 1] == b'\n' {
             line.pop();
         }
-        leroy.handle_line(&line);
+        banner.handle_line(&line);
         line.clear();
     }
-
     Ok(())
 }
+
+/// Waits until stdin (if `input_open`) or one of `wakeup_fds` is readable,
+/// or a signal arrives. Returns `true` if stdin is readable.
+fn wait_for_input(input_open: bool, wakeup_fds: &[RawFd]) -> io::Result<bool> {
+    let stdin_fd = io::stdin().as_raw_fd();
+    let mut fds: Vec<libc::pollfd> = input_open
+        .then_some(&stdin_fd)
+        .into_iter()
+        .chain(wakeup_fds)
+        .map(|fd| libc::pollfd {
+            fd: *fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect();
+    if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } >= 0 {
+        // Closed input is reported as readable, so that the loop ends.
+        return Ok(input_open && fds[0].revents != 0);
+    }
+    let err = io::Error::last_os_error();
+    if err.kind() == io::ErrorKind::Interrupted {
+        // Let the caller handle the signal.
+        return Ok(false);
+    }
+    Err(err)
+}
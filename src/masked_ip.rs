@@ -0,0 +1,159 @@
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+#[cfg(target_os = "linux")]
+use ipset::types::NetDataType;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::ip_family::IpFamily;
+
+/// An IP address with all bits beyond the prefix length cleared, i.e. the
+/// network it belongs to. The key of the rate limiters, so that different
+/// spellings of an address, like `::1` and `0::1`, share a limit.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MaskedIpAddr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl MaskedIpAddr {
+    /// Masks `addr` to `prefix_len` bits. Prefix lengths larger than the
+    /// address are clamped to the full address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> MaskedIpAddr {
+        match addr {
+            IpAddr::V4(addr) => {
+                let prefix_len = prefix_len.min(32);
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                MaskedIpAddr {
+                    addr: IpAddr::V4(Ipv4Addr::from_bits(addr.to_bits() & mask)),
+                    prefix_len,
+                }
+            }
+            IpAddr::V6(addr) => {
+                let prefix_len = prefix_len.min(128);
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                MaskedIpAddr {
+                    addr: IpAddr::V6(Ipv6Addr::from_bits(addr.to_bits() & mask)),
+                    prefix_len,
+                }
+            }
+        }
+    }
+
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn family(&self) -> IpFamily {
+        IpFamily::from_ipv4(self.addr.is_ipv4())
+    }
+
+    pub fn is_single_addr(&self) -> bool {
+        match self.addr {
+            IpAddr::V4(_) => self.prefix_len == 32,
+            IpAddr::V6(_) => self.prefix_len == 128,
+        }
+    }
+
+    /// Tests if either network contains the other.
+    pub fn overlaps(&self, other: &MaskedIpAddr) -> bool {
+        let prefix_len = self.prefix_len.min(other.prefix_len);
+        self.addr.is_ipv4() == other.addr.is_ipv4()
+            && MaskedIpAddr::new(self.addr, prefix_len) == MaskedIpAddr::new(other.addr, prefix_len)
+    }
+}
+
+/// Hashes the address as one integer, which is cheaper than hashing the
+/// bytes of an [`IpAddr`].
+impl Hash for MaskedIpAddr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let (bits, family) = match self.addr {
+            IpAddr::V4(addr) => (u128::from(addr.to_bits()), 4),
+            IpAddr::V6(addr) => (addr.to_bits(), 6),
+        };
+        state.write_u128(bits);
+        state.write_u16(family << 8 | u16::from(self.prefix_len));
+    }
+}
+
+impl From<IpAddr> for MaskedIpAddr {
+    fn from(addr: IpAddr) -> MaskedIpAddr {
+        MaskedIpAddr::new(addr, u8::MAX)
+    }
+}
+
+impl FromStr for MaskedIpAddr {
+    type Err = String;
+
+    /// Parses a single address like `192.0.2.1` or a network in CIDR
+    /// notation like `192.0.2.0/24`.
+    fn from_str(s: &str) -> Result<MaskedIpAddr, String> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("invalid address {addr:?}: {err}"))?;
+        let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| format!("invalid prefix length {prefix_len:?}"))?,
+            None => max_prefix_len,
+        };
+        Ok(MaskedIpAddr::new(addr, prefix_len))
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<NetDataType> for MaskedIpAddr {
+    fn from(net: NetDataType) -> MaskedIpAddr {
+        MaskedIpAddr::new(net.ip(), net.cidr())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl From<MaskedIpAddr> for NetDataType {
+    fn from(net: MaskedIpAddr) -> NetDataType {
+        NetDataType::new(net.addr, net.prefix_len)
+    }
+}
+
+impl fmt::Display for MaskedIpAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_single_addr() {
+            write!(f, "{}", self.addr)
+        } else {
+            write!(f, "{}/{}", self.addr, self.prefix_len)
+        }
+    }
+}
+
+impl Serialize for MaskedIpAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MaskedIpAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<MaskedIpAddr, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
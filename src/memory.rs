@@ -0,0 +1,24 @@
+use std::{fs, io};
+
+/// Only pages that are in use, not those that the allocator merely
+/// reserved.
+#[cfg(target_os = "linux")]
+const LOCK_FLAGS: libc::c_int = libc::MCL_CURRENT | libc::MCL_FUTURE | libc::MCL_ONFAULT;
+#[cfg(not(target_os = "linux"))]
+const LOCK_FLAGS: libc::c_int = libc::MCL_CURRENT | libc::MCL_FUTURE;
+
+/// Locks the pages of the process into memory as they are faulted in.
+pub fn lock_all() -> io::Result<()> {
+    if unsafe { libc::mlockall(LOCK_FLAGS) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The resident set size of the process, on Linux.
+pub fn resident_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = u64::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) }).ok()?;
+    Some(pages * page_size)
+}
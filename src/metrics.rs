@@ -0,0 +1,299 @@
+use std::{fmt, time::Duration};
+
+use crate::{
+    ban_reason::BanReason, event_log::BanCategory, ip_family::ByIpFamily, masked_ip::MaskedIpAddr,
+};
+
+const TOP_OFFENDERS: usize = 5;
+
+/// How many ban reasons get their own metric label. Bans for further
+/// reasons are counted as `other`, so that a misbehaving input can not
+/// create an unbounded number of series.
+const MAX_REASONS: usize = 16;
+
+/// Counters since startup.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Metrics {
+    pub lines: u64,
+    pub parse_errors: u64,
+    pub good_events: u64,
+    pub bans: ByIpFamily<u64>,
+    /// Bans skipped because of --warmup or --max-banned, or because adding
+    /// them failed.
+    pub skipped_bans: u64,
+    /// Ban decisions for IPs that were already known to be banned.
+    pub ban_cache_hits: u64,
+    pub ban_cache_misses: u64,
+    /// Inserts into a full cache, which evicted another entry (or were not
+    /// admitted) because of --cache-max-size.
+    pub ban_cache_evictions: u64,
+    /// Ban decisions for IPs that were banned before.
+    pub recidivism_cache_hits: u64,
+    pub recidivism_cache_misses: u64,
+    pub recidivism_cache_evictions: u64,
+    /// Garbage collections of all rate limiter tables. Only updated before
+    /// metrics are exported.
+    pub limiter_gc_runs: u64,
+    pub limiter_gc_removed: u64,
+    /// Failed ipset operations.
+    pub netlink_errors: u64,
+    /// Retries of failed ipset adds, whether they succeeded in the end or
+    /// not.
+    pub netlink_retries: u64,
+    /// Bans and watches skipped because the --netlink-queue was full.
+    pub netlink_queue_drops: u64,
+    /// Events of host names skipped because too many names were waiting to
+    /// be resolved, see --resolve-hostnames.
+    pub resolver_drops: u64,
+    /// Events of addresses that were banned for a while already, see
+    /// --banned-events-threshold.
+    pub banned_events: u64,
+    /// Cached bans that were missing from the ipsets, and entries of the
+    /// ipsets that were not cached, see --reconcile-interval.
+    pub reconcile_missing: u64,
+    pub reconcile_foreign: u64,
+    /// Bans that ran out, counted with --expiry-events.
+    pub expired_bans: u64,
+    /// Bans lengthened with --extend-bans, which are not counted as bans.
+    pub extended_bans: u64,
+    /// Time from sending an ipset add request until the kernel acknowledged
+    /// it, including retries.
+    pub ipset_latency: LatencyHistogram,
+    /// Bans with a reason, exported with a `reason` label.
+    pub bans_by_reason: ReasonCounts,
+}
+
+impl Metrics {
+    /// All counters with their metric names.
+    pub fn counters(&self) -> [(&'static str, u64); 23] {
+        [
+            ("lines", self.lines),
+            ("parse_errors", self.parse_errors),
+            ("good_events", self.good_events),
+            ("bans.v4", self.bans.ipv4),
+            ("bans.v6", self.bans.ipv6),
+            ("skipped_bans", self.skipped_bans),
+            ("ban_cache_hits", self.ban_cache_hits),
+            ("ban_cache_misses", self.ban_cache_misses),
+            ("ban_cache_evictions", self.ban_cache_evictions),
+            ("recidivism_cache_hits", self.recidivism_cache_hits),
+            ("recidivism_cache_misses", self.recidivism_cache_misses),
+            (
+                "recidivism_cache_evictions",
+                self.recidivism_cache_evictions,
+            ),
+            ("limiter_gc_runs", self.limiter_gc_runs),
+            ("limiter_gc_removed", self.limiter_gc_removed),
+            ("netlink_errors", self.netlink_errors),
+            ("netlink_retries", self.netlink_retries),
+            ("netlink_queue_drops", self.netlink_queue_drops),
+            ("resolver_drops", self.resolver_drops),
+            ("banned_events", self.banned_events),
+            ("reconcile_missing", self.reconcile_missing),
+            ("reconcile_foreign", self.reconcile_foreign),
+            ("expired_bans", self.expired_bans),
+            ("extended_bans", self.extended_bans),
+        ]
+    }
+}
+
+/// The percentage of `hits` in all lookups.
+pub fn hit_rate(hits: u64, misses: u64) -> f64 {
+    100.0 * hits as f64 / (hits + misses).max(1) as f64
+}
+
+/// Upper bounds of the histogram buckets in microseconds. The last bucket is
+/// unbounded.
+pub const LATENCY_BUCKETS_MICROS: [u64; 11] = [
+    50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000,
+];
+
+/// Bans by reason, in the order the reasons were first seen.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ReasonCounts {
+    reasons: [Option<BanReason>; MAX_REASONS],
+    counts: [u64; MAX_REASONS],
+    other: u64,
+}
+
+impl ReasonCounts {
+    pub fn record(&mut self, reason: BanReason) {
+        let count = if reason.as_str() == "other" {
+            &mut self.other
+        } else {
+            match self
+                .reasons
+                .iter()
+                .position(|known| known.is_none_or(|known| known == reason))
+            {
+                Some(index) => {
+                    self.reasons[index] = Some(reason);
+                    &mut self.counts[index]
+                }
+                None => &mut self.other,
+            }
+        };
+        *count += 1;
+    }
+
+    /// The counts with their label, ending with `other`.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> + '_ {
+        self.reasons
+            .iter()
+            .map_while(|reason| reason.as_ref())
+            .map(BanReason::as_str)
+            .zip(self.counts)
+            .chain([("other", self.other)])
+    }
+
+    /// The bans recorded after `earlier`.
+    pub fn since(&self, earlier: &ReasonCounts) -> ReasonCounts {
+        let mut counts = self.counts;
+        for (count, earlier) in counts.iter_mut().zip(earlier.counts) {
+            *count -= earlier;
+        }
+        ReasonCounts {
+            reasons: self.reasons,
+            counts,
+            other: self.other - earlier.other,
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct LatencyHistogram {
+    pub buckets: [u64; LATENCY_BUCKETS_MICROS.len() + 1],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let micros = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_MICROS.partition_point(|bound| *bound < micros);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.sum += latency;
+    }
+
+    /// The histogram of the latencies recorded after `earlier`.
+    pub fn since(&self, earlier: &LatencyHistogram) -> LatencyHistogram {
+        let mut buckets = self.buckets;
+        for (bucket, earlier) in buckets.iter_mut().zip(earlier.buckets) {
+            *bucket -= earlier;
+        }
+        LatencyHistogram {
+            buckets,
+            count: self.count - earlier.count,
+            sum: self.sum.saturating_sub(earlier.sum),
+        }
+    }
+
+    /// The upper bound of the bucket containing the quantile, or `None` if
+    /// nothing was recorded or it is in the unbounded bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS_MICROS) {
+            seen += bucket;
+            if seen >= rank {
+                return Some(Duration::from_micros(bound));
+            }
+        }
+        None
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        u32::try_from(self.count)
+            .ok()
+            .filter(|count| *count > 0)
+            .map(|count| self.sum / count)
+    }
+}
+
+impl fmt::Display for LatencyHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let show = |latency: Option<Duration>| match latency {
+            Some(latency) => format!("{latency:?}"),
+            None if self.count > 0 => format!(">{:?}", Duration::from_micros(100_000)),
+            None => "-".to_owned(),
+        };
+        write!(
+            f,
+            "{} requests, mean {}, p50 <= {}, p99 <= {}",
+            self.count,
+            show(self.mean()),
+            show(self.quantile(0.5)),
+            show(self.quantile(0.99))
+        )
+    }
+}
+
+/// Bans since the previous report.
+#[derive(Debug, Default)]
+pub struct BanCounts {
+    pub by_family: ByIpFamily<u64>,
+    pub rate_limit: u64,
+    pub subnet: u64,
+    pub asn: u64,
+    pub manual: u64,
+    pub peer: u64,
+    /// Not banned within --ipset-ban-ttl before.
+    pub new: u64,
+    pub recidivist: u64,
+    /// The most recidivist bans, highest first.
+    pub top_offenders: Vec<(u32, MaskedIpAddr)>,
+}
+
+impl BanCounts {
+    pub fn record(&mut self, ip: MaskedIpAddr, category: BanCategory, recidivism: u32) {
+        *self.by_family.by_family_mut(ip.family()) += 1;
+        *match category {
+            BanCategory::RateLimit => &mut self.rate_limit,
+            BanCategory::Subnet => &mut self.subnet,
+            BanCategory::Asn => &mut self.asn,
+            BanCategory::Manual => &mut self.manual,
+            BanCategory::Peer => &mut self.peer,
+        } += 1;
+        *if recidivism > 1 {
+            &mut self.recidivist
+        } else {
+            &mut self.new
+        } += 1;
+
+        if self.top_offenders.len() < TOP_OFFENDERS
+            || self
+                .top_offenders
+                .last()
+                .is_some_and(|(lowest, _)| *lowest < recidivism)
+        {
+            let index = self
+                .top_offenders
+                .partition_point(|(other, _)| *other >= recidivism);
+            self.top_offenders.insert(index, (recidivism, ip));
+            self.top_offenders.truncate(TOP_OFFENDERS);
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.by_family.ipv4 + self.by_family.ipv6
+    }
+}
+
+impl fmt::Display for BanCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} v4, {} v6; {} rate limit, {} subnet, {} asn, {} manual, {} peer; {} new, {} recidivist",
+            self.by_family.ipv4,
+            self.by_family.ipv6,
+            self.rate_limit,
+            self.subnet,
+            self.asn,
+            self.manual,
+            self.peer,
+            self.new,
+            self.recidivist
+        )
+    }
+}
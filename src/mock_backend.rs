@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    backend::{Backend, BackendError},
+    clock::Clock,
+    masked_ip::MaskedIpAddr,
+};
+
+/// A change that a [`MockBackend`] was asked to make.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockOp {
+    Add { net: MaskedIpAddr, timeout: u32 },
+    Del(MaskedIpAddr),
+}
+
+#[derive(Default)]
+struct MockState {
+    /// The timeouts of the entries, and when they expire.
+    set: HashMap<MaskedIpAddr, (u32, Instant)>,
+    ops: Vec<MockOp>,
+    error: Option<String>,
+    clock: Clock,
+}
+
+/// Records bans in memory instead of sending them over netlink, so that
+/// tests can assert what would have been sent, without root or Linux.
+/// Clones share the recording, so keep one to inspect after passing another
+/// to [`LeroyBuilder::backends`](crate::LeroyBuilder::backends).
+#[derive(Clone, Default)]
+pub struct MockBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        MockBackend::default()
+    }
+
+    /// Expires entries by `clock`, like the [`Leroy`](crate::Leroy) it is
+    /// used with.
+    pub fn with_clock(clock: Clock) -> MockBackend {
+        let backend = MockBackend::new();
+        backend.state.lock().unwrap().clock = clock;
+        backend
+    }
+
+    /// The changes so far, in order, including those that did not change
+    /// the set. Failed changes are not recorded.
+    pub fn ops(&self) -> Vec<MockOp> {
+        self.state.lock().unwrap().ops.clone()
+    }
+
+    /// The timeout that `net` was added with, if it is in the set.
+    pub fn timeout(&self, net: MaskedIpAddr) -> Option<u32> {
+        let mut state = self.state.lock().unwrap();
+        state.remove_expired();
+        state.set.get(&net).map(|(timeout, _)| *timeout)
+    }
+
+    pub fn contains(&self, net: MaskedIpAddr) -> bool {
+        self.timeout(net).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.remove_expired();
+        state.set.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes all following operations fail with `message`, like netlink
+    /// errors, until called with `None`.
+    pub fn fail_with(&self, message: Option<String>) {
+        self.state.lock().unwrap().error = message;
+    }
+}
+
+impl MockState {
+    /// Fails if asked to, and otherwise removes expired entries.
+    fn check(&mut self) -> Result<(), BackendError> {
+        match self.error {
+            Some(ref message) => Err(message.clone().into()),
+            None => {
+                self.remove_expired();
+                Ok(())
+            }
+        }
+    }
+
+    fn remove_expired(&mut self) {
+        let now = self.clock.now();
+        self.set.retain(|_, (_, expires)| *expires > now);
+    }
+}
+
+impl Backend for MockBackend {
+    fn test(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        Ok(state.set.contains_key(&net))
+    }
+
+    fn add(&mut self, net: MaskedIpAddr, timeout: u32) -> Result<bool, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        state.ops.push(MockOp::Add { net, timeout });
+        if state.set.contains_key(&net) {
+            return Ok(false);
+        }
+        let expires = state.clock.now() + Duration::from_secs(u64::from(timeout));
+        state.set.insert(net, (timeout, expires));
+        Ok(true)
+    }
+
+    fn del(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        state.ops.push(MockOp::Del(net));
+        Ok(state.set.remove(&net).is_some())
+    }
+
+    fn list(&mut self) -> Result<Vec<MaskedIpAddr>, BackendError> {
+        let mut state = self.state.lock().unwrap();
+        state.check()?;
+        Ok(state.set.keys().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Decision, Leroy};
+
+    #[test]
+    fn records_bans_over_the_rate_limit() {
+        let clock = Clock::fake();
+        let ipv4 = MockBackend::with_clock(clock.clone());
+        let ipv6 = MockBackend::with_clock(clock.clone());
+        let mut leroy = Leroy::builder()
+            .backends(ipv4.clone(), ipv6.clone())
+            .rate_limit(2, Duration::from_secs(10))
+            .ban_time(Duration::from_secs(60))
+            .clock(clock)
+            .build()
+            .unwrap();
+
+        assert_eq!(leroy.handle_line(b"192.0.2.1"), Decision::UnderLimit);
+        assert_eq!(leroy.handle_line(b"192.0.2.1"), Decision::UnderLimit);
+        assert_eq!(
+            leroy.handle_line(b"192.0.2.1"),
+            Decision::Banned {
+                timeout: 60,
+                recidivism: 1
+            }
+        );
+        assert_eq!(leroy.handle_line(b"192.0.2.1"), Decision::AlreadyBanned);
+        assert_eq!(leroy.handle_line(b"2001:db8::1"), Decision::UnderLimit);
+
+        let net = MaskedIpAddr::new("192.0.2.1".parse().unwrap(), 32);
+        assert_eq!(ipv4.ops(), [MockOp::Add { net, timeout: 60 }]);
+        assert_eq!(ipv4.timeout(net), Some(60));
+        assert!(ipv6.ops().is_empty());
+    }
+}
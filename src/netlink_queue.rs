@@ -0,0 +1,379 @@
+use std::{
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{add_with_retry, Backend, BackendError},
+    ip_family::{ByIpFamily, IpFamily},
+    masked_ip::MaskedIpAddr,
+    sched::Scheduling,
+    tenant::Tenant,
+    LeroyError,
+};
+
+/// What to do with a ban when the --netlink-queue is full.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueueOverflow {
+    /// Wait until the writer catches up, which stalls reading lines.
+    Block,
+    /// Skip the ban. The address is banned at its next event over the
+    /// limit.
+    Drop,
+}
+
+/// Which sets a change is for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Sets {
+    Bans,
+    Watch,
+    /// The sets of --port-ipset for the port.
+    Port(u16),
+    /// The sets of --tenant-ipset for the tenant.
+    Tenant(Tenant),
+}
+
+/// The ban sets, and the watch sets, per-port and per-tenant sets if any.
+pub struct Backends {
+    pub bans: ByIpFamily<Box<dyn Backend>>,
+    pub watch: Option<ByIpFamily<Box<dyn Backend>>>,
+    pub ports: Vec<(u16, ByIpFamily<Box<dyn Backend>>)>,
+    pub tenants: Vec<(Tenant, ByIpFamily<Box<dyn Backend>>)>,
+}
+
+impl Backends {
+    fn get(&mut self, net: MaskedIpAddr, sets: Sets) -> Result<&mut dyn Backend, BackendError> {
+        self.get_family(net.family(), sets)
+    }
+
+    fn get_family(
+        &mut self,
+        family: IpFamily,
+        sets: Sets,
+    ) -> Result<&mut dyn Backend, BackendError> {
+        let sets = match sets {
+            Sets::Bans => &mut self.bans,
+            Sets::Watch => self.watch.as_mut().ok_or("no watch sets")?,
+            Sets::Port(port) => self
+                .ports
+                .iter_mut()
+                .find(|(p, _)| *p == port)
+                .map(|(_, sets)| sets)
+                .ok_or_else(|| format!("no sets for port {port}"))?,
+            Sets::Tenant(tenant) => self
+                .tenants
+                .iter_mut()
+                .find(|(t, _)| *t == tenant)
+                .map(|(_, sets)| sets)
+                .ok_or_else(|| format!("no sets for tenant {tenant}"))?,
+        };
+        Ok(sets.by_family_mut(family).as_mut())
+    }
+}
+
+/// Returned by queued adds that were dropped, see [`QueueOverflow::Drop`].
+#[derive(Debug)]
+pub struct QueueFull;
+
+impl fmt::Display for QueueFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("netlink queue is full")
+    }
+}
+
+impl Error for QueueFull {}
+
+type Reply<T = bool> = SyncSender<Result<T, BackendError>>;
+
+enum Request {
+    Add {
+        net: MaskedIpAddr,
+        sets: Sets,
+        timeout: u32,
+        comment: Option<String>,
+    },
+    Test {
+        net: MaskedIpAddr,
+        sets: Sets,
+        reply: Reply,
+    },
+    Del {
+        net: MaskedIpAddr,
+        sets: Sets,
+        reply: Reply,
+    },
+    List {
+        family: IpFamily,
+        sets: Sets,
+        reply: Reply<Vec<MaskedIpAddr>>,
+    },
+}
+
+/// The outcome of a queued add.
+pub struct Report {
+    pub net: MaskedIpAddr,
+    pub sets: Sets,
+    /// Time from sending the request until the kernel acknowledged it,
+    /// including retries, but not the time in the queue.
+    pub latency: Duration,
+    pub retries: u32,
+    pub result: Result<bool, BackendError>,
+}
+
+/// Makes the ipset changes on a separate thread, so that reading lines never
+/// waits for netlink round trips. Adds are queued and their outcome is
+/// reported later. Tests, deletes and listings wait for their answer, behind
+/// the queued adds.
+pub struct NetlinkQueue {
+    thread: Option<JoinHandle<()>>,
+    reports: Receiver<Report>,
+    depth: Arc<AtomicUsize>,
+}
+
+impl NetlinkQueue {
+    /// Opens the backends on the writer thread, so that they do not have to
+    /// be `Send`, and returns backends that queue changes for it. The thread
+    /// is scheduled by `scheduling` first.
+    pub fn spawn<F>(
+        open: F,
+        capacity: usize,
+        overflow: QueueOverflow,
+        scheduling: Scheduling,
+    ) -> Result<(NetlinkQueue, Backends), LeroyError>
+    where
+        F: FnOnce() -> Result<Backends, LeroyError> + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let (report_sender, reports) = mpsc::channel();
+        let (opened_sender, opened_receiver) = mpsc::sync_channel(1);
+        let depth = Arc::new(AtomicUsize::new(0));
+        let thread = thread::Builder::new()
+            .name("netlink-writer".to_owned())
+            .spawn({
+                let depth = Arc::clone(&depth);
+                move || match scheduling.apply("netlink writer").and_then(|()| open()) {
+                    Ok(backends) => {
+                        let has_watch = backends.watch.is_some();
+                        let ports: Vec<u16> =
+                            backends.ports.iter().map(|(port, _)| *port).collect();
+                        let tenants: Vec<Tenant> =
+                            backends.tenants.iter().map(|(tenant, _)| *tenant).collect();
+                        let _ = opened_sender.send(Ok((has_watch, ports, tenants)));
+                        write(backends, receiver, report_sender, depth);
+                    }
+                    Err(err) => {
+                        let _ = opened_sender.send(Err(err));
+                    }
+                }
+            })?;
+        let (has_watch, ports, tenants) = opened_receiver
+            .recv()
+            .map_err(|_| LeroyError::Netlink("Netlink writer has stopped".to_owned()))??;
+
+        let queued = |sets| ByIpFamily::<Box<dyn Backend>> {
+            ipv4: Box::new(QueuedBackend {
+                sender: sender.clone(),
+                depth: Arc::clone(&depth),
+                overflow,
+                family: IpFamily::V4,
+                sets,
+            }),
+            ipv6: Box::new(QueuedBackend {
+                sender: sender.clone(),
+                depth: Arc::clone(&depth),
+                overflow,
+                family: IpFamily::V6,
+                sets,
+            }),
+        };
+        let backends = Backends {
+            bans: queued(Sets::Bans),
+            watch: has_watch.then(|| queued(Sets::Watch)),
+            ports: ports
+                .into_iter()
+                .map(|port| (port, queued(Sets::Port(port))))
+                .collect(),
+            tenants: tenants
+                .into_iter()
+                .map(|tenant| (tenant, queued(Sets::Tenant(tenant))))
+                .collect(),
+        };
+        Ok((
+            NetlinkQueue {
+                thread: Some(thread),
+                reports,
+                depth,
+            },
+            backends,
+        ))
+    }
+
+    /// The number of queued requests.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// The outcomes of the adds completed since the previous call.
+    pub fn reports(&self) -> impl Iterator<Item = Report> + '_ {
+        self.reports.try_iter()
+    }
+}
+
+impl Drop for NetlinkQueue {
+    /// Waits until all queued changes are made. The queued backends must be
+    /// dropped first.
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn write(
+    mut backends: Backends,
+    receiver: Receiver<Request>,
+    reports: Sender<Report>,
+    depth: Arc<AtomicUsize>,
+) {
+    for request in receiver {
+        depth.fetch_sub(1, Ordering::Relaxed);
+        match request {
+            Request::Add {
+                net,
+                sets,
+                timeout,
+                comment,
+            } => {
+                let start = Instant::now();
+                let (result, retries) = match backends.get(net, sets) {
+                    Ok(backend) => add_with_retry(backend, net, timeout, comment.as_deref()),
+                    Err(err) => (Err(err), 0),
+                };
+                let _ = reports.send(Report {
+                    net,
+                    sets,
+                    latency: start.elapsed(),
+                    retries,
+                    result,
+                });
+            }
+            Request::Test { net, sets, reply } => {
+                let _ = reply.send(backends.get(net, sets).and_then(|b| b.test(net)));
+            }
+            Request::Del { net, sets, reply } => {
+                let _ = reply.send(backends.get(net, sets).and_then(|b| b.del(net)));
+            }
+            Request::List {
+                family,
+                sets,
+                reply,
+            } => {
+                let _ = reply.send(backends.get_family(family, sets).and_then(|b| b.list()));
+            }
+        }
+    }
+}
+
+/// Sends the changes of one set to the writer thread. Adds always return
+/// `true`, because they are not made yet.
+struct QueuedBackend {
+    sender: SyncSender<Request>,
+    depth: Arc<AtomicUsize>,
+    overflow: QueueOverflow,
+    family: IpFamily,
+    sets: Sets,
+}
+
+impl QueuedBackend {
+    fn send(&self, request: Request) -> Result<(), BackendError> {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(request).map_err(|_| {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+            "netlink writer has stopped".into()
+        })
+    }
+
+    fn request<T>(&self, request: impl FnOnce(Reply<T>) -> Request) -> Result<T, BackendError> {
+        let (reply, answer) = mpsc::sync_channel(1);
+        self.send(request(reply))?;
+        answer
+            .recv()
+            .map_err(|_| BackendError::from("netlink writer has stopped"))?
+    }
+
+    fn queue_add(
+        &mut self,
+        net: MaskedIpAddr,
+        timeout: u32,
+        comment: Option<String>,
+    ) -> Result<bool, BackendError> {
+        let request = Request::Add {
+            net,
+            sets: self.sets,
+            timeout,
+            comment,
+        };
+        match self.overflow {
+            QueueOverflow::Block => self.send(request)?,
+            QueueOverflow::Drop => {
+                self.depth.fetch_add(1, Ordering::Relaxed);
+                match self.sender.try_send(request) {
+                    Ok(()) => {}
+                    Err(err) => {
+                        self.depth.fetch_sub(1, Ordering::Relaxed);
+                        return Err(match err {
+                            TrySendError::Full(_) => Box::new(QueueFull),
+                            TrySendError::Disconnected(_) => "netlink writer has stopped".into(),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Backend for QueuedBackend {
+    fn test(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+        let sets = self.sets;
+        self.request(|reply| Request::Test { net, sets, reply })
+    }
+
+    fn add(&mut self, net: MaskedIpAddr, timeout: u32) -> Result<bool, BackendError> {
+        self.queue_add(net, timeout, None)
+    }
+
+    fn add_with_comment(
+        &mut self,
+        net: MaskedIpAddr,
+        timeout: u32,
+        comment: String,
+    ) -> Result<bool, BackendError> {
+        self.queue_add(net, timeout, Some(comment))
+    }
+
+    fn del(&mut self, net: MaskedIpAddr) -> Result<bool, BackendError> {
+        let sets = self.sets;
+        self.request(|reply| Request::Del { net, sets, reply })
+    }
+
+    fn list(&mut self) -> Result<Vec<MaskedIpAddr>, BackendError> {
+        let (family, sets) = (self.family, self.sets);
+        self.request(|reply| Request::List {
+            family,
+            sets,
+            reply,
+        })
+    }
+}
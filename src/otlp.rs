@@ -0,0 +1,149 @@
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use serde_json::{json, Value};
+
+use crate::metrics::{Metrics, LATENCY_BUCKETS_MICROS};
+
+/// Exports metrics to an OpenTelemetry collector with OTLP over HTTP, using
+/// the JSON encoding. Counters are cumulative since startup. Requests are
+/// sent from a background thread, so that a slow collector never stalls
+/// banning.
+pub struct Otlp {
+    sender: SyncSender<String>,
+    period: Duration,
+    export_start: Instant,
+    start_time: SystemTime,
+}
+
+impl Otlp {
+    /// `endpoint` is the full URL, like `http://localhost:4318/v1/metrics`.
+    pub fn new(endpoint: String, period: Duration) -> std::io::Result<Otlp> {
+        let (sender, receiver) = mpsc::sync_channel::<String>(1);
+        thread::Builder::new()
+            .name("otlp".to_owned())
+            .spawn(move || {
+                let agent = ureq::AgentBuilder::new()
+                    .timeout(Duration::from_secs(10))
+                    .build();
+                for body in receiver {
+                    if let Err(err) = agent
+                        .post(&endpoint)
+                        .set("Content-Type", "application/json")
+                        .send_string(&body)
+                    {
+                        warn!("Failed to export metrics to {endpoint}: {err}");
+                    }
+                }
+            })?;
+        Ok(Otlp {
+            sender,
+            period,
+            export_start: Instant::now(),
+            start_time: SystemTime::now(),
+        })
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.export_start.elapsed() >= self.period
+    }
+
+    pub fn export(&mut self, metrics: &Metrics, gauges: &[(&str, u64)]) {
+        let start = unix_nanos(self.start_time);
+        let now = unix_nanos(SystemTime::now());
+
+        // 64 bit integers are encoded as strings in OTLP/JSON.
+        let counters = metrics.counters().into_iter().map(|(name, value)| {
+            json!({
+                "name": format!("leroyjenkins.{name}"),
+                "sum": {
+                    "dataPoints": [{
+                        "asInt": value.to_string(),
+                        "startTimeUnixNano": start,
+                        "timeUnixNano": now,
+                    }],
+                    "aggregationTemporality": 2, // Cumulative
+                    "isMonotonic": true,
+                },
+            })
+        });
+        let gauges = gauges.iter().map(|(name, value)| {
+            json!({
+                "name": format!("leroyjenkins.{name}"),
+                "gauge": {
+                    "dataPoints": [{
+                        "asInt": value.to_string(),
+                        "timeUnixNano": now,
+                    }],
+                },
+            })
+        });
+        let by_reason = json!({
+            "name": "leroyjenkins.bans_by_reason",
+            "sum": {
+                "dataPoints": metrics.bans_by_reason.iter().map(|(reason, count)| json!({
+                    "attributes": [{
+                        "key": "reason",
+                        "value": { "stringValue": reason },
+                    }],
+                    "asInt": count.to_string(),
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                })).collect::<Vec<Value>>(),
+                "aggregationTemporality": 2, // Cumulative
+                "isMonotonic": true,
+            },
+        });
+        let latency = &metrics.ipset_latency;
+        let histogram = json!({
+            "name": "leroyjenkins.ipset_latency",
+            "unit": "s",
+            "histogram": {
+                "dataPoints": [{
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": latency.count.to_string(),
+                    "sum": latency.sum.as_secs_f64(),
+                    "bucketCounts": latency.buckets.map(|count| count.to_string()),
+                    "explicitBounds": LATENCY_BUCKETS_MICROS.map(|micros| micros as f64 / 1e6),
+                }],
+                "aggregationTemporality": 2, // Cumulative
+            },
+        });
+        let body = json!({
+            "resourceMetrics": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": "leroyjenkins" },
+                    }],
+                },
+                "scopeMetrics": [{
+                    "scope": { "name": "leroyjenkins" },
+                    "metrics": counters
+                        .chain(gauges)
+                        .chain([by_reason, histogram])
+                        .collect::<Vec<Value>>(),
+                }],
+            }],
+        });
+
+        match self.sender.try_send(body.to_string()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Skipped metrics export, previous one is pending"),
+            Err(TrySendError::Disconnected(_)) => warn!("Metrics exporter has stopped"),
+        }
+        self.export_start = Instant::now();
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
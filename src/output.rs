@@ -0,0 +1,199 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Write},
+    os::unix::io::{AsFd, FromRawFd, OwnedFd, RawFd},
+    str::FromStr,
+};
+
+use log::error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{hooks::BanEvent, leroy_config::LeroyConfig};
+
+/// Where --emit-bans writes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EmitTarget {
+    Stdout,
+    /// A file descriptor inherited from the parent, like `3` for `3>bans`.
+    Fd(RawFd),
+}
+
+impl fmt::Display for EmitTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmitTarget::Stdout => f.write_str("stdout"),
+            EmitTarget::Fd(fd) => write!(f, "{fd}"),
+        }
+    }
+}
+
+impl FromStr for EmitTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<EmitTarget, String> {
+        match s {
+            "stdout" => Ok(EmitTarget::Stdout),
+            _ => match s.parse() {
+                Ok(fd) if fd > 2 => Ok(EmitTarget::Fd(fd)),
+                _ => Err(format!(
+                    "expected stdout or a file descriptor above 2, not {s:?}"
+                )),
+            },
+        }
+    }
+}
+
+impl Serialize for EmitTarget {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for EmitTarget {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<EmitTarget, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A buffered output stream, which is given up on its first error, like a
+/// closed pipe, so that banning goes on without it.
+struct Stream {
+    name: &'static str,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Stream {
+    fn new(name: &'static str, fd: OwnedFd) -> Stream {
+        Stream {
+            name,
+            writer: Some(BufWriter::new(File::from(fd))),
+        }
+    }
+
+    fn write(&mut self, f: impl FnOnce(&mut BufWriter<File>) -> io::Result<()>) {
+        if let Some(ref mut writer) = self.writer {
+            if let Err(err) = f(writer) {
+                error!("Stopped writing {}: {err}", self.name);
+                self.writer = None;
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.write(|writer| writer.flush());
+    }
+}
+
+/// The output of --emit-bans, --passthrough and --dry-run-json, which lets
+/// leroyjenkins sit inline in a pipeline. Writes are buffered until
+/// [`Output::flush`], once per batch of lines. Whatever goes to stdout shares
+/// one stream, so that the order of lines is kept.
+#[derive(Default)]
+pub struct Output {
+    bans: Option<Stream>,
+    bans_json: bool,
+    /// Whether bans go to the --passthrough stream instead.
+    bans_to_passthrough: bool,
+    passthrough: Option<Stream>,
+    /// Whether --emit-bans goes to stdout, so that --dry-run-json shares it.
+    bans_to_stdout: bool,
+    /// The --dry-run-json stream, if stdout is not used otherwise.
+    dry_run: Option<Stream>,
+}
+
+impl Output {
+    pub fn open(config: &LeroyConfig) -> io::Result<Output> {
+        let stdout = || io::stdout().as_fd().try_clone_to_owned();
+        let mut output = Output {
+            bans_json: config.emit_bans_json,
+            bans_to_passthrough: config.passthrough && config.emit_bans == Some(EmitTarget::Stdout),
+            bans_to_stdout: config.emit_bans == Some(EmitTarget::Stdout),
+            ..Output::default()
+        };
+        if config.passthrough {
+            output.passthrough = Some(Stream::new("--passthrough", stdout()?));
+        } else if config.dry_run_json && !output.bans_to_stdout {
+            output.dry_run = Some(Stream::new("--dry-run-json", stdout()?));
+        }
+        output.bans = match config.emit_bans {
+            Some(EmitTarget::Stdout) if output.bans_to_passthrough => None,
+            Some(EmitTarget::Stdout) => Some(Stream::new("--emit-bans", stdout()?)),
+            Some(EmitTarget::Fd(fd)) => {
+                // Fails on a descriptor that was not passed, instead of
+                // taking over one that is opened later.
+                if unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+                    let err = io::Error::last_os_error();
+                    return Err(io::Error::new(
+                        err.kind(),
+                        format!("--emit-bans {fd} is not an open file descriptor: {err}"),
+                    ));
+                }
+                Some(Stream::new("--emit-bans", unsafe {
+                    OwnedFd::from_raw_fd(fd)
+                }))
+            }
+            None => None,
+        };
+        Ok(output)
+    }
+
+    pub fn ban(&mut self, event: &BanEvent) {
+        let json = self.bans_json;
+        let stream = if self.bans_to_passthrough {
+            &mut self.passthrough
+        } else {
+            &mut self.bans
+        };
+        let Some(stream) = stream else {
+            return;
+        };
+        stream.write(|writer| {
+            if json {
+                serde_json::to_writer(&mut *writer, event)?;
+            } else {
+                write!(writer, "{}", event.ip)?;
+            }
+            writer.write_all(b"\n")
+        });
+    }
+
+    /// Writes a would-be ban of --dry-run-json.
+    pub fn dry_run_ban(&mut self, event: &BanEvent) {
+        let stream = if self.passthrough.is_some() {
+            &mut self.passthrough
+        } else if self.bans_to_stdout {
+            &mut self.bans
+        } else {
+            &mut self.dry_run
+        };
+        let Some(stream) = stream else {
+            return;
+        };
+        stream.write(|writer| {
+            serde_json::to_writer(&mut *writer, event)?;
+            writer.write_all(b"\n")
+        });
+    }
+
+    /// Echoes an input line that did not lead to a ban.
+    pub fn pass_through(&mut self, line: &[u8]) {
+        if let Some(ref mut stream) = self.passthrough {
+            stream.write(|writer| {
+                writer.write_all(line)?;
+                writer.write_all(b"\n")
+            });
+        }
+    }
+
+    pub fn flush(&mut self) {
+        for stream in [&mut self.bans, &mut self.passthrough, &mut self.dry_run]
+            .into_iter()
+            .flatten()
+        {
+            stream.flush();
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::{fmt, net::SocketAddr, str};
+
+use log::error;
+
+use crate::log_limiter::LogLimiter;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ParseErrorKind {
+    Empty,
+    NotAscii,
+    /// An address with a port like `192.0.2.1:443`, usually a sign of a
+    /// wrong field in the pipeline.
+    WithPort,
+    Invalid,
+}
+
+impl ParseErrorKind {
+    const ALL: [ParseErrorKind; 4] = [
+        ParseErrorKind::Empty,
+        ParseErrorKind::NotAscii,
+        ParseErrorKind::WithPort,
+        ParseErrorKind::Invalid,
+    ];
+
+    fn classify(line: &[u8]) -> ParseErrorKind {
+        if line.trim_ascii().is_empty() {
+            ParseErrorKind::Empty
+        } else if !line.is_ascii() {
+            ParseErrorKind::NotAscii
+        } else if str::from_utf8(line).is_ok_and(|line| line.parse::<SocketAddr>().is_ok()) {
+            ParseErrorKind::WithPort
+        } else {
+            ParseErrorKind::Invalid
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            ParseErrorKind::Empty => "empty",
+            ParseErrorKind::NotAscii => "not ascii",
+            ParseErrorKind::WithPort => "with port",
+            ParseErrorKind::Invalid => "invalid",
+        }
+    }
+}
+
+/// Logs only the first few unparseable lines of each period and counts the
+/// rest by kind, so that a misconfigured pipeline does not flood the log at
+/// the input line rate.
+pub struct ParseErrors {
+    counts: [u64; ParseErrorKind::ALL.len()],
+    max_examples: u64,
+}
+
+impl ParseErrors {
+    pub fn new(max_examples: u64) -> ParseErrors {
+        ParseErrors {
+            counts: [0; ParseErrorKind::ALL.len()],
+            max_examples,
+        }
+    }
+
+    /// Logs the error as an example if there are examples left in this
+    /// period and `limiter` allows it.
+    pub fn record(&mut self, line: &[u8], err: impl fmt::Display, limiter: &mut LogLimiter) {
+        let kind = ParseErrorKind::classify(line);
+        if self.total() < self.max_examples && limiter.allow() {
+            error!(
+                "Error parsing IP from {:?} ({}): {}",
+                String::from_utf8_lossy(line),
+                kind.name(),
+                err
+            );
+        }
+        self.counts[kind as usize] += 1;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The number of errors that were counted but not logged.
+    pub fn suppressed(&self) -> u64 {
+        self.total().saturating_sub(self.max_examples)
+    }
+
+    pub fn reset(&mut self) {
+        self.counts = Default::default();
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        for kind in ParseErrorKind::ALL {
+            let count = self.counts[kind as usize];
+            if count > 0 {
+                if !first {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{count} {}", kind.name())?;
+                first = false;
+            }
+        }
+        Ok(())
+    }
+}
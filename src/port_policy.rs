@@ -0,0 +1,73 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// The ipsets that bans caused by events on a destination port go to, see
+/// --port-ipset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortIpsets {
+    pub port: u16,
+    pub ipv4_name: String,
+    pub ipv6_name: String,
+}
+
+impl FromStr for PortIpsets {
+    type Err = String;
+
+    /// Parses `PORT=IPV4_SET,IPV6_SET`, e.g. `22=leroy-ssh4,leroy-ssh6`.
+    fn from_str(s: &str) -> Result<PortIpsets, String> {
+        let (port, names): (u16, String) = parse_port_value(s)?;
+        match names.split_once(',') {
+            Some((ipv4_name, ipv6_name)) if !ipv4_name.is_empty() && !ipv6_name.is_empty() => {
+                Ok(PortIpsets {
+                    port,
+                    ipv4_name: ipv4_name.to_owned(),
+                    ipv6_name: ipv6_name.to_owned(),
+                })
+            }
+            _ => Err(format!("expected PORT=IPV4_SET,IPV6_SET, got {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for PortIpsets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={},{}", self.port, self.ipv4_name, self.ipv6_name)
+    }
+}
+
+impl Serialize for PortIpsets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortIpsets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PortIpsets, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses `PORT=VALUE`, e.g. `22=3`.
+pub fn parse_port_value<T>(s: &str) -> Result<(u16, T), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let (port, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected PORT=VALUE, got {s:?}"))?;
+    Ok((
+        port.parse()
+            .map_err(|err| format!("invalid port {port:?}: {err}"))?,
+        value
+            .parse()
+            .map_err(|err| format!("invalid value {value:?}: {err}"))?,
+    ))
+}
+
+pub fn parse_port_duration(s: &str) -> Result<(u16, Duration), String> {
+    parse_port_value::<humantime::Duration>(s).map(|(port, time)| (port, time.into()))
+}
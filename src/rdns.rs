@@ -0,0 +1,86 @@
+use std::{
+    ffi::CStr,
+    mem,
+    net::IpAddr,
+    ptr,
+    sync::{
+        mpsc::{Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::event_log::Event;
+
+/// Looks up the PTR record of the address with the system resolver, which
+/// may block for a long time.
+pub fn reverse_lookup(ip: IpAddr) -> Option<String> {
+    // SAFETY: The socket addresses are fully initialized (zeroed, then the
+    // relevant fields set), their lengths are passed along, and the host
+    // buffer is NUL terminated by getnameinfo on success.
+    unsafe {
+        let mut storage: libc::sockaddr_storage = mem::zeroed();
+        let len = match ip {
+            IpAddr::V4(ip) => {
+                let addr = &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in>();
+                addr.sin_family = libc::AF_INET as libc::sa_family_t;
+                addr.sin_addr.s_addr = u32::from_ne_bytes(ip.octets());
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            IpAddr::V6(ip) => {
+                let addr = &mut *ptr::addr_of_mut!(storage).cast::<libc::sockaddr_in6>();
+                addr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                addr.sin6_addr.s6_addr = ip.octets();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        let mut host = [0 as libc::c_char; libc::NI_MAXHOST as usize];
+        let result = libc::getnameinfo(
+            ptr::addr_of!(storage).cast::<libc::sockaddr>(),
+            len as libc::socklen_t,
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        );
+        (result == 0).then(|| CStr::from_ptr(host.as_ptr()).to_string_lossy().into_owned())
+    }
+}
+
+/// Spawns threads that add hostnames to ban events of single addresses,
+/// before passing all events on.
+pub fn spawn_resolvers(
+    threads: usize,
+    receiver: Receiver<Event>,
+    sender: SyncSender<Event>,
+) -> std::io::Result<()> {
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..threads.max(1) {
+        let receiver = Arc::clone(&receiver);
+        let sender = sender.clone();
+        thread::Builder::new()
+            .name("rdns".to_owned())
+            .spawn(move || resolve_events(&receiver, &sender))?;
+    }
+    Ok(())
+}
+
+fn resolve_events(receiver: &Mutex<Receiver<Event>>, sender: &SyncSender<Event>) {
+    // The lock is only held while waiting for the next event.
+    while let Some(mut event) = receiver.lock().ok().and_then(|r| r.recv().ok()) {
+        if let Event::Ban {
+            ip,
+            ref mut hostname,
+            ..
+        } = event
+        {
+            if ip.is_single_addr() {
+                *hostname = reverse_lookup(ip.addr());
+            }
+        }
+        if sender.send(event).is_err() {
+            break;
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::{
+    fmt, fs,
+    io::{self, Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    str,
+    sync::{
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use log::debug;
+
+/// How long to wait for each nameserver.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long names without addresses are remembered, if their response has
+/// no TTL to go by.
+pub const NEGATIVE_TTL: Duration = Duration::from_secs(60);
+
+/// How many names can wait for a resolver thread.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// How many events of a name are kept until it is resolved. More are
+/// dropped.
+pub const MAX_PENDING_EVENTS: usize = 64;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// A host name from a line of input, like `bot.example.com`, see
+/// --resolve-hostnames. Lowercase, without a trailing dot.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Hostname(Box<str>);
+
+impl Hostname {
+    /// Accepts letters, digits, `-` and `_` in up to 63 bytes per label and
+    /// 253 in total. The last label must not be numeric, so that a mistyped
+    /// address like `192.0.2.256` is not taken for a name.
+    pub(crate) fn from_bytes(s: &[u8]) -> Option<Hostname> {
+        let s = s.strip_suffix(b".").unwrap_or(s);
+        if s.is_empty() || s.len() > 253 {
+            return None;
+        }
+        let valid_label = |label: &[u8]| {
+            (1..=63).contains(&label.len())
+                && label
+                    .iter()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_'))
+        };
+        if !s.split(|c| *c == b'.').all(valid_label)
+            || s.rsplit(|c| *c == b'.')
+                .next()
+                .is_some_and(|tld| tld.iter().all(u8::is_ascii_digit))
+        {
+            return None;
+        }
+        let name = str::from_utf8(s).ok()?.to_ascii_lowercase();
+        Some(Hostname(name.into_boxed_str()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Hostname {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The A and AAAA records of a name, and how long they may be cached.
+#[derive(Debug)]
+pub struct Resolved {
+    pub addrs: Vec<IpAddr>,
+    pub ttl: Duration,
+}
+
+/// The nameservers of /etc/resolv.conf, or the local one. Read before
+/// --chroot.
+pub fn nameservers() -> Vec<SocketAddr> {
+    let nameservers: Vec<SocketAddr> = fs::read_to_string("/etc/resolv.conf")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .collect();
+    if nameservers.is_empty() {
+        vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53)]
+    } else {
+        nameservers
+    }
+}
+
+/// Looks up the A and AAAA records of `name`, asking the nameservers in
+/// turn. Unlike `getaddrinfo`, this gets the TTL of the records, but skips
+/// /etc/hosts and search domains.
+pub fn lookup(name: &Hostname, nameservers: &[SocketAddr]) -> io::Result<Resolved> {
+    let mut resolved = Resolved {
+        addrs: Vec::new(),
+        ttl: Duration::MAX,
+    };
+    for record_type in [TYPE_A, TYPE_AAAA] {
+        let mut last_err = None;
+        let answer =
+            nameservers
+                .iter()
+                .find_map(|nameserver| match query(name, record_type, *nameserver) {
+                    Ok(answer) => Some(answer),
+                    Err(err) => {
+                        debug!("Failed to resolve {name} with {nameserver}: {err}");
+                        last_err = Some(err);
+                        None
+                    }
+                });
+        let Some((addrs, ttl)) = answer else {
+            return Err(last_err.unwrap_or_else(|| io::Error::other("no nameservers")));
+        };
+        resolved.addrs.extend(addrs);
+        resolved.ttl = resolved.ttl.min(ttl.unwrap_or(NEGATIVE_TTL));
+    }
+    Ok(resolved)
+}
+
+/// Returns the addresses and the lowest TTL of the answer. Names that do
+/// not exist have no addresses.
+fn query(
+    name: &Hostname,
+    record_type: u16,
+    nameserver: SocketAddr,
+) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+    // Bound by connecting, because binding is not allowed with --seccomp.
+    let domain = match nameserver {
+        SocketAddr::V4(_) => libc::AF_INET,
+        SocketAddr::V6(_) => libc::AF_INET6,
+    };
+    let fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = UdpSocket::from(unsafe { OwnedFd::from_raw_fd(fd) });
+    socket.connect(nameserver)?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+
+    let id = fastrand::u16(..);
+    let mut request = Vec::with_capacity(name.as_str().len() + 18);
+    request.extend(id.to_be_bytes());
+    // Recursion desired, one question.
+    request.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.as_str().split('.') {
+        request.push(label.len() as u8);
+        request.extend(label.as_bytes());
+    }
+    request.push(0);
+    request.extend(record_type.to_be_bytes());
+    request.extend(CLASS_IN.to_be_bytes());
+    socket.send(&request)?;
+
+    let mut response = [0; 1232];
+    loop {
+        let len = socket.recv(&mut response)?;
+        // Stray responses to earlier queries from the same port.
+        if len >= 12 && response[..2] == id.to_be_bytes() {
+            return parse_response(&response[..len], record_type);
+        }
+    }
+}
+
+fn parse_response(
+    response: &[u8],
+    record_type: u16,
+) -> io::Result<(Vec<IpAddr>, Option<Duration>)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_owned());
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        response
+            .get(pos..pos + 2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+            .ok_or_else(|| invalid("truncated response"))
+    };
+    let flags = u16_at(2)?;
+    if flags & 0x8000 == 0 {
+        return Err(invalid("not a response"));
+    }
+    // Cut off answers could hide addresses, which matter for the limit on
+    // addresses per name.
+    if flags & 0x0200 != 0 {
+        return Err(invalid("response truncated, too many records"));
+    }
+    match flags & 0x000f {
+        0 => {}
+        // The name does not exist.
+        3 => return Ok((Vec::new(), None)),
+        rcode => return Err(io::Error::other(format!("server failure, rcode {rcode}"))),
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let skip_name = |mut pos: usize| -> io::Result<usize> {
+        loop {
+            let len = *response.get(pos).ok_or_else(|| invalid("truncated name"))?;
+            match len {
+                0 => return Ok(pos + 1),
+                // A pointer to an earlier name.
+                len if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+                len => pos += 1 + usize::from(len),
+            }
+        }
+    };
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(pos)? + 4;
+    }
+    let mut addrs = Vec::new();
+    let mut ttl = None;
+    for _ in 0..answers {
+        pos = skip_name(pos)?;
+        let answer_type = u16_at(pos)?;
+        let class = u16_at(pos + 2)?;
+        let record_ttl = (u32::from(u16_at(pos + 4)?) << 16) | u32::from(u16_at(pos + 6)?);
+        let len = usize::from(u16_at(pos + 8)?);
+        let data = response
+            .get(pos + 10..pos + 10 + len)
+            .ok_or_else(|| invalid("truncated record"))?;
+        pos += 10 + len;
+        if class != CLASS_IN {
+            continue;
+        }
+        // Includes the CNAME records that lead to the addresses.
+        let record_ttl = Duration::from_secs(u64::from(record_ttl));
+        ttl = Some(ttl.map_or(record_ttl, |ttl: Duration| ttl.min(record_ttl)));
+        match (answer_type, data.len()) {
+            (TYPE_A, 4) if record_type == TYPE_A => {
+                addrs.push(IpAddr::V4(Ipv4Addr::new(
+                    data[0], data[1], data[2], data[3],
+                )));
+            }
+            (TYPE_AAAA, 16) if record_type == TYPE_AAAA => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(data);
+                addrs.push(IpAddr::V6(Ipv6Addr::from(octets)));
+            }
+            _ => {}
+        }
+    }
+    Ok((addrs, ttl))
+}
+
+/// Resolves host names on threads of its own, so that lookups never block
+/// the main loop. Like [`AdminQueue`](crate::admin::AdminQueue), the main
+/// loop polls [`Resolver::as_raw_fd`], which becomes readable when results
+/// are waiting.
+pub struct Resolver {
+    sender: SyncSender<Hostname>,
+    results: Receiver<(Hostname, io::Result<Resolved>)>,
+    wakeup_receiver: UnixStream,
+}
+
+impl Resolver {
+    pub fn spawn(threads: usize, nameservers: Vec<SocketAddr>) -> io::Result<Resolver> {
+        let (sender, receiver) = mpsc::sync_channel::<Hostname>(QUEUE_CAPACITY);
+        let (result_sender, results) = mpsc::channel();
+        let (wakeup_sender, wakeup_receiver) = UnixStream::pair()?;
+        wakeup_receiver.set_nonblocking(true)?;
+        // A full buffer wakes up the main loop anyway.
+        wakeup_sender.set_nonblocking(true)?;
+        let nameservers = Arc::new(nameservers);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            let result_sender = result_sender.clone();
+            let mut wakeup = wakeup_sender.try_clone()?;
+            let nameservers = Arc::clone(&nameservers);
+            thread::Builder::new()
+                .name("resolver".to_owned())
+                .spawn(move || {
+                    // The lock is only held while waiting for the next name.
+                    while let Some(name) = receiver.lock().ok().and_then(|r| r.recv().ok()) {
+                        let result = lookup(&name, &nameservers);
+                        if result_sender.send((name, result)).is_err() {
+                            break;
+                        }
+                        let _ = wakeup.write_all(&[0]);
+                    }
+                })?;
+        }
+        Ok(Resolver {
+            sender,
+            results,
+            wakeup_receiver,
+        })
+    }
+
+    /// Queues `name` for resolution. Returns `false` if too many names are
+    /// waiting already.
+    pub fn resolve(&self, name: Hostname) -> bool {
+        match self.sender.try_send(name) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => false,
+        }
+    }
+
+    /// Returns the lookups that have finished.
+    pub fn take_results(&mut self) -> Vec<(Hostname, io::Result<Resolved>)> {
+        let mut buf = [0; 64];
+        while matches!(self.wakeup_receiver.read(&mut buf), Ok(n) if n > 0) {}
+        self.results.try_iter().collect()
+    }
+}
+
+impl AsRawFd for Resolver {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup_receiver.as_raw_fd()
+    }
+}
@@ -0,0 +1,501 @@
+#[cfg(target_os = "linux")]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+use std::{io, path::Path};
+
+use log::info;
+
+use crate::{leroy_config::LeroyConfig, LeroyError};
+
+/// From linux/capability.h, which libc does not have.
+#[cfg(target_os = "linux")]
+const CAP_SETPCAP: u32 = 8;
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_IPC_LOCK: u32 = 14;
+const CAP_SYS_NICE: u32 = 23;
+
+/// The capabilities that leroyjenkins needs after starting with `config`:
+/// changing the ipsets, and what its other flags need.
+fn needed_capabilities(config: &LeroyConfig) -> u64 {
+    let mut caps = 0;
+    let mut keep = |cap: u32, needed: bool| {
+        if needed {
+            caps |= 1 << cap;
+        }
+    };
+    keep(CAP_NET_ADMIN, config.manages_ipsets());
+    keep(CAP_IPC_LOCK, config.lock_memory);
+    keep(
+        CAP_SYS_NICE,
+        config.sched_fifo.is_some() || config.nice.is_some_and(|nice| nice < 0),
+    );
+    keep(
+        CAP_NET_BIND_SERVICE,
+        [
+            config.health_listen,
+            config.admin_listen,
+            config.cluster_listen,
+        ]
+        .into_iter()
+        .flatten()
+        .any(|addr| addr.port() < 1024),
+    );
+    caps
+}
+
+fn capability_names(caps: u64) -> String {
+    let names: Vec<&str> = [
+        (CAP_NET_ADMIN, "CAP_NET_ADMIN"),
+        (CAP_IPC_LOCK, "CAP_IPC_LOCK"),
+        (CAP_SYS_NICE, "CAP_SYS_NICE"),
+        (CAP_NET_BIND_SERVICE, "CAP_NET_BIND_SERVICE"),
+    ]
+    .into_iter()
+    .filter(|(cap, _)| caps & (1 << cap) != 0)
+    .map(|(_, name)| name)
+    .collect();
+    if names.is_empty() {
+        "none".to_owned()
+    } else {
+        names.join(", ")
+    }
+}
+
+/// Drops all capabilities that are not needed, see --drop-capabilities, and
+/// changes the root directory and user, see --chroot and --user. Done before
+/// starting any threads, because capabilities are per thread. Threads and
+/// files opened afterwards get the reduced privileges.
+pub fn drop_privileges(config: &LeroyConfig) -> Result<(), LeroyError> {
+    let failed = |what: &str| {
+        let what = what.to_owned();
+        move |err: io::Error| LeroyError::Io(io::Error::new(err.kind(), format!("{what}: {err}")))
+    };
+    let user = config
+        .user
+        .as_deref()
+        .map(|name| lookup_user(name).map_err(failed(&format!("Failed to look up user {name:?}"))))
+        .transpose()?;
+    let caps = needed_capabilities(config)
+        & permitted_capabilities().map_err(failed("Failed to get capabilities"))?;
+    drop_bounding_set(caps).map_err(failed("Failed to drop capabilities"))?;
+    if let Some(ref dir) = config.chroot {
+        chroot(dir).map_err(failed(&format!("Failed to chroot to {dir:?}")))?;
+        info!("Changed root directory to {dir:?}");
+    }
+    if let Some((uid, gid)) = user {
+        set_user(uid, gid).map_err(failed("Failed to change user"))?;
+        info!("Changed user to uid {uid}, gid {gid}");
+    }
+    set_capabilities(caps).map_err(failed("Failed to drop capabilities"))?;
+    info!("Dropped capabilities, kept {}", capability_names(caps));
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default, Copy, Clone)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+#[cfg(target_os = "linux")]
+const CAP_VERSION_3: u32 = 0x2008_0522;
+
+#[cfg(target_os = "linux")]
+fn check(ret: libc::c_long) -> io::Result<()> {
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn permitted_capabilities() -> io::Result<u64> {
+    let mut header = CapHeader {
+        version: CAP_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapData::default(); 2];
+    check(unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) })?;
+    Ok(u64::from(data[0].permitted) | u64::from(data[1].permitted) << 32)
+}
+
+/// Sets the effective and permitted capabilities of the calling thread.
+#[cfg(target_os = "linux")]
+fn set_capabilities(caps: u64) -> io::Result<()> {
+    let mut header = CapHeader {
+        version: CAP_VERSION_3,
+        pid: 0,
+    };
+    let data = [caps as u32, (caps >> 32) as u32].map(|caps| CapData {
+        effective: caps,
+        permitted: caps,
+        inheritable: 0,
+    });
+    check(unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) })
+}
+
+/// Removes the other capabilities from the bounding set, so that they can
+/// not be regained. Needs CAP_SETPCAP, without which there is nothing to
+/// regain anyway.
+#[cfg(target_os = "linux")]
+fn drop_bounding_set(keep: u64) -> io::Result<()> {
+    if permitted_capabilities()? & (1 << CAP_SETPCAP) == 0 {
+        return Ok(());
+    }
+    for cap in 0..64 {
+        if keep & (1 << cap) != 0 {
+            continue;
+        }
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap as libc::c_ulong, 0, 0, 0) } != 0 {
+            let err = io::Error::last_os_error();
+            // Beyond the last capability of this kernel.
+            if err.raw_os_error() == Some(libc::EINVAL) {
+                break;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name).map_err(|_| io::ErrorKind::InvalidInput)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+    let mut result = std::ptr::null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::from_raw_os_error(ret));
+    }
+    if result.is_null() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no such user"));
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[cfg(target_os = "linux")]
+fn chroot(dir: &Path) -> io::Result<()> {
+    let c_dir =
+        CString::new(dir.as_os_str().as_bytes()).map_err(|_| io::ErrorKind::InvalidInput)?;
+    if unsafe { libc::chroot(c_dir.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    std::env::set_current_dir("/")
+}
+
+/// Keeps the permitted capabilities across the change, to drop them to the
+/// needed ones afterwards.
+#[cfg(target_os = "linux")]
+fn set_user(uid: libc::uid_t, gid: libc::gid_t) -> io::Result<()> {
+    let last_error = |ret: libc::c_int| check(libc::c_long::from(ret));
+    last_error(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) })?;
+    last_error(unsafe { libc::setgroups(0, std::ptr::null()) })?;
+    last_error(unsafe { libc::setgid(gid) })?;
+    last_error(unsafe { libc::setuid(uid) })?;
+    last_error(unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn permitted_capabilities() -> io::Result<u64> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_capabilities(_caps: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn drop_bounding_set(_keep: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn lookup_user(_name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn chroot(_dir: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_user(_uid: libc::uid_t, _gid: libc::gid_t) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// The syscalls of reading lines, banning, periodic work, reloading and
+/// shutting down, and of the threads for the other features. Anything else,
+/// like starting programs, fails with ENOSYS, so that libraries fall back to
+/// older syscalls where they can.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+    // Files and sockets
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_renameat2,
+    libc::SYS_unlinkat,
+    libc::SYS_mkdirat,
+    libc::SYS_faccessat,
+    libc::SYS_readlinkat,
+    libc::SYS_fcntl,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_eventfd2,
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_accept4,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_shutdown,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    // Memory
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mprotect,
+    libc::SYS_brk,
+    libc::SYS_membarrier,
+    // Threads and signals
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_prctl,
+    libc::SYS_gettid,
+    libc::SYS_getpid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigaction,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    // Time and the rest
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    libc::SYS_getrandom,
+    libc::SYS_uname,
+    libc::SYS_prlimit64,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+];
+
+/// The older variants, which only exist on some architectures.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_open,
+    libc::SYS_stat,
+    libc::SYS_lstat,
+    libc::SYS_access,
+    libc::SYS_readlink,
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_unlink,
+    libc::SYS_mkdir,
+    libc::SYS_dup2,
+    libc::SYS_pipe,
+    libc::SYS_poll,
+    libc::SYS_select,
+    libc::SYS_epoll_wait,
+];
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const LEGACY_SYSCALLS: &[libc::c_long] = &[];
+
+/// From linux/audit.h.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// From linux/filter.h and linux/seccomp.h.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const BPF_LD_W_ABS: u16 = 0x20;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const BPF_JMP_JEQ_K: u16 = 0x15;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const BPF_RET_K: u16 = 0x06;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_SET_MODE_FILTER: libc::c_ulong = 1;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_FILTER_FLAG_TSYNC: libc::c_ulong = 1;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+/// Offsets in struct seccomp_data.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_DATA_NR: u32 = 0;
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Restricts all threads to the syscalls that the running daemon needs,
+/// see --seccomp. Done after starting, because opening the ipsets,
+/// listening and spawning programs are not allowed anymore.
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+pub fn install_seccomp() -> Result<(), LeroyError> {
+    let stmt = |code: u16, k: u32| libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    };
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt: 1,
+            jf: 0,
+            k: AUDIT_ARCH,
+        },
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+    for &nr in ALLOWED_SYSCALLS.iter().chain(LEGACY_SYSCALLS) {
+        filter.push(libc::sock_filter {
+            code: BPF_JMP_JEQ_K,
+            jt: 0,
+            jf: 1,
+            k: nr as u32,
+        });
+        filter.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+    }
+    filter.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::ENOSYS as u32));
+    let program = libc::sock_fprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_mut_ptr(),
+    };
+
+    let failed = |err: io::Error| {
+        LeroyError::Io(io::Error::new(
+            err.kind(),
+            format!("Failed to install seccomp filter: {err}"),
+        ))
+    };
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(failed(io::Error::last_os_error()));
+    }
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            SECCOMP_SET_MODE_FILTER,
+            SECCOMP_FILTER_FLAG_TSYNC,
+            &program,
+        )
+    };
+    check(ret).map_err(failed)?;
+    if ret > 0 {
+        return Err(format!("Failed to install seccomp filter in thread {ret}").into());
+    }
+    info!(
+        "Installed seccomp filter, allowing {} syscalls",
+        ALLOWED_SYSCALLS.len() + LEGACY_SYSCALLS.len()
+    );
+    Ok(())
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+pub fn install_seccomp() -> Result<(), LeroyError> {
+    Err(LeroyError::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "seccomp filters are only supported on Linux on x86_64 and aarch64",
+    )))
+}
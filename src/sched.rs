@@ -0,0 +1,113 @@
+use std::io;
+
+use log::info;
+
+use crate::{leroy_config::LeroyConfig, LeroyError};
+
+/// How a thread is scheduled, see --pin-cpu, --sched-fifo and --nice.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Scheduling {
+    pub cpu: Option<usize>,
+    pub fifo_priority: Option<u8>,
+    pub nice: Option<i8>,
+}
+
+impl Scheduling {
+    /// For the thread that reads lines.
+    pub fn for_lines(config: &LeroyConfig) -> Scheduling {
+        Scheduling {
+            cpu: config.pin_cpu,
+            fifo_priority: config.sched_fifo,
+            nice: config.nice,
+        }
+    }
+
+    /// For the --netlink-queue writer thread.
+    pub fn for_netlink(config: &LeroyConfig) -> Scheduling {
+        Scheduling {
+            cpu: config.netlink_pin_cpu,
+            fifo_priority: config.sched_fifo,
+            nice: config.nice,
+        }
+    }
+
+    /// Applies to the calling thread only. Threads that it spawns afterwards
+    /// inherit the settings.
+    pub fn apply(self, thread: &str) -> Result<(), LeroyError> {
+        let failed = |what: String| {
+            move |err: io::Error| {
+                LeroyError::Io(io::Error::new(err.kind(), format!("{what}: {err}")))
+            }
+        };
+        if let Some(cpu) = self.cpu {
+            pin_cpu(cpu).map_err(failed(format!("Failed to pin {thread} to CPU {cpu}")))?;
+            info!("Pinned {thread} to CPU {cpu}");
+        }
+        if let Some(priority) = self.fifo_priority {
+            set_fifo(priority).map_err(failed(format!(
+                "Failed to schedule {thread} with SCHED_FIFO"
+            )))?;
+            info!("Scheduled {thread} with SCHED_FIFO priority {priority}");
+        }
+        if let Some(nice) = self.nice {
+            set_nice(nice).map_err(failed(format!("Failed to set niceness of {thread}")))?;
+            info!("Set niceness of {thread} to {nice}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn pin_cpu(cpu: usize) -> io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if cpu >= libc::CPU_SETSIZE as usize {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+    // Thread 0 is the calling thread.
+    check(unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) })
+}
+
+#[cfg(target_os = "linux")]
+fn set_fifo(priority: u8) -> io::Result<()> {
+    let param = libc::sched_param {
+        sched_priority: libc::c_int::from(priority),
+    };
+    check(unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) })
+}
+
+/// Unlike POSIX, the niceness of a thread on Linux is its own.
+#[cfg(target_os = "linux")]
+fn set_nice(nice: i8) -> io::Result<()> {
+    let tid = unsafe { libc::gettid() };
+    check(unsafe {
+        libc::setpriority(
+            libc::PRIO_PROCESS,
+            tid as libc::id_t,
+            libc::c_int::from(nice),
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_cpu(_cpu: usize) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_fifo(_priority: u8) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_nice(_nice: i8) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
@@ -0,0 +1,178 @@
+use std::{
+    io::{self, Write},
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    anonymize::{AnonymizedIp, IpAnonymizer},
+    event_log::Event,
+    masked_ip::MaskedIpAddr,
+};
+
+const VENDOR: &str = "lichess";
+const PRODUCT: &str = "leroyjenkins";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+fn millis_since_epoch(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis())
+}
+
+/// The event ID, name and severity (0 to 10) for the header.
+fn header(event: &Event) -> (&'static str, &'static str, u8) {
+    match event {
+        Event::Ban { .. } => ("ban", "IP banned", 5),
+        Event::Unban { .. } => ("unban", "IP unbanned", 1),
+    }
+}
+
+/// The address or network to write, as is or truncated, or the hash that
+/// replaces it.
+fn source(event: &Event, anonymizer: Option<&IpAnonymizer>) -> Result<MaskedIpAddr, AnonymizedIp> {
+    match anonymizer.map(|anonymizer| anonymizer.anonymize(event.ip())) {
+        None => Ok(event.ip()),
+        Some(AnonymizedIp::Truncated(net)) => Ok(net),
+        Some(hashed) => Err(hashed),
+    }
+}
+
+/// Writes an ArcSight CEF record (without the syslog prefix).
+pub fn write_cef<W: Write>(
+    writer: &mut W,
+    event: &Event,
+    anonymizer: Option<&IpAnonymizer>,
+) -> io::Result<()> {
+    let (signature, name, severity) = header(event);
+    write!(
+        writer,
+        "CEF:0|{VENDOR}|{PRODUCT}|{VERSION}|{signature}|{name}|{severity}|"
+    )?;
+
+    let mut extension = Vec::new();
+    let timestamp = match event {
+        Event::Ban { timestamp, .. } | Event::Unban { timestamp, .. } => timestamp,
+    };
+    extension.push(("rt", millis_since_epoch(*timestamp).to_string()));
+    match source(event, anonymizer) {
+        Ok(ip) => {
+            match ip.addr() {
+                IpAddr::V4(addr) => extension.push(("src", addr.to_string())),
+                IpAddr::V6(addr) => {
+                    extension.push(("c6a2", addr.to_string()));
+                    extension.push(("c6a2Label", "Source IPv6 Address".to_owned()));
+                }
+            }
+            if !ip.is_single_addr() {
+                extension.push(("cs1", ip.to_string()));
+                extension.push(("cs1Label", "network".to_owned()));
+            }
+        }
+        Err(hashed) => {
+            extension.push(("cs3", hashed.to_string()));
+            extension.push(("cs3Label", "anonymized source".to_owned()));
+        }
+    }
+    match event {
+        Event::Ban {
+            timeout,
+            recidivism,
+            category,
+            reason,
+            hostname,
+            ..
+        } => {
+            extension.push(("act", "ban".to_owned()));
+            extension.push(("cat", category.to_string()));
+            if let Some(reason) = reason {
+                extension.push(("reason", reason.to_string()));
+            }
+            extension.push(("cn1", recidivism.to_string()));
+            extension.push(("cn1Label", "recidivism".to_owned()));
+            extension.push(("cn2", timeout.to_string()));
+            extension.push(("cn2Label", "timeout".to_owned()));
+            if let Some(hostname) = hostname {
+                extension.push(("shost", hostname.clone()));
+            }
+        }
+        Event::Unban { reason, .. } => {
+            extension.push(("act", "unban".to_owned()));
+            extension.push(("reason", reason.to_string()));
+        }
+    }
+
+    for (i, (key, value)) in extension.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b" ")?;
+        }
+        write!(writer, "{key}=")?;
+        for c in value.chars() {
+            match c {
+                '\\' => writer.write_all(b"\\\\")?,
+                '=' => writer.write_all(b"\\=")?,
+                '\n' => writer.write_all(b"\\n")?,
+                '\r' => writer.write_all(b"\\r")?,
+                c => write!(writer, "{c}")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Writes a QRadar LEEF 1.0 record with tab separated attributes.
+pub fn write_leef<W: Write>(
+    writer: &mut W,
+    event: &Event,
+    anonymizer: Option<&IpAnonymizer>,
+) -> io::Result<()> {
+    let (event_id, _, severity) = header(event);
+    write!(writer, "LEEF:1.0|{VENDOR}|{PRODUCT}|{VERSION}|{event_id}|")?;
+
+    let timestamp = match event {
+        Event::Ban { timestamp, .. } | Event::Unban { timestamp, .. } => timestamp,
+    };
+    let mut attributes = vec![("devTime", millis_since_epoch(*timestamp).to_string())];
+    match source(event, anonymizer) {
+        Ok(ip) => {
+            attributes.push(("src", ip.addr().to_string()));
+            attributes.push(("srcPrefixLen", ip.prefix_len().to_string()));
+        }
+        Err(hashed) => attributes.push(("anonymizedSrc", hashed.to_string())),
+    }
+    attributes.push(("sev", severity.to_string()));
+    match event {
+        Event::Ban {
+            timeout,
+            recidivism,
+            category,
+            reason,
+            hostname,
+            ..
+        } => {
+            attributes.push(("cat", category.to_string()));
+            if let Some(reason) = reason {
+                attributes.push(("reason", reason.to_string()));
+            }
+            attributes.push(("recidivism", recidivism.to_string()));
+            attributes.push(("timeout", timeout.to_string()));
+            if let Some(hostname) = hostname {
+                attributes.push(("srcHostName", hostname.clone()));
+            }
+        }
+        Event::Unban { reason, .. } => attributes.push(("reason", reason.to_string())),
+    }
+
+    for (i, (key, value)) in attributes.iter().enumerate() {
+        if i > 0 {
+            writer.write_all(b"\t")?;
+        }
+        // LEEF has no escaping, so control characters (e.g. in hostnames
+        // from PTR records) are replaced to keep the record on one line.
+        let value: String = value
+            .chars()
+            .map(|c| if c.is_control() { ' ' } else { c })
+            .collect();
+        write!(writer, "{key}={value}")?;
+    }
+    Ok(())
+}
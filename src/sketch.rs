@@ -0,0 +1,155 @@
+use std::{
+    hash::{BuildHasher, BuildHasherDefault, Hash},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::debug;
+use rustc_hash::FxHasher;
+use serde::{Deserialize, Serialize};
+
+use crate::{clock::Clock, space_saving::SpaceSaving};
+
+const DEPTH: usize = 4;
+
+/// How the --sketch-width pre-filter estimates event counts.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SketchAlgorithm {
+    /// A count-min sketch, with 4 rows of --sketch-width counters of 4 bytes
+    /// each.
+    CountMin,
+    /// Space-Saving, with --sketch-width counters that each track one key.
+    /// Only counts the events that an IP certainly had, so unlike count-min
+    /// it never promotes early, even during floods of distinct IPs. But IPs
+    /// that lose their counter to others start over, so busy IPs can be
+    /// promoted late if there are too few counters.
+    SpaceSaving,
+}
+
+/// Pre-filters keys by their estimated event counts, see [`SketchAlgorithm`].
+pub enum Sketch<K> {
+    CountMin(CountMinSketch),
+    SpaceSaving {
+        counts: SpaceSaving<K>,
+        window: Duration,
+        window_start: Instant,
+        clock: Clock,
+    },
+}
+
+impl<K: Hash + Eq + Clone> Sketch<K> {
+    pub fn new(
+        algorithm: SketchAlgorithm,
+        width: usize,
+        window: Duration,
+        clock: Clock,
+    ) -> Sketch<K> {
+        match algorithm {
+            SketchAlgorithm::CountMin => {
+                Sketch::CountMin(CountMinSketch::new(width, window, clock))
+            }
+            SketchAlgorithm::SpaceSaving => Sketch::SpaceSaving {
+                counts: SpaceSaving::new(width),
+                window,
+                window_start: clock.now(),
+                clock,
+            },
+        }
+    }
+
+    /// Counts an event and returns the estimated number of events for the
+    /// key so far.
+    pub fn increment(&mut self, key: &K) -> u32 {
+        match self {
+            Sketch::CountMin(sketch) => sketch.increment(key),
+            Sketch::SpaceSaving { counts, .. } => counts.increment(key),
+        }
+    }
+
+    /// Halves the counts every window.
+    pub fn maybe_decay(&mut self) {
+        match self {
+            Sketch::CountMin(sketch) => sketch.maybe_decay(),
+            Sketch::SpaceSaving {
+                counts,
+                window,
+                window_start,
+                clock,
+            } => {
+                if clock.elapsed(*window_start) < *window {
+                    return;
+                }
+                counts.halve();
+                debug!("Decayed Space-Saving counters");
+                *window_start = clock.now();
+            }
+        }
+    }
+}
+
+/// Approximate event counts in fixed memory. Estimates are never too low,
+/// but colliding keys can make them too high. Counts are halved every
+/// `window`, so that old events are eventually forgotten.
+pub struct CountMinSketch {
+    counters: Vec<u32>,
+    width: usize,
+    hasher: BuildHasherDefault<FxHasher>,
+    window: Duration,
+    window_start: Instant,
+    clock: Clock,
+}
+
+impl CountMinSketch {
+    pub fn new(width: usize, window: Duration, clock: Clock) -> CountMinSketch {
+        let width = width.max(1);
+        CountMinSketch {
+            counters: vec![0; width * DEPTH],
+            width,
+            hasher: BuildHasherDefault::default(),
+            window,
+            window_start: clock.now(),
+            clock,
+        }
+    }
+
+    /// Counts an event and returns the estimated number of events for the
+    /// key so far. Uses conservative update, i.e. only increments the
+    /// smallest counters, which reduces overestimation.
+    pub fn increment<K: Hash>(&mut self, key: &K) -> u32 {
+        // FxHash is weak in the low bits, so mix before splitting the hash
+        // into two halves, from which the columns for all rows are derived.
+        let mut hash = self.hasher.hash_one(key);
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+        hash ^= hash >> 33;
+        let (h1, h2) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+        let mut indices = [0; DEPTH];
+        for (row, index) in indices.iter_mut().enumerate() {
+            let column = h1.wrapping_add((row as u64).wrapping_mul(h2)) % self.width as u64;
+            *index = row * self.width + column as usize;
+        }
+
+        let estimate = indices
+            .iter()
+            .map(|index| self.counters[*index])
+            .min()
+            .unwrap_or_default()
+            .saturating_add(1);
+        for index in indices {
+            self.counters[index] = self.counters[index].max(estimate);
+        }
+        estimate
+    }
+
+    pub fn maybe_decay(&mut self) {
+        if self.clock.elapsed(self.window_start) < self.window {
+            return;
+        }
+        for counter in &mut self.counters {
+            *counter /= 2;
+        }
+        debug!("Decayed count-min sketch");
+        self.window_start = self.clock.now();
+    }
+}
@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    hash::{BuildHasherDefault, Hash},
+};
+
+use rustc_hash::FxHasher;
+
+const NIL: u32 = u32::MAX;
+
+struct Slot<K> {
+    /// `None` until the slot is first used.
+    key: Option<K>,
+    /// The count that the key took over from the previous one, by which
+    /// its count may be too high.
+    error: u32,
+    bucket: u32,
+    prev: u32,
+    next: u32,
+}
+
+/// The slots with the same count.
+struct Bucket {
+    count: u32,
+    first: u32,
+    prev: u32,
+    next: u32,
+}
+
+/// Approximate event counts of the most frequent keys, with a fixed number
+/// of counters (Metwally et al., "Efficient Computation of Frequent and
+/// Top-k Elements in Data Streams"). A new key takes over the counter with
+/// the lowest count and continues from there, so estimates are never too
+/// low, but can be too high by up to that count. Also keeps that error, to
+/// tell how many events a key had at least.
+///
+/// Counters are kept in a stream summary, buckets of equal counts in
+/// ascending order, so that counting an event is O(1).
+pub struct SpaceSaving<K> {
+    slots: Vec<Slot<K>>,
+    buckets: Vec<Bucket>,
+    free_buckets: Vec<u32>,
+    /// The bucket with the lowest count.
+    min_bucket: u32,
+    index: HashMap<K, u32, BuildHasherDefault<FxHasher>>,
+}
+
+impl<K: Hash + Eq + Clone> SpaceSaving<K> {
+    pub fn new(capacity: usize) -> SpaceSaving<K> {
+        let capacity = u32::try_from(capacity.max(1)).unwrap_or(NIL - 1);
+        let slots = (0..capacity)
+            .map(|i| Slot {
+                key: None,
+                error: 0,
+                bucket: 0,
+                prev: i.checked_sub(1).unwrap_or(NIL),
+                next: if i + 1 < capacity { i + 1 } else { NIL },
+            })
+            .collect();
+        SpaceSaving {
+            slots,
+            buckets: vec![Bucket {
+                count: 0,
+                first: 0,
+                prev: NIL,
+                next: NIL,
+            }],
+            free_buckets: Vec::new(),
+            min_bucket: 0,
+            index: HashMap::with_capacity_and_hasher(capacity as usize, Default::default()),
+        }
+    }
+
+    /// Counts an event and returns the number of events that the key had
+    /// at least, i.e. since it last took over a counter.
+    pub fn increment(&mut self, key: &K) -> u32 {
+        let slot = match self.index.get(key) {
+            Some(slot) => *slot,
+            None => {
+                let min_bucket = &self.buckets[self.min_bucket as usize];
+                let (slot, min_count) = (min_bucket.first, min_bucket.count);
+                let entry = &mut self.slots[slot as usize];
+                entry.error = min_count;
+                if let Some(evicted) = entry.key.replace(key.clone()) {
+                    self.index.remove(&evicted);
+                }
+                self.index.insert(key.clone(), slot);
+                slot
+            }
+        };
+        self.bump(slot) - self.slots[slot as usize].error
+    }
+
+    /// Moves the slot to the bucket of the next higher count.
+    fn bump(&mut self, slot: u32) -> u32 {
+        let bucket = self.slots[slot as usize].bucket;
+        let Some(count) = self.buckets[bucket as usize].count.checked_add(1) else {
+            return u32::MAX;
+        };
+        let next = self.buckets[bucket as usize].next;
+        let target = if next != NIL && self.buckets[next as usize].count == count {
+            next
+        } else {
+            self.insert_bucket_after(bucket, count)
+        };
+        self.detach(slot);
+        self.attach(slot, target);
+        if self.buckets[bucket as usize].first == NIL {
+            self.remove_bucket(bucket);
+        }
+        count
+    }
+
+    /// Halves all counts, so that old events are eventually forgotten.
+    /// Buckets whose counts become equal are merged.
+    pub fn halve(&mut self) {
+        let mut bucket = self.min_bucket;
+        while bucket != NIL {
+            let next = self.buckets[bucket as usize].next;
+            self.buckets[bucket as usize].count /= 2;
+            let mut slot = self.buckets[bucket as usize].first;
+            while slot != NIL {
+                self.slots[slot as usize].error /= 2;
+                slot = self.slots[slot as usize].next;
+            }
+            let prev = self.buckets[bucket as usize].prev;
+            if prev != NIL
+                && self.buckets[prev as usize].count == self.buckets[bucket as usize].count
+            {
+                while self.buckets[bucket as usize].first != NIL {
+                    let slot = self.buckets[bucket as usize].first;
+                    self.detach(slot);
+                    self.attach(slot, prev);
+                }
+                self.remove_bucket(bucket);
+            }
+            bucket = next;
+        }
+    }
+
+    fn insert_bucket_after(&mut self, prev: u32, count: u32) -> u32 {
+        let next = self.buckets[prev as usize].next;
+        let bucket = Bucket {
+            count,
+            first: NIL,
+            prev,
+            next,
+        };
+        let index = match self.free_buckets.pop() {
+            Some(index) => {
+                self.buckets[index as usize] = bucket;
+                index
+            }
+            None => {
+                self.buckets.push(bucket);
+                (self.buckets.len() - 1) as u32
+            }
+        };
+        self.buckets[prev as usize].next = index;
+        if next != NIL {
+            self.buckets[next as usize].prev = index;
+        }
+        index
+    }
+
+    /// Unlinks an empty bucket.
+    fn remove_bucket(&mut self, bucket: u32) {
+        let Bucket { prev, next, .. } = self.buckets[bucket as usize];
+        if prev == NIL {
+            self.min_bucket = next;
+        } else {
+            self.buckets[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.buckets[next as usize].prev = prev;
+        }
+        self.free_buckets.push(bucket);
+    }
+
+    fn detach(&mut self, slot: u32) {
+        let Slot {
+            bucket, prev, next, ..
+        } = self.slots[slot as usize];
+        if prev == NIL {
+            self.buckets[bucket as usize].first = next;
+        } else {
+            self.slots[prev as usize].next = next;
+        }
+        if next != NIL {
+            self.slots[next as usize].prev = prev;
+        }
+    }
+
+    fn attach(&mut self, slot: u32, bucket: u32) {
+        let first = self.buckets[bucket as usize].first;
+        if first != NIL {
+            self.slots[first as usize].prev = slot;
+        }
+        let entry = &mut self.slots[slot as usize];
+        entry.bucket = bucket;
+        entry.prev = NIL;
+        entry.next = first;
+        self.buckets[bucket as usize].first = slot;
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::{ban_reason::BanReason, masked_ip::MaskedIpAddr};
+
+const HEADER_V1: &str = "leroyjenkins state v1";
+const HEADER_V2: &str = "leroyjenkins state v2";
+const HEADER_V3: &str = "leroyjenkins state v3";
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone)]
+pub struct Recidivism {
+    pub count: u32,
+    pub last_ban: SystemTime,
+    /// The reason of the last ban.
+    pub reason: Option<BanReason>,
+}
+
+#[derive(Debug, Default)]
+pub struct State {
+    pub recidivism: Vec<(MaskedIpAddr, Recidivism)>,
+    /// Active bans and when they expire.
+    pub bans: Vec<(MaskedIpAddr, SystemTime)>,
+}
+
+/// Atomically replaces the state file with one line per entry, either
+/// `recidivism <ip or network> <ban count> <unix time of last ban> [reason]`
+/// or `ban <ip or network> <unix time of expiry>`.
+pub fn save<'a, R, B>(path: &Path, recidivism: R, bans: B) -> io::Result<()>
+where
+    R: IntoIterator<Item = (&'a MaskedIpAddr, &'a Recidivism)>,
+    B: IntoIterator<Item = (&'a MaskedIpAddr, &'a SystemTime)>,
+{
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    writeln!(writer, "{HEADER_V3}")?;
+    for (net, recidivism) in recidivism {
+        let last_ban = unix_secs(recidivism.last_ban);
+        write!(writer, "recidivism {net} {} {last_ban}", recidivism.count)?;
+        if let Some(reason) = recidivism.reason {
+            write!(writer, " {reason}")?;
+        }
+        writeln!(writer)?;
+    }
+    for (net, expires) in bans {
+        writeln!(writer, "ban {net} {}", unix_secs(*expires))?;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(tmp_path, path)
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn from_unix_secs(s: &str) -> Option<SystemTime> {
+    UNIX_EPOCH.checked_add(Duration::from_secs(s.parse().ok()?))
+}
+
+/// Loads a state file written by [`save`]. A missing file is treated as
+/// empty, a file of an unknown version is ignored, and corrupt lines are
+/// skipped, so that a bad state file can never prevent startup.
+pub fn load(path: &Path) -> io::Result<State> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(State::default()),
+        Err(err) => return Err(err),
+    };
+
+    let mut lines = content.lines();
+    let v1 = match lines.next() {
+        Some(HEADER_V1) => true,
+        Some(HEADER_V2 | HEADER_V3) => false,
+        _ => {
+            warn!("Ignoring state file {path:?} with unknown format");
+            return Ok(State::default());
+        }
+    };
+
+    let mut state = State::default();
+    for (index, line) in lines.enumerate() {
+        // Version 1 only had recidivism lines, without the leading kind.
+        let parsed = match line.split_once(' ') {
+            _ if v1 => parse_recidivism(line).map(|entry| state.recidivism.push(entry)),
+            Some(("recidivism", rest)) => {
+                parse_recidivism(rest).map(|entry| state.recidivism.push(entry))
+            }
+            Some(("ban", rest)) => parse_ban(rest).map(|entry| state.bans.push(entry)),
+            _ => None,
+        };
+        if parsed.is_none() {
+            warn!("Skipping corrupt line {} of state file {path:?}", index + 2);
+        }
+    }
+    Ok(state)
+}
+
+fn parse_recidivism(line: &str) -> Option<(MaskedIpAddr, Recidivism)> {
+    let mut parts = line.split(' ');
+    let net = parts.next()?.parse().ok()?;
+    let count = parts.next()?.parse().ok()?;
+    let last_ban = from_unix_secs(parts.next()?)?;
+    // Only since version 3.
+    let reason = parts.next().map(str::parse).transpose().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((
+        net,
+        Recidivism {
+            count,
+            last_ban,
+            reason,
+        },
+    ))
+}
+
+fn parse_ban(line: &str) -> Option<(MaskedIpAddr, SystemTime)> {
+    let mut parts = line.split(' ');
+    let net = parts.next()?.parse().ok()?;
+    let expires = from_unix_secs(parts.next()?)?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((net, expires))
+}
@@ -0,0 +1,114 @@
+use std::{
+    error::Error,
+    fmt::Write as _,
+    net::{ToSocketAddrs, UdpSocket},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+
+use crate::metrics::Metrics;
+
+/// Sends metrics to a statsd (or DogStatsD, if there are tags) server.
+/// Counters are sent as the difference since the previous flush.
+pub struct Statsd {
+    socket: UdpSocket,
+    prefix: String,
+    /// Already formatted, like `|#host:edge1,env:prod`.
+    tags: String,
+    period: Duration,
+    flush_start: Instant,
+    flushed: Metrics,
+}
+
+impl Statsd {
+    pub fn new(
+        addr: &str,
+        prefix: &str,
+        tags: &[String],
+        period: Duration,
+    ) -> Result<Statsd, Box<dyn Error>> {
+        let addr = addr
+            .to_socket_addrs()
+            .map_err(|err| format!("Invalid statsd address {addr:?}: {err}"))?
+            .next()
+            .ok_or_else(|| format!("Statsd address {addr:?} did not resolve"))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        })?;
+        socket.connect(addr)?;
+        Ok(Statsd {
+            socket,
+            prefix: if prefix.is_empty() {
+                String::new()
+            } else {
+                format!("{prefix}.")
+            },
+            tags: if tags.is_empty() {
+                String::new()
+            } else {
+                format!("|#{}", tags.join(","))
+            },
+            period,
+            flush_start: Instant::now(),
+            flushed: Metrics::default(),
+        })
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.flush_start.elapsed() >= self.period
+    }
+
+    pub fn flush(&mut self, metrics: &Metrics, gauges: &[(&str, u64)]) {
+        let mut payload = String::new();
+        for ((name, value), (_, flushed)) in
+            metrics.counters().into_iter().zip(self.flushed.counters())
+        {
+            let _ = writeln!(
+                payload,
+                "{}{name}:{}|c{}",
+                self.prefix,
+                value - flushed,
+                self.tags
+            );
+        }
+        // A label where tags are understood, or else part of the name.
+        let by_reason = metrics.bans_by_reason.since(&self.flushed.bans_by_reason);
+        for (reason, count) in by_reason.iter().filter(|(_, count)| *count > 0) {
+            let _ = if self.tags.is_empty() {
+                writeln!(payload, "{}bans_by_reason.{reason}:{count}|c", self.prefix)
+            } else {
+                writeln!(
+                    payload,
+                    "{}bans_by_reason:{count}|c{},reason:{reason}",
+                    self.prefix, self.tags
+                )
+            };
+        }
+        for (name, value) in gauges {
+            let _ = writeln!(payload, "{}{name}:{value}|g{}", self.prefix, self.tags);
+        }
+        let latency = metrics.ipset_latency.since(&self.flushed.ipset_latency);
+        for (name, q) in [("ipset_latency.p50", 0.5), ("ipset_latency.p99", 0.99)] {
+            if let Some(latency) = latency.quantile(q) {
+                let _ = writeln!(
+                    payload,
+                    "{}{name}:{}|g{}",
+                    self.prefix,
+                    latency.as_micros(),
+                    self.tags
+                );
+            }
+        }
+        payload.pop(); // Trailing newline
+
+        if let Err(err) = self.socket.send(payload.as_bytes()) {
+            debug!("Failed to send metrics to statsd: {err}");
+        }
+
+        self.flushed = *metrics;
+        self.flush_start = Instant::now();
+    }
+}
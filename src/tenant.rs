@@ -0,0 +1,146 @@
+use std::{fmt, num::NonZeroU8, str::FromStr, time::Duration};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+const MAX_LEN: usize = 23;
+
+/// The site or customer that a line is about, like `shop` in
+/// `shop@192.0.2.1`, which picks its rate limit, ban time and ipsets, see
+/// --tenant-ipset.
+///
+/// Up to 23 ASCII letters, digits, `.`, `_` and `-`, stored inline like a
+/// [`BanReason`](crate::BanReason).
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Tenant {
+    len: NonZeroU8,
+    /// Zero after `len`, so that the derived comparisons work.
+    bytes: [u8; MAX_LEN],
+}
+
+impl Tenant {
+    pub fn as_str(&self) -> &str {
+        // Only ASCII is accepted.
+        std::str::from_utf8(&self.bytes[..usize::from(self.len.get())]).unwrap_or_default()
+    }
+
+    pub(crate) fn from_bytes(s: &[u8]) -> Result<Tenant, InvalidTenant> {
+        if s.len() > MAX_LEN
+            || !s
+                .iter()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'.' | b'_' | b'-'))
+        {
+            return Err(InvalidTenant);
+        }
+        let len = u8::try_from(s.len())
+            .ok()
+            .and_then(NonZeroU8::new)
+            .ok_or(InvalidTenant)?;
+        let mut bytes = [0; MAX_LEN];
+        bytes[..s.len()].copy_from_slice(s);
+        Ok(Tenant { len, bytes })
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct InvalidTenant;
+
+impl fmt::Display for InvalidTenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid tenant, expected up to {MAX_LEN} letters, digits, '.', '_' or '-'"
+        )
+    }
+}
+
+impl std::error::Error for InvalidTenant {}
+
+impl FromStr for Tenant {
+    type Err = InvalidTenant;
+
+    fn from_str(s: &str) -> Result<Tenant, InvalidTenant> {
+        Tenant::from_bytes(s.as_bytes())
+    }
+}
+
+impl fmt::Display for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Tenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The ipsets that bans of a tenant go to, see --tenant-ipset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantIpsets {
+    pub tenant: Tenant,
+    pub ipv4_name: String,
+    pub ipv6_name: String,
+}
+
+impl FromStr for TenantIpsets {
+    type Err = String;
+
+    /// Parses `TENANT=IPV4_SET,IPV6_SET`, e.g. `shop=leroy-shop4,leroy-shop6`.
+    fn from_str(s: &str) -> Result<TenantIpsets, String> {
+        let (tenant, names): (Tenant, String) = parse_tenant_value(s)?;
+        match names.split_once(',') {
+            Some((ipv4_name, ipv6_name)) if !ipv4_name.is_empty() && !ipv6_name.is_empty() => {
+                Ok(TenantIpsets {
+                    tenant,
+                    ipv4_name: ipv4_name.to_owned(),
+                    ipv6_name: ipv6_name.to_owned(),
+                })
+            }
+            _ => Err(format!("expected TENANT=IPV4_SET,IPV6_SET, got {s:?}")),
+        }
+    }
+}
+
+impl fmt::Display for TenantIpsets {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={},{}", self.tenant, self.ipv4_name, self.ipv6_name)
+    }
+}
+
+impl Serialize for TenantIpsets {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TenantIpsets {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<TenantIpsets, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Parses `TENANT=VALUE`, e.g. `shop=3`.
+pub fn parse_tenant_value<T>(s: &str) -> Result<(Tenant, T), String>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let (tenant, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected TENANT=VALUE, got {s:?}"))?;
+    Ok((
+        tenant
+            .parse()
+            .map_err(|err| format!("invalid tenant {tenant:?}: {err}"))?,
+        value
+            .parse()
+            .map_err(|err| format!("invalid value {value:?}: {err}"))?,
+    ))
+}
+
+pub fn parse_tenant_duration(s: &str) -> Result<(Tenant, Duration), String> {
+    parse_tenant_value::<humantime::Duration>(s).map(|(tenant, time)| (tenant, time.into()))
+}
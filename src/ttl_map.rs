@@ -0,0 +1,342 @@
+use std::{
+    borrow::Borrow,
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+    hash::{BuildHasherDefault, Hash},
+    time::{Duration, Instant},
+};
+
+use rustc_hash::FxHasher;
+
+use crate::clock::Clock;
+
+/// The cache of bans and recidivism counts, a [`TtlMap`] unless built with
+/// the `moka-caches` feature.
+#[cfg(not(feature = "moka-caches"))]
+pub type TtlCache<K, V> = TtlMap<K, V>;
+#[cfg(feature = "moka-caches")]
+pub type TtlCache<K, V> = mini_moka::unsync::Cache<K, V, BuildHasherDefault<FxHasher>>;
+
+/// Entries of a [`TtlMap`] get their time to live when inserted with
+/// [`insert_with_ttl`], so `ttl` is only for a mini-moka cache.
+#[cfg(not(feature = "moka-caches"))]
+pub fn ttl_cache<K: Hash + Eq + Clone, V>(
+    initial_capacity: usize,
+    max_capacity: u64,
+    _ttl: Option<Duration>,
+    clock: &Clock,
+) -> TtlCache<K, V> {
+    TtlMap::new(initial_capacity, max_capacity, clock.clone())
+}
+
+/// Expires entries by real time, not by `clock`.
+#[cfg(feature = "moka-caches")]
+pub fn ttl_cache<K: Hash + Eq, V>(
+    initial_capacity: usize,
+    max_capacity: u64,
+    ttl: Option<Duration>,
+    _clock: &Clock,
+) -> TtlCache<K, V> {
+    let builder = mini_moka::unsync::Cache::builder()
+        .initial_capacity(initial_capacity)
+        .max_capacity(max_capacity);
+    match ttl {
+        Some(ttl) => builder.time_to_live(ttl),
+        None => builder,
+    }
+    .build_with_hasher(Default::default())
+}
+
+/// See [`TtlMap::prewarm`]. Does nothing to a mini-moka cache.
+#[cfg(not(feature = "moka-caches"))]
+pub fn prewarm_cache<K: Hash + Eq + Clone, V: Clone>(
+    cache: &mut TtlCache<K, V>,
+    keys: impl Iterator<Item = K>,
+    value: V,
+) {
+    cache.prewarm(keys, value);
+}
+
+#[cfg(feature = "moka-caches")]
+pub fn prewarm_cache<K, V>(_cache: &mut TtlCache<K, V>, _keys: impl Iterator<Item = K>, _value: V) {
+}
+
+/// See [`TtlMap::insert_with_ttl`].
+#[cfg(not(feature = "moka-caches"))]
+pub fn insert_with_ttl<K: Hash + Eq + Clone, V>(
+    cache: &mut TtlCache<K, V>,
+    key: K,
+    value: V,
+    ttl: Duration,
+) {
+    cache.insert_with_ttl(key, value, ttl);
+}
+
+/// A mini-moka cache expires all entries after the `ttl` it was built with
+/// instead.
+#[cfg(feature = "moka-caches")]
+pub fn insert_with_ttl<K: Hash + Eq, V: Clone>(
+    cache: &mut TtlCache<K, V>,
+    key: K,
+    value: V,
+    _ttl: Duration,
+) {
+    cache.insert(key, value);
+}
+
+struct Entry<V> {
+    value: V,
+    /// `None` to never expire.
+    expires: Option<Instant>,
+    /// Matches the entry in the expiry order.
+    seq: u64,
+}
+
+impl<V> Entry<V> {
+    fn is_expired(&self, now: Instant) -> bool {
+        is_expired(self.expires, now)
+    }
+}
+
+fn is_expired(expires: Option<Instant>, now: Instant) -> bool {
+    expires.is_some_and(|expires| now >= expires)
+}
+
+/// A key in the expiry order.
+struct Expiry<K> {
+    expires: Option<Instant>,
+    seq: u64,
+    key: K,
+}
+
+impl<K> Expiry<K> {
+    /// Entries that never expire come last, and otherwise the oldest first.
+    fn rank(&self) -> (bool, Option<Instant>, u64) {
+        (self.expires.is_none(), self.expires, self.seq)
+    }
+}
+
+impl<K> PartialEq for Expiry<K> {
+    fn eq(&self, other: &Expiry<K>) -> bool {
+        self.rank() == other.rank()
+    }
+}
+
+impl<K> Eq for Expiry<K> {}
+
+impl<K> PartialOrd for Expiry<K> {
+    fn partial_cmp(&self, other: &Expiry<K>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Reversed, so that the heap pops the entry that expires first.
+impl<K> Ord for Expiry<K> {
+    fn cmp(&self, other: &Expiry<K>) -> Ordering {
+        other.rank().cmp(&self.rank())
+    }
+}
+
+/// A map with at most `max_capacity` entries, which expire after the time
+/// to live they were inserted with. Expired entries are found at the top of
+/// a heap in expiry order, without timers or scans. When full, the entry
+/// that expires first is evicted.
+///
+/// Has the methods of the mini-moka cache it replaces, which does the same
+/// with more bookkeeping per entry and lookup, but only with one time to
+/// live for all entries.
+#[cfg_attr(feature = "moka-caches", allow(dead_code))]
+pub struct TtlMap<K, V> {
+    entries: HashMap<K, Entry<V>, BuildHasherDefault<FxHasher>>,
+    /// Keys in expiry order. Keys that were inserted again or removed since
+    /// are skipped when their sequence number no longer matches.
+    order: BinaryHeap<Expiry<K>>,
+    next_seq: u64,
+    max_capacity: usize,
+    clock: Clock,
+}
+
+#[cfg_attr(feature = "moka-caches", allow(dead_code))]
+impl<K: Hash + Eq + Clone, V> TtlMap<K, V> {
+    pub fn new(initial_capacity: usize, max_capacity: u64, clock: Clock) -> TtlMap<K, V> {
+        let max_capacity = usize::try_from(max_capacity).unwrap_or(usize::MAX);
+        let initial_capacity = initial_capacity.min(max_capacity);
+        TtlMap {
+            entries: HashMap::with_capacity_and_hasher(initial_capacity, Default::default()),
+            order: BinaryHeap::with_capacity(initial_capacity),
+            next_seq: 0,
+            max_capacity,
+            clock,
+        }
+    }
+
+    pub fn get<Q>(&mut self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.clock.now();
+        self.entries
+            .get(key)
+            .filter(|entry| !entry.is_expired(now))
+            .map(|entry| &entry.value)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.clock.now();
+        self.entries
+            .get(key)
+            .is_some_and(|entry| !entry.is_expired(now))
+    }
+
+    /// Inserts or replaces the entry, which then expires `ttl` from now, or
+    /// never if that is too far in the future.
+    pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) {
+        if self.max_capacity == 0 {
+            return;
+        }
+        let now = self.clock.now();
+        self.expire(now);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let expires = now.checked_add(ttl);
+        let entry = Entry {
+            value,
+            expires,
+            seq,
+        };
+        if let Some(existing) = self.entries.get_mut(&key) {
+            *existing = entry;
+        } else {
+            if self.entries.len() >= self.max_capacity {
+                self.evict_first();
+            }
+            self.entries.insert(key.clone(), entry);
+        }
+        self.order.push(Expiry { expires, seq, key });
+        self.maybe_compact();
+    }
+
+    pub fn invalidate<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.entries.remove(key);
+        self.maybe_compact();
+    }
+
+    pub fn invalidate_entries_if(&mut self, mut predicate: impl FnMut(&K, &V) -> bool) {
+        self.entries
+            .retain(|key, entry| !predicate(key, &entry.value));
+        self.maybe_compact();
+    }
+
+    /// Includes expired entries that were not removed yet.
+    pub fn entry_count(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    /// The entries that have not expired, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let now = self.clock.now();
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| !entry.is_expired(now))
+            .map(|(key, entry)| (key, &entry.value))
+    }
+
+    /// Fills the map up to the capacity it was allocated with, using `keys`
+    /// and copies of `value`, and clears it again, so that its memory is
+    /// faulted in before it is needed. Only for an empty map.
+    pub fn prewarm(&mut self, keys: impl Iterator<Item = K>, value: V)
+    where
+        V: Clone,
+    {
+        let capacity = self.entries.capacity().min(self.max_capacity);
+        for key in keys.take(capacity) {
+            self.order.push(Expiry {
+                expires: None,
+                seq: 0,
+                key: key.clone(),
+            });
+            self.entries.insert(
+                key,
+                Entry {
+                    value: value.clone(),
+                    expires: None,
+                    seq: 0,
+                },
+            );
+        }
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn is_current(&self, expiry: &Expiry<K>) -> bool {
+        self.entries
+            .get(&expiry.key)
+            .is_some_and(|entry| entry.seq == expiry.seq)
+    }
+
+    /// Removes expired entries from the top of the expiry order.
+    fn expire(&mut self, now: Instant) {
+        while let Some(expiry) = self.order.peek() {
+            if self.is_current(expiry) {
+                if !is_expired(expiry.expires, now) {
+                    break;
+                }
+                self.entries.remove(&expiry.key);
+            }
+            self.order.pop();
+        }
+    }
+
+    fn evict_first(&mut self) {
+        while let Some(expiry) = self.order.pop() {
+            if self.is_current(&expiry) {
+                self.entries.remove(&expiry.key);
+                return;
+            }
+        }
+    }
+
+    /// Drops skipped keys from the expiry order once they make up more than
+    /// half of it, so that it stays proportional to the entries.
+    fn maybe_compact(&mut self) {
+        if self.order.len() > 2 * self.entries.len().max(1024) {
+            let entries = &self.entries;
+            self.order.retain(|expiry| {
+                entries
+                    .get(&expiry.key)
+                    .is_some_and(|entry| entry.seq == expiry.seq)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_expire_after_their_ttl() {
+        let clock = Clock::fake();
+        let mut map = TtlMap::new(16, 16, clock.clone());
+        map.insert_with_ttl("ban", 1, Duration::from_secs(60));
+        map.insert_with_ttl("forever", 2, Duration::MAX);
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(map.get("ban"), Some(&1));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(map.get("ban"), None);
+        assert!(!map.contains_key("ban"));
+        assert_eq!(map.get("forever"), Some(&2));
+        assert_eq!(map.iter().count(), 1);
+    }
+}
@@ -0,0 +1,60 @@
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// The JSON payload expected by the webhook.
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebhookFormat {
+    /// `{"text": ...}`, also understood by Mattermost and Matrix hookshot.
+    Slack,
+    /// `{"content": ...}`
+    Discord,
+}
+
+/// Posts messages to a chat webhook from a background thread.
+pub struct Webhook {
+    sender: SyncSender<String>,
+}
+
+impl Webhook {
+    pub fn new(url: String, format: WebhookFormat) -> std::io::Result<Webhook> {
+        let (sender, receiver) = mpsc::sync_channel::<String>(4);
+        thread::Builder::new()
+            .name("webhook".to_owned())
+            .spawn(move || {
+                let agent = ureq::AgentBuilder::new()
+                    .timeout(Duration::from_secs(10))
+                    .build();
+                for message in receiver {
+                    let body = match format {
+                        WebhookFormat::Slack => json!({ "text": message }),
+                        WebhookFormat::Discord => json!({ "content": message }),
+                    };
+                    if let Err(err) = agent
+                        .post(&url)
+                        .set("Content-Type", "application/json")
+                        .send_string(&body.to_string())
+                    {
+                        warn!("Failed to post to webhook: {err}");
+                    }
+                }
+            })?;
+        Ok(Webhook { sender })
+    }
+
+    pub fn notify(&self, message: String) {
+        match self.sender.try_send(message) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Skipped webhook notification, too many pending"),
+            Err(TrySendError::Disconnected(_)) => warn!("Webhook notifier has stopped"),
+        }
+    }
+}